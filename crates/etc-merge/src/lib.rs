@@ -4,8 +4,8 @@
 
 use fn_error_context::context;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-use std::ffi::OsStr;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
 use std::io::BufReader;
 use std::io::Write;
 use std::os::fd::{AsFd, AsRawFd};
@@ -22,7 +22,8 @@ use composefs::fsverity::{FsVerityHashValue, Sha256HashValue, Sha512HashValue};
 use composefs::generic_tree::{Directory, Inode, Leaf, LeafContent, Stat};
 use composefs::tree::ImageError;
 use rustix::fs::{
-    AtFlags, Gid, Uid, XattrFlags, lgetxattr, llistxattr, lsetxattr, readlinkat, symlinkat,
+    AtFlags, Gid, RenameFlags, Uid, XattrFlags, lgetxattr, llistxattr, lsetxattr, readlinkat,
+    renameat, renameat_with, symlinkat,
 };
 
 /// Metadata associated with a file, directory, or symlink entry.
@@ -91,6 +92,23 @@ pub struct Diff {
     removed: Vec<PathBuf>,
 }
 
+impl Diff {
+    /// Paths that exist in the current /etc but not in the pristine
+    pub fn added(&self) -> &[PathBuf] {
+        &self.added
+    }
+
+    /// Paths that exist in both pristine and current /etc but differ
+    pub fn modified(&self) -> &[PathBuf] {
+        &self.modified
+    }
+
+    /// Paths that exist in the pristine /etc but not in the current one
+    pub fn removed(&self) -> &[PathBuf] {
+        &self.removed
+    }
+}
+
 fn collect_all_files(
     root: &Directory<CustomMetadata>,
     current_path: PathBuf,
@@ -402,6 +420,90 @@ pub fn print_diff(diff: &Diff, writer: &mut impl Write) {
     }
 }
 
+/// The extended attribute holding the SELinux label.
+const SELINUX_XATTR: &str = "security.selinux";
+
+/// How a path differs between the pristine and the current /etc.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Modification {
+    /// Whether the file type, contents or symlink target changed
+    pub content: bool,
+    /// The pristine and current permission bits, if they differ
+    pub mode: Option<(u32, u32)>,
+    /// The pristine and current owner as `(uid, gid)`, if they differ
+    pub owner: Option<((u32, u32), (u32, u32))>,
+    /// The pristine and current SELinux label, if they differ
+    pub selinux_label: Option<(Option<String>, Option<String>)>,
+    /// The names of the other extended attributes which differ
+    pub xattrs: Vec<String>,
+}
+
+fn selinux_label(stat: &Stat) -> Option<String> {
+    let xattrs = stat.xattrs.borrow();
+    let label: &[u8] = xattrs.get(OsStr::new(SELINUX_XATTR))?;
+    let label = label.strip_suffix(&[0]).unwrap_or(label);
+    Some(String::from_utf8_lossy(label).into_owned())
+}
+
+/// Describes how a modified path, as found in [`Diff::modified`], differs between
+/// the pristine and the current /etc. Returns `None` if the path is missing from either.
+pub fn describe_modification(
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_files: &Directory<CustomMetadata>,
+    path: &Path,
+) -> Option<Modification> {
+    use composefs::generic_tree::LeafContent::*;
+
+    let pristine = lookup(pristine_etc_files, path)?;
+    let current = lookup(current_etc_files, path)?;
+
+    let content = match (pristine, current) {
+        (Inode::Directory(..), Inode::Directory(..)) => false,
+        (Inode::Leaf(pristine), Inode::Leaf(current)) => {
+            match (&pristine.content, &current.content) {
+                (Regular(pristine), Regular(current)) => {
+                    pristine.content_hash != current.content_hash
+                }
+                (Symlink(pristine), Symlink(current)) => pristine != current,
+                _ => true,
+            }
+        }
+        _ => true,
+    };
+
+    let (pristine, current) = (pristine.stat(), current.stat());
+
+    let mode = (pristine.st_mode & 0o7777, current.st_mode & 0o7777);
+    let owner = (
+        (pristine.st_uid, pristine.st_gid),
+        (current.st_uid, current.st_gid),
+    );
+    let selinux = (selinux_label(pristine), selinux_label(current));
+
+    let xattrs = {
+        let pristine = pristine.xattrs.borrow();
+        let current = current.xattrs.borrow();
+        let mut names = pristine
+            .keys()
+            .chain(current.keys())
+            .filter(|name| &***name != OsStr::new(SELINUX_XATTR))
+            .filter(|name| pristine.get(*name) != current.get(*name))
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+        names.dedup();
+        names
+    };
+
+    Some(Modification {
+        content,
+        mode: (mode.0 != mode.1).then_some(mode),
+        owner: (owner.0 != owner.1).then_some(owner),
+        selinux_label: (selinux.0 != selinux.1).then_some(selinux),
+        xattrs,
+    })
+}
+
 #[context("Collecting xattrs")]
 fn collect_xattrs(etc_fd: &CapStdDir, rel_path: impl AsRef<Path>) -> anyhow::Result<Xattrs> {
     let link = format!("/proc/self/fd/{}", etc_fd.as_fd().as_raw_fd());
@@ -561,10 +663,14 @@ fn recurse_dir(dir: &CapStdDir, root: &mut Directory<CustomMetadata>) -> anyhow:
     Ok(())
 }
 
-#[derive(Debug)]
-enum ModificationType {
+/// The kind of change made to a path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModificationType {
+    /// The path was added
     Added,
+    /// The path was modified
     Modified,
+    /// The path was removed
     Removed,
 }
 
@@ -584,6 +690,313 @@ impl ModificationType {
     }
 }
 
+/// A local change to a path which was also changed in the new /etc.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Conflict {
+    /// The path, relative to /etc
+    pub path: PathBuf,
+    /// How the path was changed locally
+    pub local: ModificationType,
+    /// How the conflict was resolved
+    pub resolution: Resolution,
+}
+
+/// What to do with a path which was changed both locally and upstream.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Keep the local version of the path
+    #[default]
+    KeepLocal,
+    /// Discard the local change in favor of the new default
+    TakeUpstream,
+    /// Merge `key=value` lines, falling back to keeping the local version
+    /// for anything other than a regular file modified on both sides
+    MergeLines,
+    /// Refuse to merge
+    Fail,
+}
+
+/// How a conflict was resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resolution {
+    /// The local version was kept
+    KeepLocal,
+    /// The new default was kept
+    TakeUpstream,
+    /// The local and new `key=value` lines were merged
+    MergeLines,
+}
+
+fn inode_eq(this: &Inode<CustomMetadata>, other: &Inode<CustomMetadata>) -> bool {
+    use composefs::generic_tree::LeafContent::*;
+
+    match (this, other) {
+        (Inode::Directory(this), Inode::Directory(other)) => {
+            stat_eq_ignore_mtime(&this.stat, &other.stat)
+        }
+
+        (Inode::Leaf(this), Inode::Leaf(other)) => {
+            if !stat_eq_ignore_mtime(&this.stat, &other.stat) {
+                return false;
+            }
+
+            match (&this.content, &other.content) {
+                (Regular(this), Regular(other)) => this.content_hash == other.content_hash,
+                (Symlink(this), Symlink(other)) => this == other,
+                _ => false,
+            }
+        }
+
+        _ => false,
+    }
+}
+
+fn lookup<'a>(
+    root: &'a Directory<CustomMetadata>,
+    path: &Path,
+) -> Option<&'a Inode<CustomMetadata>> {
+    // Any error here means some parent is missing or not a directory
+    let (dir, filename) = root.split(path.as_os_str()).ok()?;
+    dir.lookup(filename)
+}
+
+/// Finds the local changes in `diff` which shadow an upstream change, i.e. paths
+/// which also differ between the pristine and the new /etc.
+pub fn compute_conflicts(
+    pristine_etc_files: &Directory<CustomMetadata>,
+    new_etc_files: &Directory<CustomMetadata>,
+    diff: &Diff,
+) -> Vec<Conflict> {
+    let changes = [
+        (ModificationType::Added, &diff.added),
+        (ModificationType::Modified, &diff.modified),
+        (ModificationType::Removed, &diff.removed),
+    ];
+
+    let mut conflicts = vec![];
+
+    for (local, paths) in changes {
+        for path in paths {
+            let upstream_changed = match (
+                lookup(pristine_etc_files, path),
+                lookup(new_etc_files, path),
+            ) {
+                (Some(pristine), Some(new)) => !inode_eq(pristine, new),
+                (None, None) => false,
+                _ => true,
+            };
+
+            if upstream_changed {
+                conflicts.push(Conflict {
+                    path: path.clone(),
+                    local,
+                    resolution: Resolution::KeepLocal,
+                });
+            }
+        }
+    }
+
+    conflicts
+}
+
+/// Returns the key of a `key=value` line, or `None` for blank lines, comments
+/// and anything else.
+fn line_key(line: &str) -> Option<&str> {
+    let line = line.trim_start();
+    if line.starts_with(['#', ';']) {
+        return None;
+    }
+    let (key, _) = line.split_once('=')?;
+    Some(key.trim_end())
+}
+
+/// Maps each key to its lines, in order; a key may be repeated.
+fn key_lines(contents: &str) -> BTreeMap<&str, Vec<&str>> {
+    let mut r = BTreeMap::<_, Vec<_>>::new();
+    for line in contents.lines() {
+        if let Some(key) = line_key(line) {
+            r.entry(key).or_default().push(line);
+        }
+    }
+    r
+}
+
+/// Performs a three-way merge of files made of `key=value` lines.
+///
+/// The result is the new file, with the keys which were set or removed
+/// locally applied on top. Other lines, such as comments, are taken from the
+/// new file. A repeated key is treated as a unit, so all of its lines are
+/// taken from the same side. It is an error for a key to be changed
+/// differently on both sides.
+fn merge_key_value(pristine: &str, current: &str, new: &str) -> anyhow::Result<String> {
+    let pristine_keys = key_lines(pristine);
+    let current_keys = key_lines(current);
+    let new_keys = key_lines(new);
+
+    let keys = pristine_keys.keys().chain(current_keys.keys());
+    let mut local_changes = BTreeMap::new();
+    for &key in keys {
+        let local = current_keys.get(key).map(Vec::as_slice).unwrap_or_default();
+        let default = pristine_keys
+            .get(key)
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        if local == default {
+            continue;
+        }
+
+        let upstream = new_keys.get(key).map(Vec::as_slice).unwrap_or_default();
+        if upstream != default && upstream != local {
+            anyhow::bail!("Key {key} was changed both locally and upstream");
+        }
+
+        local_changes.insert(key, local);
+    }
+
+    let mut merged = vec![];
+    let mut replaced = BTreeSet::new();
+    for line in new.lines() {
+        match line_key(line).and_then(|key| local_changes.get_key_value(key)) {
+            // All the local lines for a key go where it first appears
+            Some((key, local)) => {
+                if replaced.insert(*key) {
+                    merged.extend(local.iter().copied());
+                }
+            }
+            None => merged.push(line),
+        }
+    }
+
+    // Keys added locally which do not exist in the new file
+    let added = current.lines().filter(|line| {
+        line_key(line)
+            .is_some_and(|key| local_changes.contains_key(key) && !new_keys.contains_key(key))
+    });
+    merged.extend(added);
+
+    let mut merged = merged.join("\n");
+    if !merged.is_empty() && (new.ends_with('\n') || new.is_empty()) {
+        merged.push('\n');
+    }
+
+    Ok(merged)
+}
+
+/// Merges the `key=value` lines of a regular file which was modified both
+/// locally and upstream, returning the merged contents. Returns `None` if the
+/// file is not a regular file on all sides.
+fn merge_lines(
+    pristine_etc_fd: &CapStdDir,
+    current_etc_fd: &CapStdDir,
+    new_etc_fd: &CapStdDir,
+    path: &Path,
+) -> anyhow::Result<Option<String>> {
+    for fd in [pristine_etc_fd, current_etc_fd, new_etc_fd] {
+        if !fd.symlink_metadata(path)?.is_file() {
+            return Ok(None);
+        }
+    }
+
+    merge_key_value(
+        &pristine_etc_fd.read_to_string(path)?,
+        &current_etc_fd.read_to_string(path)?,
+        &new_etc_fd.read_to_string(path)?,
+    )
+    .map(Some)
+}
+
+/// Returns an error listing all the `conflicts` which have the [`Policy::Fail`] policy.
+///
+/// This is also done by [`resolve_conflicts`], but can be used on its own to find out
+/// whether a merge would fail without writing anything.
+pub fn check_fail_policies(
+    conflicts: &[Conflict],
+    policy: impl Fn(&Path) -> Policy,
+) -> anyhow::Result<()> {
+    let failed = conflicts
+        .iter()
+        .filter(|c| policy(&c.path) == Policy::Fail)
+        .map(|c| format!("{} ({})", c.path.display(), c.local))
+        .collect::<Vec<_>>();
+    if !failed.is_empty() {
+        anyhow::bail!(
+            "Local changes conflict with the new /etc: {}",
+            failed.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Applies a policy to each of the `conflicts`, as returned by [`compute_conflicts`].
+///
+/// If any conflict has the [`Policy::Fail`] policy, or a file can't be merged line
+/// by line, an error listing all such paths is returned without making any changes.
+/// Otherwise the local changes which are not to be kept are dropped from `diff`,
+/// and files merged line by line are written to `new_etc_fd`; the rest of the
+/// local changes should then be applied with [`merge`].
+#[context("Resolving conflicts")]
+pub fn resolve_conflicts(
+    pristine_etc_fd: &CapStdDir,
+    current_etc_fd: &CapStdDir,
+    new_etc_fd: &CapStdDir,
+    diff: &mut Diff,
+    conflicts: &mut [Conflict],
+    policy: impl Fn(&Path) -> Policy,
+) -> anyhow::Result<()> {
+    check_fail_policies(conflicts, &policy)?;
+
+    // Compute all the line merges before writing anything
+    let mut merged_lines = BTreeMap::new();
+    let mut unmergeable = Vec::new();
+    for conflict in conflicts.iter() {
+        if policy(&conflict.path) != Policy::MergeLines
+            || conflict.local != ModificationType::Modified
+        {
+            continue;
+        }
+        match merge_lines(pristine_etc_fd, current_etc_fd, new_etc_fd, &conflict.path) {
+            Ok(Some(merged)) => {
+                merged_lines.insert(conflict.path.clone(), merged);
+            }
+            Ok(None) => {}
+            Err(e) => unmergeable.push(format!("{}: {e:#}", conflict.path.display())),
+        }
+    }
+    if !unmergeable.is_empty() {
+        anyhow::bail!("Failed to merge lines: {}", unmergeable.join(", "));
+    }
+
+    for conflict in conflicts.iter_mut() {
+        let resolution = match policy(&conflict.path) {
+            Policy::KeepLocal | Policy::Fail => Resolution::KeepLocal,
+            Policy::TakeUpstream => Resolution::TakeUpstream,
+            Policy::MergeLines => match merged_lines.get(&conflict.path) {
+                Some(merged) => {
+                    // This keeps the metadata of the new file
+                    new_etc_fd
+                        .write(&conflict.path, merged)
+                        .with_context(|| format!("Writing {:?}", conflict.path))?;
+                    Resolution::MergeLines
+                }
+                None => Resolution::KeepLocal,
+            },
+        };
+
+        if resolution != Resolution::KeepLocal {
+            let paths = match conflict.local {
+                ModificationType::Added => &mut diff.added,
+                ModificationType::Modified => &mut diff.modified,
+                ModificationType::Removed => &mut diff.removed,
+            };
+            paths.retain(|p| *p != conflict.path);
+        }
+
+        conflict.resolution = resolution;
+    }
+
+    Ok(())
+}
+
 fn create_dir_with_perms(
     new_etc_fd: &CapStdDir,
     dir_name: &PathBuf,
@@ -648,30 +1061,72 @@ fn merge_leaf(
         anyhow::bail!("Modified config file {file:?} newly defaults to directory. Cannot merge")
     };
 
-    // If a new file with the same path exists, we delete it
+    // The file is created under a temporary name and then renamed over the existing
+    // one, so that the path never goes missing or is left half written
+    let tmp = tmp_path(file)?;
     new_etc_fd
-        .remove_all_optional(&file)
-        .context(format!("Deleting {file:?}"))?;
+        .remove_all_optional(&tmp)
+        .context(format!("Deleting {tmp:?}"))?;
+
+    let res = (|| -> anyhow::Result<()> {
+        if let Some(target) = symlink {
+            // Using rustix's symlinkat here as we might have absolute symlinks which clash with ambient_authority
+            symlinkat(&**target, new_etc_fd, &tmp).context(format!("Creating symlink {file:?}"))?;
+        } else {
+            current_etc_fd
+                .copy(&file, new_etc_fd, &tmp)
+                .with_context(|| format!("Copying file {file:?}"))?;
+        };
 
-    if let Some(target) = symlink {
-        // Using rustix's symlinkat here as we might have absolute symlinks which clash with ambient_authority
-        symlinkat(&**target, new_etc_fd, file).context(format!("Creating symlink {file:?}"))?;
-    } else {
-        current_etc_fd
-            .copy(&file, new_etc_fd, &file)
-            .with_context(|| format!("Copying file {file:?}"))?;
-    };
+        rustix::fs::chownat(
+            &new_etc_fd,
+            &tmp,
+            Some(Uid::from_raw(leaf.stat.st_uid)),
+            Some(Gid::from_raw(leaf.stat.st_gid)),
+            AtFlags::SYMLINK_NOFOLLOW,
+        )
+        .context(format!("chown {file:?}"))?;
 
-    rustix::fs::chownat(
-        &new_etc_fd,
-        file,
-        Some(Uid::from_raw(leaf.stat.st_uid)),
-        Some(Gid::from_raw(leaf.stat.st_gid)),
-        AtFlags::SYMLINK_NOFOLLOW,
-    )
-    .context(format!("chown {file:?}"))?;
+        copy_xattrs(&leaf.stat.xattrs, new_etc_fd, &tmp)?;
+
+        replace_path(new_etc_fd, &tmp, file)
+    })();
+    if res.is_err() {
+        let _ = new_etc_fd.remove_all_optional(&tmp);
+    }
+
+    res
+}
 
-    copy_xattrs(&leaf.stat.xattrs, new_etc_fd, file)?;
+/// A temporary name for `path` in the same directory, used to replace it atomically.
+fn tmp_path(path: &Path) -> anyhow::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("Invalid path {path:?}"))?;
+    let mut tmp = OsString::from(".");
+    tmp.push(name);
+    tmp.push(".etc-merge-tmp");
+    Ok(path.with_file_name(tmp))
+}
+
+/// Atomically replaces `path` with `tmp`. A directory can't be renamed over or onto
+/// a file, so in that case the two are exchanged and the old `path` is then removed.
+fn replace_path(etc_fd: &CapStdDir, tmp: &Path, path: &Path) -> anyhow::Result<()> {
+    let exchange = match etc_fd.symlink_metadata_optional(path)? {
+        Some(existing) => existing.is_dir() || etc_fd.symlink_metadata(tmp)?.is_dir(),
+        None => false,
+    };
+
+    if exchange {
+        renameat_with(etc_fd, tmp, etc_fd, path, RenameFlags::EXCHANGE)
+            .with_context(|| format!("Exchanging {tmp:?} and {path:?}"))?;
+        etc_fd
+            .remove_all_optional(tmp)
+            .with_context(|| format!("Deleting {tmp:?}"))?;
+    } else {
+        renameat(etc_fd, tmp, etc_fd, path)
+            .with_context(|| format!("Renaming {tmp:?} to {path:?}"))?;
+    }
 
     Ok(())
 }
@@ -778,6 +1233,155 @@ pub fn merge(
     Ok(())
 }
 
+/// Removes the extended attributes of `path` which are not in `wanted`.
+fn remove_extra_xattrs(
+    current: &Xattrs,
+    wanted: &Xattrs,
+    etc_fd: &CapStdDir,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let wanted = wanted.borrow();
+    for attr in current.borrow().keys() {
+        if wanted.contains_key(attr) {
+            continue;
+        }
+        let fdpath = &Path::new(&format!("/proc/self/fd/{}", etc_fd.as_raw_fd())).join(path);
+        rustix::fs::lremovexattr(fdpath, attr.as_ref())
+            .with_context(|| format!("removexattr {attr:?} for {fdpath:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Copies `path` and everything below it from the pristine /etc.
+fn restore_tree(
+    pristine_etc_fd: &CapStdDir,
+    current_etc_fd: &CapStdDir,
+    inode: &Inode<CustomMetadata>,
+    path: &PathBuf,
+) -> anyhow::Result<()> {
+    match inode {
+        Inode::Directory(dir) => {
+            let existing = current_etc_fd.symlink_metadata_optional(path)?;
+            if existing.as_ref().is_some_and(|m| !m.is_dir()) {
+                // Replace the file with the directory in one go
+                let tmp = tmp_path(path)?;
+                current_etc_fd
+                    .remove_all_optional(&tmp)
+                    .context(format!("Deleting {tmp:?}"))?;
+                create_dir_with_perms(current_etc_fd, &tmp, &dir.stat, None)?;
+                replace_path(current_etc_fd, &tmp, path)?;
+            } else {
+                // The inode is only used to tell that the directory exists already
+                create_dir_with_perms(current_etc_fd, path, &dir.stat, existing.map(|_| inode))?;
+            }
+
+            for (name, child) in dir.sorted_entries() {
+                restore_tree(pristine_etc_fd, current_etc_fd, child, &path.join(name))?;
+            }
+        }
+
+        Inode::Leaf(leaf) => merge_leaf(pristine_etc_fd, current_etc_fd, leaf, None, path)?,
+    }
+
+    Ok(())
+}
+
+/// Restores `path`, and everything below it, to the pristine /etc; missing parent
+/// directories are restored as well.
+fn restore_path(
+    pristine_etc_fd: &CapStdDir,
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_fd: &CapStdDir,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let mut parent = PathBuf::new();
+    for component in path.parent().into_iter().flat_map(|p| p.components()) {
+        parent.push(component);
+        if current_etc_fd.try_exists(&parent)? {
+            continue;
+        }
+        let Some(Inode::Directory(dir)) = lookup(pristine_etc_files, &parent) else {
+            anyhow::bail!("{parent:?} is not a directory in the pristine /etc");
+        };
+        create_dir_with_perms(current_etc_fd, &parent, &dir.stat, None)?;
+    }
+
+    let inode = lookup(pristine_etc_files, path)
+        .ok_or_else(|| anyhow::anyhow!("{path:?} not found in the pristine /etc"))?;
+    restore_tree(pristine_etc_fd, current_etc_fd, inode, &path.to_owned())
+}
+
+/// Restores `paths` (relative to /etc), and everything below them, to their state in the
+/// pristine /etc, including their metadata and extended attributes. This removes the paths
+/// which were added locally, and restores those which were modified or removed.
+///
+/// Returns the local changes which were reverted, or with `dry_run`, which would be.
+#[context("Reverting")]
+pub fn revert(
+    pristine_etc_fd: &CapStdDir,
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_fd: &CapStdDir,
+    current_etc_files: &Directory<CustomMetadata>,
+    diff: &Diff,
+    paths: &[PathBuf],
+    dry_run: bool,
+) -> anyhow::Result<Vec<(ModificationType, PathBuf)>> {
+    let selected = |p: &&PathBuf| paths.iter().any(|path| p.starts_with(path));
+    let mut reverted = vec![];
+
+    for added in diff.added.iter().filter(selected) {
+        if !dry_run {
+            current_etc_fd
+                .remove_all_optional(added)
+                .with_context(|| format!("Removing {added:?}"))?;
+        }
+        reverted.push((ModificationType::Added, added.clone()));
+    }
+
+    for modified in diff.modified.iter().filter(selected) {
+        if !dry_run {
+            let pristine = lookup(pristine_etc_files, modified)
+                .ok_or_else(|| anyhow::anyhow!("{modified:?} not found"))?;
+            match (pristine, lookup(current_etc_files, modified)) {
+                // Only restore the metadata of the directory itself
+                (Inode::Directory(pristine), Some(current @ Inode::Directory(..))) => {
+                    create_dir_with_perms(current_etc_fd, modified, &pristine.stat, Some(current))?;
+                    remove_extra_xattrs(
+                        &current.stat().xattrs,
+                        &pristine.stat.xattrs,
+                        current_etc_fd,
+                        modified,
+                    )?;
+                }
+                _ => restore_tree(pristine_etc_fd, current_etc_fd, pristine, modified)?,
+            }
+        }
+        reverted.push((ModificationType::Modified, modified.clone()));
+    }
+
+    for removed in diff.removed.iter() {
+        // Either the whole removed path was selected, or only part of it
+        let path = if selected(&removed) {
+            removed
+        } else if let Some(path) = paths.iter().find(|p| p.starts_with(removed)) {
+            path
+        } else {
+            continue;
+        };
+        if lookup(pristine_etc_files, path).is_none() {
+            continue;
+        }
+        if !dry_run {
+            restore_path(pristine_etc_fd, pristine_etc_files, current_etc_fd, path)
+                .with_context(|| format!("Restoring {path:?}"))?;
+        }
+        reverted.push((ModificationType::Removed, path.clone()));
+    }
+
+    Ok(reverted)
+}
+
 #[cfg(test)]
 mod tests {
     use cap_std::fs::PermissionsExt;
@@ -1095,6 +1699,406 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_conflicts() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+        tempdir.create_dir("new_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+
+        for d in [&p, &c, &n] {
+            d.write("unchanged", "unchanged")?;
+        }
+
+        // Modified locally, unchanged upstream
+        for d in [&p, &n] {
+            d.write("local-only", "default")?;
+        }
+        c.write("local-only", "local")?;
+
+        // Modified locally and upstream
+        p.write("both", "default")?;
+        c.write("both", "local")?;
+        n.write("both", "upstream")?;
+
+        // Permissions changed upstream
+        p.write("perms", "default")?;
+        c.write("perms", "local")?;
+        n.atomic_write_with_perms("perms", "default", Permissions::from_mode(0o600))?;
+
+        // Removed locally, changed upstream
+        p.write("removed", "default")?;
+        n.write("removed", "upstream")?;
+
+        // Added locally and upstream
+        c.write("added", "local")?;
+        n.write("added", "upstream")?;
+
+        // Added locally only
+        c.write("added-local", "local")?;
+
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc(&p, &c, Some(&n))?;
+        let new_etc_files = new_etc_files.unwrap();
+        let diff = compute_diff(&pristine_etc_files, &current_etc_files, &new_etc_files)?;
+
+        let conflicts = compute_conflicts(&pristine_etc_files, &new_etc_files, &diff);
+        assert_eq!(
+            conflicts,
+            [
+                ("added", ModificationType::Added),
+                ("both", ModificationType::Modified),
+                ("perms", ModificationType::Modified),
+                ("removed", ModificationType::Removed),
+            ]
+            .map(|(path, local)| Conflict {
+                path: path.into(),
+                local,
+                resolution: Resolution::KeepLocal,
+            })
+        );
+
+        check_fail_policies(&conflicts, |_| Policy::KeepLocal)?;
+        let err = check_fail_policies(&conflicts, |path| match path.to_str() {
+            Some("both" | "removed") => Policy::Fail,
+            _ => Policy::TakeUpstream,
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Local changes conflict with the new /etc: both (Modified), removed (Removed)"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_key_value() {
+        let cases = [
+            // Local and upstream changes to different keys
+            (
+                "a=1\nb=2\n",
+                "a=10\nb=2\n",
+                "# comment\na=1\nb=20\n",
+                Some("# comment\na=10\nb=20\n"),
+            ),
+            // Keys added and removed locally
+            (
+                "a=1\nb=2\n",
+                "b=2\nc=3\n",
+                "a=1\nb=2\nd=4\n",
+                Some("b=2\nd=4\nc=3\n"),
+            ),
+            // The same change on both sides
+            ("a=1\n", "a = 2\n", "a = 2\n", Some("a = 2\n")),
+            // Repeated keys are kept, and replaced as a whole
+            (
+                "a=1\nlisten=x\nb=2\n",
+                "a=1\nlisten=x\nb=2\nlisten=y\n",
+                "a=10\nlisten=x\nb=2\n",
+                Some("a=10\nlisten=x\nlisten=y\nb=2\n"),
+            ),
+            (
+                "listen=x\nlisten=y\n",
+                "listen=x\n",
+                "# comment\nlisten=x\nlisten=y\n",
+                Some("# comment\nlisten=x\n"),
+            ),
+            // Conflicting changes to the same key
+            ("a=1\n", "a=2\n", "a=3\n", None),
+            ("listen=x\n", "listen=x\nlisten=y\n", "listen=z\n", None),
+            ("a=1\n", "", "a=3\n", None),
+            ("", "a=2\n", "a=3\n", None),
+        ];
+        for (pristine, current, new, expected) in cases {
+            let merged = merge_key_value(pristine, current, new).ok();
+            assert_eq!(merged.as_deref(), expected, "{current:?} {new:?}");
+        }
+    }
+
+    #[test]
+    fn test_resolve_conflicts() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+        tempdir.create_dir("new_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+
+        for name in ["keep", "upstream", "lines", "fail"] {
+            p.write(name, "a=1\nb=2\n")?;
+            c.write(name, "a=10\nb=2\n")?;
+            n.write(name, "a=1\nb=20\n")?;
+        }
+
+        let policy = |path: &Path| match path.to_str().unwrap() {
+            "upstream" => Policy::TakeUpstream,
+            "lines" => Policy::MergeLines,
+            "fail" => Policy::Fail,
+            _ => Policy::KeepLocal,
+        };
+
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc(&p, &c, Some(&n))?;
+        let new_etc_files = new_etc_files.unwrap();
+        let mut diff = compute_diff(&pristine_etc_files, &current_etc_files, &new_etc_files)?;
+        let mut conflicts = compute_conflicts(&pristine_etc_files, &new_etc_files, &diff);
+        assert_eq!(conflicts.len(), 4);
+
+        let err = resolve_conflicts(&p, &c, &n, &mut diff, &mut conflicts, policy).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Local changes conflict with the new /etc: fail (Modified)"
+        );
+        assert_eq!(n.read_to_string("lines")?, "a=1\nb=20\n");
+
+        c.write("fail", "a=1\nb=2\n")?;
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc(&p, &c, Some(&n))?;
+        let new_etc_files = new_etc_files.unwrap();
+        let mut diff = compute_diff(&pristine_etc_files, &current_etc_files, &new_etc_files)?;
+        let mut conflicts = compute_conflicts(&pristine_etc_files, &new_etc_files, &diff);
+        resolve_conflicts(&p, &c, &n, &mut diff, &mut conflicts, policy)?;
+        assert_eq!(
+            conflicts
+                .iter()
+                .map(|c| (c.path.to_str().unwrap(), c.resolution))
+                .collect::<Vec<_>>(),
+            [
+                ("keep", Resolution::KeepLocal),
+                ("lines", Resolution::MergeLines),
+                ("upstream", Resolution::TakeUpstream),
+            ]
+        );
+
+        merge(&c, &current_etc_files, &n, &new_etc_files, &diff)?;
+        assert_eq!(n.read_to_string("keep")?, "a=10\nb=2\n");
+        assert_eq!(n.read_to_string("lines")?, "a=10\nb=20\n");
+        assert_eq!(n.read_to_string("upstream")?, "a=1\nb=20\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_conflicts_unmergeable() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+        tempdir.create_dir("new_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+
+        // Sorted before the file which fails to merge
+        p.write("a-lines", "a=1\nb=2\n")?;
+        c.write("a-lines", "a=10\nb=2\n")?;
+        n.write("a-lines", "a=1\nb=20\n")?;
+        p.write("b-lines", "a=1\n")?;
+        c.write("b-lines", "a=2\n")?;
+        n.write("b-lines", "a=3\n")?;
+
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc(&p, &c, Some(&n))?;
+        let new_etc_files = new_etc_files.unwrap();
+        let mut diff = compute_diff(&pristine_etc_files, &current_etc_files, &new_etc_files)?;
+        let mut conflicts = compute_conflicts(&pristine_etc_files, &new_etc_files, &diff);
+        assert_eq!(conflicts.len(), 2);
+
+        let err = resolve_conflicts(&p, &c, &n, &mut diff, &mut conflicts, |_| {
+            Policy::MergeLines
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            "Failed to merge lines: b-lines: Key a was changed both locally and upstream"
+        );
+        // Nothing was written
+        assert_eq!(n.read_to_string("a-lines")?, "a=1\nb=20\n");
+        assert_eq!(diff.modified.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_modification() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+
+        p.write("content", "default")?;
+        c.write("content", "local")?;
+
+        p.write("perms", "default")?;
+        c.atomic_write_with_perms("perms", "default", Permissions::from_mode(0o600))?;
+
+        p.symlink("target", "link")?;
+        c.symlink("other-target", "link")?;
+
+        let (pristine_etc_files, current_etc_files, _) = traverse_etc(&p, &c, None)?;
+        let diff = compute_diff(
+            &pristine_etc_files,
+            &current_etc_files,
+            &Directory::new(Stat::uninitialized()),
+        )?;
+        assert_eq!(
+            diff.modified(),
+            ["content", "link", "perms"].map(PathBuf::from)
+        );
+
+        let describe = |path: &str| {
+            describe_modification(&pristine_etc_files, &current_etc_files, path.as_ref())
+        };
+
+        for path in ["content", "link"] {
+            let m = describe(path).unwrap();
+            assert!(m.content, "{path}");
+            assert_eq!(m.mode, None, "{path}");
+        }
+
+        let m = describe("perms").unwrap();
+        assert!(!m.content);
+        assert_eq!(m.mode.map(|(_, current)| current), Some(0o600));
+        assert_eq!(m.owner, None);
+        assert_eq!(m.selinux_label, None);
+        assert!(m.xattrs.is_empty());
+
+        assert_eq!(describe("missing"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_revert() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+
+        for d in [&p, &c] {
+            d.create_dir("a")?;
+            d.write("a/file", "default")?;
+            d.write("perms", "default")?;
+        }
+        p.write("modified", "default")?;
+        c.write("modified", "local")?;
+        c.set_permissions("perms", Permissions::from_mode(0o600))?;
+        c.write("added", "local")?;
+        c.write("a/added", "local")?;
+        p.create_dir_all("removed/sub")?;
+        p.write("removed/sub/file", "default")?;
+
+        let diff_etc = || -> anyhow::Result<_> {
+            let (pristine_etc_files, current_etc_files, _) = traverse_etc(&p, &c, None)?;
+            let diff = compute_diff(
+                &pristine_etc_files,
+                &current_etc_files,
+                &Directory::new(Stat::uninitialized()),
+            )?;
+            Ok((pristine_etc_files, current_etc_files, diff))
+        };
+
+        let paths = ["added", "modified", "perms", "removed/sub/file"].map(PathBuf::from);
+        let expected = [
+            (ModificationType::Added, "added"),
+            (ModificationType::Modified, "modified"),
+            (ModificationType::Modified, "perms"),
+            (ModificationType::Removed, "removed/sub/file"),
+        ]
+        .map(|(t, p)| (t, PathBuf::from(p)));
+
+        let (pristine_etc_files, current_etc_files, diff) = diff_etc()?;
+        let reverted = revert(
+            &p,
+            &pristine_etc_files,
+            &c,
+            &current_etc_files,
+            &diff,
+            &paths,
+            true,
+        )?;
+        assert_eq!(reverted, expected);
+        assert_eq!(c.read_to_string("modified")?, "local");
+        assert!(c.try_exists("added")?);
+
+        let reverted = revert(
+            &p,
+            &pristine_etc_files,
+            &c,
+            &current_etc_files,
+            &diff,
+            &paths,
+            false,
+        )?;
+        assert_eq!(reverted, expected);
+        assert_eq!(c.read_to_string("modified")?, "default");
+        assert_eq!(c.read_to_string("removed/sub/file")?, "default");
+        assert!(files_eq(&p, &c, "perms")?);
+        assert!(!c.try_exists("added")?);
+
+        let (_, _, diff) = diff_etc()?;
+        assert_eq!(diff.added(), [PathBuf::from("a/added")]);
+        assert!(diff.modified().is_empty());
+        assert!(diff.removed().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+
+        p.write("file", "default")?;
+        p.write("dir-to-file", "default")?;
+        p.create_dir("file-to-dir")?;
+        p.write("file-to-dir/file", "default")?;
+        c.write("file", "local")?;
+        c.create_dir_all("dir-to-file/sub")?;
+        c.write("file-to-dir", "local")?;
+
+        let (pristine_etc_files, _, _) = traverse_etc(&p, &c, None)?;
+        for name in ["file", "dir-to-file", "file-to-dir"] {
+            let inode = lookup(&pristine_etc_files, Path::new(name)).unwrap();
+            restore_tree(&p, &c, inode, &PathBuf::from(name))?;
+        }
+
+        assert_eq!(c.read_to_string("file")?, "default");
+        assert_eq!(c.read_to_string("dir-to-file")?, "default");
+        assert_eq!(c.read_to_string("file-to-dir/file")?, "default");
+
+        // No temporary files are left behind
+        let mut names = c
+            .entries()?
+            .map(|e| Ok(e?.file_name().into_string().unwrap()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        names.sort();
+        assert_eq!(names, ["dir-to-file", "file", "file-to-dir"]);
+
+        Ok(())
+    }
+
     #[test]
     fn file_to_dir() -> anyhow::Result<()> {
         let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
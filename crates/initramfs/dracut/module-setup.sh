@@ -13,6 +13,9 @@ depends() {
 install() {
     local service=bootc-root-setup.service
     dracut_install /usr/lib/bootc/initramfs-setup
+    if [ -f /usr/lib/composefs/setup-root-conf.toml ]; then
+        inst_simple /usr/lib/composefs/setup-root-conf.toml
+    fi
     inst_simple "${systemdsystemunitdir}/${service}"
     mkdir -p "${initdir}${systemdsystemconfdir}/initrd-root-fs.target.wants"
     ln_r "${systemdsystemunitdir}/${service}" \
@@ -1,11 +1,12 @@
 //! Mount helpers for bootc-initramfs
 
 use std::{
+    collections::BTreeSet,
     ffi::{CString, OsString},
     fmt::Debug,
     io::ErrorKind,
     os::fd::{AsFd, AsRawFd, OwnedFd},
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 use anyhow::{Context, Result};
@@ -22,7 +23,7 @@ use rustix::{
     path,
 };
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use cfsctl::composefs;
 use cfsctl::composefs_boot;
@@ -81,44 +82,182 @@ fn set_mount_readonly(fd: impl AsFd) -> Result<()> {
     mount_setattr(fd, libc::AT_EMPTY_PATH, &attr)
 }
 
+/// The default path of the configuration file
+pub const CONFIG_PATH: &str = "usr/lib/composefs/setup-root-conf.toml";
+
+/// Relative path to the state shared by all deployments, relative to the sysroot
+const SHARED_STATE_PATH: &str = "state/os/default";
+
 /// Types of mounts supported by the configuration
-#[derive(Clone, Copy, Debug, Deserialize)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum MountType {
-    /// No mount
+    /// No mount; the directory from the image is used read-only
     None,
-    /// Bind mount
+    /// Bind mount of the directory in the state
     Bind,
-    /// Overlay mount
+    /// Overlay mount with the directory from the image as lower directory,
+    /// and the upper directory in the state
     Overlay,
-    /// Transient mount
+    /// Overlay mount with the directory from the image as lower directory,
+    /// and the upper directory on a tmpfs
     Transient,
+    /// An empty tmpfs
+    Tmpfs,
+}
+
+impl std::fmt::Display for MountType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            MountType::None => "none",
+            MountType::Bind => "bind",
+            MountType::Overlay => "overlay",
+            MountType::Transient => "transient",
+            MountType::Tmpfs => "tmpfs",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Where the persistent state of a mount is stored
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StateLocation {
+    /// The state directory of the deployment, `state/deploy/<digest>`
+    #[default]
+    Deployment,
+    /// The state directory shared by all deployments, `state/os/default`
+    Shared,
 }
 
-#[derive(Debug, Default, Deserialize)]
-struct RootConfig {
+/// Configuration for the root filesystem
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct RootConfig {
+    /// Whether changes to the root filesystem are lost on reboot
     #[serde(default)]
-    transient: bool,
+    pub transient: bool,
 }
 
 /// Configuration for mount operations
-#[derive(Debug, Default, Deserialize)]
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
 pub struct MountConfig {
     /// The type of mount to use
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub mount: Option<MountType>,
     #[serde(default)]
     /// Whether this mount should be transient (temporary)
     pub transient: bool,
+    /// Where the persistent state is stored
+    #[serde(default)]
+    pub state: StateLocation,
+    /// Directories below the mount point which are bind mounted from the state,
+    /// for `transient` and `tmpfs` mounts
+    #[serde(default)]
+    pub persist: Vec<String>,
 }
 
-#[derive(Deserialize, Default)]
-struct Config {
+impl MountConfig {
+    /// The mount type to use, given the default for this mount point
+    pub fn mount_type(&self, default: MountType) -> MountType {
+        match self.mount {
+            Some(mt) => mt,
+            None => match self.transient {
+                true => MountType::Transient,
+                false => default,
+            },
+        }
+    }
+
+    fn validate(&self, subdir: &str) -> Result<()> {
+        if self.transient && !matches!(self.mount, None | Some(MountType::Transient)) {
+            anyhow::bail!(
+                "{subdir}: transient = true conflicts with mount = \"{}\"",
+                self.mount_type(MountType::Bind)
+            );
+        }
+        let mount_type = self.mount_type(MountType::Bind);
+        if !self.persist.is_empty()
+            && !matches!(mount_type, MountType::Transient | MountType::Tmpfs)
+        {
+            anyhow::bail!(
+                "{subdir}: persist requires a transient or tmpfs mount, not {mount_type}"
+            );
+        }
+        for p in self.persist.iter() {
+            let path = Path::new(p);
+            let valid = path.components().next().is_some()
+                && path
+                    .components()
+                    .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+            if !valid {
+                anyhow::bail!("{subdir}: persisted path must be relative and below /{subdir}: {p}");
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The configuration of the root filesystem setup
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Config {
+    /// How `/etc` is set up
     #[serde(default)]
-    etc: MountConfig,
+    pub etc: MountConfig,
+    /// How `/var` is set up
     #[serde(default)]
-    var: MountConfig,
+    pub var: MountConfig,
+    /// How the root filesystem is set up
     #[serde(default)]
-    root: RootConfig,
+    pub root: RootConfig,
+}
+
+impl Config {
+    /// Parse and validate a configuration file, also returning the unknown keys
+    fn parse_with_unknown(text: &str) -> Result<(Self, BTreeSet<String>)> {
+        let mut unknown = BTreeSet::new();
+        let de = toml::Deserializer::parse(text)?;
+        let config: Self = serde_ignored::deserialize(de, |path| {
+            unknown.insert(path.to_string());
+        })?;
+        config.etc.validate("etc")?;
+        config.var.validate("var")?;
+        if config.etc.state == StateLocation::Shared {
+            anyhow::bail!("etc: shared state is only supported for /var");
+        }
+        Ok((config, unknown))
+    }
+
+    /// Parse and validate a configuration file. Unknown keys are ignored with
+    /// a warning, so that the system still boots with a configuration written
+    /// for a newer version.
+    pub fn parse(text: &str) -> Result<Self> {
+        let (config, unknown) = Self::parse_with_unknown(text)?;
+        for key in unknown {
+            eprintln!("warning: Ignoring unknown key {key} in the setup-root configuration");
+        }
+        Ok(config)
+    }
+
+    /// Like [`Self::parse`], but unknown keys are an error; for checking an
+    /// image before it is booted.
+    pub fn parse_strict(text: &str) -> Result<Self> {
+        let (config, unknown) = Self::parse_with_unknown(text)?;
+        if !unknown.is_empty() {
+            let keys = unknown.into_iter().collect::<Vec<_>>().join(", ");
+            anyhow::bail!("Unknown keys: {keys}");
+        }
+        Ok(config)
+    }
+
+    /// Read the configuration at `path`, returning the defaults if it does not exist
+    #[context("Loading {path:?}")]
+    pub fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
 }
 
 /// Command-line arguments
@@ -207,10 +346,14 @@ fn bind_mount(fd: impl AsFd, path: &str) -> Result<OwnedFd> {
 
 /// Mount a tmpfs, inheriting the SELinux label from the base filesystem
 /// if provided. See <https://github.com/containers/bootc/issues/1992>.
-#[context("Mounting tmpfs for overlay")]
-fn mount_tmpfs_for_overlay(base: Option<impl AsFd>) -> Result<OwnedFd> {
+#[context("Mounting tmpfs")]
+fn mount_tmpfs(base: Option<impl AsFd>, mode: Option<Mode>) -> Result<OwnedFd> {
     let tmpfs = FsHandle::open("tmpfs")?;
 
+    if let Some(mode) = mode {
+        fsconfig_set_string(tmpfs.as_fd(), "mode", format!("{:o}", mode.bits()))?;
+    }
+
     if let Some(base_fd) = base {
         let base_dir = Dir::reopen_dir(&base_fd.as_fd())?;
         if let Some(label) = base_dir.getxattr(".", "security.selinux")? {
@@ -264,7 +407,7 @@ pub fn overlay_transient(
     mode: Option<rustix::fs::Mode>,
     mount_attr_flags: Option<MountAttrFlags>,
 ) -> Result<()> {
-    let tmpfs = mount_tmpfs_for_overlay(Some(&base))?;
+    let tmpfs = mount_tmpfs(Some(&base), None)?;
     overlay_state(
         base,
         prepare_mount(tmpfs)?,
@@ -310,6 +453,17 @@ pub fn mount_composefs_image(
     Ok(rootfs)
 }
 
+/// Bind mount `path` below `subdir` in the state over the same path in the new root,
+/// creating it in both places if needed.
+#[context("Persisting {subdir}/{path}")]
+fn persist_path(new_root: impl AsFd, state: impl AsFd, subdir: &str, path: &str) -> Result<()> {
+    let target = Dir::reopen_dir(&open_dir(&new_root, subdir)?.as_fd())?;
+    target.create_dir_all(path)?;
+    let source = Dir::reopen_dir(&ensure_dir(&state, subdir, Some(0o755.into()))?.as_fd())?;
+    source.create_dir_all(path)?;
+    mount_at_wrapper(bind_mount(&source, path)?, &target, path)
+}
+
 /// Mounts a subdirectory with the specified configuration
 #[context("Mounting subdirectory")]
 pub fn mount_subdir(
@@ -319,30 +473,27 @@ pub fn mount_subdir(
     config: MountConfig,
     default: MountType,
 ) -> Result<()> {
-    let mount_type = match config.mount {
-        Some(mt) => mt,
-        None => match config.transient {
-            true => MountType::Transient,
-            false => default,
-        },
-    };
-
-    match mount_type {
-        MountType::None => Ok(()),
-        MountType::Bind => Ok(mount_at_wrapper(
-            bind_mount(&state, subdir)?,
-            &new_root,
-            subdir,
-        )?),
+    match config.mount_type(default) {
+        MountType::None => {}
+        MountType::Bind => mount_at_wrapper(bind_mount(&state, subdir)?, &new_root, subdir)?,
         MountType::Overlay => overlay_state(
             open_dir(&new_root, subdir)?,
             open_dir(&state, subdir)?,
             "overlay",
             None,
             None,
-        ),
-        MountType::Transient => overlay_transient(open_dir(&new_root, subdir)?, None, None),
+        )?,
+        MountType::Transient => overlay_transient(open_dir(&new_root, subdir)?, None, None)?,
+        MountType::Tmpfs => {
+            let tmpfs = mount_tmpfs(Some(open_dir(&new_root, subdir)?), Some(0o755.into()))?;
+            mount_at_wrapper(tmpfs, &new_root, subdir)?
+        }
+    }
+
+    for path in config.persist.iter() {
+        persist_path(&new_root, &state, subdir, path)?;
     }
+    Ok(())
 }
 
 #[context("GPT workaround")]
@@ -369,11 +520,7 @@ pub fn gpt_workaround() -> Result<()> {
 /// Sets up /sysroot for switch-root
 #[context("Setting up /sysroot")]
 pub fn setup_root(args: Args) -> Result<()> {
-    let config = match std::fs::read_to_string(args.config) {
-        Ok(text) => toml::from_str(&text)?,
-        Err(err) if err.kind() == ErrorKind::NotFound => Config::default(),
-        Err(err) => Err(err)?,
-    };
+    let config = Config::load(&args.config)?;
 
     let sysroot = open_dir(CWD, &args.sysroot)
         .with_context(|| format!("Failed to open sysroot {:?}", args.sysroot))?;
@@ -413,9 +560,14 @@ pub fn setup_root(args: Args) -> Result<()> {
     }
 
     // etc + var
-    let state = open_dir(open_dir(&sysroot, "state/deploy")?, image.to_hex())?;
-    mount_subdir(&new_root, &state, "etc", config.etc, MountType::Bind)?;
-    mount_subdir(&new_root, &state, "var", config.var, MountType::Bind)?;
+    let deployment_state = open_dir(open_dir(&sysroot, "state/deploy")?, image.to_hex())?;
+    for (subdir, config) in [("etc", config.etc), ("var", config.var)] {
+        let state = match config.state {
+            StateLocation::Deployment => deployment_state.try_clone()?,
+            StateLocation::Shared => open_dir(&sysroot, SHARED_STATE_PATH)?,
+        };
+        mount_subdir(&new_root, &state, subdir, config, MountType::Bind)?;
+    }
 
     if cfg!(not(feature = "pre-6.15")) {
         // Replace the /sysroot with the new composed root filesystem
@@ -425,3 +577,61 @@ pub fn setup_root(args: Args) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        assert_eq!(Config::parse("")?, Config::default());
+
+        let config = Config::parse(
+            r#"
+[etc]
+transient = true
+
+[var]
+mount = "tmpfs"
+persist = ["lib/app", "./log"]
+"#,
+        )?;
+        assert_eq!(config.etc.mount_type(MountType::Bind), MountType::Transient);
+        assert_eq!(config.var.mount_type(MountType::Bind), MountType::Tmpfs);
+        assert_eq!(config.var.state, StateLocation::Deployment);
+        assert_eq!(config.var.persist, ["lib/app", "./log"]);
+        // The normalized form can be parsed again
+        assert_eq!(Config::parse(&toml::to_string(&config)?)?, config);
+
+        let config = Config::parse("[var]\nmount = \"bind\"\nstate = \"shared\"\n")?;
+        assert_eq!(config.var.state, StateLocation::Shared);
+
+        for invalid in [
+            "[etc]\nmount = \"bind\"\ntransient = true\n",
+            "[var]\npersist = [\"lib/app\"]\n",
+            "[var]\nmount = \"tmpfs\"\npersist = [\"/lib/app\"]\n",
+            "[var]\nmount = \"tmpfs\"\npersist = [\"lib/../../etc\"]\n",
+            "[var]\nmount = \"tmpfs\"\npersist = [\"\"]\n",
+            "[etc]\nstate = \"shared\"\n",
+        ] {
+            assert!(Config::parse(invalid).is_err(), "{invalid}");
+        }
+
+        // Unknown keys are only rejected when parsing strictly
+        for unknown in [
+            "[etc]\nmonut = \"bind\"\n",
+            "[usr]\nmount = \"bind\"\n",
+            "[root]\nread-only = true\n",
+        ] {
+            assert_eq!(Config::parse(unknown)?, Config::default());
+            assert!(Config::parse_strict(unknown).is_err(), "{unknown}");
+        }
+        let err = Config::parse_strict("[etc]\nmonut = \"bind\"\nfoo = 1\n").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown keys: etc.foo, etc.monut");
+        assert_eq!(
+            Config::parse_strict("[var]\nmount = \"tmpfs\"\n")?,
+            Config::parse("[var]\nmount = \"tmpfs\"\n")?
+        );
+        Ok(())
+    }
+}
@@ -93,6 +93,12 @@ use rustix::{mount::MountFlags, path::Arg};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
+use crate::bootc_composefs::kargs::copy_uki_addon;
+use crate::progress_jsonl::Phase;
+use crate::{
+    bootc_composefs::boot_counting::{DEFAULT_BOOT_TRIES, counted_file_name},
+    parsers::grub_menuconfig::get_boot_counting_source,
+};
 use crate::{
     bootc_composefs::repo::get_imgref,
     composefs_consts::{TYPE1_ENT_PATH, TYPE1_ENT_PATH_STAGED},
@@ -292,7 +298,6 @@ pub(crate) fn get_uki_addon_dir_name(depl_verity: &str) -> String {
     format!("{UKI_NAME_PREFIX}{depl_verity}{EFI_ADDON_DIR_EXT}")
 }
 
-#[allow(dead_code)]
 /// Returns the name of a UKI Addon given verity digest
 pub(crate) fn get_uki_addon_file_name(depl_verity: &str) -> String {
     format!("{UKI_NAME_PREFIX}{depl_verity}{EFI_ADDON_FILE_EXT}")
@@ -537,16 +542,17 @@ pub(crate) fn setup_composefs_bls_boot(
             // /etc/fstab which conflicts with transient etc (see #1388).
             if let Some(boot) = root_setup.boot_mount_spec() {
                 if !boot.source.is_empty() {
-                    let mount_extra = format!(
-                        "systemd.mount-extra={}:/boot:{}:{}",
-                        boot.source,
-                        boot.fstype,
-                        boot.options.as_deref().unwrap_or("defaults"),
-                    );
+                    let mount_extra = boot.to_mount_extra_karg();
                     cmdline_options.extend(&Cmdline::from(mount_extra.as_str()));
                     tracing::debug!("Added /boot mount karg: {mount_extra}");
                 }
             }
+            // Likewise for any additional filesystems such as a separate /var
+            for mount in root_setup.extra_mount_specs() {
+                let mount_extra = mount.to_mount_extra_karg();
+                cmdline_options.extend(&Cmdline::from(mount_extra.as_str()));
+                tracing::debug!("Added {} mount karg: {mount_extra}", mount.target);
+            }
 
             // Locate ESP partition device
             let esp_part = root_setup.device_info.find_partition_of_esp()?;
@@ -792,10 +798,15 @@ pub(crate) fn setup_composefs_bls_boot(
     let loader_entries_dir = Dir::open_ambient_dir(&config_path, ambient_authority())
         .with_context(|| format!("Opening {config_path:?}"))?;
 
-    loader_entries_dir.atomic_write(
-        type1_entry_conf_file_name(&os_id, &bls_config.version(), FILENAME_PRIORITY_PRIMARY),
-        bls_config.to_string().as_bytes(),
-    )?;
+    let mut primary_file_name =
+        type1_entry_conf_file_name(&os_id, &bls_config.version(), FILENAME_PRIORITY_PRIMARY);
+
+    // Grub keeps the boot counter in grubenv, which is armed on finalization
+    if is_upgrade && bootloader == Bootloader::Systemd {
+        primary_file_name = counted_file_name(&primary_file_name, DEFAULT_BOOT_TRIES);
+    }
+
+    loader_entries_dir.atomic_write(primary_file_name, bls_config.to_string().as_bytes())?;
 
     if let Some(booted_bls) = booted_bls {
         loader_entries_dir.atomic_write(
@@ -966,6 +977,7 @@ fn write_grub_uki_menuentry(
         grub_dir
             .atomic_replace_with(user_cfg_name, |f| -> std::io::Result<_> {
                 f.write_all(efi_uuid_source.as_bytes())?;
+                f.write_all(get_boot_counting_source(DEFAULT_BOOT_TRIES).as_bytes())?;
                 f.write_all(
                     MenuEntry::new(&boot_label, &id.to_hex())
                         .to_string()
@@ -1056,11 +1068,15 @@ fn write_systemd_uki_config(
         }
     };
 
+    let mut primary_file_name =
+        type1_entry_conf_file_name(os_id, &bls_conf.version(), FILENAME_PRIORITY_PRIMARY);
+
+    if booted_bls.is_some() {
+        primary_file_name = counted_file_name(&primary_file_name, DEFAULT_BOOT_TRIES);
+    }
+
     entries_dir
-        .atomic_write(
-            type1_entry_conf_file_name(os_id, &bls_conf.version(), FILENAME_PRIORITY_PRIMARY),
-            bls_conf.to_string().as_bytes(),
-        )
+        .atomic_write(primary_file_name, bls_conf.to_string().as_bytes())
         .context("Writing conf file")?;
 
     if let Some(booted_bls) = booted_bls {
@@ -1183,6 +1199,11 @@ pub(crate) fn setup_composefs_uki_boot(
         };
     }
 
+    // Carry over the local kernel arguments
+    if let BootSetupType::Upgrade((_, booted_cfs, ..)) = &setup_type {
+        copy_uki_addon(&esp_mount.fd, &booted_cfs.cmdline.digest, &id.to_hex())?;
+    }
+
     let uki_info =
         uki_info.ok_or_else(|| anyhow::anyhow!("Failed to get version and boot label from UKI"))?;
 
@@ -1278,6 +1299,8 @@ pub(crate) async fn setup_composefs_boot(
 
     let postfetch = PostFetchState::new(state, &mounted_fs)?;
 
+    let prog = &state.prog;
+    prog.start_phase(Phase::Bootloader).await;
     let boot_uuid = root_setup
         .get_boot_uuid()?
         .or(root_setup.rootfs_uuid.as_deref())
@@ -1327,7 +1350,9 @@ pub(crate) async fn setup_composefs_boot(
             entries,
         )?,
     };
+    prog.complete_phase(Phase::Bootloader).await;
 
+    prog.start_phase(Phase::Deploy).await;
     write_composefs_state(
         &root_setup.physical_root_path,
         &id,
@@ -1343,6 +1368,7 @@ pub(crate) async fn setup_composefs_boot(
         allow_missing_fsverity,
     )
     .await?;
+    prog.complete_phase(Phase::Deploy).await;
 
     Ok(())
 }
@@ -0,0 +1,326 @@
+//! Automatic boot assessment for the composefs backend.
+//!
+//! A newly staged deployment is given a limited number of attempts to boot
+//! successfully. If it never reaches `boot-complete.target`, the bootloader
+//! falls back to the previous deployment, and on that boot we make the
+//! fallback permanent by performing a rollback.
+//!
+//! See <https://systemd.io/AUTOMATIC_BOOT_ASSESSMENT/>
+//!
+//! ## systemd-boot
+//!
+//! systemd-boot natively implements boot counting via the Type1 entry file
+//! name: `<name>+LEFT-DONE.conf`. Each boot attempt decrements `LEFT` and
+//! increments `DONE`; entries with `LEFT` at zero are sorted last. Blessing an
+//! entry removes the counter from the file name.
+//!
+//! ## Grub
+//!
+//! Grub does not parse counters from file names, so we keep the counter in
+//! the grubenv variables `boot_counter` and `boot_success`. These are the
+//! same variables used by the Fedora grub fallback counting logic, and for
+//! UKIs we write an equivalent snippet into `user.cfg`
+//! (see [`crate::parsers::grub_menuconfig::get_boot_counting_source`]).
+//!
+//! With grub and Type1 entries, the entries are read by grub's `blscfg`
+//! module, and there is no place for the snippet which decrements the
+//! counter; these deployments are not counted.
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::bootc_composefs::boot::BootType;
+use crate::bootc_composefs::rollback::composefs_rollback;
+use crate::bootc_composefs::status::get_composefs_status;
+use crate::composefs_consts::{TYPE1_ENT_PATH, TYPE1_ENT_PATH_STAGED};
+use crate::parsers::bls_config::parse_bls_config;
+use crate::parsers::grubenv::GrubEnv;
+use crate::progress_jsonl::ProgressWriter;
+use crate::spec::{Bootloader, Host};
+use crate::store::{BootedComposefs, Storage};
+
+/// The number of attempts a new deployment gets to boot successfully
+pub(crate) const DEFAULT_BOOT_TRIES: u32 = 3;
+
+/// grubenv variable holding the number of remaining boot attempts
+pub(crate) const BOOT_COUNTER: &str = "boot_counter";
+/// grubenv variable which is "1" once the boot was marked as successful
+pub(crate) const BOOT_SUCCESS: &str = "boot_success";
+
+/// Path to grubenv, relative to the boot directory
+const GRUBENV_PATH: &str = "grub2/grubenv";
+
+const CONF_EXT: &str = ".conf";
+
+/// Split a Type1 entry file name (`<name>+LEFT[-DONE].conf`) into the
+/// file name without the counter, and the number of boot attempts left if
+/// there is a counter.
+pub(crate) fn parse_counted_file_name(file_name: &str) -> (String, Option<u32>) {
+    let Some(stem) = file_name.strip_suffix(CONF_EXT) else {
+        return (file_name.to_owned(), None);
+    };
+
+    let Some((name, counter)) = stem.rsplit_once('+') else {
+        return (file_name.to_owned(), None);
+    };
+
+    let (left, done) = match counter.split_once('-') {
+        Some((left, done)) => (left, Some(done)),
+        None => (counter, None),
+    };
+
+    // Per the spec, anything that doesn't parse is not a counter
+    let done_valid = done.is_none_or(|done| done.parse::<u32>().is_ok());
+    match left.parse::<u32>() {
+        Ok(left) if done_valid => (format!("{name}{CONF_EXT}"), Some(left)),
+        _ => (file_name.to_owned(), None),
+    }
+}
+
+/// Add a boot counter with `tries` attempts to a Type1 entry file name.
+pub(crate) fn counted_file_name(file_name: &str, tries: u32) -> String {
+    let (file_name, _) = parse_counted_file_name(file_name);
+    let stem = file_name.strip_suffix(CONF_EXT).unwrap_or(&file_name);
+    format!("{stem}+{tries}{CONF_EXT}")
+}
+
+/// Returns the composefs verity digest, file name and boot attempts left
+/// for every Type1 entry in `entries_dir` which has a boot counter.
+#[context("Reading Type1 boot counters")]
+fn read_type1_boot_counters(entries_dir: &Dir) -> Result<Vec<(String, String, u32)>> {
+    let mut counters = vec![];
+
+    for entry in entries_dir.entries_utf8()? {
+        let entry = entry?;
+        let file_name = entry.file_name()?;
+
+        let (_, Some(left)) = parse_counted_file_name(&file_name) else {
+            continue;
+        };
+
+        let contents = entries_dir
+            .read_to_string(&file_name)
+            .with_context(|| format!("Reading {file_name}"))?;
+        let cfg = parse_bls_config(&contents).with_context(|| format!("Parsing {file_name}"))?;
+
+        counters.push((cfg.get_verity()?, file_name, left));
+    }
+
+    Ok(counters)
+}
+
+/// Read the grubenv from the boot directory, if present
+fn read_grubenv(boot_dir: &Dir) -> Result<Option<GrubEnv>> {
+    boot_dir
+        .read_to_string_optional(GRUBENV_PATH)
+        .with_context(|| format!("Reading {GRUBENV_PATH}"))?
+        .map(|s| GrubEnv::parse(&s))
+        .transpose()
+        .with_context(|| format!("Parsing {GRUBENV_PATH}"))
+}
+
+#[context("Writing grubenv")]
+fn write_grubenv(boot_dir: &Dir, env: &GrubEnv) -> Result<()> {
+    boot_dir
+        .atomic_write(GRUBENV_PATH, env.to_block()?)
+        .with_context(|| format!("Writing {GRUBENV_PATH}"))
+}
+
+/// Arm the grub boot counter for the deployment which was just made the default.
+/// Called when finalizing a staged UKI deployment.
+#[context("Arming grub boot counter")]
+pub(crate) fn arm_grub_boot_counter(boot_dir: &Dir) -> Result<()> {
+    let mut env = read_grubenv(boot_dir)?.unwrap_or_default();
+    env.set(BOOT_COUNTER, DEFAULT_BOOT_TRIES.to_string());
+    env.set(BOOT_SUCCESS, "0");
+    write_grubenv(boot_dir, &env)
+}
+
+/// Fill in the number of remaining boot attempts for every deployment
+/// which is still being assessed.
+#[context("Populating boot tries")]
+pub(crate) fn populate_boot_tries_left(boot_dir: &Dir, host: &mut Host) -> Result<()> {
+    let booted = host.require_composefs_booted()?;
+
+    let tries: Vec<(String, u32)> = match booted.bootloader {
+        Bootloader::Systemd => {
+            let mut counters = read_type1_boot_counters(&boot_dir.open_dir(TYPE1_ENT_PATH)?)?;
+
+            if let Some(staged) = boot_dir.open_dir_optional(TYPE1_ENT_PATH_STAGED)? {
+                counters.extend(read_type1_boot_counters(&staged)?);
+            }
+
+            counters
+                .into_iter()
+                .map(|(verity, _, left)| (verity, left))
+                .collect()
+        }
+
+        // The counter is not decremented for Type1 entries, see above
+        Bootloader::Grub if booted.boot_type == BootType::Bls => vec![],
+
+        Bootloader::Grub => {
+            let Some(env) = read_grubenv(boot_dir)? else {
+                return Ok(());
+            };
+
+            // The counter only ever applies to the default entry, which is
+            // the booted one unless a rollback is queued.
+            let counter = match (env.get(BOOT_SUCCESS), env.get(BOOT_COUNTER)) {
+                (Some("0"), Some(counter)) => counter,
+                _ => return Ok(()),
+            };
+
+            let left = counter.parse::<i64>().map(|v| v.max(0) as u32).ok();
+            let default_entry = if host.status.rollback_queued {
+                host.status.rollback.as_ref()
+            } else {
+                host.status.booted.as_ref()
+            };
+
+            match (left, default_entry.and_then(|e| e.composefs.as_ref())) {
+                (Some(left), Some(cfs)) => vec![(cfs.verity.clone(), left)],
+                _ => vec![],
+            }
+        }
+
+        Bootloader::None => unreachable!("Checked at install time"),
+    };
+
+    for entry in host
+        .status
+        .staged
+        .iter_mut()
+        .chain(host.status.booted.iter_mut())
+        .chain(host.status.rollback.iter_mut())
+        .chain(host.status.other_deployments.iter_mut())
+    {
+        let Some(cfs) = entry.composefs.as_mut() else {
+            continue;
+        };
+
+        cfs.boot_tries_left = tries
+            .iter()
+            .find(|(verity, _)| *verity == cfs.verity)
+            .map(|(_, left)| *left);
+    }
+
+    Ok(())
+}
+
+/// Mark the booted deployment as good, and if the default deployment ran out
+/// of boot attempts, make the fallback we booted into the default again.
+///
+/// This is run by `bootc-boot-complete.service` once `boot-complete.target`
+/// is reached.
+#[context("Completing boot")]
+pub(crate) async fn composefs_boot_complete(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+) -> Result<()> {
+    const COMPOSEFS_BOOT_COMPLETE_JOURNAL_ID: &str = "3c2b1a0f9e8d7c6b5a4f3e2d1c0b9a8f7";
+
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    let booted = host.require_composefs_booted()?;
+    let boot_dir = storage.require_boot_dir()?;
+
+    let exhausted = match booted.bootloader {
+        Bootloader::Systemd => {
+            let entries_dir = boot_dir.open_dir(TYPE1_ENT_PATH)?;
+
+            // systemd-bless-boot.service may well have done this already
+            for (verity, file_name, _) in read_type1_boot_counters(&entries_dir)? {
+                if verity != *booted_cfs.cmdline.digest {
+                    continue;
+                }
+
+                let (blessed, _) = parse_counted_file_name(&file_name);
+                tracing::debug!("Renaming {file_name} to {blessed}");
+                entries_dir
+                    .rename(&file_name, &entries_dir, &blessed)
+                    .with_context(|| format!("Renaming {file_name}"))?;
+            }
+
+            rustix::fs::fsync(entries_dir.reopen_as_ownedfd()?).context("fsync")?;
+
+            host.status
+                .rollback
+                .as_ref()
+                .and_then(|r| r.composefs.as_ref())
+                .is_some_and(|r| r.boot_tries_left == Some(0))
+        }
+
+        Bootloader::Grub => match read_grubenv(boot_dir)? {
+            Some(mut env) => {
+                let exhausted = env.get(BOOT_COUNTER) == Some("-1");
+                env.unset(BOOT_COUNTER);
+                env.set(BOOT_SUCCESS, "1");
+                write_grubenv(boot_dir, &env)?;
+                exhausted
+            }
+            None => false,
+        },
+
+        Bootloader::None => unreachable!("Checked at install time"),
+    };
+
+    tracing::info!(
+        message_id = COMPOSEFS_BOOT_COMPLETE_JOURNAL_ID,
+        bootc.operation = "boot-complete",
+        bootc.current_deployment = booted_cfs.cmdline.digest,
+        "Marked booted deployment as good"
+    );
+
+    // We booted into the fallback entry; make that permanent
+    if exhausted && host.status.rollback_queued {
+        tracing::warn!(
+            message_id = COMPOSEFS_BOOT_COMPLETE_JOURNAL_ID,
+            bootc.operation = "boot-complete",
+            "Default deployment ran out of boot attempts, rolling back"
+        );
+        composefs_rollback(storage, booted_cfs, &ProgressWriter::default()).await?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_counted_file_name() {
+        let cases = [
+            ("fedora-42.conf", "fedora-42.conf", None),
+            ("fedora-42+3.conf", "fedora-42.conf", Some(3)),
+            ("fedora-42+0-3.conf", "fedora-42.conf", Some(0)),
+            // Not a counter
+            ("fedora-42+foo.conf", "fedora-42+foo.conf", None),
+            ("fedora-42+1-foo.conf", "fedora-42+1-foo.conf", None),
+            ("fedora-42+3", "fedora-42+3", None),
+        ];
+
+        for (input, name, left) in cases {
+            assert_eq!(
+                parse_counted_file_name(input),
+                (name.to_owned(), left),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_counted_file_name() {
+        assert_eq!(
+            counted_file_name("fedora-42.conf", DEFAULT_BOOT_TRIES),
+            "fedora-42+3.conf"
+        );
+        // Existing counters are replaced
+        assert_eq!(
+            counted_file_name("fedora-42+1-2.conf", 5),
+            "fedora-42+5.conf"
+        );
+    }
+}
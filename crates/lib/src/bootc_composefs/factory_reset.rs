@@ -0,0 +1,539 @@
+//! Factory reset for composefs systems.
+//!
+//! The new state is assembled in [`STAGING_DIR`] and queued in [`PENDING_DIR`].
+//! The running system keeps using its state until it shuts down: the finalize
+//! service then copies the paths to keep, moves the new state to
+//! [`FACTORY_RESET_DIR`] and atomically exchanges it with the state of the
+//! booted deployment in `state/deploy/<verity>` and the shared `/var`. This
+//! way, nothing written after the reset was queued is lost from the kept paths.
+//! The previous state ends up in [`FACTORY_RESET_DIR`], where it is kept until
+//! it is discarded; reverting exchanges it back, also at shutdown.
+//!
+//! While exchanging, [`EXCHANGING`] records which state is put in use, so that
+//! an interrupted exchange is completed at the next shutdown instead of the
+//! state being mixed up or removed.
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use rustix::fs::{RenameFlags, fsync, renameat, renameat_with};
+use serde::{Deserialize, Serialize};
+
+use crate::bootc_composefs::service::start_finalize_stated_svc;
+use crate::bootc_composefs::state::initialize_state;
+use crate::bootc_composefs::status::get_composefs_status;
+use crate::composefs_consts::{ROOT_SETUP_EXT, SHARED_VAR_PATH, STATE_DIR_RELATIVE};
+use crate::factory_reset::{KeepPath, copy_kept_paths};
+use crate::spec::Host;
+use crate::store::{BootedComposefs, Storage};
+
+/// Holds the state which is not in use after a factory reset, relative to the
+/// physical root.
+const FACTORY_RESET_DIR: &str = "state/factory-reset";
+
+/// Where the state for a factory reset is assembled.
+const STAGING_DIR: &str = "state/factory-reset.tmp";
+
+/// Holds the new state until the factory reset is applied at shutdown.
+const PENDING_DIR: &str = "state/factory-reset.pending";
+
+/// The paths to keep, in [`PENDING_DIR`].
+const KEEP: &str = "keep.json";
+
+/// Created in [`FACTORY_RESET_DIR`] when reverting is queued.
+const REVERT_PENDING: &str = "revert";
+
+/// Created in [`FACTORY_RESET_DIR`] once the factory reset was reverted.
+const REVERTED: &str = "reverted";
+
+/// Holds the ID of the last boot which used the state in [`FACTORY_RESET_DIR`].
+const IN_USE_BOOT_ID: &str = "boot-id";
+
+/// Created in [`FACTORY_RESET_DIR`] while its state is exchanged with the
+/// state in use; holds an [`Exchange`].
+const EXCHANGING: &str = "exchanging";
+
+/// The state in [`FACTORY_RESET_DIR`] to put in use, identified by inode
+/// numbers, which are kept when exchanging.
+#[derive(Debug, Serialize, Deserialize)]
+struct Exchange {
+    /// The deployment whose state is exchanged
+    verity: String,
+    /// The inode of the deployment state
+    deployment: u64,
+    /// The inode of /var
+    var: u64,
+}
+
+fn current_boot_id() -> Result<String> {
+    let id =
+        std::fs::read_to_string("/proc/sys/kernel/random/boot_id").context("Reading boot ID")?;
+    Ok(id.trim().to_owned())
+}
+
+/// Record that the state of deployment `verity` in `saved`, which is or is
+/// about to become [`FACTORY_RESET_DIR`], is to be put in use.
+fn begin_exchange(saved: &Dir, verity: &str) -> Result<()> {
+    let exchange = Exchange {
+        verity: verity.to_owned(),
+        deployment: saved.symlink_metadata(format!("deploy/{verity}"))?.ino(),
+        var: saved.symlink_metadata("var")?.ino(),
+    };
+    saved
+        .atomic_write(EXCHANGING, serde_json::to_vec(&exchange)?)
+        .context("Writing exchange marker")
+}
+
+/// Exchange the state of the deployment and the shared /var with the ones in
+/// [`FACTORY_RESET_DIR`], as recorded by [`begin_exchange`]. Parts which were
+/// already exchanged are skipped, so this also completes an interrupted
+/// exchange.
+#[context("Exchanging state")]
+fn exchange_state(physical_root: &Dir, saved: &Dir) -> Result<()> {
+    let exchange: Exchange = serde_json::from_str(&saved.read_to_string(EXCHANGING)?)
+        .context("Parsing exchange marker")?;
+    let verity = &exchange.verity;
+    let deployment_state = format!("{STATE_DIR_RELATIVE}/{verity}");
+    let saved_state = format!("deploy/{verity}");
+    let exchange_deployment = || {
+        renameat_with(
+            saved,
+            &saved_state,
+            physical_root,
+            &deployment_state,
+            RenameFlags::EXCHANGE,
+        )
+    };
+    let deployment_pending = saved.symlink_metadata(&saved_state)?.ino() == exchange.deployment;
+    if deployment_pending {
+        exchange_deployment().context("Exchanging deployment state")?;
+    }
+
+    if saved.symlink_metadata("var")?.ino() == exchange.var {
+        if let Err(e) = renameat_with(
+            saved,
+            "var",
+            physical_root,
+            SHARED_VAR_PATH,
+            RenameFlags::EXCHANGE,
+        ) {
+            // Don't leave behind a deployment with a mismatched /etc and /var
+            if deployment_pending {
+                exchange_deployment().context("Restoring deployment state")?;
+            }
+            return Err(e).context("Exchanging /var");
+        }
+    }
+
+    let shared_var_parent = Utf8Path::new(SHARED_VAR_PATH).parent().expect("parent");
+    for parent in [STATE_DIR_RELATIVE, shared_var_parent.as_str()] {
+        let dir = physical_root
+            .open_dir(parent)
+            .with_context(|| format!("Opening {parent}"))?;
+        fsync(&dir).with_context(|| format!("fsync {parent}"))?;
+    }
+    fsync(saved.open_dir("deploy")?).context("fsync")?;
+    fsync(saved).context("fsync")?;
+    Ok(())
+}
+
+/// Put the state in [`FACTORY_RESET_DIR`] in use, completing a revert if one
+/// is queued.
+fn complete_exchange(physical_root: &Dir, saved: &Dir, boot_id: &str) -> Result<()> {
+    // After the exchange, this holds the state of the system shutting down
+    saved
+        .atomic_write(IN_USE_BOOT_ID, boot_id)
+        .context("Writing boot ID")?;
+    exchange_state(physical_root, saved)?;
+    if saved.try_exists(REVERT_PENDING)? {
+        saved
+            .atomic_write(REVERTED, "")
+            .context("Marking factory reset as reverted")?;
+        saved.remove_file(REVERT_PENDING)?;
+    }
+    saved.remove_file(EXCHANGING)?;
+    fsync(saved).context("fsync")?;
+    Ok(())
+}
+
+/// Fail if the state in [`FACTORY_RESET_DIR`] is being exchanged.
+fn ensure_not_exchanging(saved: &Dir) -> Result<()> {
+    if saved.try_exists(EXCHANGING)? {
+        anyhow::bail!(
+            "Exchanging the state for the factory reset was interrupted; it is completed when the system shuts down"
+        );
+    }
+    Ok(())
+}
+
+/// Fail if a factory reset or reverting it is queued, as the state of the
+/// booted deployment will be replaced at shutdown.
+pub(crate) fn ensure_none_queued(storage: &Storage) -> Result<()> {
+    let physical_root = &storage.physical_root;
+    if physical_root.try_exists(PENDING_DIR)?
+        || physical_root.try_exists(format!("{FACTORY_RESET_DIR}/{REVERT_PENDING}"))?
+        || physical_root.try_exists(format!("{FACTORY_RESET_DIR}/{EXCHANGING}"))?
+    {
+        anyhow::bail!(
+            "A factory reset is queued; reboot first, or cancel it with `bootc state factory-reset --revert`"
+        );
+    }
+    Ok(())
+}
+
+#[context("Factory reset (composefs)")]
+pub(crate) async fn composefs_factory_reset(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    keep: &[KeepPath<'_>],
+) -> Result<()> {
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    // The reset applies to the booted deployment, which must be the next one booted
+    if host.status.staged.is_some() {
+        anyhow::bail!("Cannot factory reset with a staged deployment");
+    }
+    if host.status.rollback_queued {
+        anyhow::bail!("Cannot factory reset with a queued rollback");
+    }
+
+    let physical_root = &storage.physical_root;
+    if physical_root.try_exists(FACTORY_RESET_DIR)? {
+        anyhow::bail!(
+            "A factory reset was already performed; remove the previous state with `bootc state factory-reset --discard` first"
+        );
+    }
+    physical_root
+        .remove_all_optional(STAGING_DIR)
+        .context("Removing leftover staging dir")?;
+    // A factory reset which was queued, but not applied because the system
+    // did not shut down cleanly, is replaced
+    physical_root
+        .remove_all_optional(PENDING_DIR)
+        .context("Removing previously queued factory reset")?;
+
+    let verity = booted_cfs.cmdline.digest.to_string();
+    let current_state_path = format!("{STATE_DIR_RELATIVE}/{verity}");
+    let new_state_path = format!("{STAGING_DIR}/deploy/{verity}");
+    physical_root.create_dir_all(format!("{new_state_path}/etc"))?;
+    physical_root.create_dir(format!("{STAGING_DIR}/var"))?;
+
+    let current_state = physical_root
+        .open_dir(&current_state_path)
+        .context("Opening state dir")?;
+    let new_state = physical_root.open_dir(&new_state_path)?;
+
+    // The /var symlink points to the shared /var, which is exchanged at shutdown
+    let var_target = current_state.read_link("var").context("Reading var link")?;
+    new_state.symlink(var_target, "var")?;
+
+    // Carry over the origin and other metadata of the deployment
+    for entry in current_state.entries_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name()?;
+        if !entry.file_type()?.is_file() || name == format!("{verity}.{ROOT_SETUP_EXT}") {
+            continue;
+        }
+        current_state
+            .copy(&name, &new_state, &name)
+            .with_context(|| format!("Copying {name}"))?;
+    }
+
+    let root_path = &storage.physical_root_path;
+    initialize_state(
+        root_path,
+        &verity,
+        &root_path.join(&new_state_path),
+        false,
+        booted_cfs.cmdline.allow_missing_fsverity,
+    )?;
+
+    // The paths to keep are copied at shutdown, so that later changes are kept
+    let keep = keep.iter().map(|k| k.absolute()).collect::<Vec<_>>();
+    physical_root
+        .atomic_write(format!("{STAGING_DIR}/{KEEP}"), serde_json::to_vec(&keep)?)
+        .context("Writing paths to keep")?;
+    renameat(physical_root, STAGING_DIR, physical_root, PENDING_DIR)
+        .context("Renaming staging dir")?;
+    start_finalize_stated_svc()?;
+
+    println!(
+        "Queued factory reset; it is applied when the system shuts down, and the previous state is kept until it is discarded"
+    );
+    Ok(())
+}
+
+/// Apply the factory reset queued in [`PENDING_DIR`] for deployment `verity`,
+/// copying the paths to keep from the system whose root is `source`.
+#[context("Applying factory reset")]
+fn apply_pending(
+    physical_root: &Dir,
+    physical_root_path: &Utf8Path,
+    verity: &str,
+    source: &Utf8Path,
+    boot_id: &str,
+) -> Result<()> {
+    let pending = physical_root.open_dir(PENDING_DIR)?;
+    // Missing if a previous attempt already copied the paths to keep
+    if let Some(keep) = pending.read_to_string_optional(KEEP)? {
+        let keep: Vec<Utf8PathBuf> =
+            serde_json::from_str(&keep).context("Parsing paths to keep")?;
+        let keep = keep
+            .iter()
+            .map(|p| KeepPath::parse(p))
+            .collect::<Result<Vec<_>>>()?;
+        let pending_path = physical_root_path.join(PENDING_DIR);
+        copy_kept_paths(
+            &keep,
+            source,
+            &pending_path.join(format!("deploy/{verity}/etc")),
+            &pending_path.join("var"),
+        )?;
+        pending.remove_file(KEEP)?;
+    }
+
+    // From here on, an interrupted factory reset is completed rather than dropped
+    begin_exchange(&pending, verity)?;
+    renameat(physical_root, PENDING_DIR, physical_root, FACTORY_RESET_DIR)
+        .context("Renaming pending dir")?;
+    fsync(physical_root.open_dir("state")?).context("fsync")?;
+    complete_exchange(physical_root, &pending, boot_id)
+}
+
+/// Apply a queued factory reset, or reverting it; called when the system shuts
+/// down, as the state must not be in use anymore.
+#[context("Finalizing factory reset")]
+pub(crate) fn finalize_factory_reset(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    host: &Host,
+) -> Result<()> {
+    let physical_root = &storage.physical_root;
+    let verity = &*booted_cfs.cmdline.digest;
+    let next_is_booted =
+        host.status.staged.as_ref().is_none_or(|s| s.download_only) && !host.status.rollback_queued;
+
+    let saved = physical_root.open_dir_optional(FACTORY_RESET_DIR)?;
+    if let Some(saved) = saved.as_ref() {
+        if saved.try_exists(EXCHANGING)? {
+            complete_exchange(physical_root, saved, &current_boot_id()?)?;
+            println!("Completed interrupted factory reset");
+            return Ok(());
+        }
+    }
+
+    if physical_root.try_exists(PENDING_DIR)? {
+        if !next_is_booted || !physical_root.try_exists(format!("{PENDING_DIR}/deploy/{verity}"))? {
+            tracing::warn!(
+                "Dropping the queued factory reset, as another deployment is booted next"
+            );
+            physical_root.remove_dir_all(PENDING_DIR)?;
+        } else {
+            apply_pending(
+                physical_root,
+                &storage.physical_root_path,
+                verity,
+                Utf8Path::new("/"),
+                &current_boot_id()?,
+            )?;
+            println!("Applied factory reset");
+        }
+        return Ok(());
+    }
+
+    let Some(saved) = saved else {
+        return Ok(());
+    };
+    if !saved.try_exists(REVERT_PENDING)? {
+        return Ok(());
+    }
+    if !next_is_booted {
+        tracing::warn!("Not reverting the factory reset, as another deployment is booted next");
+        saved.remove_file(REVERT_PENDING)?;
+        return Ok(());
+    }
+    begin_exchange(&saved, verity)?;
+    complete_exchange(physical_root, &saved, &current_boot_id()?)?;
+    println!("Reverted factory reset");
+    Ok(())
+}
+
+#[context("Reverting factory reset (composefs)")]
+pub(crate) fn composefs_revert_factory_reset(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+) -> Result<()> {
+    let physical_root = &storage.physical_root;
+    if physical_root.try_exists(PENDING_DIR)? {
+        physical_root
+            .remove_dir_all(PENDING_DIR)
+            .context("Removing queued factory reset")?;
+        println!("Cancelled the queued factory reset");
+        return Ok(());
+    }
+    let Some(saved) = physical_root.open_dir_optional(FACTORY_RESET_DIR)? else {
+        anyhow::bail!("No factory reset found");
+    };
+    ensure_not_exchanging(&saved)?;
+    if saved.try_exists(REVERTED)? {
+        anyhow::bail!(
+            "The factory reset was already reverted; use `bootc state factory-reset --discard` to remove its state"
+        );
+    }
+    if saved.try_exists(REVERT_PENDING)? {
+        anyhow::bail!("Reverting the factory reset is already queued");
+    }
+    let verity = &*booted_cfs.cmdline.digest;
+    if !saved.try_exists(format!("deploy/{verity}"))? {
+        anyhow::bail!("The factory reset was not performed for the booted deployment");
+    }
+
+    saved
+        .atomic_write(REVERT_PENDING, "")
+        .context("Queueing revert")?;
+    start_finalize_stated_svc()?;
+
+    println!("Next boot: state from before the factory reset");
+    Ok(())
+}
+
+#[context("Discarding factory reset (composefs)")]
+pub(crate) fn composefs_discard_factory_reset(storage: &Storage) -> Result<()> {
+    let physical_root = &storage.physical_root;
+    let Some(saved) = physical_root.open_dir_optional(FACTORY_RESET_DIR)? else {
+        anyhow::bail!("No factory reset found");
+    };
+    ensure_not_exchanging(&saved)?;
+    if saved.try_exists(REVERT_PENDING)? {
+        anyhow::bail!("Reverting the factory reset is queued; reboot first");
+    }
+    // The mounts of the running system still refer to the exchanged state
+    if saved.read_to_string_optional(IN_USE_BOOT_ID)?.as_deref()
+        == Some(current_boot_id()?.as_str())
+    {
+        anyhow::bail!("The state to discard is still in use; reboot first");
+    }
+    physical_root
+        .remove_dir_all(FACTORY_RESET_DIR)
+        .with_context(|| format!("Removing {FACTORY_RESET_DIR}"))?;
+
+    println!("Removed the state which is not in use");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cap_std_ext::cap_std;
+
+    /// Create the current state of deployment `verity`, and the one queued by
+    /// the factory reset.
+    fn setup_pending(root: &Dir, verity: &str) -> Result<()> {
+        root.create_dir_all(format!("{STATE_DIR_RELATIVE}/{verity}/etc"))?;
+        root.write(format!("{STATE_DIR_RELATIVE}/{verity}/etc/local"), "local")?;
+        root.create_dir_all(SHARED_VAR_PATH)?;
+        root.write(format!("{SHARED_VAR_PATH}/data"), "data")?;
+        root.create_dir_all(format!("{PENDING_DIR}/deploy/{verity}/etc"))?;
+        root.write(format!("{PENDING_DIR}/deploy/{verity}/etc/pristine"), "")?;
+        root.create_dir(format!("{PENDING_DIR}/var"))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_pending() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path()).unwrap();
+        let root_path = &td.join("sysroot");
+        let source = &td.join("booted");
+        std::fs::create_dir_all(root_path)?;
+        let root = &Dir::open_ambient_dir(root_path, cap_std::ambient_authority())?;
+        let verity = "0123abcd";
+
+        setup_pending(root, verity)?;
+        root.write(
+            format!("{PENDING_DIR}/{KEEP}"),
+            r#"["/etc/ssh", "/var/lib/app/state", "/var/missing"]"#,
+        )?;
+
+        // Written to the running system after the reset was queued
+        std::fs::create_dir_all(source.join("etc/ssh"))?;
+        std::fs::write(source.join("etc/ssh/key"), "rotated key")?;
+        std::fs::create_dir_all(source.join("var/lib/app"))?;
+        std::fs::write(source.join("var/lib/app/state"), "late write")?;
+
+        apply_pending(root, root_path, verity, source, "boot1")?;
+
+        let etc = format!("{STATE_DIR_RELATIVE}/{verity}/etc");
+        assert!(root.try_exists(format!("{etc}/pristine"))?);
+        assert!(!root.try_exists(format!("{etc}/local"))?);
+        assert_eq!(
+            root.read_to_string(format!("{etc}/ssh/key"))?,
+            "rotated key"
+        );
+        assert!(!root.try_exists(format!("{SHARED_VAR_PATH}/data"))?);
+        assert_eq!(
+            root.read_to_string(format!("{SHARED_VAR_PATH}/lib/app/state"))?,
+            "late write"
+        );
+
+        // The previous state is kept, and marked as in use by this boot
+        assert!(!root.try_exists(PENDING_DIR)?);
+        let saved = root.open_dir(FACTORY_RESET_DIR)?;
+        assert_eq!(
+            saved.read_to_string(format!("deploy/{verity}/etc/local"))?,
+            "local"
+        );
+        assert_eq!(saved.read_to_string("var/data")?, "data");
+        assert_eq!(saved.read_to_string(IN_USE_BOOT_ID)?, "boot1");
+        assert!(!saved.try_exists(KEEP)?);
+        assert!(!saved.try_exists(EXCHANGING)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_exchange() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let root = &Dir::open_ambient_dir(td.path(), cap_std::ambient_authority())?;
+        let verity = "0123abcd";
+        setup_pending(root, verity)?;
+
+        // Interrupted after exchanging the deployment state, but not /var
+        begin_exchange(&root.open_dir(PENDING_DIR)?, verity)?;
+        renameat(root, PENDING_DIR, root, FACTORY_RESET_DIR)?;
+        renameat_with(
+            root,
+            format!("{FACTORY_RESET_DIR}/deploy/{verity}"),
+            root,
+            format!("{STATE_DIR_RELATIVE}/{verity}"),
+            RenameFlags::EXCHANGE,
+        )?;
+
+        // Reverting or discarding must not remove the saved state now
+        let saved = root.open_dir(FACTORY_RESET_DIR)?;
+        assert!(ensure_not_exchanging(&saved).is_err());
+        let marker = saved.read_to_string(EXCHANGING)?;
+
+        let check = || -> Result<()> {
+            let etc = format!("{STATE_DIR_RELATIVE}/{verity}/etc");
+            assert!(root.try_exists(format!("{etc}/pristine"))?);
+            assert!(!root.try_exists(format!("{SHARED_VAR_PATH}/data"))?);
+            assert_eq!(
+                saved.read_to_string(format!("deploy/{verity}/etc/local"))?,
+                "local"
+            );
+            assert_eq!(saved.read_to_string("var/data")?, "data");
+            assert_eq!(saved.read_to_string(IN_USE_BOOT_ID)?, "boot2");
+            assert!(!saved.try_exists(EXCHANGING)?);
+            Ok(())
+        };
+        complete_exchange(root, &saved, "boot2")?;
+        check()?;
+
+        // Interrupted after the exchange, before removing the marker
+        saved.write(EXCHANGING, marker)?;
+        complete_exchange(root, &saved, "boot2")?;
+        check()?;
+        Ok(())
+    }
+}
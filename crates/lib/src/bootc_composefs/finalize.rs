@@ -1,6 +1,8 @@
 use std::path::Path;
 
 use crate::bootc_composefs::boot::BootType;
+use crate::bootc_composefs::boot_counting::arm_grub_boot_counter;
+use crate::bootc_composefs::factory_reset::finalize_factory_reset;
 use crate::bootc_composefs::rollback::{rename_exchange_bls_entries, rename_exchange_user_cfg};
 use crate::bootc_composefs::status::get_composefs_status;
 use crate::composefs_consts::STATE_DIR_ABS;
@@ -11,44 +13,11 @@ use bootc_initramfs_setup::mount_composefs_image;
 use bootc_mount::tempmount::TempMount;
 use cap_std_ext::cap_std::{ambient_authority, fs::Dir};
 use cap_std_ext::dirext::CapStdExtDirExt;
-use cfsctl::composefs;
-use composefs::generic_tree::{Directory, Stat};
-use etc_merge::{compute_diff, merge, print_diff, traverse_etc};
 use rustix::fs::{fsync, renameat};
 use rustix::path::Arg;
 
 use fn_error_context::context;
 
-pub(crate) async fn get_etc_diff(storage: &Storage, booted_cfs: &BootedComposefs) -> Result<()> {
-    let host = get_composefs_status(storage, booted_cfs).await?;
-    let booted_composefs = host.require_composefs_booted()?;
-
-    // Mount the booted EROFS image to get pristine etc
-    let sysroot_fd = storage.physical_root.reopen_as_ownedfd()?;
-    let composefs_fd = mount_composefs_image(
-        &sysroot_fd,
-        &booted_composefs.verity,
-        booted_cfs.cmdline.allow_missing_fsverity,
-    )?;
-
-    let erofs_tmp_mnt = TempMount::mount_fd(&composefs_fd)?;
-
-    let pristine_etc =
-        Dir::open_ambient_dir(erofs_tmp_mnt.dir.path().join("etc"), ambient_authority())?;
-    let current_etc = Dir::open_ambient_dir("/etc", ambient_authority())?;
-
-    let (pristine_files, current_files, _) = traverse_etc(&pristine_etc, &current_etc, None)?;
-    let diff = compute_diff(
-        &pristine_files,
-        &current_files,
-        &Directory::new(Stat::uninitialized()),
-    )?;
-
-    print_diff(&diff, &mut std::io::stdout());
-
-    Ok(())
-}
-
 pub(crate) async fn composefs_backend_finalize(
     storage: &Storage,
     booted_cfs: &BootedComposefs,
@@ -64,6 +33,8 @@ pub(crate) async fn composefs_backend_finalize(
 
     let host = get_composefs_status(storage, booted_cfs).await?;
 
+    finalize_factory_reset(storage, booted_cfs, &host)?;
+
     let booted_composefs = host.require_composefs_booted()?;
 
     let Some(staged_depl) = host.status.staged.as_ref() else {
@@ -110,16 +81,21 @@ pub(crate) async fn composefs_backend_finalize(
 
     let new_etc = Dir::open_ambient_dir(new_etc_path, ambient_authority())?;
 
-    let (pristine_files, current_files, new_files) =
-        traverse_etc(&pristine_etc, &current_etc, Some(&new_etc))?;
-
-    let new_files =
-        new_files.ok_or_else(|| anyhow::anyhow!("Failed to get dirtree for new etc"))?;
+    // Mount the staged EROFS image to get the merge policies
+    let staged_composefs_fd = mount_composefs_image(
+        &sysroot_fd,
+        &staged_composefs.verity,
+        booted_cfs.cmdline.allow_missing_fsverity,
+    )?;
+    let staged_tmp_mnt = TempMount::mount_fd(&staged_composefs_fd)?;
+    let config = crate::etcmerge::config_from_root(&staged_tmp_mnt.fd)?;
 
-    let diff = compute_diff(&pristine_files, &current_files, &new_files)?;
-    merge(&current_etc, &current_files, &new_etc, &new_files, &diff)?;
+    let conflicts =
+        crate::etcmerge::merge_etc_dirs(&pristine_etc, &current_etc, &new_etc, &new_etc, &config)?;
+    crate::etcmerge::log_conflicts(&conflicts);
 
     // Unmount EROFS
+    drop(staged_tmp_mnt);
     drop(erofs_tmp_mnt);
 
     let boot_dir = storage.require_boot_dir()?;
@@ -128,13 +104,20 @@ pub(crate) async fn composefs_backend_finalize(
 
     // NOTE: Assuming here we won't have two bootloaders at the same time
     match booted_composefs.bootloader {
-        Bootloader::Grub => match staged_composefs.boot_type {
-            BootType::Bls => {
-                let entries_dir = boot_dir.open_dir("loader")?;
-                rename_exchange_bls_entries(&entries_dir)?;
+        Bootloader::Grub => {
+            match staged_composefs.boot_type {
+                BootType::Bls => {
+                    let entries_dir = boot_dir.open_dir("loader")?;
+                    rename_exchange_bls_entries(&entries_dir)?;
+                }
+                BootType::Uki => {
+                    finalize_staged_grub_uki(&esp_mount.fd, boot_dir)?;
+                    // systemd-boot counters are part of the entry file names, but for
+                    // grub the staged deployment needs to be given its boot attempts here
+                    arm_grub_boot_counter(boot_dir)?;
+                }
             }
-            BootType::Uki => finalize_staged_grub_uki(&esp_mount.fd, boot_dir)?,
-        },
+        }
 
         Bootloader::Systemd => {
             if matches!(staged_composefs.boot_type, BootType::Uki) {
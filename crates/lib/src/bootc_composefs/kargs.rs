@@ -0,0 +1,271 @@
+//! Kernel arguments of composefs deployments.
+//!
+//! For Type1 boot entries, all kernel arguments are in the `options` of the
+//! BLS config, which is updated in place. The kernel arguments built into a
+//! UKI can't be changed; local ones are instead written to a UKI addon in the
+//! `.extra.d` directory of the UKI, which is loaded by systemd-stub.
+
+use std::process::Command;
+
+use anyhow::{Context, Result, anyhow};
+use bootc_kernel_cmdline::utf8::{Cmdline, CmdlineOwned};
+use bootc_utils::CommandRunExt;
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use cfsctl::composefs_boot;
+use fn_error_context::context;
+use rustix::fs::fsync;
+
+use crate::bootc_composefs::boot::{
+    BOOTC_UKI_DIR, BootType, get_uki_addon_dir_name, get_uki_addon_file_name,
+};
+use crate::bootc_composefs::status::{
+    get_composefs_status, get_sorted_staged_type1_boot_entries, get_sorted_type1_boot_entries,
+};
+use crate::bootc_composefs::utils::get_uki_cmdline;
+use crate::composefs_consts::{
+    IMAGE_KARGS_EXT, STATE_DIR_RELATIVE, TYPE1_ENT_PATH, TYPE1_ENT_PATH_STAGED,
+};
+use crate::kargs::{DeploymentKargs, split_kargs};
+use crate::parsers::bls_config::{BLSConfig, parse_bls_config};
+use crate::spec::Host;
+use crate::store::{BootedComposefs, Storage};
+use crate::ukify::SecureBootSigningOpts;
+
+/// Returns the path of the UKI addon holding the local kernel arguments,
+/// relative to the ESP.
+fn addon_path(verity: &str) -> String {
+    format!(
+        "{BOOTC_UKI_DIR}/{}/{}",
+        get_uki_addon_dir_name(verity),
+        get_uki_addon_file_name(verity)
+    )
+}
+
+fn read_addon(esp: &Dir, verity: &str) -> Result<Option<Vec<u8>>> {
+    let path = addon_path(verity);
+    if !esp.try_exists(&path)? {
+        return Ok(None);
+    }
+    let addon = esp.read(&path).with_context(|| format!("Reading {path}"))?;
+    Ok(Some(addon))
+}
+
+/// Returns the local kernel arguments of a UKI deployment.
+fn read_addon_kargs(esp: &Dir, verity: &str) -> Result<Option<CmdlineOwned>> {
+    let Some(addon) = read_addon(esp, verity)? else {
+        return Ok(None);
+    };
+    let cmdline = composefs_boot::uki::get_cmdline(&addon).context("Getting addon cmdline")?;
+    Ok(Some(Cmdline::from(cmdline.to_owned())))
+}
+
+/// Build a UKI addon holding the given kernel arguments.
+#[context("Building UKI addon")]
+fn build_addon(kargs: &Cmdline, signing: &SecureBootSigningOpts) -> Result<Vec<u8>> {
+    if !crate::utils::have_executable("ukify")? {
+        anyhow::bail!("ukify executable not found in PATH; it is required to build UKI addons");
+    }
+    let td = tempfile::tempdir()?;
+    let output = td.path().join("kargs.addon.efi");
+    // Without a kernel, ukify builds an addon
+    Command::new("ukify")
+        .arg("build")
+        .arg(format!("--cmdline={kargs}"))
+        .args(signing.ukify_args()?)
+        .arg("--output")
+        .arg(&output)
+        .run_capture_stderr()?;
+    Ok(std::fs::read(&output)?)
+}
+
+/// Carry over the local kernel arguments of the UKI deployment `from` to `to`.
+#[context("Copying UKI addon")]
+pub(crate) fn copy_uki_addon(esp: &Dir, from: &str, to: &str) -> Result<()> {
+    let Some(addon) = read_addon(esp, from)? else {
+        return Ok(());
+    };
+    esp.create_dir_all(format!("{BOOTC_UKI_DIR}/{}", get_uki_addon_dir_name(to)))?;
+    esp.atomic_write(addon_path(to), addon)?;
+    Ok(())
+}
+
+/// Find the BLS config of a deployment in `entries`, along with its file name.
+fn find_bls_config(entries: &Dir, verity: &str) -> Result<Option<(String, BLSConfig)>> {
+    for entry in entries.entries_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name()?;
+        if !name.ends_with(".conf") {
+            continue;
+        }
+        let contents = entries
+            .read_to_string(&name)
+            .with_context(|| format!("Reading {name}"))?;
+        let config = parse_bls_config(&contents).with_context(|| format!("Parsing {name}"))?;
+        if config.get_verity()? == verity {
+            return Ok(Some((name, config)));
+        }
+    }
+    Ok(None)
+}
+
+/// Returns the kernel arguments from the image, as recorded at deployment time.
+fn read_image_kargs(storage: &Storage, verity: &str) -> Result<Option<CmdlineOwned>> {
+    let path = format!("{STATE_DIR_RELATIVE}/{verity}/{verity}.{IMAGE_KARGS_EXT}");
+    let kargs = storage
+        .physical_root
+        .read_to_string_optional(&path)
+        .with_context(|| format!("Reading {path}"))?;
+    Ok(kargs.map(Cmdline::from))
+}
+
+#[context("Updating boot entry of {verity}")]
+fn update_bls_kargs(
+    storage: &Storage,
+    verity: &str,
+    staged: bool,
+    f: impl FnOnce(&mut DeploymentKargs) -> Result<bool>,
+) -> Result<bool> {
+    let boot_dir = storage.require_boot_dir()?;
+    // The entries of a staged deployment are only moved into place on shutdown
+    let entries_path = if staged {
+        TYPE1_ENT_PATH_STAGED
+    } else {
+        TYPE1_ENT_PATH
+    };
+    let entries = boot_dir
+        .open_dir(entries_path)
+        .with_context(|| format!("Opening {entries_path}"))?;
+    let (name, mut config) = find_bls_config(&entries, verity)?
+        .ok_or_else(|| anyhow!("No boot entry found for {verity}"))?;
+
+    // Deployed before the kernel arguments from the image were recorded, if missing
+    let image_kargs = read_image_kargs(storage, verity)?.unwrap_or_default();
+    let mut kargs = DeploymentKargs::from_full(config.get_cmdline()?, &image_kargs);
+    if !f(&mut kargs)? {
+        return Ok(false);
+    }
+    config.set_cmdline(kargs.full())?;
+    entries
+        .atomic_write(&name, config.to_string())
+        .with_context(|| format!("Writing {name}"))?;
+    fsync(entries.reopen_as_ownedfd()?).context("fsync")?;
+
+    println!("Updated kernel arguments in boot entry {name}");
+    Ok(true)
+}
+
+#[context("Updating UKI addon of {verity}")]
+fn update_uki_addon_kargs(
+    storage: &Storage,
+    verity: &str,
+    signing: &SecureBootSigningOpts,
+    f: impl FnOnce(&mut DeploymentKargs) -> Result<bool>,
+) -> Result<bool> {
+    let esp = &storage.require_esp()?.fd;
+    let mut kargs = DeploymentKargs {
+        fixed: get_uki_cmdline(storage, verity)?,
+        editable: read_addon_kargs(esp, verity)?.unwrap_or_default(),
+    };
+    if !f(&mut kargs)? {
+        return Ok(false);
+    }
+
+    let path = addon_path(verity);
+    if kargs.editable.iter().next().is_none() {
+        esp.remove_file_optional(&path)
+            .with_context(|| format!("Removing {path}"))?;
+    } else {
+        // systemd-stub only loads signed addons with Secure Boot enabled
+        if !signing.is_set() && crate::utils::secure_boot_enabled()? {
+            anyhow::bail!(
+                "Secure Boot is enabled; a key to sign the UKI addon is required (--secureboot-key)"
+            );
+        }
+        let addon = build_addon(&kargs.editable, signing)?;
+        let addon_dir = format!("{BOOTC_UKI_DIR}/{}", get_uki_addon_dir_name(verity));
+        esp.create_dir_all(&addon_dir)?;
+        esp.atomic_write(&path, addon)
+            .with_context(|| format!("Writing {path}"))?;
+        fsync(esp.open_dir(&addon_dir)?.reopen_as_ownedfd()?).context("fsync")?;
+    }
+
+    println!("Updated local kernel arguments in UKI addon {path}");
+    Ok(true)
+}
+
+#[context("Changing kernel arguments (composefs)")]
+pub(crate) async fn composefs_update_kargs(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    signing: &SecureBootSigningOpts,
+    f: impl FnOnce(&mut DeploymentKargs) -> Result<bool>,
+) -> Result<bool> {
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    if host.status.rollback_queued {
+        anyhow::bail!("Cannot change kernel arguments with a queued rollback");
+    }
+    // Change the deployment which is booted next
+    let staged = host.status.staged.as_ref();
+    let target = staged
+        .or(host.status.booted.as_ref())
+        .ok_or_else(|| anyhow!("No booted deployment found"))?
+        .require_composefs()?;
+
+    match target.boot_type {
+        BootType::Bls => {
+            if signing.is_set() {
+                anyhow::bail!("Signing keys are only used for UKI addons");
+            }
+            update_bls_kargs(storage, &target.verity, staged.is_some(), f)
+        }
+        BootType::Uki => update_uki_addon_kargs(storage, &target.verity, signing, f),
+    }
+}
+
+/// Fill in the kernel arguments of all deployments.
+#[context("Reading kernel arguments")]
+pub(crate) fn set_kargs(storage: &Storage, host: &mut Host) -> Result<()> {
+    let boot_type = host.require_composefs_booted()?.boot_type;
+    let bls_configs = match boot_type {
+        BootType::Bls => {
+            let boot_dir = storage.require_boot_dir()?;
+            let mut configs = get_sorted_staged_type1_boot_entries(boot_dir, true)?;
+            configs.extend(get_sorted_type1_boot_entries(boot_dir, true)?);
+            configs
+        }
+        BootType::Uki => Vec::new(),
+    };
+
+    let status = &mut host.status;
+    let entries = status
+        .staged
+        .iter_mut()
+        .chain(status.booted.iter_mut())
+        .chain(status.rollback.iter_mut())
+        .chain(status.other_deployments.iter_mut());
+    for entry in entries {
+        let verity = entry.require_composefs()?.verity.clone();
+        entry.kargs = match boot_type {
+            BootType::Bls => {
+                let config = bls_configs
+                    .iter()
+                    .find(|c| c.get_verity().is_ok_and(|v| v == verity));
+                match (config, read_image_kargs(storage, &verity)?) {
+                    (Some(config), Some(image)) => Some(split_kargs(config.get_cmdline()?, &image)),
+                    // Deployed before the kernel arguments from the image were recorded
+                    _ => None,
+                }
+            }
+            BootType::Uki => {
+                let image = get_uki_cmdline(storage, &verity)?;
+                let mut full = image.clone();
+                if let Some(local) = read_addon_kargs(&storage.require_esp()?.fd, &verity)? {
+                    full.extend(&local);
+                }
+                Some(split_kargs(&full, &image))
+            }
+        };
+    }
+    Ok(())
+}
@@ -1,9 +1,12 @@
 pub(crate) mod boot;
+pub(crate) mod boot_counting;
 pub(crate) mod delete;
 pub(crate) mod digest;
 pub(crate) mod export;
+pub(crate) mod factory_reset;
 pub(crate) mod finalize;
 pub(crate) mod gc;
+pub(crate) mod kargs;
 pub(crate) mod repo;
 pub(crate) mod rollback;
 pub(crate) mod selinux;
@@ -10,8 +10,11 @@ use crate::bootc_composefs::boot::{
     BootType, FILENAME_PRIORITY_PRIMARY, FILENAME_PRIORITY_SECONDARY, primary_sort_key,
     secondary_sort_key, type1_entry_conf_file_name,
 };
+use crate::bootc_composefs::boot_counting::DEFAULT_BOOT_TRIES;
 use crate::bootc_composefs::status::{get_composefs_status, get_sorted_type1_boot_entries};
 use crate::composefs_consts::TYPE1_ENT_PATH_STAGED;
+use crate::parsers::grub_menuconfig::get_boot_counting_source;
+use crate::progress_jsonl::{Phase, ProgressWriter};
 use crate::spec::Bootloader;
 use crate::store::{BootedComposefs, Storage};
 use crate::{
@@ -98,6 +101,7 @@ fn rollback_grub_uki_entries(boot_dir: &Dir) -> Result<()> {
     entries_dir
         .atomic_replace_with(USER_CFG_STAGED, |f| -> std::io::Result<_> {
             f.write_all(get_efi_uuid_source().as_bytes())?;
+            f.write_all(get_boot_counting_source(DEFAULT_BOOT_TRIES).as_bytes())?;
 
             for entry in menuentries {
                 f.write_all(entry.to_string().as_bytes())?;
@@ -185,6 +189,7 @@ fn rollback_composefs_entries(boot_dir: &Dir, bootloader: Bootloader) -> Result<
 pub(crate) async fn composefs_rollback(
     storage: &Storage,
     booted_cfs: &BootedComposefs,
+    prog: &ProgressWriter,
 ) -> Result<()> {
     const COMPOSEFS_ROLLBACK_JOURNAL_ID: &str = "6f5e4d3c2b1a0f9e8d7c6b5a4e3d2c1b0";
 
@@ -224,6 +229,7 @@ pub(crate) async fn composefs_rollback(
 
     let boot_dir = storage.require_boot_dir()?;
 
+    prog.start_phase(Phase::Bootloader).await;
     match &rollback_entry.bootloader {
         Bootloader::Grub => match rollback_entry.boot_type {
             BootType::Bls => {
@@ -241,6 +247,7 @@ pub(crate) async fn composefs_rollback(
 
         Bootloader::None => unreachable!("Checked at install time"),
     }
+    prog.complete_phase(Phase::Bootloader).await;
 
     if reverting {
         println!("Next boot: current deployment");
@@ -34,8 +34,9 @@ use crate::parsers::bls_config::BLSConfigType;
 use crate::store::{BootedComposefs, Storage};
 use crate::{
     composefs_consts::{
-        COMPOSEFS_STAGED_DEPLOYMENT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR, ORIGIN_KEY_BOOT,
-        ORIGIN_KEY_BOOT_DIGEST, ORIGIN_KEY_BOOT_TYPE, SHARED_VAR_PATH, STATE_DIR_RELATIVE,
+        COMPOSEFS_STAGED_DEPLOYMENT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR, IMAGE_KARGS_EXT,
+        ORIGIN_KEY_BOOT, ORIGIN_KEY_BOOT_DIGEST, ORIGIN_KEY_BOOT_TYPE, ROOT_SETUP_EXT,
+        SHARED_VAR_PATH, STATE_DIR_RELATIVE,
     },
     parsers::bls_config::BLSConfig,
     spec::ImageReference,
@@ -99,6 +100,38 @@ pub(crate) fn initialize_state(
 
     let tempdir = TempMount::mount_fd(composefs_fd)?;
 
+    // Refuse images which would fail to boot, and record how /etc and /var
+    // will be set up for `bootc status`
+    let root_setup = match tempdir
+        .fd
+        .read_to_string_optional(bootc_initramfs_setup::CONFIG_PATH)?
+    {
+        Some(text) => bootc_initramfs_setup::Config::parse_strict(&text)
+            .with_context(|| format!("Parsing /{}", bootc_initramfs_setup::CONFIG_PATH))?,
+        None => Default::default(),
+    };
+    let state_dir =
+        Dir::open_ambient_dir(state_path, ambient_authority()).context("Opening state dir")?;
+    state_dir
+        .atomic_write(
+            format!("{erofs_id}.{ROOT_SETUP_EXT}"),
+            toml::to_string(&root_setup)?,
+        )
+        .context("Writing root setup")?;
+
+    // Likewise record the kernel arguments from the image, to tell them apart
+    // from local ones
+    let image_kargs = crate::bootc_kargs::get_kargs_in_root(
+        &tempdir.fd,
+        crate::bootc_kargs::KargsMatchContext::host()?,
+    )?;
+    state_dir
+        .atomic_write(
+            format!("{erofs_id}.{IMAGE_KARGS_EXT}"),
+            image_kargs.to_string(),
+        )
+        .context("Writing image kernel arguments")?;
+
     // TODO: Replace this with a function to cap_std_ext
     if initialize_var {
         Command::new("cp")
@@ -109,6 +142,9 @@ pub(crate) fn initialize_state(
                 &format!("{state_path}/var/."),
             ])
             .run_capture_stderr()?;
+        let var = Dir::open_ambient_dir(state_path.join("var"), ambient_authority())
+            .context("Opening var")?;
+        crate::install::apply_var_tmpfiles(&tempdir.fd, &var)?;
     }
 
     let cp_ret = Command::new("cp")
@@ -9,6 +9,8 @@ use serde::{Deserialize, Serialize};
 use crate::{
     bootc_composefs::{
         boot::BootType,
+        boot_counting::populate_boot_tries_left,
+        kargs::set_kargs,
         repo::get_imgref,
         selinux::are_selinux_policies_compatible,
         state::get_composefs_usr_overlay_status,
@@ -43,9 +45,9 @@ use tokio::io::AsyncReadExt;
 
 use crate::composefs_consts::{
     COMPOSEFS_STAGED_DEPLOYMENT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR, ORIGIN_KEY_BOOT,
-    ORIGIN_KEY_BOOT_TYPE, STATE_DIR_RELATIVE,
+    ORIGIN_KEY_BOOT_TYPE, ROOT_SETUP_EXT, STATE_DIR_RELATIVE,
 };
-use crate::spec::Bootloader;
+use crate::spec::{Bootloader, RootSetup, RootSetupMount, RootSetupMountType, RootSetupState};
 
 /// Used for storing the container image info alongside of .origin file
 #[derive(Debug, Serialize, Deserialize)]
@@ -445,6 +447,49 @@ pub(crate) async fn get_imginfo(
     Ok(img_conf)
 }
 
+fn root_setup_mount(
+    config: bootc_initramfs_setup::MountConfig,
+    default: bootc_initramfs_setup::MountType,
+) -> RootSetupMount {
+    use bootc_initramfs_setup::{MountType, StateLocation};
+    let mount = match config.mount_type(default) {
+        MountType::None => RootSetupMountType::None,
+        MountType::Bind => RootSetupMountType::Bind,
+        MountType::Overlay => RootSetupMountType::Overlay,
+        MountType::Transient => RootSetupMountType::Transient,
+        MountType::Tmpfs => RootSetupMountType::Tmpfs,
+    };
+    let state = match config.state {
+        StateLocation::Deployment => RootSetupState::Deployment,
+        StateLocation::Shared => RootSetupState::Shared,
+    };
+    RootSetupMount {
+        mount,
+        state,
+        persist: config.persist,
+    }
+}
+
+/// Reads how /etc and /var are set up for the provided deployment, as recorded
+/// when it was created.
+#[context("Reading root setup")]
+fn get_root_setup(storage: &Storage, deployment_id: &str) -> Result<Option<RootSetup>> {
+    let path = std::path::PathBuf::from(STATE_DIR_RELATIVE)
+        .join(deployment_id)
+        .join(format!("{deployment_id}.{ROOT_SETUP_EXT}"));
+    let Some(text) = storage.physical_root.read_to_string_optional(&path)? else {
+        return Ok(None);
+    };
+    let config = bootc_initramfs_setup::Config::parse(&text)?;
+    // Keep in sync with the defaults in setup_root()
+    let default = bootc_initramfs_setup::MountType::Bind;
+    Ok(Some(RootSetup {
+        root_transient: config.root.transient,
+        etc: root_setup_mount(config.etc, default),
+        var: root_setup_mount(config.var, default),
+    }))
+}
+
 #[context("Getting composefs deployment metadata")]
 async fn boot_entry_from_composefs_deployment(
     storage: &Storage,
@@ -502,8 +547,15 @@ async fn boot_entry_from_composefs_deployment(
             boot_type,
             bootloader: get_bootloader()?,
             boot_digest,
+            boot_tries_left: None, // Set later on
+            root_setup: get_root_setup(storage, verity)?,
         }),
         soft_reboot_capable: false,
+        etc_merge_conflicts: Vec::new(),
+        // Drift detection is only implemented for ostree
+        id_drift: Vec::new(),
+        bound_images: Vec::new(),
+        kargs: None, // Set later on
     };
 
     Ok(e)
@@ -745,6 +797,10 @@ async fn composefs_deployment_status_from(
     let host_spec = HostSpec {
         image: None,
         boot_order: BootOrder::Default,
+        update_policy: crate::update_policy::load_host().unwrap_or_else(|e| {
+            tracing::warn!("{e:#}");
+            None
+        }),
     };
 
     let mut host = Host::new(host_spec);
@@ -938,8 +994,15 @@ async fn composefs_deployment_status_from(
 
     host.status.usr_overlay = get_composefs_usr_overlay_status().ok().flatten();
 
+    populate_boot_tries_left(boot_dir, &mut host)?;
+
     set_soft_reboot_capability(storage, &mut host, sorted_bls_config, cmdline)?;
 
+    // Failing to tell the kernel arguments apart must not break status
+    if let Err(e) = set_kargs(storage, &mut host) {
+        tracing::warn!("{e:#}");
+    }
+
     Ok(host)
 }
 
@@ -8,6 +8,7 @@ use crate::{
         update::{DoUpgradeOpts, UpdateAction, do_upgrade, is_image_pulled, validate_update},
     },
     cli::{SwitchOpts, imgref_for_switch},
+    progress_jsonl::ProgressWriter,
     store::{BootedComposefs, Storage},
 };
 
@@ -16,6 +17,7 @@ pub(crate) async fn switch_composefs(
     opts: SwitchOpts,
     storage: &Storage,
     booted_cfs: &BootedComposefs,
+    prog: ProgressWriter,
 ) -> Result<()> {
     let target = imgref_for_switch(&opts)?;
 
@@ -82,6 +84,7 @@ pub(crate) async fn switch_composefs(
                     &target_imgref,
                     &img_config,
                     &do_upgrade_opts,
+                    &prog,
                 )
                 .await;
             }
@@ -102,6 +105,7 @@ pub(crate) async fn switch_composefs(
         &target_imgref,
         &img_config,
         &do_upgrade_opts,
+        &prog,
     )
     .await?;
 
@@ -1,4 +1,6 @@
 use anyhow::{Context, Result};
+use bootc_initramfs_setup::mount_composefs_image;
+use bootc_mount::tempmount::TempMount;
 use camino::Utf8PathBuf;
 use cap_std_ext::{cap_std::fs::Dir, dirext::CapStdExtDirExt};
 use cfsctl::composefs;
@@ -14,6 +16,7 @@ use ostree_ext::container::ManifestDiff;
 use crate::{
     bootc_composefs::{
         boot::{BootSetupType, BootType, setup_composefs_bls_boot, setup_composefs_uki_boot},
+        factory_reset::ensure_none_queued,
         gc::composefs_gc,
         repo::{get_imgref, pull_composefs_repo},
         service::start_finalize_stated_svc,
@@ -29,6 +32,7 @@ use crate::{
         COMPOSEFS_STAGED_DEPLOYMENT_FNAME, COMPOSEFS_TRANSIENT_STATE_DIR, STATE_DIR_RELATIVE,
         TYPE1_ENT_PATH_STAGED, USER_CFG_STAGED,
     },
+    progress_jsonl::{Phase, ProgressWriter},
     spec::{Bootloader, Host, ImageReference},
     store::{BootedComposefs, ComposefsRepository, Storage},
 };
@@ -243,6 +247,26 @@ async fn apply_upgrade(
     Ok(())
 }
 
+/// Fail before staging `new_root` if merging the current /etc into it at
+/// finalization would run into a `fail` policy.
+fn check_etc_merge(storage: &Storage, booted_cfs: &BootedComposefs, new_root: &Dir) -> Result<()> {
+    // Mount the booted EROFS image to get pristine etc
+    let sysroot_fd = storage.physical_root.reopen_as_ownedfd()?;
+    let composefs_fd = mount_composefs_image(
+        &sysroot_fd,
+        &booted_cfs.cmdline.digest,
+        booted_cfs.cmdline.allow_missing_fsverity,
+    )?;
+    let erofs_tmp_mnt = TempMount::mount_fd(&composefs_fd)?;
+
+    let pristine_etc = erofs_tmp_mnt.fd.open_dir("etc")?;
+    let current_etc = Dir::open_ambient_dir("/etc", ambient_authority())?;
+    let new_pristine_etc = new_root.open_dir("etc")?;
+    let config = crate::etcmerge::config_from_root(new_root)?;
+
+    crate::etcmerge::check_etc_dirs(&pristine_etc, &current_etc, &new_pristine_etc, &config)
+}
+
 /// Performs the Update or Switch operation
 #[context("Performing Upgrade Operation")]
 pub(crate) async fn do_upgrade(
@@ -252,7 +276,9 @@ pub(crate) async fn do_upgrade(
     imgref: &ImageReference,
     img_manifest_config: &ImgConfigManifest,
     opts: &DoUpgradeOpts,
+    prog: &ProgressWriter,
 ) -> Result<()> {
+    ensure_none_queued(storage)?;
     start_finalize_stated_svc()?;
 
     // Pre-flight disk space check before pulling any data.
@@ -262,12 +288,14 @@ pub(crate) async fn do_upgrade(
         imgref,
     )?;
 
+    prog.start_phase(Phase::Pull).await;
     let (repo, entries, id, fs) = pull_composefs_repo(
         &imgref.transport,
         &imgref.image,
         booted_cfs.cmdline.allow_missing_fsverity,
     )
     .await?;
+    prog.complete_phase(Phase::Pull).await;
 
     let Some(entry) = entries.iter().next() else {
         anyhow::bail!("No boot entries!");
@@ -279,8 +307,11 @@ pub(crate) async fn do_upgrade(
             .context("Failed to mount composefs image")?,
     )?;
 
+    check_etc_merge(storage, booted_cfs, &mounted_fs)?;
+
     let boot_type = BootType::from(entry);
 
+    prog.start_phase(Phase::Bootloader).await;
     let boot_digest = match boot_type {
         BootType::Bls => setup_composefs_bls_boot(
             BootSetupType::Upgrade((storage, booted_cfs, &fs, &host)),
@@ -297,7 +328,9 @@ pub(crate) async fn do_upgrade(
             entries,
         )?,
     };
+    prog.complete_phase(Phase::Bootloader).await;
 
+    prog.start_phase(Phase::Deploy).await;
     write_composefs_state(
         &Utf8PathBuf::from("/sysroot"),
         &id,
@@ -312,6 +345,7 @@ pub(crate) async fn do_upgrade(
         booted_cfs.cmdline.allow_missing_fsverity,
     )
     .await?;
+    prog.complete_phase(Phase::Deploy).await;
 
     // We take into account the staged bootloader entries so this won't remove
     // the currently staged entry
@@ -325,6 +359,7 @@ pub(crate) async fn upgrade_composefs(
     opts: UpgradeOpts,
     storage: &Storage,
     composefs: &BootedComposefs,
+    prog: ProgressWriter,
 ) -> Result<()> {
     const COMPOSEFS_UPGRADE_JOURNAL_ID: &str = "9c8d7f6e5a4b3c2d1e0f9a8b7c6d5e4f3";
 
@@ -460,6 +495,7 @@ pub(crate) async fn upgrade_composefs(
                         booted_imgref,
                         &img_config,
                         &do_upgrade_opts,
+                        &prog,
                     )
                     .await;
                 }
@@ -496,6 +532,7 @@ pub(crate) async fn upgrade_composefs(
                     booted_imgref,
                     &img_config,
                     &do_upgrade_opts,
+                    &prog,
                 )
                 .await;
             }
@@ -521,6 +558,7 @@ pub(crate) async fn upgrade_composefs(
         booted_imgref,
         &img_config,
         &do_upgrade_opts,
+        &prog,
     )
     .await?;
 
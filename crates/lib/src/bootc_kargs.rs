@@ -1,25 +1,41 @@
 //! This module handles the bootc-owned kernel argument lists in `/usr/lib/bootc/kargs.d`.
-use anyhow::{Context, Result};
+use std::process::Command;
+use std::sync::OnceLock;
+
+use anyhow::{Context, Result, anyhow};
 use bootc_kernel_cmdline::utf8::{Cmdline, CmdlineOwned};
 use camino::Utf8Path;
 use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::cap_std::fs_utf8::Dir as DirUtf8;
 use cap_std_ext::dirext::CapStdExtDirExt;
 use cap_std_ext::dirext::CapStdExtDirExtUtf8;
+use fn_error_context::context;
 use ostree::gio;
 use ostree_ext::ostree;
 use ostree_ext::ostree::Deployment;
 use ostree_ext::prelude::Cast;
 use ostree_ext::prelude::FileEnumeratorExt;
 use ostree_ext::prelude::FileExt;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
 use crate::deploy::ImageState;
+use crate::install::InstallConfigOpts;
+use crate::install::config::{InstallConfiguration, InstallConfigurationToplevel};
 use crate::store::Storage;
 
 /// The relative path to the kernel arguments which may be embedded in an image.
 const KARGS_PATH: &str = "usr/lib/bootc/kargs.d";
 
+/// Where the kernel exposes the DMI system vendor.
+const DMI_SYS_VENDOR: &str = "/sys/class/dmi/id/sys_vendor";
+
+/// The install configuration in effect at install time, including options
+/// given on the command line, relative to the physical root.
+pub(crate) const INSTALL_CONFIG_RECORD_PATH: &str = ".bootc-install-config.toml";
+
+/// The physical root of the running system.
+const SYSROOT: &str = "/sysroot";
+
 /// The default root filesystem mount specification.
 pub(crate) const ROOT_KEY: &str = "root";
 /// This is used by dracut.
@@ -27,15 +43,216 @@ pub(crate) const INITRD_ARG_PREFIX: &str = "rd.";
 /// The kernel argument for configuring the rootfs flags.
 pub(crate) const ROOTFLAGS_KEY: &str = "rootflags";
 
+/// Firmware types which kargs.d files can match on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Firmware {
+    /// Booted via UEFI
+    Uefi,
+    /// Booted via legacy BIOS
+    Bios,
+}
+
+/// Properties of the target system which kargs.d files can match on. If a
+/// property is not known, files which match on it are skipped.
+#[derive(Debug, Default)]
+pub(crate) struct KargsMatchContext {
+    /// The architecture, using the Rust naming conventions.
+    pub(crate) arch: String,
+    /// The virtualization type as reported by `systemd-detect-virt --vm`,
+    /// i.e. `none` on bare metal.
+    pub(crate) virtualization: Option<String>,
+    /// The firmware the system is booted with.
+    pub(crate) firmware: Option<Firmware>,
+    /// The DMI system vendor.
+    pub(crate) dmi_vendor: Option<String>,
+    /// The install configuration, i.e. the `[install]` table.
+    pub(crate) install_config: Option<toml::Table>,
+}
+
+/// The properties of the target system; see [`KargsMatchContext::host`].
+static TARGET: OnceLock<KargsMatchContext> = OnceLock::new();
+
+fn install_config_table(config: &InstallConfiguration) -> Result<toml::Table> {
+    match toml::Value::try_from(config).context("Serializing install configuration")? {
+        toml::Value::Table(t) => Ok(t),
+        _ => anyhow::bail!("Install configuration is not a table"),
+    }
+}
+
+/// Record the install configuration in effect in the physical root of the
+/// system being installed.
+#[context("Recording install configuration")]
+pub(crate) fn write_install_config(root: &Dir, config: &InstallConfiguration) -> Result<()> {
+    let toplevel = InstallConfigurationToplevel {
+        install: Some(config.clone()),
+    };
+    root.atomic_write(INSTALL_CONFIG_RECORD_PATH, toml::to_string(&toplevel)?)?;
+    Ok(())
+}
+
+/// Read the install configuration recorded by [`write_install_config`].
+/// This is parsed as a plain table, so that keys unknown to this version
+/// are accepted.
+fn read_install_config(root: &Dir) -> Result<Option<toml::Table>> {
+    let Some(contents) = root.read_to_string_optional(INSTALL_CONFIG_RECORD_PATH)? else {
+        return Ok(None);
+    };
+    let mut toplevel: toml::Table = toml::from_str(&contents)
+        .with_context(|| format!("Parsing {INSTALL_CONFIG_RECORD_PATH}"))?;
+    match toplevel.remove("install") {
+        Some(toml::Value::Table(t)) => Ok(Some(t)),
+        None => Ok(Some(Default::default())),
+        Some(_) => anyhow::bail!("Invalid {INSTALL_CONFIG_RECORD_PATH}"),
+    }
+}
+
+impl KargsMatchContext {
+    /// Only the architecture is known, e.g. when building a container image.
+    pub(crate) fn for_arch(arch: &str) -> Self {
+        Self {
+            arch: arch.to_owned(),
+            ..Default::default()
+        }
+    }
+
+    /// The properties of the target system. At install time, these are set
+    /// up via [`Self::init_install`]; otherwise, e.g. when staging a deployment,
+    /// it is the running system, with the install configuration recorded at
+    /// install time.
+    pub(crate) fn host() -> Result<&'static Self> {
+        if let Some(ctx) = TARGET.get() {
+            return Ok(ctx);
+        }
+        let ctx = Self::detect_running()?;
+        Ok(TARGET.get_or_init(|| ctx))
+    }
+
+    /// Set up the properties of the system being installed, with the install
+    /// configuration in effect. This must be called before [`Self::host`].
+    pub(crate) fn init_install(
+        opts: &InstallConfigOpts,
+        install_config: &InstallConfiguration,
+    ) -> Result<()> {
+        let ctx = Self::for_install(opts, install_config)?;
+        tracing::debug!("kargs.d match context: {ctx:?}");
+        TARGET
+            .set(ctx)
+            .map_err(|_| anyhow!("kargs.d match context already initialized"))
+    }
+
+    /// The system running the installation is only assumed to be the target
+    /// system if this isn't a generic image; either way, the properties can
+    /// be overridden via the install options.
+    #[context("Detecting system properties for kargs.d")]
+    fn for_install(
+        opts: &InstallConfigOpts,
+        install_config: &InstallConfiguration,
+    ) -> Result<Self> {
+        let detected = if opts.generic_image {
+            Self::for_arch(std::env::consts::ARCH)
+        } else {
+            Self::detect_system()?
+        };
+        Ok(Self {
+            virtualization: opts
+                .target_virtualization
+                .clone()
+                .or(detected.virtualization),
+            firmware: opts.target_firmware.or(detected.firmware),
+            dmi_vendor: opts.target_dmi_vendor.clone().or(detected.dmi_vendor),
+            install_config: Some(install_config_table(install_config)?),
+            ..detected
+        })
+    }
+
+    #[context("Detecting system properties for kargs.d")]
+    fn detect_running() -> Result<Self> {
+        let sysroot = Dir::open_ambient_dir(SYSROOT, cap_std_ext::cap_std::ambient_authority())
+            .with_context(|| format!("Opening {SYSROOT}"))?;
+        let install_config = match read_install_config(&sysroot)? {
+            Some(config) => Some(config),
+            // Installed before the install configuration was recorded; the
+            // one of the running image is the best approximation
+            None => crate::install::config::load_config()?
+                .map(|c| install_config_table(&c))
+                .transpose()?,
+        };
+        let ctx = Self {
+            install_config,
+            ..Self::detect_system()?
+        };
+        tracing::debug!("kargs.d match context: {ctx:?}");
+        Ok(ctx)
+    }
+
+    /// Detect the properties of the running system, except for the install
+    /// configuration.
+    fn detect_system() -> Result<Self> {
+        let firmware = if Utf8Path::new("/sys/firmware/efi").try_exists()? {
+            Firmware::Uefi
+        } else {
+            Firmware::Bios
+        };
+        let dmi_vendor = match std::fs::read_to_string(DMI_SYS_VENDOR) {
+            Ok(v) => Some(v.trim().to_owned()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e).context("Reading DMI system vendor"),
+        };
+        Ok(Self {
+            arch: std::env::consts::ARCH.to_owned(),
+            virtualization: detect_virtualization(),
+            firmware: Some(firmware),
+            dmi_vendor,
+            install_config: None,
+        })
+    }
+}
+
+/// Returns the virtualization type, or `None` if it could not be detected.
+fn detect_virtualization() -> Option<String> {
+    // This exits with an error when not virtualized, but still prints `none`
+    let output = match Command::new("systemd-detect-virt").arg("--vm").output() {
+        Ok(o) => o,
+        Err(e) => {
+            tracing::debug!("Failed to run systemd-detect-virt: {e}");
+            return None;
+        }
+    };
+    let virt = String::from_utf8(output.stdout).ok()?;
+    let virt = virt.trim();
+    (!virt.is_empty()).then(|| virt.to_owned())
+}
+
 /// The kargs.d configuration file.
 #[derive(Deserialize)]
 #[serde(rename_all = "kebab-case", deny_unknown_fields)]
 struct Config {
     /// Ordered list of kernel arguments.
+    #[serde(default)]
     kargs: Vec<String>,
+    /// Kernel arguments to remove from the ones of the preceding files; either
+    /// all with the same key (`foo`), or the exactly matching ones (`foo=bar`).
+    #[serde(default)]
+    delete: Vec<String>,
+    /// Kernel arguments which replace all with the same key from the preceding
+    /// files.
+    #[serde(default)]
+    replace: Vec<String>,
     /// Optional list of architectures (using the Rust naming conventions);
     /// if present and the current architecture doesn't match, the file is skipped.
     match_architectures: Option<Vec<String>>,
+    /// Optional list of virtualization types as reported by
+    /// `systemd-detect-virt --vm`; `none` matches bare metal.
+    match_virtualization: Option<Vec<String>>,
+    /// Optional firmware type, `uefi` or `bios`.
+    match_firmware: Option<Firmware>,
+    /// Optional list of strings; the file applies if the DMI system vendor
+    /// contains one of them.
+    match_dmi_vendor: Option<Vec<String>>,
+    /// Optional table of install configuration keys and their values, e.g.
+    /// `root-fs-type = "xfs"`; the file applies if all of them match.
+    match_install_config: Option<toml::Table>,
 }
 
 impl Config {
@@ -43,6 +260,55 @@ impl Config {
     fn filename_matches(name: &str) -> bool {
         matches!(Utf8Path::new(name).extension(), Some("toml"))
     }
+
+    /// Return true if all match conditions are fulfilled by the target system.
+    fn matches(&self, ctx: &KargsMatchContext) -> bool {
+        fn one_of(values: &Option<Vec<String>>, f: impl Fn(&str) -> bool) -> bool {
+            values.as_ref().is_none_or(|v| v.iter().any(|s| f(s)))
+        }
+        one_of(&self.match_architectures, |s| s == ctx.arch)
+            && one_of(&self.match_virtualization, |s| {
+                ctx.virtualization.as_deref() == Some(s)
+            })
+            && self.match_firmware.is_none_or(|f| ctx.firmware == Some(f))
+            && one_of(&self.match_dmi_vendor, |s| {
+                ctx.dmi_vendor.as_ref().is_some_and(|v| v.contains(s))
+            })
+            && self.match_install_config.as_ref().is_none_or(|expected| {
+                ctx.install_config
+                    .as_ref()
+                    .is_some_and(|c| table_matches(expected, c))
+            })
+    }
+
+    /// Apply this file to the kernel arguments of the preceding files.
+    fn apply(&self, kargs: &mut CmdlineOwned) {
+        for arg in &Cmdline::from(self.delete.join(" ")) {
+            if arg.value().is_some() {
+                kargs.remove_exact(&arg);
+            } else {
+                kargs.remove(&arg.key());
+            }
+        }
+        for arg in &Cmdline::from(self.replace.join(" ")) {
+            kargs.add_or_modify(&arg);
+        }
+        kargs.extend(&Cmdline::from(self.kargs.join(" ")));
+    }
+}
+
+/// Return true if `actual` has all keys of `expected`, with the same values.
+fn table_matches(expected: &toml::Table, actual: &toml::Table) -> bool {
+    expected.iter().all(|(k, expected)| {
+        actual
+            .get(k)
+            .is_some_and(|actual| match (expected, actual) {
+                (toml::Value::Table(expected), toml::Value::Table(actual)) => {
+                    table_matches(expected, actual)
+                }
+                (expected, actual) => expected == actual,
+            })
+    })
 }
 
 /// Compute the diff between existing and remote kargs
@@ -82,7 +348,8 @@ fn compute_apply_kargs_diff(
 /// 4. Applies that difference to the provided `new_kargs` cmdline
 ///
 /// This allows bootc to maintain user customizations while applying changes from
-/// updated container images.
+/// updated container images. The match conditions of the kargs.d files are
+/// evaluated for the running system.
 ///
 /// # Arguments
 /// * `new_fs`       - Directory handle to the new filesystem containing kargs.d files
@@ -93,10 +360,11 @@ pub(crate) fn compute_new_kargs(
     current_root: Option<&Dir>,
     new_kargs: &mut Cmdline,
 ) -> Result<()> {
-    let remote_kargs = get_kargs_in_root(new_fs, std::env::consts::ARCH)?;
+    let ctx = KargsMatchContext::host()?;
+    let remote_kargs = get_kargs_in_root(new_fs, ctx)?;
 
     let existing_kargs = match current_root {
-        Some(root) => get_kargs_in_root(root, std::env::consts::ARCH)?,
+        Some(root) => get_kargs_in_root(root, ctx)?,
         None => Cmdline::new(),
     };
 
@@ -107,7 +375,7 @@ pub(crate) fn compute_new_kargs(
 
 /// Load and parse all bootc kargs.d files in the specified root, returning
 /// a combined list.
-pub(crate) fn get_kargs_in_root(d: &Dir, sys_arch: &str) -> Result<CmdlineOwned> {
+pub(crate) fn get_kargs_in_root(d: &Dir, ctx: &KargsMatchContext) -> Result<CmdlineOwned> {
     // If the directory doesn't exist, that's OK.
     let Some(d) = d.open_dir_optional(KARGS_PATH)?.map(DirUtf8::from_cap_std) else {
         return Ok(Default::default());
@@ -116,10 +384,10 @@ pub(crate) fn get_kargs_in_root(d: &Dir, sys_arch: &str) -> Result<CmdlineOwned>
     let entries = d.filenames_filtered_sorted(|_, name| Config::filename_matches(name))?;
     for name in entries {
         let buf = d.read_to_string(&name)?;
-        if let Some(kargs) =
-            parse_kargs_toml(&buf, sys_arch).with_context(|| format!("Parsing {name}"))?
+        if let Some(config) =
+            parse_kargs_toml(&buf, ctx).with_context(|| format!("Parsing {name}"))?
         {
-            ret.extend(&kargs)
+            config.apply(&mut ret);
         }
     }
     Ok(ret)
@@ -143,27 +411,27 @@ pub(crate) fn root_args_from_cmdline(cmdline: &Cmdline) -> CmdlineOwned {
 pub(crate) fn get_kargs_from_ostree_root(
     repo: &ostree::Repo,
     root: &ostree::RepoFile,
-    sys_arch: &str,
+    ctx: &KargsMatchContext,
 ) -> Result<CmdlineOwned> {
     let kargsd = root.resolve_relative_path(KARGS_PATH);
     let kargsd = kargsd.downcast_ref::<ostree::RepoFile>().expect("downcast");
     if !kargsd.query_exists(gio::Cancellable::NONE) {
         return Ok(Default::default());
     }
-    get_kargs_from_ostree(repo, kargsd, sys_arch)
+    get_kargs_from_ostree(repo, kargsd, ctx)
 }
 
 /// Load kargs.d files from the target dir
 fn get_kargs_from_ostree(
     repo: &ostree::Repo,
     fetched_tree: &ostree::RepoFile,
-    sys_arch: &str,
+    ctx: &KargsMatchContext,
 ) -> Result<CmdlineOwned> {
     let cancellable = gio::Cancellable::NONE;
     let queryattrs = "standard::name,standard::type";
     let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
     let fetched_iter = fetched_tree.enumerate_children(queryattrs, queryflags, cancellable)?;
-    let mut ret = Cmdline::new();
+    let mut children = Vec::new();
     while let Some(fetched_info) = fetched_iter.next_file(cancellable)? {
         // only read and parse the file if it is a toml file
         let name = fetched_info.name();
@@ -173,8 +441,13 @@ fn get_kargs_from_ostree(
         if !Config::filename_matches(name) {
             continue;
         }
+        children.push((name.to_owned(), fetched_iter.child(&fetched_info)));
+    }
+    // Later files may delete or replace kernel arguments of earlier ones
+    children.sort_by(|a, b| a.0.cmp(&b.0));
 
-        let fetched_child = fetched_iter.child(&fetched_info);
+    let mut ret = Cmdline::new();
+    for (name, fetched_child) in children {
         let fetched_child = fetched_child
             .downcast::<ostree::RepoFile>()
             .expect("downcast");
@@ -185,10 +458,10 @@ fn get_kargs_from_ostree(
         let mut reader =
             ostree_ext::prelude::InputStreamExtManual::into_read(file_content.unwrap());
         let s = std::io::read_to_string(&mut reader)?;
-        if let Some(parsed_kargs) =
-            parse_kargs_toml(&s, sys_arch).with_context(|| format!("Parsing {name}"))?
+        if let Some(config) =
+            parse_kargs_toml(&s, ctx).with_context(|| format!("Parsing {name}"))?
         {
-            ret.extend(&parsed_kargs);
+            config.apply(&mut ret);
         }
     }
     Ok(ret)
@@ -205,7 +478,7 @@ pub(crate) fn get_kargs(
     let cancellable = gio::Cancellable::NONE;
     let ostree = sysroot.get_ostree()?;
     let repo = &ostree.repo();
-    let sys_arch = std::env::consts::ARCH;
+    let ctx = KargsMatchContext::host()?;
 
     // Get the kargs used for the merge in the bootloader config
     let mut kargs = ostree::Deployment::bootconfig(merge_deployment)
@@ -217,7 +490,7 @@ pub(crate) fn get_kargs(
 
     // Get the kargs in kargs.d of the merge
     let merge_root = &crate::utils::deployment_fd(ostree, merge_deployment)?;
-    let existing_kargs = get_kargs_in_root(merge_root, sys_arch)?;
+    let existing_kargs = get_kargs_in_root(merge_root, ctx)?;
 
     // Get the kargs in kargs.d of the pending image
     let (fetched_tree, _) = repo.read_commit(fetched.ostree_commit.as_str(), cancellable)?;
@@ -233,30 +506,20 @@ pub(crate) fn get_kargs(
     }
 
     // Fetch the kernel arguments from the new root
-    let remote_kargs = get_kargs_from_ostree(repo, &fetched_tree, sys_arch)?;
+    let remote_kargs = get_kargs_from_ostree(repo, &fetched_tree, ctx)?;
 
     compute_apply_kargs_diff(&existing_kargs, &remote_kargs, &mut kargs);
 
     Ok(kargs)
 }
 
-/// This parses a bootc kargs.d toml file, returning the resulting
-/// vector of kernel arguments. Architecture matching is performed using
-/// `sys_arch`.
-fn parse_kargs_toml(contents: &str, sys_arch: &str) -> Result<Option<CmdlineOwned>> {
+/// This parses a bootc kargs.d toml file, returning it if its match
+/// conditions are fulfilled by the target system described by `ctx`.
+fn parse_kargs_toml(contents: &str, ctx: &KargsMatchContext) -> Result<Option<Config>> {
     let de: Config = toml::from_str(contents)?;
-    // if arch specified, apply kargs only if the arch matches
-    // if arch not specified, apply kargs unconditionally
-    let matched = de
-        .match_architectures
-        .map(|arches| arches.iter().any(|s| s == sys_arch))
-        .unwrap_or(true);
-    let r = if matched {
-        Some(Cmdline::from(de.kargs.join(" ")))
-    } else {
-        None
-    };
-    Ok(r)
+    // if conditions are specified, apply the file only if they match
+    // if none are specified, apply it unconditionally
+    Ok(de.matches(ctx).then_some(de))
 }
 
 #[cfg(test)]
@@ -272,17 +535,28 @@ mod tests {
         assert_eq!(actual_params, expected_params);
     }
 
+    /// Parse a kargs.d file for the given architecture, returning its kernel arguments.
+    fn parse(contents: &str, sys_arch: &str) -> Result<Option<CmdlineOwned>> {
+        let ctx = KargsMatchContext::for_arch(sys_arch);
+        let r = parse_kargs_toml(contents, &ctx)?.map(|config| {
+            let mut kargs = Cmdline::new();
+            config.apply(&mut kargs);
+            kargs
+        });
+        Ok(r)
+    }
+
     #[test]
     /// Verify that kargs are only applied to supported architectures
     fn test_arch() {
         // no arch specified, kargs ensure that kargs are applied unconditionally
         let sys_arch = "x86_64";
         let file_content = r##"kargs = ["console=tty0", "nosmt"]"##.to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap().unwrap();
+        let parsed_kargs = parse(&file_content, sys_arch).unwrap().unwrap();
         assert_cmdline_eq(&parsed_kargs, &["console=tty0", "nosmt"]);
 
         let sys_arch = "aarch64";
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap().unwrap();
+        let parsed_kargs = parse(&file_content, sys_arch).unwrap().unwrap();
         assert_cmdline_eq(&parsed_kargs, &["console=tty0", "nosmt"]);
 
         // one arch matches and one doesn't, ensure that kargs are only applied for the matching arch
@@ -291,13 +565,13 @@ mod tests {
 match-architectures = ["x86_64"]
 "##
         .to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap();
+        let parsed_kargs = parse(&file_content, sys_arch).unwrap();
         assert!(parsed_kargs.is_none());
         let file_content = r##"kargs = ["console=tty0", "nosmt"]
 match-architectures = ["aarch64"]
 "##
         .to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap().unwrap();
+        let parsed_kargs = parse(&file_content, sys_arch).unwrap().unwrap();
         assert_cmdline_eq(&parsed_kargs, &["console=tty0", "nosmt"]);
 
         // multiple arch specified, ensure that kargs are applied to both archs
@@ -306,11 +580,11 @@ match-architectures = ["aarch64"]
 match-architectures = ["x86_64", "aarch64"]
 "##
         .to_string();
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap().unwrap();
+        let parsed_kargs = parse(&file_content, sys_arch).unwrap().unwrap();
         assert_cmdline_eq(&parsed_kargs, &["console=tty0", "nosmt"]);
 
         let sys_arch = "aarch64";
-        let parsed_kargs = parse_kargs_toml(&file_content, sys_arch).unwrap().unwrap();
+        let parsed_kargs = parse(&file_content, sys_arch).unwrap().unwrap();
         assert_cmdline_eq(&parsed_kargs, &["console=tty0", "nosmt"]);
     }
 
@@ -318,10 +592,151 @@ match-architectures = ["x86_64", "aarch64"]
     /// Verify some error cases
     fn test_invalid() {
         let test_invalid_extra = r#"kargs = ["console=tty0", "nosmt"]\nfoo=bar"#;
-        assert!(parse_kargs_toml(test_invalid_extra, "x86_64").is_err());
+        assert!(parse(test_invalid_extra, "x86_64").is_err());
 
         let test_missing = r#"foo=bar"#;
-        assert!(parse_kargs_toml(test_missing, "x86_64").is_err());
+        assert!(parse(test_missing, "x86_64").is_err());
+    }
+
+    #[test]
+    /// Verify the match conditions besides the architecture
+    fn test_match_conditions() -> Result<()> {
+        let install_config = toml::from_str(indoc::indoc! { r#"
+            root-fs-type = "xfs"
+            [filesystem.root]
+            type = "xfs"
+        "# })?;
+        let ctx = KargsMatchContext {
+            virtualization: Some("kvm".into()),
+            firmware: Some(Firmware::Uefi),
+            dmi_vendor: Some("QEMU Corporation".into()),
+            install_config: Some(install_config),
+            ..KargsMatchContext::for_arch("x86_64")
+        };
+        let matches = |conditions: &str| -> Result<bool> {
+            let contents = format!("kargs = [\"nosmt\"]\n{conditions}");
+            Ok(parse_kargs_toml(&contents, &ctx)?.is_some())
+        };
+
+        assert!(matches(r#"match-virtualization = ["kvm", "qemu"]"#)?);
+        assert!(!matches(r#"match-virtualization = ["none"]"#)?);
+        assert!(matches(r#"match-firmware = "uefi""#)?);
+        assert!(!matches(r#"match-firmware = "bios""#)?);
+        assert!(matches(r#"match-dmi-vendor = ["Dell", "QEMU"]"#)?);
+        assert!(!matches(r#"match-dmi-vendor = ["Dell"]"#)?);
+        assert!(matches(
+            r#"match-install-config = { root-fs-type = "xfs" }"#
+        )?);
+        assert!(matches(
+            r#"match-install-config = { filesystem.root.type = "xfs" }"#
+        )?);
+        assert!(!matches(
+            r#"match-install-config = { root-fs-type = "ext4" }"#
+        )?);
+        assert!(!matches(r#"match-install-config = { stateroot = "xfs" }"#)?);
+        // All conditions must match
+        assert!(matches(indoc::indoc! { r#"
+            match-architectures = ["x86_64"]
+            match-firmware = "uefi"
+            match-virtualization = ["kvm"]
+        "# })?);
+        assert!(!matches(indoc::indoc! { r#"
+            match-architectures = ["x86_64"]
+            match-firmware = "bios"
+        "# })?);
+        assert!(parse_kargs_toml(r#"match-firmware = "coreboot""#, &ctx).is_err());
+
+        // Files which match on unknown properties are skipped
+        let ctx = KargsMatchContext::for_arch("x86_64");
+        for conditions in [
+            r#"match-virtualization = ["none"]"#,
+            r#"match-firmware = "uefi""#,
+            r#"match-dmi-vendor = ["QEMU"]"#,
+            r#"match-install-config = { root-fs-type = "xfs" }"#,
+        ] {
+            assert!(parse_kargs_toml(conditions, &ctx)?.is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    /// Verify the properties of the system being installed
+    fn test_for_install() -> Result<()> {
+        let install_config: InstallConfiguration = toml::from_str(r#"root-fs-type = "btrfs""#)?;
+        let opts: InstallConfigOpts = serde_json::from_value(serde_json::json!({
+            "generic_image": true,
+            "target_firmware": "bios",
+        }))?;
+        let ctx = KargsMatchContext::for_install(&opts, &install_config)?;
+        assert_eq!(ctx.firmware, Some(Firmware::Bios));
+        // The system running the installation isn't used for generic images
+        assert_eq!(ctx.virtualization, None);
+        assert_eq!(ctx.dmi_vendor, None);
+        let contents = indoc::indoc! { r#"
+            kargs = ["rootflags=compress=zstd"]
+            match-install-config = { root-fs-type = "btrfs" }
+        "# };
+        assert!(parse_kargs_toml(contents, &ctx)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    /// Verify recording the install configuration
+    fn test_install_config_record() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        assert!(read_install_config(&td)?.is_none());
+
+        let config: InstallConfiguration = toml::from_str(r#"root-fs-type = "btrfs""#)?;
+        write_install_config(&td, &config)?;
+        let table = read_install_config(&td)?.unwrap();
+        assert_eq!(table["root-fs-type"].as_str(), Some("btrfs"));
+
+        // Keys unknown to this version are accepted
+        td.write(
+            INSTALL_CONFIG_RECORD_PATH,
+            "[install]\nroot-fs-type = \"xfs\"\nfuture-key = 1\n",
+        )?;
+        let table = read_install_config(&td)?.unwrap();
+        assert_eq!(table["root-fs-type"].as_str(), Some("xfs"));
+        Ok(())
+    }
+
+    #[test]
+    /// Verify that later files can delete and replace kernel arguments
+    fn test_delete_replace() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
+        td.create_dir_all("usr/lib/bootc/kargs.d")?;
+        td.write(
+            "usr/lib/bootc/kargs.d/10-base.toml",
+            r#"kargs = ["console=tty0", "console=ttyS0", "quiet", "nosmt", "mitigations=auto"]"#,
+        )?;
+        td.write(
+            "usr/lib/bootc/kargs.d/50-derived.toml",
+            indoc::indoc! { r#"
+                delete = ["console", "nosmt=1", "quiet"]
+                replace = ["mitigations=off"]
+                kargs = ["console=ttyS1"]
+            "# },
+        )?;
+        let args = get_kargs_in_root(&td, &KargsMatchContext::for_arch("x86_64"))?;
+        assert_cmdline_eq(&args, &["nosmt", "mitigations=off", "console=ttyS1"]);
+
+        // A file without kernel arguments is fine
+        td.write("usr/lib/bootc/kargs.d/60-empty.toml", "")?;
+        let args = get_kargs_in_root(&td, &KargsMatchContext::for_arch("x86_64"))?;
+        assert_cmdline_eq(&args, &["nosmt", "mitigations=off", "console=ttyS1"]);
+
+        // The kernel arguments of the previous image are removed on upgrade
+        let mut kargs = Cmdline::from(
+            "root=UUID=abc console=tty0 console=ttyS0 quiet nosmt mitigations=auto".to_owned(),
+        );
+        let existing = Cmdline::from("console=tty0 console=ttyS0 quiet nosmt mitigations=auto");
+        compute_apply_kargs_diff(&existing, &args, &mut kargs);
+        assert_cmdline_eq(
+            &kargs,
+            &["root=UUID=abc", "nosmt", "mitigations=off", "console=ttyS1"],
+        );
+        Ok(())
     }
 
     #[context("writing test kargs")]
@@ -343,17 +758,35 @@ match-architectures = ["x86_64", "aarch64"]
         let td = cap_std_ext::cap_tempfile::TempDir::new(cap_std::ambient_authority())?;
 
         // No directory
-        assert_eq!(get_kargs_in_root(&td, "x86_64").unwrap().iter().count(), 0);
+        assert_eq!(
+            get_kargs_in_root(&td, &KargsMatchContext::for_arch("x86_64"))
+                .unwrap()
+                .iter()
+                .count(),
+            0
+        );
         // Empty directory
         td.create_dir_all("usr/lib/bootc/kargs.d")?;
-        assert_eq!(get_kargs_in_root(&td, "x86_64").unwrap().iter().count(), 0);
+        assert_eq!(
+            get_kargs_in_root(&td, &KargsMatchContext::for_arch("x86_64"))
+                .unwrap()
+                .iter()
+                .count(),
+            0
+        );
         // Non-toml file
         td.write("usr/lib/bootc/kargs.d/somegarbage", "garbage")?;
-        assert_eq!(get_kargs_in_root(&td, "x86_64").unwrap().iter().count(), 0);
+        assert_eq!(
+            get_kargs_in_root(&td, &KargsMatchContext::for_arch("x86_64"))
+                .unwrap()
+                .iter()
+                .count(),
+            0
+        );
 
         write_test_kargs(&td)?;
 
-        let args = get_kargs_in_root(&td, "x86_64").unwrap();
+        let args = get_kargs_in_root(&td, &KargsMatchContext::for_arch("x86_64")).unwrap();
         assert_cmdline_eq(&args, &["console=tty0", "nosmt", "console=ttyS1"]);
 
         Ok(())
@@ -422,7 +855,7 @@ match-architectures = ["x86_64", "aarch64"]
             if !fetched_tree.query_exists(cancellable) {
                 return Ok(Default::default());
             }
-            get_kargs_from_ostree(repo, &fetched_tree, sys_arch)
+            get_kargs_from_ostree(repo, &fetched_tree, &KargsMatchContext::for_arch(sys_arch))
         };
 
         // rootfs is empty
@@ -11,6 +11,7 @@ use cap_std_ext::cap_std::fs::Dir;
 use cap_std_ext::dirext::CapStdExtDirExt;
 use fn_error_context::context;
 use ostree_ext::containers_image_proxy;
+use ostree_ext::ostree;
 use ostree_ext::ostree::Deployment;
 
 use crate::podstorage::{CStorage, PullMode};
@@ -20,15 +21,51 @@ use crate::store::Storage;
 /// symbolic links to `.container` or `.image` files.
 const BOUND_IMAGE_DIR: &str = "usr/lib/bootc/bound-images.d";
 
+/// Where the resolved bound images of each deployment are recorded,
+/// relative to the physical root.
+const REPORT_DIR: &str = "ostree/bootc/bound-images";
+
 /// A subset of data parsed from a `.image` or `.container` file with
 /// the minimal information necessary to fetch the image.
-///
-/// In the future this may be extended to include e.g. certificates or
-/// other pull options.
 #[derive(Debug, PartialEq, Eq)]
 pub(crate) struct BoundImage {
     pub(crate) image: String,
     pub(crate) auth_file: Option<String>,
+    pub(crate) pull_options: PullOptions,
+}
+
+/// The Quadlet pull policy, i.e. `Policy=` in `.image` and `Pull=`
+/// in `.container` files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum PullPolicy {
+    Always,
+    Missing,
+    Never,
+    Newer,
+}
+
+/// The section of a `.image` or `.container` file holding bootc-specific keys;
+/// systemd and Quadlet ignore sections starting with `X-`.
+const BOOTC_SECTION: &str = "X-Bootc";
+
+/// Options for fetching a bound image, passed through to `podman pull`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct PullOptions {
+    /// `TLSVerify=`
+    pub(crate) tls_verify: Option<bool>,
+    /// `CertDir=`: Certificates (e.g. a private CA) for the registry
+    pub(crate) cert_dir: Option<String>,
+    /// `Policy=` (or `Pull=`)
+    pub(crate) policy: Option<PullPolicy>,
+    /// `Arch=`
+    pub(crate) arch: Option<String>,
+    /// `Variant=`
+    pub(crate) variant: Option<String>,
+    /// `DecryptionKey=`
+    pub(crate) decryption_key: Option<String>,
+    /// `SignaturePolicy=` in the `[X-Bootc]` section: A `policy.json` used
+    /// instead of the system-wide one to verify this image
+    pub(crate) signature_policy: Option<String>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -56,7 +93,34 @@ pub(crate) async fn pull_bound_images(sysroot: &Storage, deployment: &Deployment
         "Found {} bound images to pull",
         bound_images.len()
     );
-    pull_images(sysroot, bound_images).await
+    let resolved = pull_images(sysroot, bound_images).await?;
+    write_report(ostree, deployment, &resolved)
+}
+
+/// Record the resolved bound images of `deployment`.
+#[context("Writing bound images report")]
+pub(crate) fn write_report(
+    sysroot: &ostree::Sysroot,
+    deployment: &Deployment,
+    images: &[ResolvedBoundImage],
+) -> Result<()> {
+    let images = images
+        .iter()
+        .map(|i| crate::spec::BoundImage {
+            image: i.image.clone(),
+            digest: i.digest.clone(),
+        })
+        .collect::<Vec<_>>();
+    crate::deployment_report::write(sysroot, REPORT_DIR, deployment, &images)
+}
+
+/// Load the resolved bound images of a deployment, if recorded.
+#[context("Reading bound images report")]
+pub(crate) fn read_report(
+    sysroot: &ostree::Sysroot,
+    deployment: &Deployment,
+) -> Result<Vec<crate::spec::BoundImage>> {
+    crate::deployment_report::read(sysroot, REPORT_DIR, deployment)
 }
 
 #[context("Querying bound images")]
@@ -134,6 +198,109 @@ impl ResolvedBoundImage {
     }
 }
 
+impl PullPolicy {
+    fn as_str(&self) -> &'static str {
+        match self {
+            PullPolicy::Always => "always",
+            PullPolicy::Missing => "missing",
+            PullPolicy::Never => "never",
+            PullPolicy::Newer => "newer",
+        }
+    }
+}
+
+impl std::str::FromStr for PullPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let r = match s {
+            "always" => PullPolicy::Always,
+            "missing" => PullPolicy::Missing,
+            "never" => PullPolicy::Never,
+            "newer" => PullPolicy::Newer,
+            o => anyhow::bail!("Invalid pull policy: {o}"),
+        };
+        Ok(r)
+    }
+}
+
+impl PullOptions {
+    /// Parse the pull-related keys from the given section; `policy_key` is the
+    /// name of the key for the pull policy, which differs between file types.
+    fn from_ini(file_contents: &tini::Ini, section: &str, policy_key: &str) -> Result<Self> {
+        let get_in = |section: &str, key: &str| -> Result<Option<String>> {
+            file_contents
+                .get::<String>(section, key)
+                .map(|v| parse_spec_value(&v).with_context(|| format!("Invalid {key} value")))
+                .transpose()
+        };
+        let get = |key: &str| get_in(section, key);
+        let signature_policy = get_in(BOOTC_SECTION, "SignaturePolicy")?;
+        if let Some(v) = signature_policy.as_deref() {
+            if !v.starts_with('/') {
+                anyhow::bail!("SignaturePolicy must be an absolute path: {v}");
+            }
+        }
+        let tls_verify = get("TLSVerify")?
+            .map(|v| parse_bool(&v).context("Invalid TLSVerify value"))
+            .transpose()?;
+        let policy = get(policy_key)?.map(|v| v.parse()).transpose()?;
+        Ok(Self {
+            tls_verify,
+            cert_dir: get("CertDir")?,
+            policy,
+            arch: get("Arch")?,
+            variant: get("Variant")?,
+            decryption_key: get("DecryptionKey")?,
+            signature_policy,
+        })
+    }
+
+    /// Whether the image should be fetched even if it is already present.
+    pub(crate) fn pull_mode(&self) -> PullMode {
+        match self.policy {
+            Some(PullPolicy::Always | PullPolicy::Newer) => PullMode::Always,
+            _ => PullMode::IfNotExists,
+        }
+    }
+
+    /// The corresponding arguments for `podman pull`.
+    pub(crate) fn to_podman_args(&self) -> Vec<String> {
+        let mut r = Vec::new();
+        if let Some(v) = self.tls_verify {
+            r.push(format!("--tls-verify={v}"));
+        }
+        if let Some(v) = self.cert_dir.as_deref() {
+            r.push(format!("--cert-dir={v}"));
+        }
+        if let Some(v) = self.policy {
+            r.push(format!("--policy={}", v.as_str()));
+        }
+        if let Some(v) = self.arch.as_deref() {
+            r.push(format!("--arch={v}"));
+        }
+        if let Some(v) = self.variant.as_deref() {
+            r.push(format!("--variant={v}"));
+        }
+        if let Some(v) = self.decryption_key.as_deref() {
+            r.push(format!("--decryption-key={v}"));
+        }
+        if let Some(v) = self.signature_policy.as_deref() {
+            r.push(format!("--signature-policy={v}"));
+        }
+        r
+    }
+}
+
+/// Parse a boolean the way systemd does.
+fn parse_bool(value: &str) -> Result<bool> {
+    match value.to_ascii_lowercase().as_str() {
+        "1" | "yes" | "y" | "true" | "t" | "on" => Ok(true),
+        "0" | "no" | "n" | "false" | "f" | "off" => Ok(false),
+        _ => anyhow::bail!("Invalid boolean: {value}"),
+    }
+}
+
 fn parse_image_file(file_contents: &tini::Ini) -> Result<BoundImage> {
     let image: String = file_contents
         .get("Image", "Image")
@@ -147,7 +314,8 @@ fn parse_image_file(file_contents: &tini::Ini) -> Result<BoundImage> {
         anyhow::bail!("AuthFile is not supported by bound bootc images");
     }
 
-    let bound_image = BoundImage::new(image.to_string(), None)?;
+    let pull_options = PullOptions::from_ini(file_contents, "Image", "Policy")?;
+    let bound_image = BoundImage::new(image.to_string(), None, pull_options)?;
     Ok(bound_image)
 }
 
@@ -156,7 +324,8 @@ fn parse_container_file(file_contents: &tini::Ini) -> Result<BoundImage> {
         .get("Container", "Image")
         .ok_or_else(|| anyhow::anyhow!("Missing Image field"))?;
 
-    let bound_image = BoundImage::new(image.to_string(), None)?;
+    let pull_options = PullOptions::from_ini(file_contents, "Container", "Pull")?;
+    let bound_image = BoundImage::new(image.to_string(), None, pull_options)?;
     Ok(bound_image)
 }
 
@@ -164,45 +333,59 @@ fn parse_container_file(file_contents: &tini::Ini) -> Result<BoundImage> {
 pub(crate) async fn pull_images(
     sysroot: &Storage,
     bound_images: Vec<crate::boundimage::BoundImage>,
-) -> Result<()> {
+) -> Result<Vec<ResolvedBoundImage>> {
     // Always initialize the img store to ensure labels are set when upgrading
     let imgstore = sysroot.get_ensure_imgstore()?;
     if bound_images.is_empty() {
-        return Ok(());
+        return Ok(Vec::new());
     }
     pull_images_impl(imgstore, bound_images).await
 }
 
+/// Pull the given images into the bootc storage, returning the digest of
+/// each stored image.
 #[context("Pulling bound images")]
 pub(crate) async fn pull_images_impl(
     imgstore: &CStorage,
     bound_images: Vec<crate::boundimage::BoundImage>,
-) -> Result<()> {
+) -> Result<Vec<ResolvedBoundImage>> {
     let n = bound_images.len();
     tracing::debug!("Pulling bound images: {n}");
+    let mut resolved = Vec::with_capacity(n);
     // TODO: do this in parallel
     for bound_image in bound_images {
         let image = &bound_image.image;
-        if imgstore.exists(image).await? {
+        let mode = bound_image.pull_options.pull_mode();
+        if mode == PullMode::IfNotExists && imgstore.exists(image).await? {
             tracing::debug!("Bound image already present: {image}");
-            continue;
+        } else {
+            let args = bound_image.pull_options.to_podman_args();
+            let desc = format!("Fetching bound image: {image}");
+            crate::utils::async_task_with_spinner(&desc, async {
+                imgstore
+                    .pull_with_args(&bound_image.image, mode, &args)
+                    .await
+            })
+            .await?;
         }
-        let desc = format!("Fetching bound image: {image}");
-        crate::utils::async_task_with_spinner(&desc, async move {
-            imgstore
-                .pull(&bound_image.image, PullMode::IfNotExists)
-                .await
-        })
-        .await?;
+        let digest = imgstore.image_digest(image).await?;
+        resolved.push(ResolvedBoundImage {
+            image: bound_image.image,
+            digest,
+        });
     }
 
     println!("Bound images stored: {n}");
 
-    Ok(())
+    Ok(resolved)
 }
 
 impl BoundImage {
-    fn new(image: String, auth_file: Option<String>) -> Result<BoundImage> {
+    fn new(
+        image: String,
+        auth_file: Option<String>,
+        pull_options: PullOptions,
+    ) -> Result<BoundImage> {
         let image = parse_spec_value(&image).context("Invalid image value")?;
 
         let auth_file = if let Some(auth_file) = &auth_file {
@@ -211,7 +394,11 @@ impl BoundImage {
             None
         };
 
-        Ok(BoundImage { image, auth_file })
+        Ok(BoundImage {
+            image,
+            auth_file,
+            pull_options,
+        })
     }
 }
 
@@ -374,6 +561,52 @@ mod tests {
         let bound_image = parse_image_file(&file_contents).unwrap();
         assert_eq!(bound_image.image, "quay.io/foo/foo:latest");
         assert_eq!(bound_image.auth_file, None);
+        assert_eq!(bound_image.pull_options, PullOptions::default());
+        assert!(bound_image.pull_options.to_podman_args().is_empty());
+
+        //should parse pull options
+        let file_contents = tini::Ini::from_string(indoc::indoc! { "
+            [Image]
+            Image=registry.example.com/foo/foo:latest
+            TLSVerify=no
+            CertDir=/etc/containers/certs.d/registry.example.com
+            Policy=newer
+            Arch=arm64
+            Variant=v8
+            DecryptionKey=/etc/pki/foo.pem
+
+            [X-Bootc]
+            SignaturePolicy=/usr/lib/bootc/foo-policy.json
+        " })
+        .unwrap();
+        let bound_image = parse_image_file(&file_contents).unwrap();
+        assert_eq!(bound_image.pull_options.pull_mode(), PullMode::Always);
+        similar_asserts::assert_eq!(
+            bound_image.pull_options.to_podman_args(),
+            [
+                "--tls-verify=false",
+                "--cert-dir=/etc/containers/certs.d/registry.example.com",
+                "--policy=newer",
+                "--arch=arm64",
+                "--variant=v8",
+                "--decryption-key=/etc/pki/foo.pem",
+                "--signature-policy=/usr/lib/bootc/foo-policy.json",
+            ]
+        );
+
+        //should error on invalid pull options
+        for invalid in [
+            "TLSVerify=maybe",
+            "Policy=sometimes",
+            "Arch=%a",
+            "[X-Bootc]\nSignaturePolicy=policy.json",
+        ] {
+            let file_contents = tini::Ini::from_string(&format!(
+                "[Image]\nImage=quay.io/foo/foo:latest\n{invalid}"
+            ))
+            .unwrap();
+            assert!(parse_image_file(&file_contents).is_err(), "{invalid}");
+        }
 
         //should error when auth_file is present
         let file_contents = tini::Ini::from_string(indoc::indoc! { "
@@ -400,6 +633,32 @@ mod tests {
         assert_eq!(bound_image.image, "quay.io/foo/foo:latest");
         assert_eq!(bound_image.auth_file, None);
 
+        //should use Pull= for the pull policy
+        let file_contents = tini::Ini::from_string(indoc::indoc! { "
+            [Container]
+            Image=quay.io/foo/foo:latest
+            Pull=never
+        " })
+        .unwrap();
+        let bound_image = parse_container_file(&file_contents).unwrap();
+        assert_eq!(bound_image.pull_options.policy, Some(PullPolicy::Never));
+        assert_eq!(bound_image.pull_options.pull_mode(), PullMode::IfNotExists);
+
+        //should read the signature policy from the bootc section
+        let file_contents = tini::Ini::from_string(indoc::indoc! { "
+            [Container]
+            Image=quay.io/foo/foo:latest
+
+            [X-Bootc]
+            SignaturePolicy=/usr/lib/bootc/foo-policy.json
+        " })
+        .unwrap();
+        let bound_image = parse_container_file(&file_contents).unwrap();
+        assert_eq!(
+            bound_image.pull_options.to_podman_args(),
+            ["--signature-policy=/usr/lib/bootc/foo-policy.json"]
+        );
+
         //should return error when missing image field
         let file_contents = tini::Ini::from_string("[Container]\n").unwrap();
         assert!(parse_container_file(&file_contents).is_err());
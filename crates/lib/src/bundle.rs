@@ -0,0 +1,380 @@
+//! # Offline update bundles
+//!
+//! A bundle is an OCI image layout, either as a directory or as a tar archive
+//! of one, which holds a host image together with all of its logically bound
+//! images. This allows updating systems which have no access to a registry,
+//! e.g. from removable media.
+//!
+//! Each image is stored in the layout under its own name; in addition the layout
+//! contains a [`BUNDLE_METADATA`] file describing the contents.
+//!
+//! OCI layouts cannot hold image signatures. The bound images are verified
+//! against the signature policy when the bundle is created, and loaded from it
+//! without verification: the bundle itself must come from a trusted source. The
+//! host image is verified as configured for the tracked image when it is staged.
+
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+use bootc_utils::{AsyncCommandRunExt, CommandRunExt};
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use fn_error_context::context;
+use ostree_ext::container as ostree_container;
+use ostree_ext::container::{SignatureSource, Transport};
+use serde::{Deserialize, Serialize};
+use tokio::process::Command as AsyncCommand;
+
+use crate::boundimage::BoundImage;
+use crate::spec::ImageReference;
+use crate::store::Storage;
+
+/// The name of the file describing the bundle contents, in the root
+/// of the OCI layout.
+pub(crate) const BUNDLE_METADATA: &str = "bootc-bundle.json";
+
+/// The contents of a bundle.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct BundleMetadata {
+    /// The host image; this is also the name of the image in the layout.
+    pub(crate) host: ImageReference,
+    /// The logically bound images of the host image.
+    #[serde(default)]
+    pub(crate) bound_images: Vec<String>,
+}
+
+/// A bundle which was opened for reading.
+#[derive(Debug)]
+pub(crate) struct Bundle {
+    path: Utf8PathBuf,
+    archive: bool,
+    metadata: BundleMetadata,
+}
+
+impl Bundle {
+    /// Open the bundle at `path`, which is either an OCI layout directory or
+    /// an OCI archive.
+    #[context("Opening bundle {path}")]
+    pub(crate) fn open(path: &Utf8Path) -> Result<Self> {
+        let path = path.canonicalize_utf8()?;
+        let archive = !path.is_dir();
+        let metadata = if archive {
+            read_archive_metadata(&path)?
+        } else {
+            let f = std::fs::File::open(path.join(BUNDLE_METADATA))
+                .with_context(|| format!("Opening {BUNDLE_METADATA}"))?;
+            serde_json::from_reader(BufReader::new(f))
+                .with_context(|| format!("Parsing {BUNDLE_METADATA}"))?
+        };
+        Ok(Self {
+            path,
+            archive,
+            metadata,
+        })
+    }
+
+    fn transport(&self) -> Transport {
+        if self.archive {
+            Transport::OciArchive
+        } else {
+            Transport::OciDir
+        }
+    }
+
+    /// Return the reference to `image` inside the bundle, in the containers-image
+    /// syntax (e.g. `oci-archive:/path/to/bundle.tar:quay.io/exampleos/myos:latest`).
+    fn image_in_bundle(&self, image: &str) -> ostree_container::ImageReference {
+        ostree_container::ImageReference {
+            transport: self.transport(),
+            name: format!("{}:{image}", self.path),
+        }
+    }
+
+    /// The host image, as it should be tracked by a system updated from this
+    /// bundle.
+    pub(crate) fn host_imgref(&self, sigverify: SignatureSource) -> Result<ImageReference> {
+        let imgref = ostree_container::ImageReference {
+            transport: self.metadata.host.transport()?,
+            name: self.metadata.host.image.clone(),
+        };
+        let target = ostree_container::OstreeImageReference { sigverify, imgref };
+        Ok(ImageReference::from(target))
+    }
+
+    /// Verify that this bundle contains an update for the image tracked by
+    /// the system.
+    pub(crate) fn ensure_host(&self, tracked: &ImageReference) -> Result<()> {
+        let host = &self.metadata.host;
+        if host.image != tracked.image || host.transport != tracked.transport {
+            anyhow::bail!(
+                "Bundle contains {host:#}, but the system tracks {tracked:#}; use `bootc switch --from-bundle` to change images"
+            );
+        }
+        Ok(())
+    }
+
+    /// The location of the host image in the bundle; `target` is the tracked
+    /// image reference, whose signature verification is used.
+    pub(crate) fn host_source(&self, target: &ImageReference) -> ImageReference {
+        let imgref = self.image_in_bundle(&self.metadata.host.image);
+        ImageReference {
+            image: imgref.name,
+            transport: imgref.transport.serializable_name().to_owned(),
+            signature: target.signature.clone(),
+        }
+    }
+
+    /// Copy all bound images from the bundle into the bootc storage, so that
+    /// they do not need to be fetched when staging the host image.
+    /// They were verified when the bundle was created and are trusted as is.
+    #[context("Preloading bound images from bundle")]
+    pub(crate) async fn preload_bound_images(&self, storage: &Storage) -> Result<()> {
+        let imgstore = storage.get_ensure_imgstore()?;
+        let images = &self.metadata.bound_images;
+        for image in images {
+            let src = self.image_in_bundle(image).to_string();
+            let desc = format!("Loading bound image: {image}");
+            crate::utils::async_task_with_spinner(&desc, imgstore.copy_from(&src, image)).await?;
+        }
+        println!("Bound images loaded from bundle: {}", images.len());
+        Ok(())
+    }
+}
+
+#[context("Reading {BUNDLE_METADATA} from archive")]
+fn read_archive_metadata(path: &Utf8Path) -> Result<BundleMetadata> {
+    let f = std::fs::File::open(path).map(BufReader::new)?;
+    let mut archive = tar::Archive::new(f);
+    for entry in archive.entries()? {
+        let entry = entry?;
+        if entry.path()?.as_ref() == Path::new(BUNDLE_METADATA) {
+            return Ok(serde_json::from_reader(BufReader::new(entry))?);
+        }
+    }
+    anyhow::bail!("Not a bundle: missing {BUNDLE_METADATA}")
+}
+
+/// Write the OCI layout at `layout` as an OCI archive, putting the
+/// bundle metadata first so that it can be found without reading the whole
+/// archive.
+#[context("Writing archive")]
+fn write_archive(layout: &Utf8Path, output: &mut impl Write) -> Result<()> {
+    let mut builder = tar::Builder::new(output);
+    builder.mode(tar::HeaderMode::Deterministic);
+    for name in [BUNDLE_METADATA, "oci-layout", "index.json"] {
+        builder.append_path_with_name(layout.join(name), name)?;
+    }
+    builder.append_dir_all("blobs", layout.join("blobs"))?;
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Find the logically bound images of an image in the default container storage.
+#[context("Querying bound images of {image}")]
+fn query_image_bound_images(image: &str) -> Result<Vec<BoundImage>> {
+    let mountpoint = Command::new("podman")
+        .args(["image", "mount", image])
+        .run_get_string()?;
+    let r = Dir::open_ambient_dir(mountpoint.trim(), cap_std::ambient_authority())
+        .map_err(anyhow::Error::from)
+        .and_then(|root| crate::boundimage::query_bound_images(&root));
+    Command::new("podman")
+        .args(["image", "unmount", image])
+        .run_capture_stderr()?;
+    let mut images = r?;
+    images.sort_by(|a, b| a.image.cmp(&b.image));
+    images.dedup_by(|a, b| a.image == b.image);
+    Ok(images)
+}
+
+/// Copy a bound image from its registry into the OCI layout, verifying it
+/// against its signature policy (or the system-wide one). The signatures are
+/// not stored, as OCI layouts do not support them.
+#[context("Copying bound image {}", bound_image.image)]
+async fn copy_bound_image(bound_image: &BoundImage, layout: &Utf8Path) -> Result<()> {
+    let image = &bound_image.image;
+    let mut cmd = Command::new("skopeo");
+    cmd.stdin(Stdio::null());
+    cmd.stdout(Stdio::null());
+    if let Some(policy) = bound_image.pull_options.signature_policy.as_deref() {
+        cmd.args(["--policy", policy]);
+    }
+    cmd.args(["copy", "--remove-signatures"])
+        .arg(format!("docker://{image}"))
+        .arg(format!("oci:{layout}:{image}"));
+    let mut cmd = AsyncCommand::from(cmd);
+    cmd.run().await
+}
+
+/// Implementation of `bootc image bundle create`.
+#[context("Creating bundle")]
+pub(crate) async fn create(
+    image: &str,
+    transport: &str,
+    output: &Utf8Path,
+    archive: bool,
+) -> Result<()> {
+    let transport = Transport::try_from(transport)?;
+    if !matches!(transport, Transport::Registry | Transport::ContainerStorage) {
+        anyhow::bail!("Unsupported transport for bundles: {transport}");
+    }
+    if output.symlink_metadata().is_ok() {
+        anyhow::bail!("Output path already exists: {output}");
+    }
+
+    let r = write_bundle(image, transport, output, archive).await;
+    if r.is_err() {
+        // The output did not exist before, so anything there is incomplete
+        let cleanup = if archive {
+            std::fs::remove_file(output)
+        } else {
+            std::fs::remove_dir_all(output)
+        };
+        match cleanup {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("Failed to remove incomplete bundle {output}: {e}");
+            }
+            _ => {}
+        }
+    }
+    r
+}
+
+/// Write the bundle for [`create`], whose `output` does not exist yet.
+async fn write_bundle(
+    image: &str,
+    transport: Transport,
+    output: &Utf8Path,
+    archive: bool,
+) -> Result<()> {
+    crate::podstorage::ensure_floating_c_storage_initialized();
+    // The host image is needed in the default container storage to find
+    // its bound images.
+    if transport == Transport::Registry {
+        let mut cmd = AsyncCommand::new("podman");
+        cmd.args(["pull", image]);
+        cmd.run().await?;
+    }
+    let bound_images = query_image_bound_images(image)?;
+
+    let tempdir;
+    let layout = if archive {
+        tempdir = tempfile::tempdir_in("/var/tmp")?;
+        Utf8Path::from_path(tempdir.path())
+            .ok_or_else(|| anyhow::anyhow!("Invalid non-UTF8 temporary directory"))?
+            .to_owned()
+    } else {
+        std::fs::create_dir(output)?;
+        output.to_owned()
+    };
+
+    println!("Copying host image: {image}");
+    let src = ostree_container::ImageReference {
+        transport: Transport::ContainerStorage,
+        name: image.to_owned(),
+    };
+    let dest = ostree_container::ImageReference {
+        transport: Transport::OciDir,
+        name: format!("{layout}:{image}"),
+    };
+    ostree_container::skopeo::copy(&src, &dest, None, None, true).await?;
+    for bound_image in bound_images.iter() {
+        println!("Copying bound image: {}", bound_image.image);
+        copy_bound_image(bound_image, &layout).await?;
+    }
+
+    let metadata = BundleMetadata {
+        host: ImageReference {
+            image: image.to_owned(),
+            transport: transport.serializable_name().to_owned(),
+            signature: None,
+        },
+        bound_images: bound_images.into_iter().map(|i| i.image).collect(),
+    };
+    let f = std::fs::File::create(layout.join(BUNDLE_METADATA))?;
+    let mut f = BufWriter::new(f);
+    serde_json::to_writer_pretty(&mut f, &metadata)?;
+    f.flush()?;
+
+    if archive {
+        let output = output.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let f = std::fs::File::create_new(&output)?;
+            write_archive(&layout, &mut BufWriter::new(f))
+        })
+        .await??;
+    }
+
+    println!(
+        "Wrote bundle with {image} and {} bound images",
+        metadata.bound_images.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let td = Utf8Path::from_path(td.path())
+            .unwrap()
+            .canonicalize_utf8()?;
+        let layout = &td.join("layout");
+        std::fs::create_dir_all(layout.join("blobs/sha256"))?;
+        std::fs::write(
+            layout.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )?;
+        std::fs::write(
+            layout.join("index.json"),
+            r#"{"schemaVersion":2,"manifests":[]}"#,
+        )?;
+        let metadata = BundleMetadata {
+            host: ImageReference {
+                image: "quay.io/exampleos/myos:latest".into(),
+                transport: "registry".into(),
+                signature: None,
+            },
+            bound_images: vec!["quay.io/exampleos/app:v1".into()],
+        };
+        std::fs::write(layout.join(BUNDLE_METADATA), serde_json::to_vec(&metadata)?)?;
+
+        let archive_path = &td.join("bundle.tar");
+        let mut f = std::fs::File::create(archive_path)?;
+        write_archive(layout, &mut f)?;
+
+        let tracked = Bundle::open(layout)?.host_imgref(SignatureSource::ContainerPolicy)?;
+        for (path, transport) in [(layout, "oci"), (archive_path, "oci-archive")] {
+            let bundle = Bundle::open(path)?;
+            assert_eq!(bundle.metadata, metadata);
+            bundle.ensure_host(&tracked)?;
+            let source = bundle.host_source(&tracked);
+            assert_eq!(source.transport, transport);
+            assert_eq!(
+                source.image,
+                format!("{path}:quay.io/exampleos/myos:latest")
+            );
+            assert_eq!(source.signature, tracked.signature);
+        }
+
+        let other = ImageReference {
+            image: "quay.io/exampleos/otheros:latest".into(),
+            ..tracked
+        };
+        assert!(Bundle::open(layout)?.ensure_host(&other).is_err());
+
+        // An archive without metadata is not a bundle
+        let plain = &td.join("plain.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(plain)?);
+        builder.append_path_with_name(layout.join("index.json"), "index.json")?;
+        builder.finish()?;
+        assert!(Bundle::open(plain).is_err());
+        Ok(())
+    }
+}
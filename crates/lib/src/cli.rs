@@ -37,23 +37,26 @@ use ostree_ext::sysroot::SysrootLock;
 use schemars::schema_for;
 use serde::{Deserialize, Serialize};
 
+use crate::bootc_composefs::boot_counting::composefs_boot_complete;
 use crate::bootc_composefs::delete::delete_composefs_deployment;
 use crate::bootc_composefs::gc::composefs_gc;
 use crate::bootc_composefs::soft_reboot::{prepare_soft_reboot_composefs, reset_soft_reboot};
 use crate::bootc_composefs::{
     digest::{compute_composefs_digest, new_temp_composefs_repo},
-    finalize::{composefs_backend_finalize, get_etc_diff},
+    finalize::composefs_backend_finalize,
     rollback::composefs_rollback,
     state::composefs_usr_overlay,
+    status::get_composefs_status,
     switch::switch_composefs,
     update::upgrade_composefs,
 };
 use crate::deploy::{MergeState, RequiredHostSpec};
+use crate::kargs::KargsOp;
 use crate::podstorage::set_additional_image_store;
-use crate::progress_jsonl::{ProgressWriter, RawProgressFd};
+use crate::progress_jsonl::{Phase, ProgressWriter, RawProgressFd};
 use crate::spec::FilesystemOverlayAccessMode;
-use crate::spec::Host;
 use crate::spec::ImageReference;
+use crate::spec::{Host, HostSpec};
 use crate::status::get_host;
 use crate::store::{BootedOstree, Storage};
 use crate::store::{BootedStorage, BootedStorageKind};
@@ -61,7 +64,7 @@ use crate::utils::sigpolicy_from_opt;
 use crate::{bootc_composefs, lints};
 
 /// Shared progress options
-#[derive(Debug, Parser, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, Parser, PartialEq, Eq)]
 pub(crate) struct ProgressOptions {
     /// File descriptor number which must refer to an open pipe.
     ///
@@ -70,6 +73,16 @@ pub(crate) struct ProgressOptions {
     pub(crate) progress_fd: Option<RawProgressFd>,
 }
 
+impl ProgressOptions {
+    /// Take ownership of the file descriptor, returning a writer for it.
+    pub(crate) fn take_writer(&mut self) -> Result<ProgressWriter> {
+        Self {
+            progress_fd: self.progress_fd.take(),
+        }
+        .try_into()
+    }
+}
+
 impl TryFrom<ProgressOptions> for ProgressWriter {
     type Error = anyhow::Error;
 
@@ -131,6 +144,14 @@ pub(crate) struct UpgradeOpts {
     #[clap(long)]
     pub(crate) tag: Option<String>,
 
+    /// Upgrade from a bundle created by `bootc image bundle create`, instead of
+    /// fetching from the image source.
+    ///
+    /// The bundle must contain the currently tracked image; its logically bound
+    /// images are loaded into the bootc storage before staging.
+    #[clap(long, conflicts_with_all = ["check", "from_downloaded", "tag"])]
+    pub(crate) from_bundle: Option<Utf8PathBuf>,
+
     #[clap(flatten)]
     pub(crate) progress: ProgressOptions,
 }
@@ -188,8 +209,17 @@ pub(crate) struct SwitchOpts {
     #[clap(long = "experimental-unified-storage", hide = true)]
     pub(crate) unified_storage_exp: bool,
 
+    /// Switch to the host image of a bundle created by `bootc image bundle create`.
+    ///
+    /// The host image is imported from the bundle, and its logically bound images
+    /// are loaded into the bootc storage before staging. The system will track
+    /// the image under its original name.
+    #[clap(long, conflicts_with_all = ["target", "transport", "mutate_in_place", "unified_storage_exp"])]
+    pub(crate) from_bundle: Option<Utf8PathBuf>,
+
     /// Target image to use for the next boot.
-    pub(crate) target: String,
+    #[clap(required_unless_present = "from_bundle")]
+    pub(crate) target: Option<String>,
 
     #[clap(flatten)]
     pub(crate) progress: ProgressOptions,
@@ -211,6 +241,9 @@ pub(crate) struct RollbackOpts {
     /// 'required' fails if soft reboot unavailable, 'auto' falls back to regular reboot.
     #[clap(long = "soft-reboot")]
     pub(crate) soft_reboot: Option<SoftRebootMode>,
+
+    #[clap(flatten)]
+    pub(crate) progress: ProgressOptions,
 }
 
 /// Perform an edit operation
@@ -272,6 +305,85 @@ pub(crate) struct StatusOpts {
     /// Include additional fields in human readable format.
     #[clap(long, short = 'v')]
     pub(crate) verbose: bool,
+
+    /// Show the files, layers and packages which differ between the booted
+    /// deployment and the staged (default) or rollback deployment.
+    #[clap(
+        long,
+        value_name = "SLOT",
+        num_args = 0..=1,
+        default_missing_value = "staged",
+        conflicts_with = "booted"
+    )]
+    pub(crate) diff: Option<crate::deployment_diff::DiffTarget>,
+}
+
+/// Show local changes to /etc
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct ConfigDiffOpts {
+    /// The output format.
+    #[clap(long, default_value = "humanreadable")]
+    pub(crate) format: OutputFormat,
+
+    /// Only show changes to this path in `/etc`, and anything below it.
+    #[clap(long)]
+    pub(crate) path: Option<Utf8PathBuf>,
+}
+
+/// Restore paths in /etc to the defaults
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct ConfigRevertOpts {
+    /// Paths in `/etc` to restore, including everything below them.
+    #[clap(required = true)]
+    pub(crate) paths: Vec<Utf8PathBuf>,
+
+    /// Only show what would be reverted.
+    #[clap(long)]
+    pub(crate) dry_run: bool,
+}
+
+/// Change kernel arguments
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct KargsChangeOpts {
+    /// Kernel arguments, e.g. `nosmt` or `console=ttyS0`.
+    #[clap(required = true)]
+    pub(crate) kargs: Vec<String>,
+
+    /// Reboot if the kernel arguments changed.
+    #[clap(long)]
+    pub(crate) apply: bool,
+
+    #[clap(flatten)]
+    pub(crate) signing: crate::ukify::SecureBootSigningOpts,
+}
+
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum KargsOpts {
+    /// List the kernel arguments of the deployment which is booted next.
+    List {
+        /// Only list local kernel arguments, i.e. the ones not from the image.
+        #[clap(long)]
+        local: bool,
+    },
+    /// Add kernel arguments, unless they are already present.
+    Append(KargsChangeOpts),
+    /// Remove kernel arguments.
+    ///
+    /// A kernel argument without a value (`foo`) removes all kernel arguments
+    /// with that key; with a value (`foo=bar`), only the exactly matching one
+    /// is removed.
+    Delete(KargsChangeOpts),
+    /// Set the value of kernel arguments, replacing all with the same key.
+    Replace(KargsChangeOpts),
+    /// Edit the local kernel arguments in the system default `$EDITOR`.
+    Edit {
+        /// Reboot if the kernel arguments changed.
+        #[clap(long)]
+        apply: bool,
+
+        #[clap(flatten)]
+        signing: crate::ukify::SecureBootSigningOpts,
+    },
 }
 
 /// Add a transient overlayfs on /usr
@@ -389,6 +501,24 @@ pub(crate) enum ContainerOpts {
         #[clap(long)]
         skip: Vec<String>,
 
+        /// Read lint configuration from this TOML file. It is applied on top of
+        /// `/usr/lib/bootc/lint.toml` in the target root, if present.
+        #[clap(long, value_name = "PATH")]
+        config: Option<Utf8PathBuf>,
+
+        /// The output format. The `json` format is described by a versioned
+        /// JSON schema; `sarif` generates a SARIF 2.1.0 log suitable for code
+        /// scanning tools.
+        #[clap(long, default_value = "humanreadable")]
+        format: lints::LintOutputFormat,
+
+        /// Before running the lints, generate systemd sysusers.d entries for users
+        /// and groups in /etc/passwd and /etc/group which lack them, and tmpfiles.d
+        /// entries for content in /var. Content in /var which is translated to
+        /// tmpfiles.d is removed.
+        #[clap(long, conflicts_with = "list")]
+        fix: bool,
+
         /// Don't truncate the output. By default, only a limited number of entries are
         /// shown for each lint, followed by a count of remaining entries.
         #[clap(long)]
@@ -438,6 +568,9 @@ pub(crate) enum ContainerOpts {
         #[clap(long)]
         allow_missing_verity: bool,
 
+        #[clap(flatten)]
+        signing: crate::ukify::UkiSigningOpts,
+
         /// Additional arguments to pass to ukify (after `--`).
         #[clap(last = true)]
         args: Vec<OsString>,
@@ -479,6 +612,31 @@ pub(crate) enum ExportFormat {
     Tar,
 }
 
+/// Operations on offline update bundles.
+#[derive(Debug, clap::Subcommand, PartialEq, Eq)]
+pub(crate) enum BundleOpts {
+    /// Write a bundle containing a host image and all of its logically bound images.
+    ///
+    /// The bundle is an OCI image layout directory, or with `--archive` an OCI
+    /// archive, which can be used with `bootc switch --from-bundle` and
+    /// `bootc upgrade --from-bundle` on systems without registry access.
+    Create {
+        /// The transport; either registry or containers-storage.
+        #[clap(long, default_value = "registry")]
+        transport: String,
+
+        /// Write an OCI archive instead of a directory.
+        #[clap(long)]
+        archive: bool,
+
+        /// The host image
+        image: String,
+
+        /// The output path; it must not exist.
+        output: Utf8PathBuf,
+    },
+}
+
 /// Subcommands which operate on images.
 #[derive(Debug, clap::Subcommand, PartialEq, Eq)]
 pub(crate) enum ImageCmdOpts {
@@ -588,6 +746,19 @@ pub(crate) enum ImageOpts {
         /// The image to pull
         image: String,
     },
+    /// Remove images from the bootc storage which are not referenced by any deployment.
+    ///
+    /// This is also done automatically after `bootc upgrade` or `bootc switch`; the
+    /// retained images are the logically bound images of all deployments (booted,
+    /// staged, rollback and pinned) and the deployment images themselves.
+    Prune {
+        /// Only list the images which would be removed.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Create offline update bundles.
+    #[clap(subcommand)]
+    Bundle(BundleOpts),
     /// Wrapper for selected `podman image` subcommands in bootc storage.
     #[clap(subcommand)]
     Cmd(ImageCmdOpts),
@@ -597,6 +768,7 @@ pub(crate) enum ImageOpts {
 pub(crate) enum SchemaType {
     Host,
     Progress,
+    Lint,
 }
 
 /// Options for consistency checking
@@ -714,12 +886,61 @@ pub(crate) enum InternalsOpts {
         #[clap(long)]
         dry_run: bool,
     },
+    /// Complete the changes bootc makes to the staged ostree deployment.
+    /// Invoked from bootc-ostree-finalize-staged.service at shutdown.
+    OstreeFinalizeStaged,
+    /// Mark the booted deployment as successfully booted, rolling back
+    /// if the default deployment ran out of boot attempts.
+    /// Invoked from bootc-boot-complete.service.
+    BootComplete,
+    /// Switch users and groups whose IDs in /etc differ from the static IDs
+    /// defined by the booted image's sysusers.d to the image IDs, changing the
+    /// ownership of the affected files in /var accordingly. Only supported
+    /// for the ostree backend.
+    RemapIds {
+        /// Only print the IDs which would be remapped
+        #[clap(long)]
+        dry_run: bool,
+    },
+}
+
+/// Options for `bootc state factory-reset`
+#[derive(Debug, Parser, PartialEq, Eq)]
+pub(crate) struct FactoryResetOpts {
+    /// Carry over a path in `/etc` or `/var` to the new state, e.g. `/etc/ssh`.
+    ///
+    /// May be specified multiple times. Paths which do not exist are skipped.
+    #[clap(long, value_name = "PATH", conflicts_with_all = ["revert", "discard"])]
+    pub(crate) keep: Vec<Utf8PathBuf>,
+
+    /// Reboot after queueing the factory reset or reverting it.
+    #[clap(long, conflicts_with = "discard")]
+    pub(crate) apply: bool,
+
+    /// Go back to the state from before the factory reset.
+    #[clap(long, conflicts_with = "discard")]
+    pub(crate) revert: bool,
+
+    /// Remove the state which is no longer in use after a factory reset,
+    /// or after reverting it.
+    #[clap(long)]
+    pub(crate) discard: bool,
 }
 
 #[derive(Debug, clap::Subcommand, PartialEq, Eq)]
 pub(crate) enum StateOpts {
     /// Remove all ostree deployments from this system
+    #[clap(hide = true)]
     WipeOstree,
+    /// Reset `/etc` and `/var` to the defaults of the booted image.
+    ///
+    /// Queues a fresh state for the booted image, with a pristine `/etc` and an
+    /// empty `/var` which is populated by `systemd-tmpfiles` at boot. It is used
+    /// from the next boot on.
+    ///
+    /// The previous state is kept until it is removed with `--discard`, and
+    /// the factory reset can be undone with `--revert` until then.
+    FactoryReset(FactoryResetOpts),
 }
 
 impl InternalsOpts {
@@ -803,6 +1024,17 @@ pub(crate) enum Opt {
     ///
     /// Shows bootc system state. Outputs YAML by default, human-readable if terminal detected.
     Status(StatusOpts),
+    /// Change the kernel arguments of the deployment which is booted next.
+    ///
+    /// Kernel arguments from the image (`/usr/lib/bootc/kargs.d`, or built into
+    /// a UKI) are kept; the ones changed here are local, and are carried over
+    /// on upgrades.
+    ///
+    /// With the ostree backend, a new deployment of the same image is staged.
+    /// With the composefs backend, the boot entry is updated in place; for UKIs,
+    /// the local kernel arguments are written to a UKI addon.
+    #[clap(subcommand)]
+    Kargs(KargsOpts),
     /// Add a transient overlayfs on `/usr`.
     ///
     /// Allows temporary package installation that will be discarded on reboot.
@@ -828,16 +1060,24 @@ pub(crate) enum Opt {
         args: Vec<OsString>,
     },
     /// Modify the state of the system
-    #[clap(hide = true)]
     #[clap(subcommand)]
     State(StateOpts),
     #[clap(subcommand)]
     #[clap(hide = true)]
     Internals(InternalsOpts),
     ComposefsFinalizeStaged,
-    /// Diff current /etc configuration versus default
-    #[clap(hide = true)]
-    ConfigDiff,
+    /// Show local changes to `/etc`.
+    ///
+    /// Compares `/etc` to the defaults from the booted image, and shows the
+    /// paths which were added, removed or modified. Unified diffs are shown for
+    /// modified text files, and changes to metadata are reported separately.
+    ConfigDiff(ConfigDiffOpts),
+    /// Restore paths in `/etc` to the defaults.
+    ///
+    /// Restores the content, metadata and SELinux labels of the given paths, and
+    /// everything below them, to the defaults from the booted image. Files which
+    /// were added locally are removed.
+    ConfigRevert(ConfigRevertOpts),
     /// Generate shell completion script for supported shells.
     ///
     /// Example: `bootc completion bash` prints a bash completion script to stdout.
@@ -1051,6 +1291,7 @@ async fn upgrade(
     opts: UpgradeOpts,
     storage: &Storage,
     booted_ostree: &BootedOstree<'_>,
+    prog: ProgressWriter,
 ) -> Result<()> {
     let repo = &booted_ostree.repo();
 
@@ -1068,7 +1309,6 @@ async fn upgrade(
     };
 
     let imgref = derived_image.as_ref().or(current_image);
-    let prog: ProgressWriter = opts.progress.try_into()?;
 
     // If there's no specified image, let's be nice and check if the booted system is using rpm-ostree
     if imgref.is_none() {
@@ -1084,6 +1324,14 @@ async fn upgrade(
     }
 
     let imgref = imgref.ok_or_else(|| anyhow::anyhow!("No image source specified"))?;
+    let bundle = opts
+        .from_bundle
+        .as_deref()
+        .map(crate::bundle::Bundle::open)
+        .transpose()?;
+    if let Some(bundle) = bundle.as_ref() {
+        bundle.ensure_host(imgref)?;
+    }
     // Use the derived image reference (if --tag was specified) instead of the spec's image
     let spec = RequiredHostSpec { image: imgref };
     let booted_image = host
@@ -1149,7 +1397,18 @@ async fn upgrade(
             }
         }
     } else {
-        let fetched = if use_unified {
+        let fetched = if let Some(bundle) = bundle.as_ref() {
+            bundle.preload_bound_images(storage).await?;
+            crate::deploy::pull(
+                repo,
+                &bundle.host_source(imgref),
+                Some(&imgref.clone().into()),
+                opts.quiet,
+                prog.clone(),
+                Some(&booted_ostree.deployment),
+            )
+            .await?
+        } else if use_unified {
             crate::deploy::pull_unified(
                 repo,
                 imgref,
@@ -1261,10 +1520,14 @@ async fn upgrade(
     Ok(())
 }
 pub(crate) fn imgref_for_switch(opts: &SwitchOpts) -> Result<ImageReference> {
+    let target = opts
+        .target
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("No target image specified"))?;
     let transport = ostree_container::Transport::try_from(opts.transport.as_str())?;
     let imgref = ostree_container::ImageReference {
         transport,
-        name: opts.target.to_string(),
+        name: target.to_string(),
     };
     let sigverify = sigpolicy_from_opt(opts.enforce_container_sigpolicy);
     let target = ostree_container::OstreeImageReference { sigverify, imgref };
@@ -1279,9 +1542,17 @@ async fn switch_ostree(
     opts: SwitchOpts,
     storage: &Storage,
     booted_ostree: &BootedOstree<'_>,
+    prog: ProgressWriter,
 ) -> Result<()> {
-    let target = imgref_for_switch(&opts)?;
-    let prog: ProgressWriter = opts.progress.try_into()?;
+    let bundle = opts
+        .from_bundle
+        .as_deref()
+        .map(crate::bundle::Bundle::open)
+        .transpose()?;
+    let target = match bundle.as_ref() {
+        Some(bundle) => bundle.host_imgref(sigpolicy_from_opt(opts.enforce_container_sigpolicy))?,
+        None => imgref_for_switch(&opts)?,
+    };
     let cancellable = gio::Cancellable::NONE;
 
     let repo = &booted_ostree.repo();
@@ -1324,11 +1595,24 @@ async fn switch_ostree(
     // Otherwise, auto-detect based on whether the image exists in bootc storage.
     let use_unified = if opts.unified_storage_exp {
         true
+    } else if bundle.is_some() {
+        false
     } else {
         crate::deploy::image_exists_in_unified_storage(storage, &target).await?
     };
 
-    let fetched = if use_unified {
+    let fetched = if let Some(bundle) = bundle.as_ref() {
+        bundle.preload_bound_images(storage).await?;
+        crate::deploy::pull(
+            repo,
+            &bundle.host_source(&target),
+            Some(&target.clone().into()),
+            opts.quiet,
+            prog.clone(),
+            Some(&booted_ostree.deployment),
+        )
+        .await?
+    } else if use_unified {
         crate::deploy::pull_unified(
             repo,
             &target,
@@ -1384,7 +1668,7 @@ async fn switch_ostree(
 
 /// Implementation of the `bootc switch` CLI command.
 #[context("Switching")]
-async fn switch(opts: SwitchOpts) -> Result<()> {
+async fn switch(opts: SwitchOpts, prog: ProgressWriter) -> Result<()> {
     // If we're doing an in-place mutation, we shortcut most of the rest of the work here
     // TODO: what we really want here is Storage::detect_from_root() that also handles
     // composefs. But for now this just assumes ostree.
@@ -1405,10 +1689,13 @@ async fn switch(opts: SwitchOpts) -> Result<()> {
     let storage = &get_storage().await?;
     match storage.kind()? {
         BootedStorageKind::Ostree(booted_ostree) => {
-            switch_ostree(opts, storage, &booted_ostree).await
+            switch_ostree(opts, storage, &booted_ostree, prog).await
         }
         BootedStorageKind::Composefs(booted_cfs) => {
-            switch_composefs(opts, storage, &booted_cfs).await
+            if opts.from_bundle.is_some() {
+                anyhow::bail!("--from-bundle only works for ostree backend");
+            }
+            switch_composefs(opts, storage, &booted_cfs, prog).await
         }
     }
 }
@@ -1419,8 +1706,11 @@ async fn rollback_ostree(
     opts: &RollbackOpts,
     storage: &Storage,
     booted_ostree: &BootedOstree<'_>,
+    prog: &ProgressWriter,
 ) -> Result<()> {
+    prog.start_phase(Phase::Bootloader).await;
     crate::deploy::rollback(storage).await?;
+    prog.complete_phase(Phase::Bootloader).await;
 
     if opts.soft_reboot.is_some() {
         // Get status of rollback deployment to check soft-reboot capability
@@ -1439,32 +1729,29 @@ async fn rollback_ostree(
 
 /// Implementation of the `bootc rollback` CLI command.
 #[context("Rollback")]
-async fn rollback(opts: &RollbackOpts) -> Result<()> {
+async fn rollback(opts: &RollbackOpts, prog: &ProgressWriter) -> Result<()> {
     let storage = &get_storage().await?;
     match storage.kind()? {
         BootedStorageKind::Ostree(booted_ostree) => {
-            rollback_ostree(opts, storage, &booted_ostree).await
+            rollback_ostree(opts, storage, &booted_ostree, prog).await
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            composefs_rollback(storage, &booted_cfs, prog).await
         }
-        BootedStorageKind::Composefs(booted_cfs) => composefs_rollback(storage, &booted_cfs).await,
     }
 }
 
-/// Implementation of the `bootc edit` CLI command for ostree backend.
-#[context("Editing spec (ostree)")]
-async fn edit_ostree(
-    opts: EditOpts,
-    storage: &Storage,
-    booted_ostree: &BootedOstree<'_>,
-) -> Result<()> {
-    let repo = &booted_ostree.repo();
-    let (_, host) = crate::status::get_status(booted_ostree)?;
-
-    let new_host: Host = if let Some(filename) = opts.filename {
+/// Read the edited host specification, either from `--filename` or by
+/// spawning an editor.
+///
+/// Returns the new spec if there are changes to perform.
+fn edit_host_spec(opts: &EditOpts, host: &Host) -> Result<Option<HostSpec>> {
+    let new_host: Host = if let Some(filename) = opts.filename.as_deref() {
         let mut r = std::io::BufReader::new(std::fs::File::open(filename)?);
         serde_yaml::from_reader(&mut r)?
     } else {
         let tmpf = tempfile::NamedTempFile::with_suffix(".yaml")?;
-        serde_yaml::to_writer(std::io::BufWriter::new(tmpf.as_file()), &host)?;
+        serde_yaml::to_writer(std::io::BufWriter::new(tmpf.as_file()), host)?;
         crate::utils::spawn_editor(&tmpf)?;
         tmpf.as_file().seek(std::io::SeekFrom::Start(0))?;
         serde_yaml::from_reader(&mut tmpf.as_file())?
@@ -1472,22 +1759,62 @@ async fn edit_ostree(
 
     if new_host.spec == host.spec {
         println!("Edit cancelled, no changes made.");
-        return Ok(());
+        return Ok(None);
     }
     host.spec.verify_transition(&new_host.spec)?;
-    let new_spec = RequiredHostSpec::from_spec(&new_host.spec)?;
+
+    Ok(Some(new_host.spec))
+}
+
+/// Whether the edit changes anything besides the update policy.
+fn edit_changes_deployment(current: &HostSpec, new: &HostSpec) -> bool {
+    let unchanged = HostSpec {
+        update_policy: current.update_policy.clone(),
+        ..new.clone()
+    };
+    &unchanged != current
+}
+
+/// Apply a changed update policy; this must only be called once everything
+/// else in the edit succeeded.
+fn edit_update_policy(current: &HostSpec, new: &HostSpec) -> Result<()> {
+    if new.update_policy != current.update_policy {
+        crate::update_policy::apply_host(new.update_policy.as_ref())?;
+        println!("Updated update policy.");
+    }
+    Ok(())
+}
+
+/// Implementation of the `bootc edit` CLI command for ostree backend.
+#[context("Editing spec (ostree)")]
+async fn edit_ostree(
+    opts: EditOpts,
+    storage: &Storage,
+    booted_ostree: &BootedOstree<'_>,
+) -> Result<()> {
+    let repo = &booted_ostree.repo();
+    let (_, host) = crate::status::get_status(booted_ostree)?;
+
+    let Some(new_spec) = edit_host_spec(&opts, &host)? else {
+        return Ok(());
+    };
+    if !edit_changes_deployment(&host.spec, &new_spec) {
+        return edit_update_policy(&host.spec, &new_spec);
+    }
+    let required_spec = RequiredHostSpec::from_spec(&new_spec)?;
 
     let prog = ProgressWriter::default();
 
     // We only support two state transitions right now; switching the image,
     // or flipping the bootloader ordering.
-    if host.spec.boot_order != new_host.spec.boot_order {
-        return crate::deploy::rollback(storage).await;
+    if host.spec.boot_order != new_spec.boot_order {
+        crate::deploy::rollback(storage).await?;
+        return edit_update_policy(&host.spec, &new_spec);
     }
 
     let fetched = crate::deploy::pull(
         repo,
-        new_spec.image,
+        required_spec.image,
         None,
         opts.quiet,
         prog.clone(),
@@ -1499,11 +1826,11 @@ async fn edit_ostree(
 
     let stateroot = booted_ostree.stateroot();
     let from = MergeState::from_stateroot(storage, &stateroot)?;
-    crate::deploy::stage(storage, from, &fetched, &new_spec, prog.clone(), false).await?;
+    crate::deploy::stage(storage, from, &fetched, &required_spec, prog.clone(), false).await?;
 
     storage.update_mtime()?;
 
-    Ok(())
+    edit_update_policy(&host.spec, &new_spec)
 }
 
 /// Implementation of the `bootc edit` CLI command.
@@ -1514,8 +1841,15 @@ async fn edit(opts: EditOpts) -> Result<()> {
         BootedStorageKind::Ostree(booted_ostree) => {
             edit_ostree(opts, storage, &booted_ostree).await
         }
-        BootedStorageKind::Composefs(_) => {
-            anyhow::bail!("Edit is not yet supported for composefs backend")
+        BootedStorageKind::Composefs(booted_cfs) => {
+            let host = get_composefs_status(storage, &booted_cfs).await?;
+            let Some(new_spec) = edit_host_spec(&opts, &host)? else {
+                return Ok(());
+            };
+            if edit_changes_deployment(&host.spec, &new_spec) {
+                anyhow::bail!("Only updatePolicy can currently be edited for composefs backend")
+            }
+            edit_update_policy(&host.spec, &new_spec)
         }
     }
 }
@@ -1653,20 +1987,31 @@ impl Opt {
 async fn run_from_opt(opt: Opt) -> Result<()> {
     let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     match opt {
-        Opt::Upgrade(opts) => {
-            let storage = &get_storage().await?;
-            match storage.kind()? {
-                BootedStorageKind::Ostree(booted_ostree) => {
-                    upgrade(opts, storage, &booted_ostree).await
-                }
-                BootedStorageKind::Composefs(booted_cfs) => {
-                    upgrade_composefs(opts, storage, &booted_cfs).await
+        Opt::Upgrade(mut opts) => {
+            let prog = opts.progress.take_writer()?;
+            prog.end_with(async {
+                let storage = &get_storage().await?;
+                match storage.kind()? {
+                    BootedStorageKind::Ostree(booted_ostree) => {
+                        upgrade(opts, storage, &booted_ostree, prog.clone()).await
+                    }
+                    BootedStorageKind::Composefs(booted_cfs) => {
+                        if opts.from_bundle.is_some() {
+                            anyhow::bail!("--from-bundle only works for ostree backend");
+                        }
+                        upgrade_composefs(opts, storage, &booted_cfs, prog.clone()).await
+                    }
                 }
-            }
+            })
+            .await
         }
-        Opt::Switch(opts) => switch(opts).await,
-        Opt::Rollback(opts) => {
-            rollback(&opts).await?;
+        Opt::Switch(mut opts) => {
+            let prog = opts.progress.take_writer()?;
+            prog.end_with(switch(opts, prog.clone())).await
+        }
+        Opt::Rollback(mut opts) => {
+            let prog = opts.progress.take_writer()?;
+            prog.end_with(rollback(&opts, &prog)).await?;
             if opts.apply {
                 crate::reboot::reboot()?;
             }
@@ -1698,6 +2043,9 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 fatal_warnings,
                 list,
                 skip,
+                config,
+                format,
+                fix,
                 no_truncate,
             } => {
                 if list {
@@ -1715,12 +2063,18 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 };
 
                 let root = &Dir::open_ambient_dir(rootfs, cap_std::ambient_authority())?;
+                if fix {
+                    // Use stderr to avoid interfering with machine readable output
+                    lints::fix(root, root_type, std::io::stderr().lock())?;
+                }
                 let skip = skip.iter().map(|s| s.as_str());
                 lints::lint(
                     root,
                     warnings,
                     root_type,
                     skip,
+                    config.as_deref(),
+                    format,
                     std::io::stdout().lock(),
                     no_truncate,
                 )?;
@@ -1791,8 +2145,9 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 rootfs,
                 kargs,
                 allow_missing_verity,
+                signing,
                 args,
-            } => crate::ukify::build_ukify(&rootfs, &kargs, &args, allow_missing_verity),
+            } => crate::ukify::build_ukify(&rootfs, &kargs, &args, allow_missing_verity, &signing),
             ContainerOpts::Export {
                 format,
                 target,
@@ -1860,6 +2215,25 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     .pull_from_host_storage(&image)
                     .await
             }
+            ImageOpts::Prune { dry_run } => {
+                let storage = get_storage().await?;
+                if let BootedStorageKind::Composefs(_) = storage.kind()? {
+                    anyhow::bail!("image prune only works for ostree backend");
+                }
+                let pruned = crate::deploy::prune_container_store(&storage, dry_run).await?;
+                let verb = if dry_run { "Would prune" } else { "Pruned" };
+                println!("{verb} images: {}", pruned.len());
+                for image in pruned.iter() {
+                    println!("  {}", image.display_name());
+                }
+                Ok(())
+            }
+            ImageOpts::Bundle(BundleOpts::Create {
+                transport,
+                archive,
+                image,
+                output,
+            }) => crate::bundle::create(&image, &transport, &output, archive).await,
             ImageOpts::Cmd(opt) => {
                 let storage = get_storage().await?;
                 let imgstore = storage.get_ensure_imgstore()?;
@@ -1881,15 +2255,31 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
         },
         Opt::Install(opts) => match opts {
             #[cfg(feature = "install-to-disk")]
-            InstallOpts::ToDisk(opts) => crate::install::install_to_disk(opts).await,
-            InstallOpts::ToFilesystem(opts) => {
-                crate::install::install_to_filesystem(opts, false, crate::install::Cleanup::Skip)
+            InstallOpts::ToDisk(mut opts) => {
+                let prog = opts.target_opts.progress.take_writer()?;
+                prog.end_with(crate::install::install_to_disk(opts, prog.clone()))
                     .await
             }
-            InstallOpts::ToExistingRoot(opts) => {
-                crate::install::install_to_existing_root(opts).await
+            InstallOpts::ToFilesystem(mut opts) => {
+                let prog = opts.target_opts.progress.take_writer()?;
+                prog.end_with(crate::install::install_to_filesystem(
+                    opts,
+                    false,
+                    crate::install::Cleanup::Skip,
+                    prog.clone(),
+                ))
+                .await
+            }
+            InstallOpts::ToExistingRoot(mut opts) => {
+                let prog = opts.target_opts.progress.take_writer()?;
+                prog.end_with(crate::install::install_to_existing_root(opts, prog.clone()))
+                    .await
+            }
+            InstallOpts::Reset(mut opts) => {
+                let prog = opts.target_opts.progress.take_writer()?;
+                prog.end_with(crate::install::install_reset(opts, prog.clone()))
+                    .await
             }
-            InstallOpts::Reset(opts) => crate::install::install_reset(opts).await,
             InstallOpts::PrintConfiguration(opts) => crate::install::print_configuration(opts),
             InstallOpts::EnsureCompletion {} => {
                 let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
@@ -1969,6 +2359,7 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 let schema = match of {
                     SchemaType::Host => schema_for!(crate::spec::Host),
                     SchemaType::Progress => schema_for!(crate::progress_jsonl::Event),
+                    SchemaType::Lint => schema_for!(crate::lints::LintReport),
                 };
                 let mut stdout = std::io::stdout().lock();
                 serde_json::to_writer_pretty(&mut stdout, &schema)?;
@@ -2110,6 +2501,46 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                     }
                 }
             }
+            InternalsOpts::OstreeFinalizeStaged => {
+                let storage = &get_storage().await?;
+
+                match storage.kind()? {
+                    BootedStorageKind::Ostree(booted_ostree) => {
+                        crate::deploy::finalize_staged(storage, booted_ostree.sysroot)
+                    }
+                    BootedStorageKind::Composefs(..) => {
+                        anyhow::bail!("ostree-finalize-staged only works for ostree backend");
+                    }
+                }
+            }
+            InternalsOpts::BootComplete => {
+                let storage = &get_storage().await?;
+
+                match storage.kind()? {
+                    BootedStorageKind::Ostree(..) => {
+                        anyhow::bail!("boot-complete only works for composefs backend");
+                    }
+
+                    BootedStorageKind::Composefs(booted_cfs) => {
+                        composefs_boot_complete(storage, &booted_cfs).await
+                    }
+                }
+            }
+            InternalsOpts::RemapIds { dry_run } => {
+                let storage = &get_storage().await?;
+
+                match storage.kind()? {
+                    BootedStorageKind::Ostree(booted_ostree) => crate::iddrift::remap_booted(
+                        booted_ostree.sysroot,
+                        &booted_ostree.deployment,
+                        dry_run,
+                        std::io::stdout().lock(),
+                    ),
+                    BootedStorageKind::Composefs(..) => {
+                        anyhow::bail!("remap-ids is not supported for the composefs backend");
+                    }
+                }
+            }
         },
         Opt::State(opts) => match opts {
             StateOpts::WipeOstree => {
@@ -2118,6 +2549,20 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
                 crate::deploy::wipe_ostree(sysroot).await?;
                 Ok(())
             }
+            StateOpts::FactoryReset(opts) => {
+                if opts.discard {
+                    return crate::factory_reset::discard_factory_reset().await;
+                }
+                if opts.revert {
+                    crate::factory_reset::revert_factory_reset().await?;
+                } else {
+                    crate::factory_reset::factory_reset(&opts.keep).await?;
+                }
+                if opts.apply {
+                    crate::reboot::reboot()?;
+                }
+                Ok(())
+            }
         },
 
         Opt::ComposefsFinalizeStaged => {
@@ -2132,16 +2577,34 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
             }
         }
 
-        Opt::ConfigDiff => {
-            let storage = &get_storage().await?;
-            match storage.kind()? {
-                BootedStorageKind::Ostree(_) => {
-                    anyhow::bail!("ConfigDiff is only supported for composefs backend")
+        Opt::Kargs(opts) => {
+            let (changed, apply) = match opts {
+                KargsOpts::List { local } => return crate::kargs::list(local).await,
+                KargsOpts::Append(opts) => {
+                    let changed =
+                        crate::kargs::change(KargsOp::Append, &opts.kargs, &opts.signing).await?;
+                    (changed, opts.apply)
                 }
-                BootedStorageKind::Composefs(booted_cfs) => {
-                    get_etc_diff(storage, &booted_cfs).await
+                KargsOpts::Delete(opts) => {
+                    let changed =
+                        crate::kargs::change(KargsOp::Delete, &opts.kargs, &opts.signing).await?;
+                    (changed, opts.apply)
                 }
+                KargsOpts::Replace(opts) => {
+                    let changed =
+                        crate::kargs::change(KargsOp::Replace, &opts.kargs, &opts.signing).await?;
+                    (changed, opts.apply)
+                }
+                KargsOpts::Edit { apply, signing } => (crate::kargs::edit(&signing).await?, apply),
+            };
+            if changed && apply {
+                crate::reboot::reboot()?;
             }
+            Ok(())
+        }
+        Opt::ConfigDiff(opts) => crate::config_diff::config_diff(opts.format, opts.path).await,
+        Opt::ConfigRevert(opts) => {
+            crate::config_diff::config_revert(opts.paths, opts.dry_run).await
         }
 
         Opt::DeleteDeployment { depl_id } => {
@@ -2230,7 +2693,8 @@ mod tests {
                 format: None,
                 format_version: None,
                 booted: false,
-                verbose: false
+                verbose: false,
+                diff: None
             })
         ));
         assert!(matches!(
@@ -2252,6 +2716,59 @@ mod tests {
             Opt::parse_including_static(["bootc", "status", "-v"]),
             Opt::Status(StatusOpts { verbose: true, .. })
         ));
+
+        // Test diff, defaulting to the staged deployment
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "status", "--diff"]),
+            Opt::Status(StatusOpts {
+                diff: Some(crate::deployment_diff::DiffTarget::Staged),
+                ..
+            })
+        ));
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "status", "--diff=rollback"]),
+            Opt::Status(StatusOpts {
+                diff: Some(crate::deployment_diff::DiffTarget::Rollback),
+                ..
+            })
+        ));
+        assert!(Opt::try_parse_from(["bootc", "status", "--diff", "--booted"]).is_err());
+        assert!(Opt::try_parse_from(["bootc", "status", "--diff=booted"]).is_err());
+
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "config-diff"]),
+            Opt::ConfigDiff(ConfigDiffOpts {
+                format: OutputFormat::HumanReadable,
+                path: None,
+            })
+        );
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "config-diff",
+                "--format=json",
+                "--path=/etc/ssh"
+            ]),
+            Opt::ConfigDiff(ConfigDiffOpts {
+                format: OutputFormat::Json,
+                path: Some("/etc/ssh".into()),
+            })
+        );
+
+        assert!(Opt::try_parse_from(["bootc", "config-revert"]).is_err());
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "config-revert",
+                "--dry-run",
+                "/etc/ssh",
+                "motd"
+            ]),
+            Opt::ConfigRevert(ConfigRevertOpts {
+                paths: vec!["/etc/ssh".into(), "motd".into()],
+                dry_run: true,
+            })
+        );
     }
 
     #[test]
@@ -2320,6 +2837,136 @@ mod tests {
             }
             _ => panic!("Expected Upgrade variant"),
         }
+
+        let o =
+            Opt::try_parse_from(["bootc", "upgrade", "--from-bundle", "/mnt/bundle.tar"]).unwrap();
+        match o {
+            Opt::Upgrade(opts) => {
+                assert_eq!(
+                    opts.from_bundle.as_deref(),
+                    Some(Utf8Path::new("/mnt/bundle.tar"))
+                );
+            }
+            _ => panic!("Expected Upgrade variant"),
+        }
+        assert!(
+            Opt::try_parse_from(["bootc", "upgrade", "--from-bundle", "/mnt/b", "--check"])
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_switch_from_bundle() {
+        let o = Opt::try_parse_from(["bootc", "switch", "--from-bundle", "/mnt/bundle"]).unwrap();
+        match o {
+            Opt::Switch(opts) => {
+                assert_eq!(
+                    opts.from_bundle.as_deref(),
+                    Some(Utf8Path::new("/mnt/bundle"))
+                );
+                assert_eq!(opts.target, None);
+            }
+            _ => panic!("Expected Switch variant"),
+        }
+        // Either a target or a bundle is required, but not both
+        assert!(Opt::try_parse_from(["bootc", "switch"]).is_err());
+        assert!(
+            Opt::try_parse_from(["bootc", "switch", "--from-bundle", "/mnt/b", "quay.io/x/y"])
+                .is_err()
+        );
+
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "image",
+                "bundle",
+                "create",
+                "--archive",
+                "quay.io/exampleos/myos:latest",
+                "/mnt/bundle.tar"
+            ]),
+            Opt::Image(ImageOpts::Bundle(BundleOpts::Create {
+                transport: "registry".into(),
+                archive: true,
+                image: "quay.io/exampleos/myos:latest".into(),
+                output: "/mnt/bundle.tar".into(),
+            }))
+        );
+    }
+
+    #[test]
+    fn test_parse_factory_reset() {
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "state",
+                "factory-reset",
+                "--keep",
+                "/etc/ssh",
+                "--keep",
+                "/var/lib/NetworkManager",
+                "--apply"
+            ]),
+            Opt::State(StateOpts::FactoryReset(FactoryResetOpts {
+                keep: vec!["/etc/ssh".into(), "/var/lib/NetworkManager".into()],
+                apply: true,
+                revert: false,
+                discard: false,
+            }))
+        );
+        for args in [
+            ["--revert", "--discard"],
+            ["--discard", "--apply"],
+            ["--keep=/etc/ssh", "--revert"],
+        ] {
+            let args = ["bootc", "state", "factory-reset"].into_iter().chain(args);
+            assert!(Opt::try_parse_from(args).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_kargs() {
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "kargs", "list", "--local"]),
+            Opt::Kargs(KargsOpts::List { local: true })
+        );
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "kargs", "append", "nosmt", "console=ttyS0"]),
+            Opt::Kargs(KargsOpts::Append(KargsChangeOpts {
+                kargs: vec!["nosmt".into(), "console=ttyS0".into()],
+                apply: false,
+                signing: Default::default(),
+            }))
+        );
+        assert_eq!(
+            Opt::parse_including_static(["bootc", "kargs", "delete", "--apply", "quiet"]),
+            Opt::Kargs(KargsOpts::Delete(KargsChangeOpts {
+                kargs: vec!["quiet".into()],
+                apply: true,
+                signing: Default::default(),
+            }))
+        );
+        assert_eq!(
+            Opt::parse_including_static([
+                "bootc",
+                "kargs",
+                "edit",
+                "--secureboot-key=/keys/db.key",
+                "--secureboot-certificate=/keys/db.crt",
+            ]),
+            Opt::Kargs(KargsOpts::Edit {
+                apply: false,
+                signing: crate::ukify::SecureBootSigningOpts {
+                    secureboot_key: Some("/keys/db.key".into()),
+                    secureboot_certificate: Some("/keys/db.crt".into()),
+                },
+            })
+        );
+        assert!(Opt::try_parse_from(["bootc", "kargs", "replace"]).is_err());
+        assert!(
+            Opt::try_parse_from(["bootc", "kargs", "edit", "--secureboot-key=/keys/db.key"])
+                .is_err()
+        );
     }
 
     #[test]
@@ -12,6 +12,12 @@ pub(crate) const STATE_DIR_ABS: &str = "/sysroot/state/deploy";
 pub(crate) const STATE_DIR_RELATIVE: &str = "state/deploy";
 /// Relative path to the shared 'var' directory. Relative to /sysroot
 pub(crate) const SHARED_VAR_PATH: &str = "state/os/default/var";
+/// Extension of the file in the deployment state directory which records how
+/// `/etc` and `/var` are set up at boot
+pub(crate) const ROOT_SETUP_EXT: &str = "rootsetup";
+/// Extension of the file in the deployment state directory which records the
+/// kernel arguments from the image
+pub(crate) const IMAGE_KARGS_EXT: &str = "kargs";
 
 /// Section in .origin file to store boot related metadata
 pub(crate) const ORIGIN_KEY_BOOT: &str = "boot";
@@ -0,0 +1,504 @@
+//! # Local changes to /etc
+//!
+//! Implementation of `bootc config-diff`, which shows how the current `/etc`
+//! differs from the default one shipped in the booted image: the paths which
+//! were added, removed or modified, with a unified diff of modified text files
+//! and the metadata changes reported separately.
+//!
+//! Also implements `bootc config-revert`, which restores paths to the defaults.
+
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use bootc_initramfs_setup::mount_composefs_image;
+use bootc_mount::tempmount::TempMount;
+use camino::{Utf8Path, Utf8PathBuf};
+use canon_json::CanonJsonSerialize;
+use cap_std_ext::cap_std;
+use cap_std_ext::cap_std::fs::Dir;
+use cfsctl::composefs;
+use composefs::generic_tree::{Directory, Stat};
+use etc_merge::{
+    Modification, ModificationType, compute_diff, describe_modification, traverse_etc,
+};
+use fn_error_context::context;
+use serde::Serialize;
+
+use crate::bootc_composefs::status::get_composefs_status;
+use crate::cli::OutputFormat;
+use crate::store::{BootedStorageKind, Storage};
+
+/// Files larger than this are not diffed.
+const MAX_DIFF_SIZE: u64 = 1024 * 1024;
+
+/// A value which changed.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct ValueChange<T> {
+    /// The default value
+    pub(crate) from: T,
+    /// The current value
+    pub(crate) to: T,
+}
+
+impl<T> From<(T, T)> for ValueChange<T> {
+    fn from((from, to): (T, T)) -> Self {
+        Self { from, to }
+    }
+}
+
+/// Changes to the metadata of a path.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct MetadataChanges {
+    /// The permission bits, in octal
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) mode: Option<ValueChange<String>>,
+    /// The owner, as `uid:gid`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) owner: Option<ValueChange<String>>,
+    /// The SELinux label
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) selinux_label: Option<ValueChange<Option<String>>>,
+    /// The names of other extended attributes which changed
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub(crate) xattrs: Vec<String>,
+}
+
+impl MetadataChanges {
+    fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl From<&Modification> for MetadataChanges {
+    fn from(m: &Modification) -> Self {
+        let owner = |(uid, gid): (u32, u32)| format!("{uid}:{gid}");
+        Self {
+            mode: m
+                .mode
+                .map(|(from, to)| (format!("{from:04o}"), format!("{to:04o}")).into()),
+            owner: m.owner.map(|(from, to)| (owner(from), owner(to)).into()),
+            selinux_label: m.selinux_label.clone().map(Into::into),
+            xattrs: m.xattrs.clone(),
+        }
+    }
+}
+
+/// A path which exists in both the default and current /etc, but differs.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ModifiedPath {
+    /// The absolute path
+    pub(crate) path: String,
+    /// Whether the file type, contents or symlink target changed
+    pub(crate) content_changed: bool,
+    /// A unified diff of the contents, for text files
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) diff: Option<String>,
+    /// Changes to the metadata
+    #[serde(skip_serializing_if = "MetadataChanges::is_empty")]
+    pub(crate) metadata: MetadataChanges,
+}
+
+/// Local changes to /etc. Paths are absolute.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ConfigDiff {
+    /// Paths which are not in the default /etc
+    pub(crate) added: Vec<String>,
+    /// Paths from the default /etc which were removed
+    pub(crate) removed: Vec<String>,
+    /// Paths which were modified
+    pub(crate) modified: Vec<ModifiedPath>,
+}
+
+/// Convert a path given on the command line to one relative to /etc.
+fn relative_to_etc(path: &Utf8Path) -> Result<&Utf8Path> {
+    if path.is_absolute() {
+        path.strip_prefix("/etc")
+            .with_context(|| format!("{path} is not in /etc"))
+    } else {
+        Ok(path)
+    }
+}
+
+/// Return a unified diff of a file, if it is a reasonably sized text file in
+/// both directories.
+fn text_diff(pristine_etc: &Dir, current_etc: &Dir, path: &Path) -> Result<Option<String>> {
+    let read = |d: &Dir| -> Result<Option<String>> {
+        let meta = d.symlink_metadata(path)?;
+        if !meta.is_file() || meta.len() > MAX_DIFF_SIZE {
+            return Ok(None);
+        }
+        let buf = d.read(path)?;
+        if buf.contains(&0) {
+            return Ok(None);
+        }
+        Ok(String::from_utf8(buf).ok())
+    };
+    let (Some(from), Some(to)) = (read(pristine_etc)?, read(current_etc)?) else {
+        return Ok(None);
+    };
+
+    let path = Path::new("etc").join(path);
+    let diff = similar::TextDiff::from_lines(&from, &to)
+        .unified_diff()
+        .header(
+            &format!("a/{}", path.display()),
+            &format!("b/{}", path.display()),
+        )
+        .to_string();
+    Ok(Some(diff))
+}
+
+/// Compute the changes from `pristine_etc` to `current_etc`, limited to paths
+/// under `filter` (relative to /etc) if provided.
+#[context("Computing /etc diff")]
+pub(crate) fn compute(
+    pristine_etc: &Dir,
+    current_etc: &Dir,
+    filter: Option<&Utf8Path>,
+) -> Result<ConfigDiff> {
+    let (pristine_files, current_files, _) = traverse_etc(pristine_etc, current_etc, None)?;
+    let diff = compute_diff(
+        &pristine_files,
+        &current_files,
+        &Directory::new(Stat::uninitialized()),
+    )?;
+
+    let included = |p: &&std::path::PathBuf| filter.is_none_or(|f| p.starts_with(f));
+    let absolute = |p: &Path| Path::new("/etc").join(p).to_string_lossy().into_owned();
+
+    let mut r = ConfigDiff {
+        added: diff
+            .added()
+            .iter()
+            .filter(included)
+            .map(|p| absolute(p))
+            .collect(),
+        removed: diff
+            .removed()
+            .iter()
+            .filter(included)
+            .map(|p| absolute(p))
+            .collect(),
+        modified: Vec::new(),
+    };
+    for path in diff.modified().iter().filter(included) {
+        let Some(m) = describe_modification(&pristine_files, &current_files, path) else {
+            continue;
+        };
+        let diff = if m.content {
+            text_diff(pristine_etc, current_etc, path)
+                .with_context(|| format!("Comparing {path:?}"))?
+        } else {
+            None
+        };
+        r.modified.push(ModifiedPath {
+            path: absolute(path),
+            content_changed: m.content,
+            diff,
+            metadata: (&m).into(),
+        });
+    }
+
+    Ok(r)
+}
+
+/// Write a human readable version of the diff.
+pub(crate) fn human_readable_output(mut out: impl Write, diff: &ConfigDiff) -> Result<()> {
+    for path in diff.added.iter() {
+        writeln!(out, "Added {path}")?;
+    }
+    for path in diff.removed.iter() {
+        writeln!(out, "Removed {path}")?;
+    }
+    for m in diff.modified.iter() {
+        writeln!(out, "Modified {}", m.path)?;
+        let meta = &m.metadata;
+        if let Some(mode) = meta.mode.as_ref() {
+            writeln!(out, "  mode: {} -> {}", mode.from, mode.to)?;
+        }
+        if let Some(owner) = meta.owner.as_ref() {
+            writeln!(out, "  owner: {} -> {}", owner.from, owner.to)?;
+        }
+        if let Some(label) = meta.selinux_label.as_ref() {
+            let none = "<none>";
+            let from = label.from.as_deref().unwrap_or(none);
+            let to = label.to.as_deref().unwrap_or(none);
+            writeln!(out, "  SELinux label: {from} -> {to}")?;
+        }
+        if !meta.xattrs.is_empty() {
+            writeln!(out, "  xattrs: {}", meta.xattrs.join(", "))?;
+        }
+        if let Some(diff) = m.diff.as_deref() {
+            write!(out, "{diff}")?;
+        } else if m.content_changed {
+            writeln!(out, "  (content changed)")?;
+        }
+    }
+    Ok(())
+}
+
+/// Open the default /etc of the booted deployment. With composefs, the booted
+/// image is mounted, and must stay mounted while the directory is in use.
+async fn open_pristine_etc(storage: &Storage) -> Result<(Dir, Option<TempMount>)> {
+    match storage.kind()? {
+        BootedStorageKind::Ostree(_) => {
+            let pristine_etc = Dir::open_ambient_dir("/usr/etc", cap_std::ambient_authority())?;
+            Ok((pristine_etc, None))
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            let host = get_composefs_status(storage, &booted_cfs).await?;
+            let booted_composefs = host.require_composefs_booted()?;
+
+            // Mount the booted EROFS image to get pristine etc
+            let sysroot_fd = storage.physical_root.reopen_as_ownedfd()?;
+            let erofs_tmp_mnt = TempMount::mount_fd(mount_composefs_image(
+                &sysroot_fd,
+                &booted_composefs.verity,
+                booted_cfs.cmdline.allow_missing_fsverity,
+            )?)?;
+            let pristine_etc = erofs_tmp_mnt.fd.open_dir("etc")?;
+            Ok((pristine_etc, Some(erofs_tmp_mnt)))
+        }
+    }
+}
+
+/// Implementation of `bootc config-diff`.
+#[context("Diffing /etc")]
+pub(crate) async fn config_diff(format: OutputFormat, path: Option<Utf8PathBuf>) -> Result<()> {
+    let filter = path.as_deref().map(relative_to_etc).transpose()?;
+    let current_etc = Dir::open_ambient_dir("/etc", cap_std::ambient_authority())?;
+
+    let storage = &crate::cli::get_storage().await?;
+    let (pristine_etc, _mount) = open_pristine_etc(storage).await?;
+    let diff = compute(&pristine_etc, &current_etc, filter)?;
+
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    match format {
+        OutputFormat::Json => diff
+            .to_canon_json_writer(&mut out)
+            .map_err(anyhow::Error::new),
+        OutputFormat::Yaml => serde_yaml::to_writer(&mut out, &diff).map_err(anyhow::Error::new),
+        OutputFormat::HumanReadable => human_readable_output(&mut out, &diff),
+    }
+    .context("Writing to stdout")
+}
+
+/// Describe a reverted local change.
+fn describe_reverted(change: ModificationType, path: &Path, dry_run: bool) -> String {
+    let path = Path::new("/etc").join(path);
+    let path = path.display();
+    match (change, dry_run) {
+        (ModificationType::Added, false) => format!("Removed {path}"),
+        (ModificationType::Added, true) => format!("Would remove {path}"),
+        (ModificationType::Modified | ModificationType::Removed, false) => {
+            format!("Restored {path}")
+        }
+        (ModificationType::Modified | ModificationType::Removed, true) => {
+            format!("Would restore {path}")
+        }
+    }
+}
+
+/// Implementation of `bootc config-revert`.
+#[context("Reverting /etc")]
+pub(crate) async fn config_revert(paths: Vec<Utf8PathBuf>, dry_run: bool) -> Result<()> {
+    let paths = paths
+        .iter()
+        .map(|p| {
+            let rel = relative_to_etc(p)?;
+            if rel.as_str().is_empty() {
+                anyhow::bail!("Refusing to revert all of /etc");
+            }
+            Ok(rel.as_std_path().to_owned())
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let current_etc = Dir::open_ambient_dir("/etc", cap_std::ambient_authority())?;
+
+    let storage = &crate::cli::get_storage().await?;
+    let (pristine_etc, _mount) = open_pristine_etc(storage).await?;
+
+    let (pristine_files, current_files, _) = traverse_etc(&pristine_etc, &current_etc, None)?;
+    let diff = compute_diff(
+        &pristine_files,
+        &current_files,
+        &Directory::new(Stat::uninitialized()),
+    )?;
+    let reverted = etc_merge::revert(
+        &pristine_etc,
+        &pristine_files,
+        &current_etc,
+        &current_files,
+        &diff,
+        &paths,
+        dry_run,
+    )?;
+
+    if reverted.is_empty() {
+        println!("No local changes to revert");
+    }
+    for (change, path) in reverted {
+        println!("{}", describe_reverted(change, &path, dry_run));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std::fs::{Permissions, PermissionsExt};
+    use cap_std_ext::dirext::CapStdExtDirExt;
+
+    use super::*;
+
+    #[test]
+    fn test_relative_to_etc() {
+        assert_eq!(
+            relative_to_etc(Utf8Path::new("/etc/ssh")).unwrap(),
+            Utf8Path::new("ssh")
+        );
+        assert_eq!(
+            relative_to_etc(Utf8Path::new("ssh")).unwrap(),
+            Utf8Path::new("ssh")
+        );
+        assert_eq!(
+            relative_to_etc(Utf8Path::new("/etc")).unwrap(),
+            Utf8Path::new("")
+        );
+        assert!(relative_to_etc(Utf8Path::new("/usr/etc/ssh")).is_err());
+    }
+
+    #[test]
+    fn test_compute() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir("pristine")?;
+        td.create_dir("current")?;
+        let p = td.open_dir("pristine")?;
+        let c = td.open_dir("current")?;
+
+        for d in [&p, &c] {
+            d.create_dir("ssh")?;
+            d.write("hostname", "localhost\n")?;
+        }
+        p.write("chrony.conf", "pool example.com iburst\nmakestep 1.0 3\n")?;
+        c.write("chrony.conf", "pool example.org iburst\nmakestep 1.0 3\n")?;
+        p.write("ssh/sshd_config", "PermitRootLogin no\n")?;
+        c.atomic_write_with_perms(
+            "ssh/sshd_config",
+            "PermitRootLogin no\n",
+            Permissions::from_mode(0o600),
+        )?;
+        p.write("binary", b"\0\x01")?;
+        c.write("binary", b"\0\x02")?;
+        p.write("motd", "hello\n")?;
+        c.write("issue", "hello\n")?;
+
+        let diff = compute(&p, &c, None)?;
+        assert_eq!(diff.added, ["/etc/issue"]);
+        assert_eq!(diff.removed, ["/etc/motd"]);
+        let modified = diff
+            .modified
+            .iter()
+            .map(|m| m.path.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            modified,
+            ["/etc/binary", "/etc/chrony.conf", "/etc/ssh/sshd_config"]
+        );
+
+        let binary = &diff.modified[0];
+        assert!(binary.content_changed);
+        assert_eq!(binary.diff, None);
+
+        let chrony = &diff.modified[1];
+        assert!(chrony.content_changed);
+        assert!(chrony.metadata.is_empty());
+        similar_asserts::assert_eq!(
+            chrony.diff.as_deref().unwrap(),
+            indoc::indoc! { r#"
+                --- a/etc/chrony.conf
+                +++ b/etc/chrony.conf
+                @@ -1,2 +1,2 @@
+                -pool example.com iburst
+                +pool example.org iburst
+                 makestep 1.0 3
+            "# }
+        );
+
+        let sshd = &diff.modified[2];
+        assert!(!sshd.content_changed);
+        assert_eq!(sshd.diff, None);
+        assert_eq!(sshd.metadata.mode.as_ref().unwrap().to, "0600");
+        assert_eq!(sshd.metadata.owner, None);
+
+        let diff = compute(&p, &c, Some(Utf8Path::new("ssh")))?;
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].path, "/etc/ssh/sshd_config");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_describe_reverted() {
+        let cases = [
+            (ModificationType::Added, false, "Removed /etc/issue"),
+            (ModificationType::Added, true, "Would remove /etc/issue"),
+            (ModificationType::Modified, false, "Restored /etc/issue"),
+            (ModificationType::Removed, true, "Would restore /etc/issue"),
+        ];
+        for (change, dry_run, expected) in cases {
+            assert_eq!(
+                describe_reverted(change, Path::new("issue"), dry_run),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_human_readable_output() -> Result<()> {
+        let diff = ConfigDiff {
+            added: vec!["/etc/issue".into()],
+            removed: vec!["/etc/motd".into()],
+            modified: vec![
+                ModifiedPath {
+                    path: "/etc/ssh/sshd_config".into(),
+                    content_changed: false,
+                    diff: None,
+                    metadata: MetadataChanges {
+                        mode: Some(("0644".to_owned(), "0600".to_owned()).into()),
+                        selinux_label: Some(
+                            (Some("system_u:object_r:etc_t:s0".to_owned()), None).into(),
+                        ),
+                        ..Default::default()
+                    },
+                },
+                ModifiedPath {
+                    path: "/etc/binary".into(),
+                    content_changed: true,
+                    diff: None,
+                    metadata: Default::default(),
+                },
+            ],
+        };
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &diff)?;
+        similar_asserts::assert_eq!(
+            String::from_utf8(w)?,
+            indoc::indoc! { r#"
+                Added /etc/issue
+                Removed /etc/motd
+                Modified /etc/ssh/sshd_config
+                  mode: 0644 -> 0600
+                  SELinux label: system_u:object_r:etc_t:s0 -> <none>
+                Modified /etc/binary
+                  (content changed)
+            "# }
+        );
+        Ok(())
+    }
+}
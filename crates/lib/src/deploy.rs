@@ -9,6 +9,7 @@ use std::process::Command;
 
 use anyhow::{Context, Result, anyhow};
 use bootc_kernel_cmdline::utf8::CmdlineOwned;
+use bootc_utils::CommandRunExt;
 use cap_std::fs::{Dir, MetadataExt};
 use cap_std_ext::cap_std;
 use cap_std_ext::dirext::CapStdExtDirExt;
@@ -23,7 +24,7 @@ use ostree_ext::ostree::{self, Sysroot};
 use ostree_ext::sysroot::SysrootLock;
 use ostree_ext::tokio_util::spawn_blocking_cancellable_flatten;
 
-use crate::progress_jsonl::{Event, ProgressWriter, SubTaskBytes, SubTaskStep};
+use crate::progress_jsonl::{Event, Phase, ProgressWriter, SubTaskBytes, SubTaskStep};
 use crate::spec::ImageReference;
 use crate::spec::{BootOrder, HostSpec};
 use crate::status::labels_of_config;
@@ -33,6 +34,9 @@ use crate::utils::async_task_with_spinner;
 // TODO use https://github.com/ostreedev/ostree-rs-ext/pull/493/commits/afc1837ff383681b947de30c0cefc70080a4f87a
 const BASE_IMAGE_PREFIX: &str = "ostree/container/baseimage/bootc";
 
+/// The unit which calls [`finalize_staged`] when the system shuts down.
+const FINALIZE_STAGED_SERVICE: &str = "bootc-ostree-finalize-staged.service";
+
 /// Create an ImageProxyConfig with bootc's user agent prefix set.
 ///
 /// This allows registries to distinguish "image pulls for bootc client runs"
@@ -353,13 +357,25 @@ async fn handle_layer_progress_print(mut config: LayerProgressConfig) -> Progres
 
 /// Gather all bound images in all deployments, then prune the image store,
 /// using the gathered images as the roots (that will not be GC'd).
-pub(crate) async fn prune_container_store(sysroot: &Storage) -> Result<()> {
+/// Returns the pruned images; with `dry_run`, those which would be pruned.
+pub(crate) async fn prune_container_store(
+    sysroot: &Storage,
+    dry_run: bool,
+) -> Result<Vec<crate::podman::ImageListEntry>> {
     let ostree = sysroot.get_ostree()?;
     let deployments = ostree.deployments();
     let mut all_bound_images = Vec::new();
+    // Keep the exact images recorded for each deployment too, even if the
+    // name now refers to a different image
+    let mut digests = Vec::new();
     for deployment in deployments {
         let bound = crate::boundimage::query_bound_images_for_deployment(ostree, &deployment)?;
         all_bound_images.extend(bound.into_iter());
+        digests.extend(
+            crate::boundimage::read_report(ostree, &deployment)?
+                .into_iter()
+                .map(|i| i.digest),
+        );
         // Also include the host image itself
         // Note: Use just the image name (not the full transport:image format) because
         // podman's image names don't include the transport prefix.
@@ -370,17 +386,19 @@ pub(crate) async fn prune_container_store(sysroot: &Storage) -> Result<()> {
             all_bound_images.push(crate::boundimage::BoundImage {
                 image: host_image.image.clone(),
                 auth_file: None,
+                pull_options: Default::default(),
             });
         }
     }
     // Convert to a hashset of just the image names
     let image_names = HashSet::from_iter(all_bound_images.iter().map(|img| img.image.as_str()));
+    let digests = HashSet::from_iter(digests.iter().map(String::as_str));
     let pruned = sysroot
         .get_ensure_imgstore()?
-        .prune_except_roots(&image_names)
+        .prune_except_roots(&image_names, &digests, dry_run)
         .await?;
     tracing::debug!("Pruned images: {}", pruned.len());
-    Ok(())
+    Ok(pruned)
 }
 
 /// Core disk space check: verify that `bytes_to_fetch` fits within available space,
@@ -397,12 +415,13 @@ fn check_disk_space_inner(
     tracing::trace!("bytes_avail: {bytes_avail} min_free: {min_free} usable: {usable}");
 
     if bytes_to_fetch > usable {
-        anyhow::bail!(
+        // Carry ENOSPC as the cause so that the failure can be classified
+        return Err(std::io::Error::from(rustix::io::Errno::NOSPC)).context(format!(
             "Insufficient free space for {image} (available: {available} required: {required})",
             available = ostree_ext::glib::format_size(usable),
             required = ostree_ext::glib::format_size(bytes_to_fetch),
             image = imgref.image,
-        );
+        ));
     }
     Ok(())
 }
@@ -625,7 +644,10 @@ pub(crate) async fn pull_unified(
     store: &Storage,
     booted_deployment: Option<&ostree::Deployment>,
 ) -> Result<Box<ImageState>> {
-    match prepare_for_pull_unified(repo, imgref, target_imgref, store, booted_deployment).await? {
+    prog.start_phase(Phase::Pull).await;
+    let prepared =
+        prepare_for_pull_unified(repo, imgref, target_imgref, store, booted_deployment).await?;
+    let fetched = match prepared {
         PreparedPullResult::AlreadyPresent(existing) => {
             // Log that the image was already present (Debug level since it's not actionable)
             const IMAGE_ALREADY_PRESENT_ID: &str = "5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9";
@@ -637,7 +659,7 @@ pub(crate) async fn pull_unified(
                 "Image already present: {}",
                 imgref
             );
-            Ok(existing)
+            existing
         }
         PreparedPullResult::Ready(prepared_image_meta) => {
             check_disk_space_unified(
@@ -651,9 +673,11 @@ pub(crate) async fn pull_unified(
                 image: imgref.image.clone(),
                 signature: imgref.signature.clone(),
             };
-            pull_from_prepared(&cs_imgref, quiet, prog, *prepared_image_meta).await
+            pull_from_prepared(&cs_imgref, quiet, prog.clone(), *prepared_image_meta).await?
         }
-    }
+    };
+    prog.complete_phase(Phase::Pull).await;
+    Ok(fetched)
 }
 
 #[context("Pulling")]
@@ -726,6 +750,7 @@ pub(crate) async fn pull_from_prepared(
             .context("Image content warning")?
     {
         tracing::info!("{}", msg);
+        prog.warn(&msg).await;
     }
     Ok(Box::new((*import).into()))
 }
@@ -739,7 +764,9 @@ pub(crate) async fn pull(
     prog: ProgressWriter,
     booted_deployment: Option<&ostree::Deployment>,
 ) -> Result<Box<ImageState>> {
-    match prepare_for_pull(repo, imgref, target_imgref, booted_deployment).await? {
+    prog.start_phase(Phase::Pull).await;
+    let prepared = prepare_for_pull(repo, imgref, target_imgref, booted_deployment).await?;
+    let fetched = match prepared {
         PreparedPullResult::AlreadyPresent(existing) => {
             // Log that the image was already present (Debug level since it's not actionable)
             const IMAGE_ALREADY_PRESENT_ID: &str = "5c4d3e2f1a0b9c8d7e6f5a4b3c2d1e0f9";
@@ -751,7 +778,7 @@ pub(crate) async fn pull(
                 "Image already present: {}",
                 imgref
             );
-            Ok(existing)
+            existing
         }
         PreparedPullResult::Ready(prepared_image_meta) => {
             // Check disk space before attempting to pull
@@ -766,9 +793,11 @@ pub(crate) async fn pull(
                 "Pulling new image: {}",
                 imgref
             );
-            Ok(pull_from_prepared(imgref, quiet, prog, *prepared_image_meta).await?)
+            pull_from_prepared(imgref, quiet, prog.clone(), *prepared_image_meta).await?
         }
-    }
+    };
+    prog.complete_phase(Phase::Pull).await;
+    Ok(fetched)
 }
 
 pub(crate) async fn wipe_ostree(sysroot: Sysroot) -> Result<()> {
@@ -791,7 +820,7 @@ pub(crate) async fn cleanup(sysroot: &Storage) -> Result<()> {
         "Starting cleanup of old images and deployments"
     );
 
-    let bound_prune = prune_container_store(sysroot);
+    let bound_prune = prune_container_store(sysroot, false);
 
     // We create clones (just atomic reference bumps) here to move to the thread.
     let ostree = sysroot.get_ostree_cloned()?;
@@ -863,6 +892,7 @@ async fn deploy(
     image: &ImageState,
     origin: &glib::KeyFile,
     lock_finalization: bool,
+    prog: &ProgressWriter,
 ) -> Result<Deployment> {
     // Compute the kernel argument overrides. In practice today this API is always expecting
     // a merge deployment. The kargs code also always looks at the booted root (which
@@ -873,12 +903,25 @@ async fn deploy(
             let kargs = crate::bootc_kargs::get_kargs(sysroot, &deployment, image)?;
             (deployment.stateroot().into(), Some(kargs))
         }
+        MergeState::MergeDeploymentWithKargs { deployment, kargs } => {
+            (deployment.stateroot().into(), Some(kargs.clone()))
+        }
         MergeState::Reset { stateroot, kargs } => (stateroot.clone(), Some(kargs.clone())),
     };
+    // Images may opt in to us merging /etc instead of libostree
+    let native_etc_merge = match from.as_merge_deployment() {
+        Some(_) => {
+            let repo = &sysroot.get_ostree()?.repo();
+            crate::etcmerge::native_enabled(repo, &image.ostree_commit)?
+        }
+        None => false,
+    };
     // Clone all the things to move to worker thread
     let ostree = sysroot.get_ostree_cloned()?;
-    // ostree::Deployment is incorrectly !Send 😢 so convert it to an integer
-    let merge_deployment = from.as_merge_deployment();
+    // ostree::Deployment is incorrectly !Send 😢 so convert it to an integer.
+    // With the native merge, libostree must not get a merge deployment, as it
+    // would otherwise merge /etc again when finalizing the staged deployment.
+    let merge_deployment = from.as_merge_deployment().filter(|_| !native_etc_merge);
     let merge_deployment = merge_deployment.map(|d| d.index() as usize);
     let ostree_commit = image.ostree_commit.to_string();
     // GKeyFile also isn't Send! So we serialize that as a string...
@@ -922,6 +965,35 @@ async fn deploy(
     let ostree = sysroot.get_ostree()?;
     let staged = ostree.staged_deployment().unwrap();
     assert_eq!(staged.index(), r);
+    if let Some(merge_deployment) = from.as_merge_deployment().filter(|_| native_etc_merge) {
+        prog.start_phase(Phase::EtcMerge).await;
+        if let Err(e) = crate::etcmerge::merge_staged(ostree, merge_deployment, &staged) {
+            // Don't leave behind a deployment with a partially merged /etc
+            let deployments = ostree
+                .deployments()
+                .into_iter()
+                .filter(|d| !d.equal(&staged))
+                .collect::<Vec<_>>();
+            ostree.write_deployments(&deployments, gio::Cancellable::NONE)?;
+            return Err(e);
+        }
+        prog.complete_phase(Phase::EtcMerge).await;
+    }
+    // Drift is not fatal; the existing IDs are kept
+    if let Some(merge_deployment) = from.as_merge_deployment() {
+        match crate::iddrift::check_staged(ostree, merge_deployment, &staged, native_etc_merge) {
+            Ok(drift) => {
+                crate::iddrift::print_staged_notice(&drift);
+                for d in &drift {
+                    prog.warn(&format!(
+                        "Keeping existing ID which differs from the new image: {d}"
+                    ))
+                    .await;
+                }
+            }
+            Err(e) => tracing::warn!("{e:#}"),
+        }
+    }
     Ok(staged)
 }
 
@@ -942,6 +1014,12 @@ fn origin_from_imageref(imgref: &ImageReference) -> Result<glib::KeyFile> {
 pub(crate) enum MergeState {
     /// Use the provided merge deployment
     MergeDeployment(Deployment),
+    /// Use the provided merge deployment, but with these kernel arguments
+    /// instead of the ones computed from it.
+    MergeDeploymentWithKargs {
+        deployment: Deployment,
+        kargs: CmdlineOwned,
+    },
     /// Don't use a merge deployment, but only this
     /// provided initial state.
     Reset {
@@ -962,12 +1040,30 @@ impl MergeState {
     /// Cast this to a merge deployment case.
     pub(crate) fn as_merge_deployment(&self) -> Option<&Deployment> {
         match self {
-            Self::MergeDeployment(d) => Some(d),
+            Self::MergeDeployment(d) | Self::MergeDeploymentWithKargs { deployment: d, .. } => {
+                Some(d)
+            }
             Self::Reset { .. } => None,
         }
     }
 }
 
+/// Start the unit which calls [`finalize_staged`] when the system shuts down.
+pub(crate) fn start_finalize_staged_svc() -> Result<()> {
+    Command::new("systemctl")
+        .args(["start", "--quiet", FINALIZE_STAGED_SERVICE])
+        .run_capture_stderr()
+        .context("Starting finalize service")
+}
+
+/// Complete the changes bootc makes to the staged deployment. Invoked at
+/// shutdown, before libostree finalizes the staged deployment.
+pub(crate) fn finalize_staged(storage: &Storage, sysroot: &Sysroot) -> Result<()> {
+    let r = crate::etcmerge::finalize_staged(sysroot);
+    crate::factory_reset::finalize_staged(storage, sysroot)?;
+    r
+}
+
 /// Stage (queue deployment of) a fetched container image.
 #[context("Staging")]
 pub(crate) async fn stage(
@@ -1034,8 +1130,10 @@ pub(crate) async fn stage(
     })
     .await;
     let origin = origin_from_imageref(spec.image)?;
+    prog.start_phase(Phase::Deploy).await;
     let deployment =
-        crate::deploy::deploy(sysroot, from, image, &origin, lock_finalization).await?;
+        crate::deploy::deploy(sysroot, from, image, &origin, lock_finalization, &prog).await?;
+    prog.complete_phase(Phase::Deploy).await;
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
@@ -1057,7 +1155,9 @@ pub(crate) async fn stage(
             .collect(),
     })
     .await;
+    prog.start_phase(Phase::BoundImages).await;
     crate::boundimage::pull_bound_images(sysroot, &deployment).await?;
+    prog.complete_phase(Phase::BoundImages).await;
 
     subtask.completed = true;
     subtasks.push(subtask.clone());
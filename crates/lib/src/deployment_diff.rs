@@ -0,0 +1,800 @@
+//! # Comparing deployments
+//!
+//! Implementation of `bootc status --diff`, which shows what changes between
+//! the booted deployment and the staged (or rollback) one: files under `/usr`,
+//! container image layers, and packages if there is an rpm database.
+//!
+//! File contents are never read; ostree compares object checksums, and for
+//! composefs we compare the fs-verity digests recorded in the image.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsStr;
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use bootc_initramfs_setup::mount_composefs_image;
+use bootc_mount::tempmount::TempMount;
+use cap_std_ext::cap_std::fs::Dir;
+use cfsctl::composefs;
+use cfsctl::composefs_boot;
+use cfsctl::composefs_oci;
+use composefs::fsverity::Sha512HashValue;
+use composefs::generic_tree::{Directory, Inode, Leaf, LeafContent, Stat};
+use composefs::tree::RegularFile;
+use composefs_boot::BootOps;
+use fn_error_context::context;
+use ostree_ext::oci_spec::image::ImageManifest;
+use ostree_ext::{container as ostree_container, ostree};
+use serde::Serialize;
+
+use crate::bootc_composefs::status::{ImgConfigManifest, get_composefs_status, get_imginfo};
+use crate::spec::{BootEntry, Host};
+use crate::status::Slot;
+use crate::store::{BootedComposefs, BootedOstree, ComposefsFilesystem, Storage};
+use crate::task::Task;
+
+/// The subdirectory we compare; everything else is either state or
+/// derived from it.
+const DIFF_SUBDIR: &str = "usr";
+
+/// Locations of the rpm database, in order of preference
+const RPMDB_PATHS: &[&str] = &["usr/lib/sysimage/rpm", "usr/share/rpm"];
+
+/// Each line is `NAME.ARCH<tab>[EPOCH:]VERSION-RELEASE`
+const RPM_QUERYFORMAT: &str = "%{NAME}.%{ARCH}\\t%|EPOCH?{%{EPOCH}:}:{}|%{VERSION}-%{RELEASE}\\n";
+
+/// A directory in a composefs image
+type ComposefsDirectory = Directory<RegularFile<Sha512HashValue>>;
+
+/// The deployment to compare the booted one with.
+#[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
+pub(crate) enum DiffTarget {
+    /// The deployment queued for the next boot
+    Staged,
+    /// The previous deployment
+    Rollback,
+}
+
+impl From<DiffTarget> for Slot {
+    fn from(target: DiffTarget) -> Self {
+        match target {
+            DiffTarget::Staged => Slot::Staged,
+            DiffTarget::Rollback => Slot::Rollback,
+        }
+    }
+}
+
+impl std::fmt::Display for DiffTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        Slot::from(*self).fmt(f)
+    }
+}
+
+/// Files which differ between two deployments. Paths are absolute,
+/// and directories have a trailing `/`.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FileChanges {
+    /// Files and directories only in the target deployment
+    pub(crate) added: Vec<String>,
+    /// Files and directories only in the booted deployment
+    pub(crate) removed: Vec<String>,
+    /// Files with different content or metadata
+    pub(crate) modified: Vec<String>,
+}
+
+/// Container image layer differences.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LayerChanges {
+    /// Number of layers in the target image
+    pub(crate) total: u64,
+    /// Size of the target image
+    pub(crate) total_size: u64,
+    /// Number of layers only in the booted image
+    pub(crate) removed: u64,
+    /// Size of the layers only in the booted image
+    pub(crate) removed_size: u64,
+    /// Number of layers only in the target image
+    pub(crate) added: u64,
+    /// Size of the layers only in the target image, i.e. what needs to be fetched
+    pub(crate) added_size: u64,
+}
+
+/// A package which is present in both deployments with a different version.
+#[derive(Debug, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackageChange {
+    /// The package name and architecture
+    pub(crate) name: String,
+    /// Version in the booted deployment
+    pub(crate) from: String,
+    /// Version in the target deployment
+    pub(crate) to: String,
+}
+
+/// Package differences, computed from the rpm database.
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct PackageChanges {
+    /// Packages only in the target deployment
+    pub(crate) added: Vec<String>,
+    /// Packages only in the booted deployment
+    pub(crate) removed: Vec<String>,
+    /// Packages with a different version
+    pub(crate) changed: Vec<PackageChange>,
+}
+
+/// The difference between the booted deployment and another one.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct DeploymentDiff {
+    /// The deployment being compared from; always `booted`
+    pub(crate) from: String,
+    /// The deployment being compared to
+    pub(crate) to: String,
+    /// Image digest of the booted deployment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) from_digest: Option<String>,
+    /// Image digest of the target deployment
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) to_digest: Option<String>,
+    /// File changes in /usr
+    pub(crate) files: FileChanges,
+    /// Layer changes, if both deployments come from container images
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) layers: Option<LayerChanges>,
+    /// Package changes, if both deployments have an rpm database
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) packages: Option<PackageChanges>,
+}
+
+impl From<ostree_ext::diff::FileTreeDiff> for FileChanges {
+    fn from(diff: ostree_ext::diff::FileTreeDiff) -> Self {
+        use ostree_ext::diff::FileSet;
+        // Paths are relative to the subdirectory, and directories are tracked separately
+        let merge = |files: FileSet, dirs: FileSet| -> Vec<String> {
+            let mut r = files
+                .into_iter()
+                .map(|p| format!("/{DIFF_SUBDIR}{p}"))
+                .chain(dirs.into_iter().map(|p| format!("/{DIFF_SUBDIR}{p}/")))
+                .collect::<Vec<_>>();
+            r.sort();
+            r
+        };
+        Self {
+            added: merge(diff.added_files, diff.added_dirs),
+            removed: merge(diff.removed_files, diff.removed_dirs),
+            modified: merge(diff.changed_files, diff.changed_dirs),
+        }
+    }
+}
+
+impl LayerChanges {
+    fn new(from: &ImageManifest, to: &ImageManifest) -> Self {
+        let diff = ostree_container::ManifestDiff::new(from, to);
+        Self {
+            total: diff.total,
+            total_size: diff.total_size,
+            removed: diff.n_removed,
+            removed_size: diff.removed_size,
+            added: diff.n_added,
+            added_size: diff.added_size,
+        }
+    }
+}
+
+/// Whether the metadata relevant for a diff is the same, including xattrs.
+fn same_stat(a: &Stat, b: &Stat) -> bool {
+    a.st_mode == b.st_mode && a.st_uid == b.st_uid && a.st_gid == b.st_gid && a.xattrs == b.xattrs
+}
+
+/// Whether two non-directory entries are the same. Regular files are
+/// compared by their fs-verity digest, or their contents if inline.
+fn same_leaf(
+    a: &Leaf<RegularFile<Sha512HashValue>>,
+    b: &Leaf<RegularFile<Sha512HashValue>>,
+) -> bool {
+    use LeafContent::*;
+    if !same_stat(&a.stat, &b.stat) {
+        return false;
+    }
+    match (&a.content, &b.content) {
+        (Regular(RegularFile::External(a, asize)), Regular(RegularFile::External(b, bsize))) => {
+            a == b && asize == bsize
+        }
+        (Regular(RegularFile::Inline(a)), Regular(RegularFile::Inline(b))) => a == b,
+        (Symlink(a), Symlink(b)) => a == b,
+        (BlockDevice(a), BlockDevice(b)) | (CharacterDevice(a), CharacterDevice(b)) => a == b,
+        (Fifo, Fifo) | (Socket, Socket) => true,
+        _ => false,
+    }
+}
+
+/// Recursively compare two composefs directory trees, appending the results
+/// to `changes`. `prefix` is the absolute path of the directories, with a
+/// trailing `/`.
+fn diff_trees(
+    from: &ComposefsDirectory,
+    to: &ComposefsDirectory,
+    prefix: &str,
+    changes: &mut FileChanges,
+) {
+    let from_entries = from.sorted_entries().collect::<BTreeMap<_, _>>();
+    let to_entries = to.sorted_entries().collect::<BTreeMap<_, _>>();
+    let names = from_entries
+        .keys()
+        .chain(to_entries.keys())
+        .copied()
+        .collect::<BTreeSet<&OsStr>>();
+
+    for name in names {
+        let path = format!("{prefix}{}", name.to_string_lossy());
+        let dirpath = |inode: &Inode<RegularFile<Sha512HashValue>>| match inode {
+            Inode::Directory(..) => format!("{path}/"),
+            Inode::Leaf(..) => path.clone(),
+        };
+
+        match (from_entries.get(name), to_entries.get(name)) {
+            (Some(Inode::Directory(from_dir)), Some(Inode::Directory(to_dir))) => {
+                if !same_stat(&from_dir.stat, &to_dir.stat) {
+                    changes.modified.push(format!("{path}/"));
+                }
+                diff_trees(from_dir, to_dir, &format!("{path}/"), changes);
+            }
+            (Some(Inode::Leaf(from_leaf)), Some(Inode::Leaf(to_leaf))) => {
+                if !same_leaf(from_leaf, to_leaf) {
+                    changes.modified.push(path);
+                }
+            }
+            // The type changed
+            (Some(_), Some(to_inode)) => changes.modified.push(dirpath(to_inode)),
+            (Some(from_inode), None) => changes.removed.push(dirpath(from_inode)),
+            (None, Some(to_inode)) => changes.added.push(dirpath(to_inode)),
+            (None, None) => {}
+        }
+    }
+}
+
+/// Compare the /usr of two composefs images.
+#[context("Comparing files")]
+fn diff_roots(from: &ComposefsDirectory, to: &ComposefsDirectory) -> Result<FileChanges> {
+    let mut changes = FileChanges::default();
+    diff_trees(
+        from.get_directory(OsStr::new(DIFF_SUBDIR))?,
+        to.get_directory(OsStr::new(DIFF_SUBDIR))?,
+        &format!("/{DIFF_SUBDIR}/"),
+        &mut changes,
+    );
+    changes.added.sort();
+    changes.removed.sort();
+    changes.modified.sort();
+    Ok(changes)
+}
+
+/// Parse the output of querying the rpm database with [`RPM_QUERYFORMAT`]
+/// into a map from `NAME.ARCH` to the installed versions; there may be more
+/// than one for e.g. kernels.
+fn parse_rpm_packages(s: &str) -> Result<BTreeMap<&str, BTreeSet<&str>>> {
+    let mut r = BTreeMap::<_, BTreeSet<_>>::new();
+    for line in s.lines().filter(|l| !l.is_empty()) {
+        let (name, evr) = line
+            .split_once('\t')
+            .ok_or_else(|| anyhow::anyhow!("Invalid package line: {line}"))?;
+        r.entry(name).or_default().insert(evr);
+    }
+    Ok(r)
+}
+
+/// Compute the package differences given the rpm database queries of the
+/// booted and target deployments.
+fn diff_packages(from: &str, to: &str) -> Result<PackageChanges> {
+    let from = parse_rpm_packages(from)?;
+    let to = parse_rpm_packages(to)?;
+    let join = |versions: &BTreeSet<&str>| versions.iter().copied().collect::<Vec<_>>().join(" ");
+    let nevras = |name: &str, versions: &BTreeSet<&str>| {
+        versions
+            .iter()
+            .map(|evr| format!("{name}-{evr}"))
+            .collect::<Vec<_>>()
+    };
+
+    let mut r = PackageChanges::default();
+    for (name, from_versions) in from.iter() {
+        match to.get(name) {
+            None => r.removed.extend(nevras(name, from_versions)),
+            Some(to_versions) if to_versions != from_versions => r.changed.push(PackageChange {
+                name: name.to_string(),
+                from: join(from_versions),
+                to: join(to_versions),
+            }),
+            Some(_) => {}
+        }
+    }
+    for (name, to_versions) in to.iter() {
+        if !from.contains_key(name) {
+            r.added.extend(nevras(name, to_versions));
+        }
+    }
+    Ok(r)
+}
+
+/// Query the rpm database of a root filesystem, if it has one.
+#[context("Querying rpm database")]
+fn query_rpmdb(root: &Dir) -> Result<Option<String>> {
+    let mut dbpath = None;
+    for p in RPMDB_PATHS {
+        if root.try_exists(p)? {
+            dbpath = Some(p);
+            break;
+        }
+    }
+    let Some(dbpath) = dbpath else {
+        return Ok(None);
+    };
+    // Run from within the root so that we don't need its absolute path
+    let out = Task::new_quiet("rpm")
+        .cwd(root)?
+        .args([
+            "--dbpath",
+            &format!("/proc/self/cwd/{dbpath}"),
+            "-qa",
+            "--queryformat",
+            RPM_QUERYFORMAT,
+        ])
+        .read()?;
+    Ok(Some(out))
+}
+
+/// Compute the package differences between two root filesystems, if both
+/// have an rpm database.
+fn diff_rpmdb(from: &Dir, to: &Dir) -> Result<Option<PackageChanges>> {
+    match (query_rpmdb(from)?, query_rpmdb(to)?) {
+        (Some(from), Some(to)) => diff_packages(&from, &to).map(Some),
+        _ => Ok(None),
+    }
+}
+
+/// Find the boot entry for the deployment to compare against.
+fn target_entry(host: &Host, target: DiffTarget) -> Result<&BootEntry> {
+    let entry = match target {
+        DiffTarget::Staged => host.status.staged.as_ref(),
+        DiffTarget::Rollback => host.status.rollback.as_ref(),
+    };
+    entry.ok_or_else(|| anyhow::anyhow!("No {target} deployment"))
+}
+
+fn image_digest(entry: &BootEntry) -> Option<String> {
+    entry.image.as_ref().map(|i| i.image_digest.clone())
+}
+
+/// Compare the booted ostree deployment with the target deployment.
+#[context("Comparing ostree deployments")]
+fn diff_ostree(booted_ostree: &BootedOstree<'_>, target: DiffTarget) -> Result<DeploymentDiff> {
+    let sysroot = booted_ostree.sysroot;
+    let repo = &booted_ostree.repo();
+    let (deployments, host) = crate::status::get_status(booted_ostree)?;
+    let from_entry = host
+        .status
+        .booted
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No booted deployment"))?;
+    let to_entry = target_entry(&host, target)?;
+    let from_deployment = &booted_ostree.deployment;
+    let to_deployment = match target {
+        DiffTarget::Staged => deployments.staged.as_ref(),
+        DiffTarget::Rollback => deployments.rollback.as_ref(),
+    }
+    .ok_or_else(|| anyhow::anyhow!("No {target} deployment"))?;
+
+    let files = ostree_ext::diff::diff(
+        repo,
+        from_deployment.csum().as_str(),
+        to_deployment.csum().as_str(),
+        Some(format!("/{DIFF_SUBDIR}")),
+    )?
+    .into();
+
+    let layers = match (from_entry.query_image(repo)?, to_entry.query_image(repo)?) {
+        (Some(from), Some(to)) => Some(LayerChanges::new(&from.manifest, &to.manifest)),
+        _ => None,
+    };
+
+    let from_root = crate::utils::deployment_fd(sysroot, from_deployment)?;
+    let to_root = crate::utils::deployment_fd(sysroot, to_deployment)?;
+    let packages = diff_rpmdb(&from_root, &to_root)?;
+
+    Ok(DeploymentDiff {
+        from: Slot::Booted.to_string(),
+        to: target.to_string(),
+        from_digest: image_digest(from_entry),
+        to_digest: image_digest(to_entry),
+        files,
+        layers,
+        packages,
+    })
+}
+
+/// Read the filesystem tree of a composefs deployment from the repository,
+/// along with its image metadata.
+#[context("Reading deployment image")]
+async fn deployment_filesystem(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    entry: &BootEntry,
+) -> Result<(ComposefsFilesystem, ImgConfigManifest)> {
+    let verity = &entry.require_composefs()?.verity;
+    let imginfo = get_imginfo(storage, verity, entry.image.as_ref().map(|i| &i.image)).await?;
+
+    let repo = &*booted_cfs.repo;
+    let config_digest = imginfo.manifest.config().digest().digest();
+    // TODO: export config_identifier function from composefs-oci/src/lib.rs and use it here
+    let config_verity = repo
+        .has_stream(&format!("oci-config-sha256:{config_digest}"))
+        .context("Checking stream")?
+        .ok_or_else(|| anyhow::anyhow!("Image config {config_digest} not found in repository"))?;
+
+    let mut fs =
+        composefs_oci::image::create_filesystem(repo, config_digest, Some(&config_verity))?;
+    fs.transform_for_boot(repo)?;
+
+    Ok((fs, imginfo))
+}
+
+/// Compare the booted composefs deployment with the target deployment.
+#[context("Comparing composefs deployments")]
+async fn diff_composefs(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    target: DiffTarget,
+) -> Result<DeploymentDiff> {
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    let from_entry = host
+        .status
+        .booted
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No booted deployment"))?;
+    let to_entry = target_entry(&host, target)?;
+
+    let (from_fs, from_img) = deployment_filesystem(storage, booted_cfs, from_entry).await?;
+    let (to_fs, to_img) = deployment_filesystem(storage, booted_cfs, to_entry).await?;
+    let files = diff_roots(&from_fs.root, &to_fs.root)?;
+    let layers = Some(LayerChanges::new(&from_img.manifest, &to_img.manifest));
+
+    // Querying the rpm database needs the actual filesystems
+    let sysroot_fd = storage.physical_root.reopen_as_ownedfd()?;
+    let allow_missing_fsverity = booted_cfs.cmdline.allow_missing_fsverity;
+    let from_mnt = TempMount::mount_fd(mount_composefs_image(
+        &sysroot_fd,
+        &from_entry.require_composefs()?.verity,
+        allow_missing_fsverity,
+    )?)?;
+    let to_mnt = TempMount::mount_fd(mount_composefs_image(
+        &sysroot_fd,
+        &to_entry.require_composefs()?.verity,
+        allow_missing_fsverity,
+    )?)?;
+    let packages = diff_rpmdb(&from_mnt.fd, &to_mnt.fd)?;
+
+    Ok(DeploymentDiff {
+        from: Slot::Booted.to_string(),
+        to: target.to_string(),
+        from_digest: image_digest(from_entry),
+        to_digest: image_digest(to_entry),
+        files,
+        layers,
+        packages,
+    })
+}
+
+/// Compare the booted deployment with the target deployment.
+pub(crate) async fn diff(target: DiffTarget) -> Result<DeploymentDiff> {
+    let storage = &crate::cli::get_storage().await?;
+    match storage.kind()? {
+        crate::store::BootedStorageKind::Ostree(booted_ostree) => {
+            diff_ostree(&booted_ostree, target)
+        }
+        crate::store::BootedStorageKind::Composefs(booted_cfs) => {
+            diff_composefs(storage, &booted_cfs, target).await
+        }
+    }
+}
+
+fn write_paths(mut out: impl Write, marker: char, paths: &[String]) -> Result<()> {
+    for path in paths {
+        writeln!(out, "  {marker} {path}")?;
+    }
+    Ok(())
+}
+
+/// Write a human readable version of the diff.
+pub(crate) fn human_readable_output(mut out: impl Write, diff: &DeploymentDiff) -> Result<()> {
+    writeln!(out, "Comparing {} and {} deployments", diff.from, diff.to)?;
+    if let (Some(from), Some(to)) = (diff.from_digest.as_deref(), diff.to_digest.as_deref()) {
+        writeln!(out, "  From: {from}")?;
+        writeln!(out, "    To: {to}")?;
+    }
+
+    if let Some(layers) = diff.layers.as_ref() {
+        let size = ostree::glib::format_size;
+        writeln!(
+            out,
+            "Layers: {} added ({}), {} removed ({}), {} total ({})",
+            layers.added,
+            size(layers.added_size),
+            layers.removed,
+            size(layers.removed_size),
+            layers.total,
+            size(layers.total_size)
+        )?;
+    }
+
+    if let Some(packages) = diff.packages.as_ref() {
+        writeln!(
+            out,
+            "Packages: {} added, {} removed, {} changed",
+            packages.added.len(),
+            packages.removed.len(),
+            packages.changed.len()
+        )?;
+        write_paths(&mut out, '+', &packages.added)?;
+        write_paths(&mut out, '-', &packages.removed)?;
+        for change in packages.changed.iter() {
+            writeln!(out, "  ~ {} {} -> {}", change.name, change.from, change.to)?;
+        }
+    }
+
+    let files = &diff.files;
+    writeln!(
+        out,
+        "Files: {} added, {} removed, {} modified",
+        files.added.len(),
+        files.removed.len(),
+        files.modified.len()
+    )?;
+    write_paths(&mut out, '+', &files.added)?;
+    write_paths(&mut out, '-', &files.removed)?;
+    write_paths(&mut out, 'M', &files.modified)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use composefs::fsverity::FsVerityHashValue;
+
+    use super::*;
+
+    #[test]
+    fn test_diff_packages() -> Result<()> {
+        let from = indoc::indoc! { "
+            bash.x86_64\t5.2.37-1.fc42
+            kernel.x86_64\t6.15.4-200.fc42
+            kernel.x86_64\t6.15.3-200.fc42
+            vim-minimal.x86_64\t2:9.1.1484-1.fc42
+            nano.x86_64\t8.3-5.fc42
+        " };
+        let to = indoc::indoc! { "
+            bash.x86_64\t5.2.37-1.fc42
+            kernel.x86_64\t6.15.5-200.fc42
+            vim-minimal.x86_64\t2:9.1.1500-1.fc42
+            tmux.x86_64\t3.5a-3.fc42
+        " };
+        let diff = diff_packages(from, to)?;
+        similar_asserts::assert_eq!(
+            diff,
+            PackageChanges {
+                added: vec!["tmux.x86_64-3.5a-3.fc42".into()],
+                removed: vec!["nano.x86_64-8.3-5.fc42".into()],
+                changed: vec![
+                    PackageChange {
+                        name: "kernel.x86_64".into(),
+                        from: "6.15.3-200.fc42 6.15.4-200.fc42".into(),
+                        to: "6.15.5-200.fc42".into(),
+                    },
+                    PackageChange {
+                        name: "vim-minimal.x86_64".into(),
+                        from: "2:9.1.1484-1.fc42".into(),
+                        to: "2:9.1.1500-1.fc42".into(),
+                    },
+                ],
+            }
+        );
+
+        assert_eq!(diff_packages(from, from)?, PackageChanges::default());
+        assert!(diff_packages("bash.x86_64 5.2.37-1.fc42", "").is_err());
+        Ok(())
+    }
+
+    fn stat(mode: u32) -> Stat {
+        Stat {
+            st_mode: mode,
+            st_uid: 0,
+            st_gid: 0,
+            st_mtim_sec: 0,
+            xattrs: Default::default(),
+        }
+    }
+
+    fn leaf(
+        dir: &mut ComposefsDirectory,
+        name: &str,
+        stat: Stat,
+        content: LeafContent<RegularFile<Sha512HashValue>>,
+    ) {
+        dir.insert(
+            OsStr::new(name),
+            Inode::Leaf(Rc::new(Leaf { stat, content })),
+        );
+    }
+
+    fn external(digest: char) -> Result<LeafContent<RegularFile<Sha512HashValue>>> {
+        let id = Sha512HashValue::from_hex(digest.to_string().repeat(128))?;
+        Ok(LeafContent::Regular(RegularFile::External(id, 4096)))
+    }
+
+    fn file(dir: &mut ComposefsDirectory, name: &str, digest: char) -> Result<()> {
+        leaf(dir, name, stat(0o644), external(digest)?);
+        Ok(())
+    }
+
+    fn symlink(dir: &mut ComposefsDirectory, name: &str, target: &str) {
+        let content = LeafContent::Symlink(OsStr::new(target).into());
+        leaf(dir, name, stat(0o777), content);
+    }
+
+    /// Build a root with /usr/bin and /usr/share/doc, returning `bin`.
+    fn root(bin: ComposefsDirectory, share: ComposefsDirectory) -> ComposefsDirectory {
+        let mut usr = Directory::new(stat(0o755));
+        usr.insert(OsStr::new("bin"), Inode::Directory(Box::new(bin)));
+        usr.insert(OsStr::new("share"), Inode::Directory(Box::new(share)));
+        let mut root = Directory::new(stat(0o755));
+        root.insert(OsStr::new("usr"), Inode::Directory(Box::new(usr)));
+        root
+    }
+
+    #[test]
+    fn test_diff_roots() -> Result<()> {
+        let mut from_bin = Directory::new(stat(0o755));
+        let mut to_bin = Directory::new(stat(0o755));
+        for bin in [&mut from_bin, &mut to_bin] {
+            file(bin, "same", 'a')?;
+            symlink(bin, "link", "same");
+        }
+        file(&mut from_bin, "changed", 'b')?;
+        file(&mut to_bin, "changed", 'c')?;
+        file(&mut from_bin, "removed", 'a')?;
+        file(&mut to_bin, "added", 'a')?;
+        symlink(&mut from_bin, "relinked", "same");
+        symlink(&mut to_bin, "relinked", "changed");
+        // Same content, but a changed xattr
+        file(&mut from_bin, "relabeled", 'a')?;
+        let relabeled = stat(0o644);
+        relabeled.xattrs.borrow_mut().insert(
+            OsStr::new("security.selinux").into(),
+            (*b"system_u:object_r:bin_t:s0").into(),
+        );
+        leaf(&mut to_bin, "relabeled", relabeled, external('a')?);
+
+        let mut from_share = Directory::new(stat(0o755));
+        let mut doc = Directory::new(stat(0o755));
+        doc.insert(
+            OsStr::new("removed"),
+            Inode::Directory(Box::new(Directory::new(stat(0o755)))),
+        );
+        from_share.insert(OsStr::new("doc"), Inode::Directory(Box::new(doc)));
+        let mut to_share = Directory::new(stat(0o755));
+        to_share.insert(
+            OsStr::new("doc"),
+            Inode::Directory(Box::new(Directory::new(stat(0o700)))),
+        );
+
+        let from = root(from_bin, from_share);
+        let to = root(to_bin, to_share);
+
+        let diff = diff_roots(&from, &to)?;
+        similar_asserts::assert_eq!(
+            diff,
+            FileChanges {
+                added: vec!["/usr/bin/added".into()],
+                removed: vec!["/usr/bin/removed".into(), "/usr/share/doc/removed/".into()],
+                modified: vec![
+                    "/usr/bin/changed".into(),
+                    "/usr/bin/relabeled".into(),
+                    "/usr/bin/relinked".into(),
+                    "/usr/share/doc/".into(),
+                ],
+            }
+        );
+
+        assert_eq!(diff_roots(&from, &from)?, FileChanges::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_changes_from_ostree() {
+        let diff = ostree_ext::diff::FileTreeDiff {
+            subdir: Some("/usr".into()),
+            added_files: ["/bin/foo".to_owned()].into(),
+            added_dirs: ["/lib/foo".to_owned()].into(),
+            removed_files: ["/bin/bar".to_owned()].into(),
+            changed_dirs: ["/share".to_owned()].into(),
+            changed_files: ["/bin/baz".to_owned()].into(),
+            ..Default::default()
+        };
+        similar_asserts::assert_eq!(
+            FileChanges::from(diff),
+            FileChanges {
+                added: vec!["/usr/bin/foo".into(), "/usr/lib/foo/".into()],
+                removed: vec!["/usr/bin/bar".into()],
+                modified: vec!["/usr/bin/baz".into(), "/usr/share/".into()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_human_readable_output() -> Result<()> {
+        let mut diff = DeploymentDiff {
+            from: "booted".into(),
+            to: "staged".into(),
+            from_digest: Some("sha256:1111".into()),
+            to_digest: Some("sha256:2222".into()),
+            files: FileChanges {
+                added: vec!["/usr/bin/foo".into(), "/usr/lib/foo/".into()],
+                removed: vec!["/usr/bin/bar".into()],
+                modified: vec!["/usr/bin/baz".into()],
+            },
+            layers: Some(LayerChanges {
+                total: 10,
+                total_size: 500_000_000,
+                removed: 1,
+                removed_size: 1_500_000,
+                added: 2,
+                added_size: 2_000_000,
+            }),
+            packages: Some(PackageChanges {
+                added: vec!["tmux.x86_64-3.5a-3.fc42".into()],
+                removed: vec![],
+                changed: vec![PackageChange {
+                    name: "kernel.x86_64".into(),
+                    from: "6.15.4-200.fc42".into(),
+                    to: "6.15.5-200.fc42".into(),
+                }],
+            }),
+        };
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &diff)?;
+        similar_asserts::assert_eq!(
+            String::from_utf8(w)?,
+            indoc::indoc! { "
+                Comparing booted and staged deployments
+                  From: sha256:1111
+                    To: sha256:2222
+                Layers: 2 added (2.0 MB), 1 removed (1.5 MB), 10 total (500.0 MB)
+                Packages: 1 added, 0 removed, 1 changed
+                  + tmux.x86_64-3.5a-3.fc42
+                  ~ kernel.x86_64 6.15.4-200.fc42 -> 6.15.5-200.fc42
+                Files: 2 added, 1 removed, 1 modified
+                  + /usr/bin/foo
+                  + /usr/lib/foo/
+                  - /usr/bin/bar
+                  M /usr/bin/baz
+            " }
+        );
+
+        // Without image or package information, only files are shown
+        diff.from_digest = None;
+        diff.layers = None;
+        diff.packages = None;
+        diff.files = FileChanges::default();
+        let mut w = Vec::new();
+        human_readable_output(&mut w, &diff)?;
+        similar_asserts::assert_eq!(
+            String::from_utf8(w)?,
+            "Comparing booted and staged deployments\nFiles: 0 added, 0 removed, 0 modified\n"
+        );
+        Ok(())
+    }
+}
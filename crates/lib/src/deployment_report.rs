@@ -0,0 +1,70 @@
+//! # Per-deployment reports
+//!
+//! Some information about a deployment is computed when it is staged and shown
+//! later by `bootc status`, such as the conflicts of the native `/etc` merge.
+//! Each kind of report lives in its own directory of the physical root, with
+//! one JSON file per deployment.
+
+use anyhow::{Context, Result};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use ostree_ext::ostree;
+use ostree_ext::ostree::Deployment;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+/// The file name of the report for a deployment.
+fn report_name(deployment: &Deployment) -> String {
+    format!("{}.{}.json", deployment.csum(), deployment.deployserial())
+}
+
+/// Persist the report in `dir` for `deployment`, and remove the reports of
+/// deployments which no longer exist. An empty report is not stored.
+pub(crate) fn write<T: Serialize>(
+    sysroot: &ostree::Sysroot,
+    dir: &str,
+    deployment: &Deployment,
+    entries: &[T],
+) -> Result<()> {
+    let physical_root = crate::utils::sysroot_dir(sysroot)?;
+    physical_root.create_dir_all(dir)?;
+    let reports = physical_root.open_dir(dir)?;
+
+    let current = sysroot
+        .deployments()
+        .iter()
+        .map(report_name)
+        .collect::<Vec<_>>();
+    for entry in reports.entries_utf8()? {
+        let name = entry?.file_name()?;
+        if !current.contains(&name) {
+            reports.remove_file_optional(&name)?;
+        }
+    }
+
+    let name = report_name(deployment);
+    if entries.is_empty() {
+        reports.remove_file_optional(&name)?;
+        return Ok(());
+    }
+    reports
+        .atomic_write(name, serde_json::to_vec(entries)?)
+        .context("Writing report")
+}
+
+/// Load the report in `dir` for `deployment`; a missing report is empty.
+pub(crate) fn read<T: DeserializeOwned>(
+    sysroot: &ostree::Sysroot,
+    dir: &str,
+    deployment: &Deployment,
+) -> Result<Vec<T>> {
+    let physical_root = crate::utils::sysroot_dir(sysroot)?;
+    let Some(reports) = physical_root.open_dir_optional(dir)? else {
+        return Ok(Vec::new());
+    };
+    let name = report_name(deployment);
+    reports
+        .read_to_string_optional(&name)?
+        .map(|s| serde_json::from_str(&s).with_context(|| format!("Parsing {name}")))
+        .transpose()
+        .map(Option::unwrap_or_default)
+}
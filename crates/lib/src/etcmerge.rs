@@ -0,0 +1,614 @@
+//! # Native /etc merge
+//!
+//! By default libostree merges `/etc` for a new deployment. Images can opt in
+//! to having bootc perform the merge itself when staging, using the same
+//! [`etc_merge`] implementation as the composefs backend. This also records a
+//! report of the local changes to `/etc` which conflict with changes in the new
+//! image, which is shown by `bootc status`.
+//!
+//! As libostree then no longer merges `/etc` when finalizing the staged
+//! deployment, `/etc` is merged again at shutdown if it was changed after the
+//! update was staged; see [`finalize_staged`].
+//!
+//! The opt-in and the policies used to resolve conflicts (for both backends)
+//! are configured in `/usr/lib/bootc/etc-merge.d/*.toml`:
+//!
+//! ```toml
+//! [etc-merge]
+//! native = true
+//!
+//! [etc-merge.policy]
+//! "chrony.conf" = "take-upstream"
+//! "sysconfig" = "merge-lines"
+//! "sudoers.d" = "fail"
+//! ```
+
+use std::collections::BTreeMap;
+use std::ops::ControlFlow;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{Context, Result, anyhow};
+use bootc_utils::CommandRunExt;
+use camino::Utf8Path;
+use cap_std_ext::cap_std::fs::{Dir, MetadataExt};
+use cap_std_ext::cmdext::CapStdExtCommandExt;
+use cap_std_ext::dirext::{CapStdExtDirExt, WalkConfiguration};
+use etc_merge::{
+    ModificationType, Policy, Resolution, check_fail_policies, compute_conflicts, compute_diff,
+    merge, resolve_conflicts, traverse_etc,
+};
+use fn_error_context::context;
+use ostree::gio;
+use ostree_ext::ostree;
+use ostree_ext::ostree::Deployment;
+use ostree_ext::prelude::{Cast, FileEnumeratorExt, FileExt};
+use rustix::fs::{RenameFlags, renameat_with};
+use serde::{Deserialize, Serialize};
+
+use crate::spec::{EtcChange, EtcMergeConflict, EtcMergeResolution};
+
+/// The relative path to the configuration which may be embedded in an image.
+const CONFIG_PATH: &str = "usr/lib/bootc/etc-merge.d";
+
+/// Where conflict reports are stored, relative to the physical root.
+const REPORT_DIR: &str = "ostree/bootc/etc-merge";
+
+/// Where the state of the merge for the staged deployment is stored, relative
+/// to the physical root.
+const STAGED_STATE_PATH: &str = "ostree/bootc/etc-merge-staged.json";
+
+/// The name of the new /etc in the staged deployment while merging it again.
+const REMERGE_TMP: &str = "etc.bootc-merge";
+
+/// The native merge of the staged deployment, as `<checksum>.<serial>`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct StagedMerge {
+    /// The staged deployment
+    staged: String,
+    /// The deployment whose /etc was merged
+    merge: String,
+    /// The [`etc_fingerprint`] of the merged /etc, from before the merge
+    fingerprint: String,
+}
+
+/// The root of an etc-merge.d configuration file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct ConfigRoot {
+    etc_merge: Option<Config>,
+}
+
+/// What to do with a path changed both locally and in the image.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum PolicyConfig {
+    /// Keep the local version; this is the default
+    KeepLocal,
+    /// Discard the local change
+    TakeUpstream,
+    /// Merge `key=value` lines
+    MergeLines,
+    /// Fail the update
+    Fail,
+}
+
+impl From<PolicyConfig> for Policy {
+    fn from(value: PolicyConfig) -> Self {
+        match value {
+            PolicyConfig::KeepLocal => Policy::KeepLocal,
+            PolicyConfig::TakeUpstream => Policy::TakeUpstream,
+            PolicyConfig::MergeLines => Policy::MergeLines,
+            PolicyConfig::Fail => Policy::Fail,
+        }
+    }
+}
+
+/// The `[etc-merge]` table.
+#[derive(Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Config {
+    /// Use the native merge instead of the one from libostree.
+    native: Option<bool>,
+    /// Conflict resolution policies, keyed by a path relative to /etc. A
+    /// policy also applies to everything below the path.
+    #[serde(default)]
+    policy: BTreeMap<String, PolicyConfig>,
+}
+
+impl Config {
+    /// Return true if the filename is one we should parse.
+    fn filename_matches(name: &str) -> bool {
+        matches!(Utf8Path::new(name).extension(), Some("toml"))
+    }
+
+    /// Apply the values set in `other`, which takes precedence.
+    fn merge(&mut self, other: Config) {
+        if other.native.is_some() {
+            self.native = other.native;
+        }
+        self.policy.extend(other.policy);
+    }
+
+    /// Parse and combine configuration files; `files` must be sorted by name.
+    fn parse<'a>(files: impl IntoIterator<Item = (&'a str, &'a str)>) -> Result<Self> {
+        let mut r = Config::default();
+        for (name, contents) in files {
+            let root: ConfigRoot =
+                toml::from_str(contents).with_context(|| format!("Parsing {name}"))?;
+            if let Some(config) = root.etc_merge {
+                if let Some(path) = config.policy.keys().find(|p| p.starts_with('/')) {
+                    anyhow::bail!("Parsing {name}: policy path {path} must be relative to /etc");
+                }
+                r.merge(config);
+            }
+        }
+        Ok(r)
+    }
+
+    /// The policy for a path relative to /etc, from its most specific entry.
+    fn policy_for(&self, path: &Path) -> Policy {
+        self.policy
+            .iter()
+            .map(|(prefix, policy)| (Path::new(prefix), policy))
+            .filter(|(prefix, _)| path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .map(|(_, policy)| (*policy).into())
+            .unwrap_or_default()
+    }
+}
+
+/// Load the etc-merge.d configuration from a root filesystem.
+#[context("Loading etc-merge configuration")]
+pub(crate) fn config_from_root(root: &Dir) -> Result<Config> {
+    let Some(configd) = root.open_dir_optional(CONFIG_PATH)? else {
+        return Ok(Config::default());
+    };
+
+    let mut files = Vec::new();
+    for entry in configd.entries_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name()?;
+        if !Config::filename_matches(&name) || !entry.file_type()?.is_file() {
+            continue;
+        }
+        let contents = configd.read_to_string(&name)?;
+        files.push((name, contents));
+    }
+    files.sort();
+
+    Config::parse(files.iter().map(|(n, c)| (n.as_str(), c.as_str())))
+}
+
+/// Load the etc-merge.d configuration from an ostree commit.
+#[context("Loading etc-merge configuration")]
+fn config_from_commit(repo: &ostree::Repo, commit: &str) -> Result<Config> {
+    let cancellable = gio::Cancellable::NONE;
+    let (root, _) = repo.read_commit(commit, cancellable)?;
+    let configd = root.resolve_relative_path(CONFIG_PATH);
+    let configd = configd.downcast::<ostree::RepoFile>().expect("downcast");
+    if !configd.query_exists(cancellable) {
+        return Ok(Config::default());
+    }
+
+    let queryattrs = "standard::name,standard::type";
+    let queryflags = gio::FileQueryInfoFlags::NOFOLLOW_SYMLINKS;
+    let iter = configd.enumerate_children(queryattrs, queryflags, cancellable)?;
+    let mut files = Vec::new();
+    while let Some(info) = iter.next_file(cancellable)? {
+        let name = info.name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        if !Config::filename_matches(name) {
+            continue;
+        }
+
+        let child = iter.child(&info);
+        let child = child.downcast::<ostree::RepoFile>().expect("downcast");
+        child.ensure_resolved()?;
+        let (contents, _, _) = repo.load_file(child.checksum().as_str(), cancellable)?;
+        let mut reader = ostree_ext::prelude::InputStreamExtManual::into_read(
+            contents.ok_or_else(|| anyhow::anyhow!("{name} is not a regular file"))?,
+        );
+        files.push((name.to_owned(), std::io::read_to_string(&mut reader)?));
+    }
+    files.sort();
+
+    Config::parse(files.iter().map(|(n, c)| (n.as_str(), c.as_str())))
+}
+
+/// Whether the image with the given commit opted in to the native /etc merge.
+pub(crate) fn native_enabled(repo: &ostree::Repo, commit: &str) -> Result<bool> {
+    Ok(config_from_commit(repo, commit)?.native.unwrap_or_default())
+}
+
+impl From<ModificationType> for EtcChange {
+    fn from(value: ModificationType) -> Self {
+        match value {
+            ModificationType::Added => EtcChange::Added,
+            ModificationType::Modified => EtcChange::Modified,
+            ModificationType::Removed => EtcChange::Removed,
+        }
+    }
+}
+
+impl From<Resolution> for EtcMergeResolution {
+    fn from(value: Resolution) -> Self {
+        match value {
+            Resolution::KeepLocal => EtcMergeResolution::KeepLocal,
+            Resolution::TakeUpstream => EtcMergeResolution::TakeUpstream,
+            Resolution::MergeLines => EtcMergeResolution::MergeLines,
+        }
+    }
+}
+
+/// Merge the local changes from `current_etc` into `new_etc`, which starts out
+/// as a copy of `new_pristine_etc`. Changes which conflict with the new image are
+/// resolved as configured in `config`, and the conflicts are returned.
+#[context("Merging /etc")]
+pub(crate) fn merge_etc_dirs(
+    pristine_etc: &Dir,
+    current_etc: &Dir,
+    new_pristine_etc: &Dir,
+    new_etc: &Dir,
+    config: &Config,
+) -> Result<Vec<EtcMergeConflict>> {
+    let (pristine_files, current_files, new_files) =
+        traverse_etc(pristine_etc, current_etc, Some(new_pristine_etc))?;
+    let new_files = new_files.expect("new /etc was traversed");
+
+    let mut diff = compute_diff(&pristine_files, &current_files, &new_files)?;
+    let mut conflicts = compute_conflicts(&pristine_files, &new_files, &diff);
+    resolve_conflicts(
+        pristine_etc,
+        current_etc,
+        new_etc,
+        &mut diff,
+        &mut conflicts,
+        |path| config.policy_for(path),
+    )?;
+    merge(current_etc, &current_files, new_etc, &new_files, &diff)?;
+
+    Ok(conflicts
+        .into_iter()
+        .map(|c| EtcMergeConflict {
+            path: c.path.to_string_lossy().into_owned(),
+            local_change: c.local.into(),
+            resolution: c.resolution.into(),
+        })
+        .collect())
+}
+
+/// Check that merging the local changes from `current_etc` with `new_pristine_etc`
+/// would not run into a `fail` policy in `config`, without writing anything.
+#[context("Checking /etc merge policies")]
+pub(crate) fn check_etc_dirs(
+    pristine_etc: &Dir,
+    current_etc: &Dir,
+    new_pristine_etc: &Dir,
+    config: &Config,
+) -> Result<()> {
+    let (pristine_files, current_files, new_files) =
+        traverse_etc(pristine_etc, current_etc, Some(new_pristine_etc))?;
+    let new_files = new_files.expect("new /etc was traversed");
+
+    let diff = compute_diff(&pristine_files, &current_files, &new_files)?;
+    let conflicts = compute_conflicts(&pristine_files, &new_files, &diff);
+    check_fail_policies(&conflicts, |path| config.policy_for(path))
+}
+
+/// Merge the /etc of `merge_deployment` into `new_etc` of the newly staged
+/// `deployment`, and return the conflicts.
+fn merge_etc(
+    sysroot: &ostree::Sysroot,
+    merge_deployment: &Deployment,
+    deployment: &Deployment,
+    new_etc: &str,
+) -> Result<Vec<EtcMergeConflict>> {
+    let merge_root = crate::utils::deployment_fd(sysroot, merge_deployment)?;
+    let new_root = crate::utils::deployment_fd(sysroot, deployment)?;
+
+    let pristine_etc = merge_root
+        .open_dir("usr/etc")
+        .context("Opening pristine /etc")?;
+    let current_etc = merge_root.open_dir("etc").context("Opening current /etc")?;
+    let new_pristine_etc = new_root
+        .open_dir("usr/etc")
+        .context("Opening new /usr/etc")?;
+    let new_etc = new_root.open_dir(new_etc).context("Opening new /etc")?;
+
+    let config = config_from_root(&new_root)?;
+    merge_etc_dirs(
+        &pristine_etc,
+        &current_etc,
+        &new_pristine_etc,
+        &new_etc,
+        &config,
+    )
+}
+
+/// Persist the conflict report for `deployment`.
+#[context("Writing /etc merge report")]
+fn write_report(
+    sysroot: &ostree::Sysroot,
+    deployment: &Deployment,
+    conflicts: &[EtcMergeConflict],
+) -> Result<()> {
+    crate::deployment_report::write(sysroot, REPORT_DIR, deployment, conflicts)
+}
+
+/// Load the conflict report for a deployment, if any.
+#[context("Reading /etc merge report")]
+pub(crate) fn read_report(
+    sysroot: &ostree::Sysroot,
+    deployment: &Deployment,
+) -> Result<Vec<EtcMergeConflict>> {
+    crate::deployment_report::read(sysroot, REPORT_DIR, deployment)
+}
+
+/// Log the conflicts found while merging /etc.
+pub(crate) fn log_conflicts(conflicts: &[EtcMergeConflict]) {
+    for conflict in conflicts.iter() {
+        tracing::debug!(
+            "Local change to /etc/{} conflicts with the image: {:?}",
+            conflict.path,
+            conflict.resolution
+        );
+    }
+    if !conflicts.is_empty() {
+        println!(
+            "Local changes to /etc conflict with {} changes in the new image",
+            conflicts.len()
+        );
+    }
+}
+
+/// The name of a deployment directory, which identifies it.
+fn deployment_name(deployment: &Deployment) -> String {
+    format!("{}.{}", deployment.csum(), deployment.deployserial())
+}
+
+/// A digest of the metadata of everything below `etc`, which changes when
+/// anything is added, removed or modified.
+#[context("Computing /etc fingerprint")]
+fn etc_fingerprint(etc: &Dir) -> Result<String> {
+    let mut entries = BTreeMap::<PathBuf, _>::new();
+    etc.walk::<_, anyhow::Error>(&WalkConfiguration::default().noxdev(), |component| {
+        let metadata = component.entry.metadata()?;
+        entries.insert(
+            component.path.into(),
+            (metadata.ino(), metadata.ctime(), metadata.ctime_nsec()),
+        );
+        Ok(ControlFlow::Continue(()))
+    })?;
+
+    let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+    for (path, (ino, ctime, ctime_nsec)) in entries {
+        hasher.update(path.as_os_str().as_bytes())?;
+        hasher.update(format!("\0{ino} {ctime}.{ctime_nsec}\n").as_bytes())?;
+    }
+    Ok(hex::encode(hasher.finish()?))
+}
+
+/// Perform the native /etc merge for a newly staged deployment, and record
+/// the conflicts.
+pub(crate) fn merge_staged(
+    sysroot: &ostree::Sysroot,
+    merge_deployment: &Deployment,
+    deployment: &Deployment,
+) -> Result<()> {
+    let merge_root = crate::utils::deployment_fd(sysroot, merge_deployment)?;
+    let state = StagedMerge {
+        staged: deployment_name(deployment),
+        merge: deployment_name(merge_deployment),
+        fingerprint: etc_fingerprint(&merge_root.open_dir("etc")?)?,
+    };
+
+    let conflicts = merge_etc(sysroot, merge_deployment, deployment, "etc")?;
+    log_conflicts(&conflicts);
+    write_report(sysroot, deployment, &conflicts)?;
+
+    crate::utils::sysroot_dir(sysroot)?
+        .atomic_write(STAGED_STATE_PATH, serde_json::to_vec(&state)?)
+        .context("Writing staged merge state")?;
+    crate::deploy::start_finalize_staged_svc()
+}
+
+/// Merge /etc of the staged deployment again from scratch, replacing the
+/// result of the merge done when staging only on success.
+fn remerge_staged(
+    sysroot: &ostree::Sysroot,
+    merge_deployment: &Deployment,
+    deployment: &Deployment,
+) -> Result<Vec<EtcMergeConflict>> {
+    let new_root = crate::utils::deployment_fd(sysroot, deployment)?;
+    new_root.remove_all_optional(REMERGE_TMP)?;
+    Command::new("cp")
+        .args(["-a", "usr/etc", REMERGE_TMP])
+        .cwd_dir(new_root.try_clone()?)
+        .run_capture_stderr()
+        .context("Copying new /usr/etc")?;
+
+    let conflicts = match merge_etc(sysroot, merge_deployment, deployment, REMERGE_TMP) {
+        Ok(conflicts) => conflicts,
+        Err(e) => {
+            new_root.remove_all_optional(REMERGE_TMP)?;
+            return Err(e);
+        }
+    };
+    renameat_with(
+        &new_root,
+        REMERGE_TMP,
+        &new_root,
+        "etc",
+        RenameFlags::EXCHANGE,
+    )
+    .context("Replacing /etc")?;
+    new_root.remove_all_optional(REMERGE_TMP)?;
+    Ok(conflicts)
+}
+
+/// Merge /etc again if it was changed after the update was staged, as
+/// libostree does not merge it when finalizing the staged deployment. If
+/// this fails, the staged deployment keeps /etc as merged when staging.
+///
+/// Invoked at shutdown, before libostree finalizes the staged deployment.
+#[context("Finalizing native /etc merge")]
+pub(crate) fn finalize_staged(sysroot: &ostree::Sysroot) -> Result<()> {
+    let physical_root = crate::utils::sysroot_dir(sysroot)?;
+    let Some(state) = physical_root.read_to_string_optional(STAGED_STATE_PATH)? else {
+        return Ok(());
+    };
+    let state: StagedMerge = serde_json::from_str(&state).context("Parsing staged merge state")?;
+    let Some(staged) = sysroot
+        .staged_deployment()
+        .filter(|d| deployment_name(d) == state.staged)
+    else {
+        tracing::debug!("Staged deployment {} not found", state.staged);
+        physical_root.remove_file_optional(STAGED_STATE_PATH)?;
+        return Ok(());
+    };
+    let deployments = sysroot.deployments();
+    let merge_deployment = deployments
+        .iter()
+        .find(|d| deployment_name(d) == state.merge)
+        .ok_or_else(|| anyhow!("Merge deployment {} not found", state.merge))?;
+
+    let merge_root = crate::utils::deployment_fd(sysroot, merge_deployment)?;
+    if etc_fingerprint(&merge_root.open_dir("etc")?)? == state.fingerprint {
+        tracing::debug!("/etc is unchanged since staging");
+        return Ok(());
+    }
+
+    println!("/etc was changed after the update was staged; merging it again");
+    // The deployment root is immutable
+    let dirpath = sysroot.deployment_dirpath(&staged);
+    let dirpath = Utf8Path::new(dirpath.as_str());
+    crate::utils::remove_immutability(&physical_root, dirpath)?;
+    let r = remerge_staged(sysroot, merge_deployment, &staged);
+    Command::new("chattr")
+        .args(["+i", dirpath.as_str()])
+        .cwd_dir(physical_root.try_clone()?)
+        .run_capture_stderr()
+        .with_context(|| format!("Making {dirpath} immutable"))?;
+    let conflicts = match r {
+        Ok(conflicts) => conflicts,
+        Err(e) => {
+            eprintln!(
+                "warning: Changes made to /etc after the update was staged are not included: {e:#}"
+            );
+            return Err(e);
+        }
+    };
+    log_conflicts(&conflicts);
+    write_report(sysroot, &staged, &conflicts)?;
+    physical_root.remove_file_optional(STAGED_STATE_PATH)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() -> Result<()> {
+        let cases: &[(&[(&str, &str)], Option<bool>)] = &[
+            (&[], None),
+            (&[("10-empty.toml", "")], None),
+            (
+                &[("10-native.toml", "[etc-merge]\nnative = true\n")],
+                Some(true),
+            ),
+            (
+                &[
+                    ("10-native.toml", "[etc-merge]\nnative = true\n"),
+                    ("20-other.toml", "[etc-merge]\n"),
+                ],
+                Some(true),
+            ),
+            (
+                &[
+                    ("10-native.toml", "[etc-merge]\nnative = true\n"),
+                    ("20-disable.toml", "[etc-merge]\nnative = false\n"),
+                ],
+                Some(false),
+            ),
+        ];
+        for (files, expected) in cases {
+            let config = Config::parse(files.iter().copied())?;
+            assert_eq!(config.native, *expected, "{files:?}");
+        }
+
+        assert!(Config::parse([("10-bad.toml", "[etc-merge]\nunknown = 1\n")]).is_err());
+        assert!(Config::parse([("10-bad.toml", "[etc-merge.policy]\nfoo = \"bad\"\n")]).is_err());
+        assert!(
+            Config::parse([(
+                "10-bad.toml",
+                "[etc-merge.policy]\n\"/etc/foo\" = \"fail\"\n"
+            )])
+            .is_err()
+        );
+        assert!(Config::filename_matches("10-native.toml"));
+        assert!(!Config::filename_matches("10-native.toml.bak"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_etc_fingerprint() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let etc = Dir::open_ambient_dir(td.path(), cap_std_ext::cap_std::ambient_authority())?;
+        etc.create_dir_all("ssh/sshd_config.d")?;
+        etc.write("ssh/sshd_config", "Port 22\n")?;
+        etc.write("hostname", "host\n")?;
+
+        let initial = etc_fingerprint(&etc)?;
+        assert_eq!(etc_fingerprint(&etc)?, initial);
+
+        etc.write("ssh/sshd_config.d/50-local.conf", "Port 2222\n")?;
+        let added = etc_fingerprint(&etc)?;
+        assert_ne!(added, initial);
+
+        etc.remove_file("ssh/sshd_config.d/50-local.conf")?;
+        etc.rename("hostname", &etc, "hostname.bak")?;
+        assert_ne!(etc_fingerprint(&etc)?, initial);
+        assert_ne!(etc_fingerprint(&etc)?, added);
+        Ok(())
+    }
+
+    #[test]
+    fn test_policy_for() -> Result<()> {
+        let config = Config::parse([
+            (
+                "10-base.toml",
+                indoc::indoc! { r#"
+                    [etc-merge.policy]
+                    "ssh" = "fail"
+                    "sysconfig" = "merge-lines"
+                "# },
+            ),
+            (
+                "20-override.toml",
+                indoc::indoc! { r#"
+                    [etc-merge.policy]
+                    "ssh/ssh_config.d" = "take-upstream"
+                    "sysconfig" = "keep-local"
+                "# },
+            ),
+        ])?;
+        let cases = [
+            ("chrony.conf", Policy::KeepLocal),
+            ("ssh", Policy::Fail),
+            ("ssh/sshd_config", Policy::Fail),
+            ("ssh/ssh_config.d/50-local.conf", Policy::TakeUpstream),
+            ("sshd", Policy::KeepLocal),
+            ("sysconfig/network", Policy::KeepLocal),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(config.policy_for(Path::new(path)), expected, "{path}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,433 @@
+//! # Factory reset
+//!
+//! Implementation of `bootc state factory-reset`, which queues a fresh state
+//! for the booted image: a pristine `/etc` from the image and an empty `/var`,
+//! which is populated by `systemd-tmpfiles` at boot. Paths in `/etc` and `/var`
+//! can be carried over to the new state, e.g. SSH host keys.
+//!
+//! The state from before the reset is kept until it is explicitly discarded,
+//! so that the reset can be reverted.
+//!
+//! With ostree, the fresh state is a new stateroot; the booted deployment is
+//! pinned so that it stays available. With composefs, the state of the booted
+//! deployment is swapped out when the system shuts down, see
+//! [`crate::bootc_composefs::factory_reset`]. With both, the paths to keep are
+//! copied when the system shuts down, so that later changes to them are kept.
+
+use std::process::Command;
+
+use anyhow::{Context, Result, anyhow};
+use bootc_kernel_cmdline::utf8::{CmdlineOwned, ParameterKey};
+use bootc_utils::CommandRunExt;
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::{self, fs::Dir};
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+use ostree_ext::ostree::{self, Deployment, gio};
+use ostree_ext::sysroot::{allocate_new_stateroot, list_stateroots};
+use serde::{Deserialize, Serialize};
+
+use crate::bootc_composefs::factory_reset::{
+    composefs_discard_factory_reset, composefs_factory_reset, composefs_revert_factory_reset,
+};
+use crate::deploy::{ImageState, MergeState, RequiredHostSpec};
+use crate::progress_jsonl::ProgressWriter;
+use crate::store::{BootedOstree, BootedStorageKind, Storage};
+
+/// Where the pending factory reset is recorded, relative to the physical root.
+const RECORD_PATH: &str = "ostree/bootc/factory-reset.json";
+
+/// A factory reset of an ostree system.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OstreeFactoryReset {
+    /// The stateroot holding the fresh state
+    stateroot: String,
+    /// The stateroot in use before the reset
+    previous_stateroot: String,
+    /// The deployment which was pinned to keep it available, as `<checksum>.<serial>`
+    pinned: Option<String>,
+    /// The paths to copy into the staged deployment when it is finalized
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keep: Vec<Utf8PathBuf>,
+}
+
+/// A path to carry over to the new state.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct KeepPath<'a> {
+    /// Either `etc` or `var`
+    pub(crate) root: &'static str,
+    /// The path relative to `root`
+    pub(crate) path: &'a Utf8Path,
+}
+
+impl<'a> KeepPath<'a> {
+    /// Validate a path given on the command line, which must be an absolute
+    /// path strictly below `/etc` or `/var`.
+    pub(crate) fn parse(path: &'a Utf8Path) -> Result<Self> {
+        let mut components = path.components();
+        let root = match (components.next(), components.next()) {
+            (Some(Utf8Component::RootDir), Some(Utf8Component::Normal("etc"))) => "etc",
+            (Some(Utf8Component::RootDir), Some(Utf8Component::Normal("var"))) => "var",
+            _ => anyhow::bail!("Can only keep absolute paths in /etc or /var: {path}"),
+        };
+        let rest = components.as_path();
+        if rest.as_str().is_empty() {
+            anyhow::bail!("Cannot keep all of /{root}");
+        }
+        if !rest
+            .components()
+            .all(|c| matches!(c, Utf8Component::Normal(_)))
+        {
+            anyhow::bail!("Invalid path to keep: {path}");
+        }
+        Ok(Self { root, path: rest })
+    }
+
+    /// The absolute path in the booted system, as accepted by [`Self::parse`].
+    pub(crate) fn absolute(&self) -> Utf8PathBuf {
+        Utf8Path::new("/").join(self.root).join(self.path)
+    }
+}
+
+/// Copy the paths to keep from the booted system, whose root is `source`,
+/// into the new `/etc` and `/var`. Paths which do not exist are skipped.
+#[context("Copying kept paths")]
+pub(crate) fn copy_kept_paths(
+    keep: &[KeepPath],
+    source: &Utf8Path,
+    etc: &Utf8Path,
+    var: &Utf8Path,
+) -> Result<()> {
+    for k in keep {
+        let src_root = source.join(k.root);
+        let dest = match k.root {
+            "etc" => etc,
+            _ => var,
+        };
+        if src_root.join(k.path).symlink_metadata().is_err() {
+            println!("Skipping missing path: {src_root}/{}", k.path);
+            continue;
+        }
+        Command::new("cp")
+            .args(["-a", "--parents", k.path.as_str(), dest.as_str()])
+            .current_dir(&src_root)
+            .run_capture_stderr()
+            .with_context(|| format!("Copying {src_root}/{}", k.path))?;
+        println!("Keeping: {src_root}/{}", k.path);
+    }
+    Ok(())
+}
+
+/// The name of a deployment directory, which identifies it.
+fn deployment_name(deployment: &Deployment) -> String {
+    format!("{}.{}", deployment.csum(), deployment.deployserial())
+}
+
+#[context("Reading factory reset record")]
+fn read_record(storage: &Storage) -> Result<Option<OstreeFactoryReset>> {
+    storage
+        .physical_root
+        .read_to_string_optional(RECORD_PATH)?
+        .map(|s| serde_json::from_str(&s).context("Parsing record"))
+        .transpose()
+}
+
+#[context("Writing factory reset record")]
+fn write_record(storage: &Storage, record: &OstreeFactoryReset) -> Result<()> {
+    storage.physical_root.create_dir_all("ostree/bootc")?;
+    storage
+        .physical_root
+        .atomic_write(RECORD_PATH, serde_json::to_vec(record)?)
+        .map_err(Into::into)
+}
+
+/// Copy the paths to keep into the staged deployment of a queued factory
+/// reset. Invoked at shutdown, before libostree finalizes the staged
+/// deployment.
+#[context("Finalizing factory reset")]
+pub(crate) fn finalize_staged(storage: &Storage, sysroot: &ostree::Sysroot) -> Result<()> {
+    let Some(mut record) = read_record(storage)? else {
+        return Ok(());
+    };
+    if record.keep.is_empty() {
+        return Ok(());
+    }
+    let Some(staged) = sysroot
+        .staged_deployment()
+        .filter(|d| d.osname().as_str() == record.stateroot)
+    else {
+        tracing::warn!("Not keeping paths, as the factory reset is not staged");
+        return Ok(());
+    };
+
+    let keep = record
+        .keep
+        .iter()
+        .map(|p| KeepPath::parse(p))
+        .collect::<Result<Vec<_>>>()?;
+    let root = &storage.physical_root_path;
+    let etc = root
+        .join(sysroot.deployment_dirpath(&staged).as_str())
+        .join("etc");
+    let var = root.join(format!("ostree/deploy/{}/var", record.stateroot));
+    copy_kept_paths(&keep, Utf8Path::new("/"), &etc, &var)?;
+
+    record.keep.clear();
+    write_record(storage, &record)
+}
+
+#[context("Factory reset (ostree)")]
+async fn ostree_factory_reset(
+    storage: &Storage,
+    booted_ostree: &BootedOstree<'_>,
+    keep: &[KeepPath<'_>],
+) -> Result<()> {
+    let ostree = booted_ostree.sysroot;
+    if read_record(storage)?.is_some() {
+        anyhow::bail!(
+            "A factory reset was already performed; remove the previous state with `bootc state factory-reset --discard` first"
+        );
+    }
+    let repo = &booted_ostree.repo();
+    let host = crate::status::get_status_require_booted(ostree)?.2;
+    let imgstate = host
+        .status
+        .booted
+        .as_ref()
+        .map(|b| b.query_image(repo))
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| anyhow!("Booted deployment is not container image based"))?;
+    let fetched = ImageState::from(*imgstate);
+    let spec = RequiredHostSpec::from_spec(&host.spec)?;
+
+    let previous_stateroot = booted_ostree.stateroot().to_string();
+    let stateroots = list_stateroots(ostree)?;
+    let stateroot = allocate_new_stateroot(ostree, &stateroots, chrono::Utc::now())?.name;
+
+    // Keep all kernel arguments of the booted deployment; ostree adds its own.
+    let bootcfg = booted_ostree
+        .deployment
+        .bootconfig()
+        .ok_or_else(|| anyhow!("Missing bootcfg for booted deployment"))?;
+    let mut kargs = CmdlineOwned::from(
+        bootcfg
+            .get("options")
+            .map(|o| o.to_string())
+            .unwrap_or_default(),
+    );
+    kargs.remove(&ParameterKey::from("ostree"));
+
+    let from = MergeState::Reset {
+        stateroot: stateroot.clone(),
+        kargs,
+    };
+    crate::deploy::stage(
+        storage,
+        from,
+        &fetched,
+        &spec,
+        ProgressWriter::default(),
+        false,
+    )
+    .await?;
+
+    let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    crate::install::copy_boot_fstab_entry(rootfs, ostree)?;
+
+    // Ensure the current state is not garbage collected by future updates
+    let booted = &booted_ostree.deployment;
+    let pinned = if booted.is_pinned() {
+        None
+    } else {
+        ostree.deployment_set_pinned(booted, true)?;
+        Some(deployment_name(booted))
+    };
+
+    // The paths to keep are copied at shutdown, so that later changes are kept
+    let record = OstreeFactoryReset {
+        stateroot,
+        previous_stateroot,
+        pinned,
+        keep: keep.iter().map(|k| k.absolute()).collect(),
+    };
+    write_record(storage, &record)?;
+    storage.update_mtime()?;
+    if !record.keep.is_empty() {
+        crate::deploy::start_finalize_staged_svc()?;
+    }
+
+    println!(
+        "Queued factory reset in new stateroot {}; the previous state is kept in {}",
+        record.stateroot, record.previous_stateroot
+    );
+    Ok(())
+}
+
+#[context("Reverting factory reset (ostree)")]
+fn ostree_revert_factory_reset(storage: &Storage, booted_ostree: &BootedOstree<'_>) -> Result<()> {
+    let ostree = booted_ostree.sysroot;
+    let record = read_record(storage)?.ok_or_else(|| anyhow!("No factory reset found"))?;
+    if booted_ostree.stateroot().as_str() != record.stateroot {
+        anyhow::bail!(
+            "Not booted into the state created by the factory reset; use `bootc state factory-reset --discard` to remove it"
+        );
+    }
+
+    let deployments = ostree.deployments();
+    let previous = deployments
+        .iter()
+        .filter(|d| d.osname().as_str() == record.previous_stateroot && !d.is_staged())
+        // Prefer the deployment pinned by the reset
+        .min_by_key(|d| Some(deployment_name(d)) != record.pinned)
+        .ok_or_else(|| anyhow!("No deployment found in {}", record.previous_stateroot))?;
+    let new_deployments = std::iter::once(previous.clone())
+        .chain(
+            deployments
+                .iter()
+                .filter(|d| !d.is_staged() && !d.equal(previous))
+                .cloned(),
+        )
+        .collect::<Vec<_>>();
+    ostree.write_deployments(&new_deployments, gio::Cancellable::NONE)?;
+    storage.update_mtime()?;
+
+    println!("Next boot: state from before the factory reset");
+    Ok(())
+}
+
+#[context("Discarding factory reset (ostree)")]
+fn ostree_discard_factory_reset(storage: &Storage, booted_ostree: &BootedOstree<'_>) -> Result<()> {
+    let ostree = booted_ostree.sysroot;
+    let record = read_record(storage)?.ok_or_else(|| anyhow!("No factory reset found"))?;
+    let booted_stateroot = booted_ostree.stateroot();
+    let remove = if booted_stateroot.as_str() == record.stateroot {
+        record.previous_stateroot.as_str()
+    } else if booted_stateroot.as_str() == record.previous_stateroot {
+        record.stateroot.as_str()
+    } else {
+        anyhow::bail!("Booted stateroot {booted_stateroot} is not part of the factory reset");
+    };
+
+    let deployments = ostree
+        .deployments()
+        .into_iter()
+        .filter(|d| d.osname().as_str() != remove)
+        .collect::<Vec<_>>();
+    ostree.write_deployments(&deployments, gio::Cancellable::NONE)?;
+    if let Some(pinned) = record.pinned.as_deref() {
+        for d in deployments.iter().filter(|d| deployment_name(d) == pinned) {
+            ostree.deployment_set_pinned(d, false)?;
+        }
+    }
+    storage
+        .physical_root
+        .remove_dir_all(format!("ostree/deploy/{remove}"))
+        .with_context(|| format!("Removing stateroot {remove}"))?;
+    storage.physical_root.remove_file(RECORD_PATH)?;
+    storage.update_mtime()?;
+
+    println!("Removed stateroot {remove}");
+    Ok(())
+}
+
+/// Implementation of `bootc state factory-reset`.
+pub(crate) async fn factory_reset(keep: &[Utf8PathBuf]) -> Result<()> {
+    let keep = keep
+        .iter()
+        .map(|p| KeepPath::parse(p))
+        .collect::<Result<Vec<_>>>()?;
+    let storage = &crate::cli::get_storage().await?;
+    match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            ostree_factory_reset(storage, &booted_ostree, &keep).await
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            composefs_factory_reset(storage, &booted_cfs, &keep).await
+        }
+    }
+}
+
+/// Implementation of `bootc state factory-reset --revert`.
+pub(crate) async fn revert_factory_reset() -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            ostree_revert_factory_reset(storage, &booted_ostree)
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            composefs_revert_factory_reset(storage, &booted_cfs)
+        }
+    }
+}
+
+/// Implementation of `bootc state factory-reset --discard`.
+pub(crate) async fn discard_factory_reset() -> Result<()> {
+    let storage = &crate::cli::get_storage().await?;
+    match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            ostree_discard_factory_reset(storage, &booted_ostree)
+        }
+        BootedStorageKind::Composefs(_) => composefs_discard_factory_reset(storage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() -> Result<()> {
+        // Records written before the paths to keep were stored still parse
+        let record: OstreeFactoryReset =
+            serde_json::from_str(r#"{"stateroot":"new","previousStateroot":"old","pinned":null}"#)?;
+        assert!(record.keep.is_empty());
+        assert_eq!(
+            serde_json::to_string(&record)?,
+            r#"{"stateroot":"new","previousStateroot":"old","pinned":null}"#
+        );
+
+        let record = OstreeFactoryReset {
+            keep: vec!["/etc/ssh".into()],
+            ..record
+        };
+        let record: OstreeFactoryReset = serde_json::from_str(&serde_json::to_string(&record)?)?;
+        assert_eq!(record.keep, ["/etc/ssh"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_path() {
+        let parse = |p: &'static str| KeepPath::parse(Utf8Path::new(p));
+        assert_eq!(
+            parse("/etc/ssh/ssh_host_ed25519_key").unwrap(),
+            KeepPath {
+                root: "etc",
+                path: Utf8Path::new("ssh/ssh_host_ed25519_key")
+            }
+        );
+        assert_eq!(
+            parse("/var/lib/NetworkManager/").unwrap(),
+            KeepPath {
+                root: "var",
+                path: Utf8Path::new("lib/NetworkManager")
+            }
+        );
+        assert_eq!(
+            parse("/var/lib/NetworkManager/").unwrap().absolute(),
+            "/var/lib/NetworkManager"
+        );
+        for invalid in [
+            "/etc",
+            "/var/",
+            "etc/ssh",
+            "/usr/etc/ssh",
+            "/etcfoo/bar",
+            "/etc/../usr/lib",
+            "/var/lib/../../usr",
+        ] {
+            assert!(parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::io::BufRead;
 
 use anyhow::{Context, Result};
+use bootc_kernel_cmdline::utf8::Cmdline;
 use camino::Utf8PathBuf;
 use cap_std::fs::Dir;
 use cap_std_ext::{cap_std, dirext::CapStdExtDirExt};
@@ -8,11 +9,13 @@ use fn_error_context::context;
 use ostree_ext::container_utils::{OSTREE_BOOTED, is_ostree_booted_in};
 use rustix::{fd::AsFd, fs::StatVfsMountFlags};
 
+use crate::composefs_consts::COMPOSEFS_CMDLINE;
 use crate::install::DESTRUCTIVE_CLEANUP;
 
 const STATUS_ONBOOT_UNIT: &str = "bootc-status-updated-onboot.target";
 const STATUS_PATH_UNIT: &str = "bootc-status-updated.path";
 const CLEANUP_UNIT: &str = "bootc-destructive-cleanup.service";
+const BOOT_COMPLETE_UNIT: &str = "bootc-boot-complete.service";
 const MULTI_USER_TARGET: &str = "multi-user.target";
 const EDIT_UNIT: &str = "bootc-fstab-edit.service";
 const FSTAB_ANACONDA_STAMP: &str = "Created by anaconda";
@@ -86,6 +89,19 @@ pub(crate) fn unit_enablement_impl(sysroot: &Dir, unit_dir: &Dir) -> Result<()>
 
 /// Main entrypoint for the generator
 pub(crate) fn generator(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    generator_impl(root, unit_dir, &Cmdline::from_proc()?)
+}
+
+fn generator_impl(root: &Dir, unit_dir: &Dir, cmdline: &Cmdline) -> Result<()> {
+    crate::update_policy::generate(root, unit_dir)?;
+
+    // Composefs backend systems are not ostree booted; they just need
+    // boot assessment enabled.
+    if cmdline.find(COMPOSEFS_CMDLINE).is_some() {
+        enable_unit(unit_dir, BOOT_COMPLETE_UNIT, MULTI_USER_TARGET)?;
+        return Ok(());
+    }
+
     // Only run on ostree systems
     if !root.try_exists(OSTREE_BOOTED)? {
         return Ok(());
@@ -212,6 +228,21 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_generator_composefs() -> Result<()> {
+        let tempdir = fixture()?;
+        let unit_dir = &tempdir.open_dir("run/systemd/system")?;
+
+        // Neither composefs nor ostree booted
+        generator_impl(&tempdir, unit_dir, &Cmdline::from("root=UUID=abc quiet"))?;
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        generator_impl(&tempdir, unit_dir, &Cmdline::from("composefs=1234 quiet"))?;
+        let wantsdir = &unit_dir.open_dir("multi-user.target.wants")?;
+        assert!(wantsdir.symlink_metadata(BOOT_COMPLETE_UNIT)?.is_symlink());
+        Ok(())
+    }
+
     #[cfg(test)]
     mod test {
         use super::*;
@@ -0,0 +1,208 @@
+//! # UID/GID drift detection
+//!
+//! Users and groups created by `systemd-sysusers` are persisted in
+//! `/etc/passwd` and `/etc/group`. If a new image defines a different static ID
+//! for one of them in `sysusers.d`, the existing ID is preserved by the `/etc`
+//! merge, but the files shipped in the image (and those it creates in `/var`)
+//! use the new one. When staging, we detect such drift and record a report for
+//! the new deployment, which is shown by `bootc status`.
+//!
+//! `bootc internals remap-ids` can be used on the booted system to switch
+//! `/etc/passwd` and `/etc/group` to the IDs defined by the image, changing the
+//! ownership of the affected files in `/var` accordingly.
+//!
+//! This is only implemented for the ostree backend.
+
+use std::io::Write;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::{
+    self,
+    fs::{Dir, MetadataExt},
+};
+use fn_error_context::context;
+use ostree_ext::ostree;
+use ostree_ext::ostree::Deployment;
+
+use crate::spec::{IdDrift, IdType};
+
+/// Where drift reports are stored, relative to the physical root.
+const REPORT_DIR: &str = "ostree/bootc/id-drift";
+
+impl From<bootc_sysusers::IdDrift> for IdDrift {
+    fn from(value: bootc_sysusers::IdDrift) -> Self {
+        let ty = match value.kind {
+            bootc_sysusers::IdKind::User => IdType::User,
+            bootc_sysusers::IdKind::Group => IdType::Group,
+        };
+        Self {
+            ty,
+            name: value.name,
+            current: value.current,
+            expected: value.expected,
+            remapped: false,
+        }
+    }
+}
+
+impl From<&IdDrift> for bootc_sysusers::IdDrift {
+    fn from(value: &IdDrift) -> Self {
+        let kind = match value.ty {
+            IdType::User => bootc_sysusers::IdKind::User,
+            IdType::Group => bootc_sysusers::IdKind::Group,
+        };
+        Self {
+            kind,
+            name: value.name.clone(),
+            current: value.current,
+            expected: value.expected,
+        }
+    }
+}
+
+impl std::fmt::Display for IdDrift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ty = match self.ty {
+            IdType::User => "user",
+            IdType::Group => "group",
+        };
+        write!(
+            f,
+            "{ty} {}: {} => {}",
+            self.name, self.current, self.expected
+        )
+    }
+}
+
+/// Persist the drift report for `deployment`.
+#[context("Writing ID drift report")]
+fn write_report(
+    sysroot: &ostree::Sysroot,
+    deployment: &Deployment,
+    drift: &[IdDrift],
+) -> Result<()> {
+    crate::deployment_report::write(sysroot, REPORT_DIR, deployment, drift)
+}
+
+/// Load the drift report for a deployment, if any.
+#[context("Reading ID drift report")]
+pub(crate) fn read_report(
+    sysroot: &ostree::Sysroot,
+    deployment: &Deployment,
+) -> Result<Vec<IdDrift>> {
+    crate::deployment_report::read(sysroot, REPORT_DIR, deployment)
+}
+
+/// Compare the static IDs defined by the newly staged `deployment` against the
+/// merged /etc, and record the result. With the native /etc merge, the /etc of
+/// the new deployment has already been merged; otherwise it is merged by
+/// libostree at shutdown, keeping the locally modified /etc/passwd and
+/// /etc/group of `merge_deployment`.
+#[context("Checking for UID/GID drift")]
+pub(crate) fn check_staged(
+    sysroot: &ostree::Sysroot,
+    merge_deployment: &Deployment,
+    deployment: &Deployment,
+    native_etc_merge: bool,
+) -> Result<Vec<IdDrift>> {
+    let new_root = crate::utils::deployment_fd(sysroot, deployment)?;
+    let etc_root = if native_etc_merge {
+        deployment
+    } else {
+        merge_deployment
+    };
+    let etc_root = crate::utils::deployment_fd(sysroot, etc_root)?;
+    let drift = bootc_sysusers::detect_drift(&new_root, &etc_root)?
+        .into_iter()
+        .map(IdDrift::from)
+        .collect::<Vec<_>>();
+    write_report(sysroot, deployment, &drift)?;
+    Ok(drift)
+}
+
+/// Print a notice about drift found when staging.
+pub(crate) fn print_staged_notice(drift: &[IdDrift]) {
+    if drift.is_empty() {
+        return;
+    }
+    println!("notice: Keeping existing IDs which differ from the new image:");
+    for d in drift {
+        println!("  {d}");
+    }
+    println!("Use `bootc internals remap-ids` after rebooting to switch to the image IDs.");
+}
+
+/// Open the filesystems configured in /etc/fstab below /var, which are not
+/// crossed when changing the ownership of the files in /var. Fails if one of
+/// them is not mounted.
+fn var_mounts(root: &Dir, var: &Dir) -> Result<Vec<Dir>> {
+    let var_dev = var.dir_metadata()?.dev();
+    let mut r = Vec::new();
+    for spec in crate::install::read_fstab(root)? {
+        if !spec.target.starts_with("/var/") {
+            continue;
+        }
+        let path = spec.target.trim_start_matches('/');
+        let dir = root
+            .open_dir(path)
+            .with_context(|| format!("Opening {}", spec.target))?;
+        if dir.dir_metadata()?.dev() == var_dev {
+            anyhow::bail!(
+                "{} is not mounted; mount it to change the ownership of its files",
+                spec.target
+            );
+        }
+        r.push(dir);
+    }
+    Ok(r)
+}
+
+/// Switch users and groups of the booted system whose IDs differ from the ones
+/// defined by the booted image to the image IDs, changing the ownership of the
+/// affected files in /var accordingly. The remapping is recorded in the report
+/// for the booted deployment.
+#[context("Remapping IDs")]
+pub(crate) fn remap_booted(
+    sysroot: &ostree::Sysroot,
+    booted: &Deployment,
+    dry_run: bool,
+    mut out: impl Write,
+) -> Result<()> {
+    let root = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
+    let drift = bootc_sysusers::detect_drift(root, root)?;
+    if drift.is_empty() {
+        writeln!(out, "No ID drift found")?;
+        return Ok(());
+    }
+    let drift = drift.into_iter().map(IdDrift::from).collect::<Vec<_>>();
+    for d in drift.iter() {
+        writeln!(out, "{d}")?;
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let sysusers_drift = drift
+        .iter()
+        .map(bootc_sysusers::IdDrift::from)
+        .collect::<Vec<_>>();
+    bootc_sysusers::check_remap(root, &sysusers_drift)?;
+    let var = root.open_dir("var").context("Opening /var")?;
+    let var_mounts = var_mounts(root, &var)?;
+    // Change /var first, so that an interrupted remapping can be resumed
+    let mut n = bootc_sysusers::remap_ownership(&var, &sysusers_drift)?;
+    for mount in var_mounts {
+        n += bootc_sysusers::remap_ownership(&mount, &sysusers_drift)?;
+    }
+    bootc_sysusers::remap_nameservice(root, &sysusers_drift)?;
+    writeln!(out, "Changed ownership of {n} files in /var")?;
+
+    let drift = drift
+        .into_iter()
+        .map(|d| IdDrift {
+            remapped: true,
+            ..d
+        })
+        .collect::<Vec<_>>();
+    write_report(sysroot, booted, &drift)
+}
@@ -124,7 +124,7 @@
 //!
 //! Key configurable options include:
 //! - Root filesystem type (xfs, ext4, btrfs)
-//! - Allowed block setups (direct, tpm2-luks)
+//! - Allowed block setups (direct, tpm2-luks, luks-passphrase, fido2-luks, pkcs11-luks)
 //! - Default kernel arguments
 //! - Architecture-specific overrides
 //!
@@ -186,7 +186,7 @@ use rustix::fs::MetadataExt as _;
 use serde::{Deserialize, Serialize};
 
 #[cfg(feature = "install-to-disk")]
-use self::baseline::InstallBlockDeviceOpts;
+use self::baseline::{BlockSetup, InstallBlockDeviceOpts};
 use crate::bootc_composefs::status::ComposefsCmdline;
 use crate::bootc_composefs::{
     boot::setup_composefs_boot,
@@ -198,7 +198,7 @@ use crate::containerenv::ContainerExecutionInfo;
 use crate::deploy::{MergeState, PreparedPullResult, prepare_for_pull, pull_from_prepared};
 use crate::install::config::Filesystem as FilesystemEnum;
 use crate::lsm;
-use crate::progress_jsonl::ProgressWriter;
+use crate::progress_jsonl::{Phase, ProgressWriter};
 use crate::spec::{Bootloader, ImageReference};
 use crate::store::Storage;
 use crate::task::Task;
@@ -294,6 +294,10 @@ pub(crate) struct InstallTargetOpts {
     #[clap(long = "experimental-unified-storage", hide = true)]
     #[serde(default)]
     pub(crate) unified_storage_exp: bool,
+
+    #[clap(flatten)]
+    #[serde(skip)]
+    pub(crate) progress: crate::cli::ProgressOptions,
 }
 
 #[derive(clap::Args, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -389,6 +393,32 @@ pub(crate) struct InstallConfigOpts {
     #[clap(long)]
     #[serde(default)]
     pub(crate) bootloader: Option<Bootloader>,
+
+    /// The firmware of the target system, for `match-firmware` in kargs.d files.
+    ///
+    /// Defaults to the firmware of the system running the installation, unless
+    /// `--generic-image` is given.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) target_firmware: Option<crate::bootc_kargs::Firmware>,
+
+    /// The virtualization type of the target system, for `match-virtualization`
+    /// in kargs.d files.
+    ///
+    /// Defaults to the one of the system running the installation, unless
+    /// `--generic-image` is given.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) target_virtualization: Option<String>,
+
+    /// The DMI system vendor of the target system, for `match-dmi-vendor` in
+    /// kargs.d files.
+    ///
+    /// Defaults to the one of the system running the installation, unless
+    /// `--generic-image` is given.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) target_dmi_vendor: Option<String>,
 }
 
 #[derive(Debug, Default, Clone, clap::Parser, Serialize, Deserialize, PartialEq, Eq)]
@@ -573,9 +603,6 @@ pub(crate) struct InstallResetOpts {
     #[clap(long)]
     pub(crate) quiet: bool,
 
-    #[clap(flatten)]
-    pub(crate) progress: crate::cli::ProgressOptions,
-
     /// Restart or reboot into the new target image.
     ///
     /// Currently, this option always reboots.  In the future this command
@@ -630,6 +657,9 @@ pub(crate) struct State {
     #[allow(dead_code)]
     pub(crate) prepareroot_config: HashMap<String, String>,
     pub(crate) install_config: Option<config::InstallConfiguration>,
+    /// The install configuration with the options given on the command line
+    /// applied, which kargs.d files are matched against.
+    pub(crate) effective_install_config: config::InstallConfiguration,
     /// The parsed contents of the authorized_keys (not the file path)
     pub(crate) root_ssh_authorized_keys: Option<String>,
     #[allow(dead_code)]
@@ -644,6 +674,9 @@ pub(crate) struct State {
 
     // If Some, then --composefs_native is passed
     pub(crate) composefs_options: InstallComposefsOpts,
+
+    /// Progress reporting for the installation phases
+    pub(crate) prog: ProgressWriter,
 }
 
 // Shared read-only global state
@@ -776,6 +809,15 @@ impl MountSpec {
         )
     }
 
+    /// Format as a `systemd.mount-extra=` kernel argument
+    pub(crate) fn to_mount_extra_karg(&self) -> String {
+        let options = self.options.as_deref().unwrap_or("defaults");
+        format!(
+            "systemd.mount-extra={}:{}:{}:{}",
+            self.source, self.target, self.fstype, options
+        )
+    }
+
     /// Append a mount option
     pub(crate) fn push_option(&mut self, opt: &str) {
         let options = self.options.get_or_insert_with(Default::default);
@@ -1073,6 +1115,8 @@ async fn install_container(
     // Auto-detection (None) is only appropriate for upgrade/switch on a running system.
     let use_unified = state.target_opts.unified_storage_exp;
 
+    let prog = &state.prog;
+    prog.start_phase(Phase::Pull).await;
     let prepared = if use_unified {
         tracing::info!("Using unified storage path for installation");
         crate::deploy::prepare_for_pull_unified(
@@ -1091,9 +1135,10 @@ async fn install_container(
         PreparedPullResult::AlreadyPresent(existing) => existing,
         PreparedPullResult::Ready(image_meta) => {
             crate::deploy::check_disk_space_ostree(repo, &image_meta, &spec_imgref)?;
-            pull_from_prepared(&spec_imgref, false, ProgressWriter::default(), *image_meta).await?
+            pull_from_prepared(&spec_imgref, false, prog.clone(), *image_meta).await?
         }
     };
+    prog.complete_phase(Phase::Pull).await;
 
     repo.set_disable_fsync(false);
 
@@ -1106,7 +1151,7 @@ async fn install_container(
     let kargsd = crate::bootc_kargs::get_kargs_from_ostree_root(
         &sysroot.repo(),
         merged_ostree_root.downcast_ref().unwrap(),
-        std::env::consts::ARCH,
+        crate::bootc_kargs::KargsMatchContext::host()?,
     )?;
 
     // If the target uses aboot, then we need to set that bootloader in the ostree
@@ -1181,11 +1226,13 @@ async fn install_container(
     options.proxy_cfg = proxy_cfg;
     options.skip_completion = true; // Must be set to avoid recursion!
     options.no_clean = has_ostree;
+    prog.start_phase(Phase::Deploy).await;
     let imgstate = crate::utils::async_task_with_spinner(
         "Deploying container image",
         ostree_container::deploy::deploy(&sysroot, stateroot, &src_imageref, Some(options)),
     )
     .await?;
+    prog.complete_phase(Phase::Deploy).await;
 
     let deployment = sysroot
         .deployments()
@@ -1224,15 +1271,33 @@ async fn install_container(
         }
     }
 
-    // Write the entry for /boot to /etc/fstab.  TODO: Encourage OSes to use the karg?
-    // Or better bind this with the grub data.
-    // We omit it if the boot mountspec argument was empty
-    if let Some(boot) = root_setup.boot.as_ref() {
-        if !boot.source.is_empty() {
-            crate::lsm::atomic_replace_labeled(&root, "etc/fstab", 0o644.into(), sepolicy, |w| {
-                writeln!(w, "{}", boot.to_fstab()).map_err(Into::into)
-            })?;
-        }
+    // Write the entry for /boot and any additional filesystems to /etc/fstab.
+    // TODO: Encourage OSes to use the karg?  Or better bind this with the grub data.
+    // We omit /boot if the boot mountspec argument was empty
+    let boot = root_setup
+        .boot
+        .as_ref()
+        .filter(|boot| !boot.source.is_empty());
+    let fstab_entries = boot
+        .into_iter()
+        .chain(root_setup.mounts.iter())
+        .collect::<Vec<_>>();
+    if !fstab_entries.is_empty() {
+        crate::lsm::atomic_replace_labeled(&root, "etc/fstab", 0o644.into(), sepolicy, |w| {
+            for entry in fstab_entries {
+                writeln!(w, "{}", entry.to_fstab())?;
+            }
+            Ok(())
+        })?;
+    }
+
+    #[cfg(feature = "install-to-disk")]
+    if let Some(crypttab) = root_setup.crypttab.as_deref() {
+        let existing = root.read_to_string_optional("etc/crypttab")?;
+        let contents = baseline::merge_crypttab(existing.as_deref(), crypttab)?;
+        crate::lsm::atomic_replace_labeled(&root, "etc/crypttab", 0o600.into(), sepolicy, |w| {
+            w.write_all(contents.as_bytes()).map_err(Into::into)
+        })?;
     }
 
     if let Some(contents) = state.root_ssh_authorized_keys.as_deref() {
@@ -1297,7 +1362,19 @@ pub(crate) fn exec_in_host_mountns(args: &[std::ffi::OsString]) -> Result<()> {
 pub(crate) struct RootSetup {
     #[cfg(feature = "install-to-disk")]
     luks_device: Option<String>,
+    /// The `/etc/crypttab` entry for the root LUKS device
+    #[cfg(feature = "install-to-disk")]
+    crypttab: Option<String>,
+    /// RAID arrays backing the root and /boot
+    #[cfg(feature = "install-to-disk")]
+    md_devices: Vec<String>,
+    /// The block setup used by `install to-disk`
+    #[cfg(feature = "install-to-disk")]
+    block_setup: Option<BlockSetup>,
     pub(crate) device_info: bootc_blockdev::Device,
+    /// Additional devices the installation is mirrored to; the bootloader
+    /// is installed to each of them as well.
+    pub(crate) mirror_devices: Vec<bootc_blockdev::Device>,
     /// Absolute path to the location where we've mounted the physical
     /// root filesystem for the system we're installing.
     pub(crate) physical_root_path: Utf8PathBuf,
@@ -1309,6 +1386,8 @@ pub(crate) struct RootSetup {
     /// True if we should skip finalizing
     skip_finalize: bool,
     boot: Option<MountSpec>,
+    /// Additional filesystems to mount, e.g. a separate /var
+    mounts: Vec<MountSpec>,
     pub(crate) kargs: CmdlineOwned,
 }
 
@@ -1329,10 +1408,37 @@ impl RootSetup {
         self.boot.as_ref()
     }
 
-    // Drop any open file descriptors and return just the mount path and backing luks device, if any
+    /// Get the mount specs for any additional filesystems.
+    pub(crate) fn extra_mount_specs(&self) -> &[MountSpec] {
+        &self.mounts
+    }
+
+    /// The disks to install the bootloader to: every disk with an ESP backing the
+    /// root filesystem or one of its mirrors, or the target device if there is
+    /// none (e.g. BIOS-only systems).
+    #[context("Finding bootloader devices")]
+    pub(crate) fn bootloader_devices(&self) -> Result<Vec<bootc_blockdev::Device>> {
+        let root = bootc_blockdev::list_dev_by_dir(&self.physical_root)?;
+        let mut devices: Vec<bootc_blockdev::Device> = Vec::new();
+        for dev in std::iter::once(&root).chain(self.mirror_devices.iter()) {
+            for esp in dev.find_colocated_esps()?.unwrap_or_default() {
+                let disk = esp.require_single_root()?;
+                if !devices.iter().any(|d| d.path() == disk.path()) {
+                    devices.push(disk);
+                }
+            }
+        }
+        if devices.is_empty() {
+            devices.push(self.device_info.clone());
+        }
+        Ok(devices)
+    }
+
+    // Drop any open file descriptors and return just the mount path, backing luks
+    // device and RAID arrays, if any
     #[cfg(feature = "install-to-disk")]
-    fn into_storage(self) -> (Utf8PathBuf, Option<String>) {
-        (self.physical_root_path, self.luks_device)
+    fn into_storage(self) -> (Utf8PathBuf, Option<String>, Vec<String>) {
+        (self.physical_root_path, self.luks_device, self.md_devices)
     }
 }
 
@@ -1539,6 +1645,7 @@ async fn prepare_install(
     target_opts: InstallTargetOpts,
     mut composefs_options: InstallComposefsOpts,
     target_fs: Option<FilesystemEnum>,
+    prog: ProgressWriter,
 ) -> Result<Arc<State>> {
     tracing::trace!("Preparing install");
     let rootfs = cap_std::fs::Dir::open_ambient_dir("/", cap_std::ambient_authority())
@@ -1698,6 +1805,20 @@ async fn prepare_install(
             .and_then(|r| r.fstype))
         .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
 
+    let mut effective_install_config = install_config.clone().unwrap_or_default();
+    effective_install_config.root_fs_type = Some(root_filesystem);
+    if let Some(root) = effective_install_config
+        .filesystem
+        .as_mut()
+        .and_then(|f| f.root.as_mut())
+    {
+        root.fstype = Some(root_filesystem);
+    }
+    effective_install_config.bootloader = config_opts.bootloader.clone();
+    if let Some(stateroot) = config_opts.stateroot.as_ref() {
+        effective_install_config.stateroot = Some(stateroot.clone());
+    }
+
     let mut is_uki = false;
 
     // For composefs backend, automatically disable fs-verity hard requirement if the
@@ -1781,6 +1902,7 @@ async fn prepare_install(
         target_opts,
         target_imgref,
         install_config,
+        effective_install_config,
         prepareroot_config,
         root_ssh_authorized_keys,
         container_root: rootfs,
@@ -1788,6 +1910,7 @@ async fn prepare_install(
         host_is_container,
         composefs_required,
         composefs_options,
+        prog,
     });
 
     Ok(state)
@@ -1843,8 +1966,15 @@ async fn install_with_sysroot(
         .physical_root
         .open_dir(&deployment_path)
         .context("Opening deployment dir")?;
+    let var = rootfs
+        .physical_root
+        .open_dir(format!("ostree/deploy/{}/var", state.stateroot()))
+        .context("Opening stateroot var")?;
+    apply_var_tmpfiles(&deployment_dir, &var)?;
     let postfetch = PostFetchState::new(state, &deployment_dir)?;
 
+    let prog = &state.prog;
+    prog.start_phase(Phase::Bootloader).await;
     if cfg!(target_arch = "s390x") {
         // TODO: Integrate s390x support into install_via_bootupd
         // zipl only supports single device
@@ -1852,15 +1982,17 @@ async fn install_with_sysroot(
     } else {
         match postfetch.detected_bootloader {
             Bootloader::Grub => {
-                crate::bootloader::install_via_bootupd(
-                    &rootfs.device_info,
-                    &rootfs
-                        .target_root_path
-                        .clone()
-                        .unwrap_or(rootfs.physical_root_path.clone()),
-                    &state.config_opts,
-                    Some(&deployment_path.as_str()),
-                )?;
+                for device in rootfs.bootloader_devices()? {
+                    crate::bootloader::install_via_bootupd(
+                        &device,
+                        &rootfs
+                            .target_root_path
+                            .clone()
+                            .unwrap_or(rootfs.physical_root_path.clone()),
+                        &state.config_opts,
+                        Some(&deployment_path.as_str()),
+                    )?;
+                }
             }
             Bootloader::Systemd => {
                 anyhow::bail!("bootupd is required for ostree-based installs");
@@ -1870,25 +2002,30 @@ async fn install_with_sysroot(
             }
         }
     }
+    prog.complete_phase(Phase::Bootloader).await;
     tracing::debug!("Installed bootloader");
 
     tracing::debug!("Performing post-deployment operations");
 
-    match bound_images {
-        BoundImages::Skip => {}
+    prog.start_phase(Phase::BoundImages).await;
+    let resolved_bound_images = match bound_images {
+        BoundImages::Skip => Vec::new(),
         BoundImages::Resolved(resolved_bound_images) => {
             // Now copy each bound image from the host's container storage into the target.
-            for image in resolved_bound_images {
+            for image in resolved_bound_images.iter() {
                 let image = image.image.as_str();
                 c_storage.pull_from_host_storage(image).await?;
             }
+            resolved_bound_images
         }
         BoundImages::Unresolved(bound_images) => {
             crate::boundimage::pull_images_impl(c_storage, bound_images)
                 .await
-                .context("pulling bound images")?;
+                .context("pulling bound images")?
         }
-    }
+    };
+    crate::boundimage::write_report(ostree, &deployment, &resolved_bound_images)?;
+    prog.complete_phase(Phase::BoundImages).await;
 
     Ok(())
 }
@@ -1986,19 +2123,34 @@ async fn install_to_filesystem_impl(
     // Drop exclusive ownership since we're done with mutation
     let rootfs = &*rootfs;
 
-    match rootfs.device_info.pttype.as_deref() {
-        Some("dos") => crate::utils::medium_visibility_warning(
-            "Installing to `dos` format partitions is not recommended",
-        ),
+    // kargs.d files are matched against the system being installed, and
+    // later against the install configuration recorded here
+    let install_config = config::InstallConfiguration {
+        #[cfg(feature = "install-to-disk")]
+        block: rootfs
+            .block_setup
+            .map(|b| vec![b])
+            .or_else(|| state.effective_install_config.block.clone()),
+        ..state.effective_install_config.clone()
+    };
+    crate::bootc_kargs::KargsMatchContext::init_install(&state.config_opts, &install_config)?;
+    crate::bootc_kargs::write_install_config(&rootfs.physical_root, &install_config)?;
+
+    let pttype_warning = match rootfs.device_info.pttype.as_deref() {
+        Some("dos") => Some("Installing to `dos` format partitions is not recommended".into()),
         Some("gpt") => {
             // The only thing we should be using in general
+            None
         }
-        Some(o) => {
-            crate::utils::medium_visibility_warning(&format!("Unknown partition table type {o}"))
-        }
+        Some(o) => Some(format!("Unknown partition table type {o}")),
         None => {
             // No partition table type - may be a filesystem install or loop device
+            None
         }
+    };
+    if let Some(msg) = pttype_warning {
+        crate::utils::medium_visibility_warning(&msg);
+        state.prog.warn(&msg).await;
     }
 
     if state.composefs_options.composefs_backend {
@@ -2019,12 +2171,14 @@ async fn install_to_filesystem_impl(
                 },
             )?;
         }
+        state.prog.start_phase(Phase::Pull).await;
         let pull_result = initialize_composefs_repository(
             state,
             rootfs,
             state.composefs_options.allow_missing_verity,
         )
         .await?;
+        state.prog.complete_phase(Phase::Pull).await;
         tracing::info!(
             "id: {}, verity: {}",
             pull_result.config_digest,
@@ -2055,6 +2209,26 @@ async fn install_to_filesystem_impl(
         ostree_install(state, rootfs, cleanup).await?;
     }
 
+    let stateroot_var = if state.composefs_options.composefs_backend {
+        Utf8PathBuf::from(crate::composefs_consts::SHARED_VAR_PATH)
+    } else {
+        Utf8Path::new("ostree/deploy")
+            .join(state.stateroot())
+            .join("var")
+    };
+    // Label what was created from tmpfiles.d by its path in the target system,
+    // rather than by its location in the physical root
+    if let Some(policy) = state.load_policy()? {
+        let parent = stateroot_var.parent().unwrap_or(Utf8Path::new("."));
+        let parent = rootfs.physical_root.open_dir(parent)?;
+        crate::lsm::ensure_dir_labeled_recurse(&parent, &mut "var".into(), &policy, None)
+            .context("SELinux labeling of /var")?;
+    }
+    populate_var_mounts(
+        &rootfs.physical_root_path.join(stateroot_var),
+        rootfs.extra_mount_specs(),
+    )?;
+
     // As the very last step before filesystem finalization, do a full SELinux
     // relabel of the physical root filesystem.  Any files that are already
     // labeled (e.g. ostree deployment contents, composefs objects) are skipped.
@@ -2078,6 +2252,72 @@ async fn install_to_filesystem_impl(
     Ok(())
 }
 
+/// Create the content of the stateroot's /var defined by the tmpfiles.d
+/// entries of the deployment `root`, so that it doesn't depend on
+/// systemd-tmpfiles running at first boot.
+#[context("Applying tmpfiles.d to /var")]
+pub(crate) fn apply_var_tmpfiles(root: &Dir, var: &Dir) -> Result<()> {
+    let tmpfiles = bootc_tmpfiles::read_all_tmpfiles(root)?;
+    for invalid in tmpfiles.invalid {
+        tracing::warn!(
+            "Ignoring tmpfiles.d line {}:{}: {}",
+            invalid.file.display(),
+            invalid.line,
+            invalid.reason
+        );
+    }
+    let userdb = bootc_sysusers::load_userdb(root)?;
+    let opts = bootc_tmpfiles::ApplyOptions {
+        prefix: Some("/var".into()),
+        ..Default::default()
+    };
+    let r = bootc_tmpfiles::apply(var, &tmpfiles.entries, &userdb, &userdb, &opts)?;
+    tracing::debug!("Applied {} tmpfiles.d entries to /var", r.applied);
+    for skipped in r.skipped {
+        tracing::debug!(
+            "Skipped tmpfiles.d entry for {}: {}",
+            skipped.entry.path.display(),
+            skipped.reason
+        );
+    }
+    Ok(())
+}
+
+/// Copy the stateroot's /var content into each additional filesystem mounted
+/// at or below /var, which would otherwise hide it once mounted.
+#[context("Populating /var mounts")]
+fn populate_var_mounts(var: &Utf8Path, mounts: &[MountSpec]) -> Result<()> {
+    for mount in mounts {
+        let Some(subpath) = Utf8Path::new(&mount.target).strip_prefix("/var").ok() else {
+            continue;
+        };
+        let src = var.join(subpath);
+        if !src.try_exists()? {
+            continue;
+        }
+        let uuid = mount
+            .get_source_uuid()
+            .ok_or_else(|| anyhow!("{} is not specified via UUID=", mount.target))?;
+        let dev = format!("/dev/disk/by-uuid/{uuid}");
+        let mnt = bootc_mount::tempmount::TempMount::mount_dev(
+            &dev,
+            &mount.fstype,
+            rustix::mount::MountFlags::empty(),
+            None,
+        )?;
+        let mntpath =
+            Utf8Path::from_path(mnt.dir.path()).ok_or_else(|| anyhow!("Non-UTF-8 mount path"))?;
+        tracing::debug!("Copying {src} to {}", mount.target);
+        Command::new("cp")
+            .args(["-a", "--"])
+            .arg(format!("{src}/."))
+            .arg(format!("{mntpath}/."))
+            .run_capture_stderr()
+            .with_context(|| format!("Copying {src} to {}", mount.target))?;
+    }
+    Ok(())
+}
+
 fn installation_complete() {
     println!("Installation complete!");
 }
@@ -2085,7 +2325,10 @@ fn installation_complete() {
 /// Implementation of the `bootc install to-disk` CLI command.
 #[context("Installing to disk")]
 #[cfg(feature = "install-to-disk")]
-pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
+pub(crate) async fn install_to_disk(
+    mut opts: InstallToDiskOpts,
+    prog: ProgressWriter,
+) -> Result<()> {
     // Log the disk installation operation to systemd journal
     const INSTALL_DISK_JOURNAL_ID: &str = "8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2";
     let source_image = opts
@@ -2112,6 +2355,9 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         .metadata()
         .with_context(|| format!("Querying {}", &block_opts.device))?;
     if opts.via_loopback {
+        if !block_opts.mirror.is_empty() {
+            anyhow::bail!("Mirroring is not supported when installing via loopback");
+        }
         if !opts.config_opts.generic_image {
             crate::utils::medium_visibility_warning(
                 "Automatically enabling --generic-image when installing via loopback",
@@ -2134,6 +2380,7 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
         opts.target_opts,
         opts.composefs_opts,
         block_opts.filesystem,
+        prog,
     )
     .await?;
 
@@ -2159,7 +2406,7 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     install_to_filesystem_impl(&state, &mut rootfs, Cleanup::Skip).await?;
 
     // Drop all data about the root except the bits we need to ensure any file descriptors etc. are closed.
-    let (root_path, luksdev) = rootfs.into_storage();
+    let (root_path, luksdev, md_devices) = rootfs.into_storage();
     Task::new_and_run(
         "Unmounting filesystems",
         "umount",
@@ -2168,6 +2415,13 @@ pub(crate) async fn install_to_disk(mut opts: InstallToDiskOpts) -> Result<()> {
     if let Some(luksdev) = luksdev.as_deref() {
         Task::new_and_run("Closing root LUKS device", "cryptsetup", ["close", luksdev])?;
     }
+    for md_device in md_devices.iter() {
+        Task::new_and_run(
+            "Stopping RAID array",
+            "mdadm",
+            ["--stop", md_device.as_str()],
+        )?;
+    }
 
     if let Some(loopback_dev) = loopback {
         loopback_dev.close()?;
@@ -2413,6 +2667,7 @@ pub(crate) async fn install_to_filesystem(
     opts: InstallToFilesystemOpts,
     targeting_host_root: bool,
     cleanup: Cleanup,
+    prog: ProgressWriter,
 ) -> Result<()> {
     // Log the installation operation to systemd journal
     const INSTALL_FILESYSTEM_JOURNAL_ID: &str = "9a8b7c6d5e4f3a2b1c0d9e8f7a6b5c4d3";
@@ -2521,6 +2776,7 @@ pub(crate) async fn install_to_filesystem(
         opts.target_opts,
         opts.composefs_opts,
         Some(inspect.fstype.as_str().try_into()?),
+        prog,
     )
     .await?;
 
@@ -2675,12 +2931,20 @@ pub(crate) async fn install_to_filesystem(
     let mut rootfs = RootSetup {
         #[cfg(feature = "install-to-disk")]
         luks_device: None,
+        #[cfg(feature = "install-to-disk")]
+        crypttab: None,
+        #[cfg(feature = "install-to-disk")]
+        md_devices: Vec::new(),
+        #[cfg(feature = "install-to-disk")]
+        block_setup: None,
         device_info,
+        mirror_devices: Vec::new(),
         physical_root_path: fsopts.root_path,
         physical_root: rootfs_fd,
         target_root_path: Some(target_root_path.clone()),
         rootfs_uuid: inspect.uuid.clone(),
         boot,
+        mounts: Vec::new(),
         kargs,
         skip_finalize,
     };
@@ -2695,7 +2959,10 @@ pub(crate) async fn install_to_filesystem(
     Ok(())
 }
 
-pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) -> Result<()> {
+pub(crate) async fn install_to_existing_root(
+    opts: InstallToExistingRootOpts,
+    prog: ProgressWriter,
+) -> Result<()> {
     // Log the existing root installation operation to systemd journal
     const INSTALL_EXISTING_ROOT_JOURNAL_ID: &str = "7c6d5e4f3a2b1c0d9e8f7a6b5c4d3e2f1";
     let source_image = opts
@@ -2740,18 +3007,19 @@ pub(crate) async fn install_to_existing_root(opts: InstallToExistingRootOpts) ->
         composefs_opts: opts.composefs_opts,
     };
 
-    install_to_filesystem(opts, true, cleanup).await
+    install_to_filesystem(opts, true, cleanup, prog).await
 }
 
-/// Read the /boot entry from /etc/fstab, if it exists
-fn read_boot_fstab_entry(root: &Dir) -> Result<Option<MountSpec>> {
+/// Read the entries of /etc/fstab, if it exists
+pub(crate) fn read_fstab(root: &Dir) -> Result<Vec<MountSpec>> {
     let fstab_path = "etc/fstab";
     let fstab = match root.open_optional(fstab_path)? {
         Some(f) => f,
-        None => return Ok(None),
+        None => return Ok(Vec::new()),
     };
 
     let reader = std::io::BufReader::new(fstab);
+    let mut r = Vec::new();
     for line in std::io::BufRead::lines(reader) {
         let line = line?;
         let line = line.trim();
@@ -2761,26 +3029,50 @@ fn read_boot_fstab_entry(root: &Dir) -> Result<Option<MountSpec>> {
             continue;
         }
 
-        // Parse the mount spec
-        let spec = MountSpec::from_str(line)?;
-
-        // Check if this is a /boot entry
-        if spec.target == "/boot" {
-            return Ok(Some(spec));
-        }
+        r.push(MountSpec::from_str(line)?);
     }
 
-    Ok(None)
+    Ok(r)
+}
+
+/// Read the /boot entry from /etc/fstab, if it exists
+fn read_boot_fstab_entry(root: &Dir) -> Result<Option<MountSpec>> {
+    Ok(read_fstab(root)?
+        .into_iter()
+        .find(|spec| spec.target == "/boot"))
+}
+
+/// Copy the /boot entry from the /etc/fstab of `rootfs`, if any, to the
+/// /etc/fstab of the staged deployment.
+pub(crate) fn copy_boot_fstab_entry(rootfs: &Dir, ostree: &ostree::Sysroot) -> Result<()> {
+    let Some(boot_spec) = read_boot_fstab_entry(rootfs)? else {
+        return Ok(());
+    };
+    let staged_deployment = ostree
+        .staged_deployment()
+        .ok_or_else(|| anyhow!("No staged deployment found"))?;
+    let deployment_path = ostree.deployment_dirpath(&staged_deployment);
+    let sysroot_dir = crate::utils::sysroot_dir(ostree)?;
+    let deployment_root = sysroot_dir.open_dir(&deployment_path)?;
+
+    // Write the /boot entry to /etc/fstab in the new deployment
+    crate::lsm::atomic_replace_labeled(&deployment_root, "etc/fstab", 0o644.into(), None, |w| {
+        writeln!(w, "{}", boot_spec.to_fstab()).map_err(Into::into)
+    })?;
+
+    tracing::debug!(
+        "Copied /boot entry to new stateroot: {}",
+        boot_spec.to_fstab()
+    );
+    Ok(())
 }
 
-pub(crate) async fn install_reset(opts: InstallResetOpts) -> Result<()> {
+pub(crate) async fn install_reset(opts: InstallResetOpts, prog: ProgressWriter) -> Result<()> {
     let rootfs = &Dir::open_ambient_dir("/", cap_std::ambient_authority())?;
     if !opts.experimental {
         anyhow::bail!("This command requires --experimental");
     }
 
-    let prog: ProgressWriter = opts.progress.try_into()?;
-
     let sysroot = &crate::cli::get_storage().await?;
     let ostree = sysroot.get_ostree()?;
     let repo = &ostree.repo();
@@ -2824,7 +3116,10 @@ pub(crate) async fn install_reset(opts: InstallResetOpts) -> Result<()> {
 
     // Compute the kernel arguments to inherit. By default, that's only those involved
     // in the root filesystem.
-    let mut kargs = crate::bootc_kargs::get_kargs_in_root(rootfs, std::env::consts::ARCH)?;
+    let mut kargs = crate::bootc_kargs::get_kargs_in_root(
+        rootfs,
+        crate::bootc_kargs::KargsMatchContext::host()?,
+    )?;
 
     // Extend with root kargs
     if !opts.no_root_kargs {
@@ -2853,28 +3148,7 @@ pub(crate) async fn install_reset(opts: InstallResetOpts) -> Result<()> {
     crate::deploy::stage(sysroot, from, &fetched, &spec, prog.clone(), false).await?;
 
     // Copy /boot entry from /etc/fstab to the new stateroot if it exists
-    if let Some(boot_spec) = read_boot_fstab_entry(rootfs)? {
-        let staged_deployment = ostree
-            .staged_deployment()
-            .ok_or_else(|| anyhow!("No staged deployment found"))?;
-        let deployment_path = ostree.deployment_dirpath(&staged_deployment);
-        let sysroot_dir = crate::utils::sysroot_dir(ostree)?;
-        let deployment_root = sysroot_dir.open_dir(&deployment_path)?;
-
-        // Write the /boot entry to /etc/fstab in the new deployment
-        crate::lsm::atomic_replace_labeled(
-            &deployment_root,
-            "etc/fstab",
-            0o644.into(),
-            None,
-            |w| writeln!(w, "{}", boot_spec.to_fstab()).map_err(Into::into),
-        )?;
-
-        tracing::debug!(
-            "Copied /boot entry to new stateroot: {}",
-            boot_spec.to_fstab()
-        );
-    }
+    copy_boot_fstab_entry(rootfs, ostree)?;
 
     sysroot.update_mtime()?;
 
@@ -2940,6 +3214,10 @@ mod tests {
         assert_eq!(ms.to_fstab(), "/dev/vda4 /boot auto ro 0 0");
         ms.push_option("relatime");
         assert_eq!(ms.to_fstab(), "/dev/vda4 /boot auto ro,relatime 0 0");
+        assert_eq!(
+            ms.to_mount_extra_karg(),
+            "systemd.mount-extra=/dev/vda4:/boot:auto:ro,relatime"
+        );
     }
 
     #[test]
@@ -1,14 +1,16 @@
 //! # The baseline installer
 //!
 //! This module handles creation of simple root filesystem setups.  At the current time
-//! it's very simple - just a direct filesystem (e.g. xfs, ext4, btrfs etc.).  It is
-//! intended to add opinionated handling of TPM2-bound LUKS too.  But that's about it;
-//! other more complex flows should set things up externally and use `bootc install to-filesystem`.
+//! it's very simple - a direct filesystem (e.g. xfs, ext4, btrfs etc.), optionally
+//! wrapped in LUKS unlocked via a passphrase, the TPM2, or a FIDO2/PKCS#11 token.
+//! But that's about it; other more complex flows should set things up externally
+//! and use `bootc install to-filesystem`.
 
 use std::borrow::Cow;
 use std::fmt::Display;
 use std::fmt::Write as _;
 use std::io::Write;
+use std::os::unix::fs::MetadataExt;
 use std::process::Command;
 use std::process::Stdio;
 
@@ -28,6 +30,7 @@ use super::RUN_BOOTC;
 use super::RW_KARG;
 use super::RootSetup;
 use super::State;
+use super::config::ExtraPartition;
 use super::config::Filesystem;
 use crate::task::Task;
 use bootc_kernel_cmdline::utf8::Cmdline;
@@ -52,6 +55,9 @@ pub(crate) enum BlockSetup {
     #[default]
     Direct,
     Tpm2Luks,
+    LuksPassphrase,
+    Fido2Luks,
+    Pkcs11Luks,
 }
 
 impl Display for BlockSetup {
@@ -60,6 +66,21 @@ impl Display for BlockSetup {
     }
 }
 
+/// How to mirror the root filesystem across multiple devices
+#[derive(clap::ValueEnum, Default, Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum RaidSetup {
+    #[default]
+    Mdraid,
+    Btrfs,
+}
+
+impl Display for RaidSetup {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.to_possible_value().unwrap().get_name().fmt(f)
+    }
+}
+
 /// Options for installing to a block device
 #[derive(Debug, Clone, clap::Args, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
@@ -76,9 +97,50 @@ pub(crate) struct InstallBlockDeviceOpts {
     ///
     /// direct: Filesystem written directly to block device
     /// tpm2-luks: Bind unlock of filesystem to presence of the default tpm2 device.
+    /// luks-passphrase: Unlock the filesystem with a passphrase entered at boot.
+    /// fido2-luks: Bind unlock of filesystem to the default FIDO2 security token.
+    /// pkcs11-luks: Bind unlock of filesystem to the default PKCS#11 security token.
     #[clap(long, value_enum)]
     pub(crate) block_setup: Option<BlockSetup>,
 
+    /// Read the passphrase for the luks-passphrase block setup from this file.
+    ///
+    /// A single trailing newline is ignored.
+    #[clap(long, conflicts_with = "luks_passphrase_credential")]
+    pub(crate) luks_passphrase_file: Option<Utf8PathBuf>,
+
+    /// Read the passphrase for the luks-passphrase block setup from this systemd
+    /// credential, looked up in `$CREDENTIALS_DIRECTORY`.
+    #[clap(long)]
+    pub(crate) luks_passphrase_credential: Option<String>,
+
+    /// Also enroll a recovery key for the tpm2-luks, fido2-luks and pkcs11-luks
+    /// block setups, and print it.
+    #[clap(long)]
+    #[serde(default)]
+    pub(crate) luks_recovery_key: bool,
+
+    /// Also enroll a recovery key for the tpm2-luks, fido2-luks and pkcs11-luks
+    /// block setups, and write it to this file instead of printing it.
+    #[clap(long)]
+    pub(crate) luks_recovery_key_file: Option<Utf8PathBuf>,
+
+    /// Additional block device to mirror the installation to (RAID1); may be specified
+    /// multiple times.
+    ///
+    /// The partition layout is replicated to every device, each device gets its own
+    /// copy of the ESP, and the bootloader is installed to each of them.
+    #[clap(long = "mirror", value_name = "DEVICE")]
+    #[serde(default)]
+    pub(crate) mirror: Vec<Utf8PathBuf>,
+
+    /// How to mirror the root filesystem when `--mirror` is used; defaults to mdraid.
+    ///
+    /// mdraid: Linux software RAID1 via mdadm
+    /// btrfs: btrfs native raid1; requires the btrfs filesystem.
+    #[clap(long, value_enum, requires = "mirror")]
+    pub(crate) raid: Option<RaidSetup>,
+
     /// Target root filesystem type.
     #[clap(long, value_enum)]
     pub(crate) filesystem: Option<Filesystem>,
@@ -95,9 +157,186 @@ impl BlockSetup {
     pub(crate) fn requires_bootpart(&self) -> bool {
         match self {
             BlockSetup::Direct => false,
-            BlockSetup::Tpm2Luks => true,
+            BlockSetup::Tpm2Luks
+            | BlockSetup::LuksPassphrase
+            | BlockSetup::Fido2Luks
+            | BlockSetup::Pkcs11Luks => true,
+        }
+    }
+
+    /// For block setups which bind unlocking LUKS to a device, returns a description
+    /// of the device and the `systemd-cryptenroll` argument to enroll it.
+    fn luks_token(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            BlockSetup::Direct | BlockSetup::LuksPassphrase => None,
+            BlockSetup::Tpm2Luks => Some(("TPM", "--tpm2-device=auto")),
+            BlockSetup::Fido2Luks => Some(("FIDO2 token", "--fido2-device=auto")),
+            BlockSetup::Pkcs11Luks => Some(("PKCS#11 token", "--pkcs11-token-uri=auto")),
+        }
+    }
+
+    /// The crypttab options (also used for `luks.options`) to unlock the LUKS device.
+    fn luks_options(&self) -> &'static [&'static str] {
+        match self {
+            BlockSetup::Direct | BlockSetup::LuksPassphrase => &[],
+            BlockSetup::Tpm2Luks => &["tpm2-device=auto", "headless=true"],
+            BlockSetup::Fido2Luks => &["fido2-device=auto"],
+            BlockSetup::Pkcs11Luks => &["pkcs11-uri=auto"],
+        }
+    }
+}
+
+impl InstallBlockDeviceOpts {
+    fn wants_luks_recovery_key(&self) -> bool {
+        self.luks_recovery_key || self.luks_recovery_key_file.is_some()
+    }
+
+    /// Verify the LUKS options make sense for the chosen block setup.
+    fn validate_luks_opts(&self, block_setup: BlockSetup) -> Result<()> {
+        let has_passphrase =
+            self.luks_passphrase_file.is_some() || self.luks_passphrase_credential.is_some();
+        match block_setup {
+            BlockSetup::LuksPassphrase if !has_passphrase => {
+                anyhow::bail!(
+                    "Block setup {block_setup} requires --luks-passphrase-file or --luks-passphrase-credential"
+                )
+            }
+            BlockSetup::LuksPassphrase => {}
+            _ if has_passphrase => {
+                anyhow::bail!("A LUKS passphrase is not supported with block setup {block_setup}")
+            }
+            _ => {}
+        }
+        if self.wants_luks_recovery_key() && block_setup.luks_token().is_none() {
+            anyhow::bail!("A LUKS recovery key is not supported with block setup {block_setup}");
+        }
+        Ok(())
+    }
+
+    /// Verify the mirroring options, returning how to mirror the root, if at all.
+    fn validate_mirror(
+        &self,
+        block_setup: BlockSetup,
+        root_filesystem: Filesystem,
+        has_extra_partitions: bool,
+        composefs_backend: bool,
+    ) -> Result<Option<RaidSetup>> {
+        if self.mirror.is_empty() {
+            return Ok(None);
+        }
+        if composefs_backend {
+            anyhow::bail!("Mirroring (--mirror) is not supported with the composefs backend");
+        }
+        if has_extra_partitions {
+            anyhow::bail!(
+                "Mirroring (--mirror) is not supported with additional partitions from [install.partitions]"
+            );
+        }
+        let raid = self.raid.unwrap_or_default();
+        if raid == RaidSetup::Btrfs {
+            if root_filesystem != Filesystem::Btrfs {
+                anyhow::bail!("RAID setup {raid} requires the btrfs filesystem");
+            }
+            if block_setup != BlockSetup::Direct {
+                anyhow::bail!("RAID setup {raid} is not supported with block setup {block_setup}");
+            }
+        }
+        Ok(Some(raid))
+    }
+
+    /// Read the passphrase for [`BlockSetup::LuksPassphrase`].
+    #[context("Reading LUKS passphrase")]
+    fn read_luks_passphrase(&self) -> Result<String> {
+        let path = if let Some(path) = self.luks_passphrase_file.as_ref() {
+            path.clone()
+        } else if let Some(name) = self.luks_passphrase_credential.as_deref() {
+            let dir = std::env::var("CREDENTIALS_DIRECTORY")
+                .context("Looking up credential: CREDENTIALS_DIRECTORY is not set")?;
+            Utf8Path::new(&dir).join(name)
+        } else {
+            anyhow::bail!("No LUKS passphrase specified");
+        };
+        let passphrase =
+            std::fs::read_to_string(&path).with_context(|| format!("Reading {path}"))?;
+        let passphrase = passphrase.strip_suffix('\n').unwrap_or(&passphrase);
+        if passphrase.is_empty() {
+            anyhow::bail!("Empty LUKS passphrase in {path}");
         }
+        Ok(passphrase.to_owned())
+    }
+}
+
+/// Compute the kernel arguments and the `/etc/crypttab` entry to unlock
+/// the LUKS device with the provided UUID.
+fn luks_unlock_config(block_setup: BlockSetup, name: &str, uuid: &str) -> (Vec<String>, String) {
+    let options = block_setup.luks_options();
+    let mut kargs = vec![format!("luks.uuid={uuid}")];
+    if !options.is_empty() {
+        kargs.push(format!("luks.options={}", options.join(",")));
+    }
+    let crypttab_options = std::iter::once("luks")
+        .chain(options.iter().copied())
+        .collect::<Vec<_>>()
+        .join(",");
+    let crypttab = format!("{name} UUID={uuid} none {crypttab_options}");
+    (kargs, crypttab)
+}
+
+/// Add `entry` to the `existing` contents of `/etc/crypttab`, keeping the entries
+/// for other devices. An existing entry for the same device name is an error.
+pub(crate) fn merge_crypttab(existing: Option<&str>, entry: &str) -> Result<String> {
+    let name = entry.split_whitespace().next().unwrap_or_default();
+    let mut contents = existing.unwrap_or_default().to_owned();
+    for line in contents.lines() {
+        let line = line.trim_start();
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.split_whitespace().next() == Some(name) {
+            anyhow::bail!("/etc/crypttab in the image already has an entry for {name}");
+        }
+    }
+    if !contents.is_empty() && !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    writeln!(contents, "{entry}")?;
+    Ok(contents)
+}
+
+/// Enroll a recovery key for the LUKS device, and print it or write it to `dest`.
+#[context("Enrolling LUKS recovery key")]
+fn enroll_luks_recovery_key(
+    devpath: &str,
+    keyfile: &std::path::Path,
+    dest: Option<&Utf8Path>,
+) -> Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut cmd = Command::new("systemd-cryptenroll");
+    cmd.args(["--recovery-key", "--unlock-key-file"])
+        .arg(keyfile)
+        .arg(devpath);
+    cmd.stdin(Stdio::null());
+    let key = Task::new_cmd("Enrolling root device recovery key", cmd).read()?;
+    let key = key.trim();
+    if key.is_empty() {
+        anyhow::bail!("systemd-cryptenroll did not output a recovery key");
     }
+    if let Some(dest) = dest {
+        let mut f = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(dest)
+            .with_context(|| format!("Creating {dest}"))?;
+        writeln!(f, "{key}")?;
+        f.sync_all()?;
+        println!("Wrote LUKS recovery key to {dest}");
+    } else {
+        println!("LUKS recovery key for root device: {key}");
+        println!("Store this key securely; it can be used to unlock the root device.");
+    }
+    Ok(())
 }
 
 #[cfg(feature = "install-to-disk")]
@@ -165,24 +404,41 @@ pub(crate) fn udev_settle() -> Result<()> {
     Ok(())
 }
 
-#[context("Creating rootfs")]
-#[cfg(feature = "install-to-disk")]
-pub(crate) fn install_create_rootfs(
-    state: &State,
-    opts: InstallBlockDeviceOpts,
-) -> Result<RootSetup> {
-    let install_config = state.install_config.as_ref();
-    let luks_name = "root";
-    // Ensure we have a root filesystem upfront
-    let root_filesystem = opts
-        .filesystem
-        .or(install_config
-            .and_then(|c| c.filesystem_root())
-            .and_then(|r| r.fstype))
-        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
+/// Generate the sfdisk input for an additional partition.
+fn extra_partition_spec(part: &ExtraPartition) -> Result<String> {
+    let size = part
+        .size_mib()?
+        .map(|v| Cow::Owned(format!("size={v}MiB, ")))
+        .unwrap_or_else(|| Cow::Borrowed(""));
+    let parttype = part.parttype_guid()?;
+    let name = part.name();
+    Ok(format!(r#"{size}type={parttype}, name="{name}""#))
+}
+
+/// Verify that no device is specified more than once, including via different
+/// paths (e.g. a `/dev/disk/by-id` link) to the same device node.
+fn check_distinct_devices<'a>(devices: impl IntoIterator<Item = &'a Utf8PathBuf>) -> Result<()> {
+    let mut seen = std::collections::HashMap::new();
+    for dev in devices {
+        let rdev = std::fs::metadata(dev)
+            .with_context(|| format!("Querying {dev}"))?
+            .rdev();
+        if let Some(other) = seen.insert(rdev, dev) {
+            if other == dev {
+                anyhow::bail!("Device {dev} specified multiple times");
+            }
+            anyhow::bail!("Devices {other} and {dev} are the same");
+        }
+    }
+    Ok(())
+}
+
+/// Verify that the target device is not in use and is empty, wiping it if requested.
+#[context("Preparing {path}")]
+fn prepare_target_device(path: &Utf8Path, wipe: bool) -> Result<bootc_blockdev::Device> {
     // Verify that the target is empty (if not already wiped in particular, but it's
     // also good to verify that the wipe worked)
-    let mut device = bootc_blockdev::list_dev(&opts.device)?;
+    let device = bootc_blockdev::list_dev(path)?;
 
     // Always disallow writing to mounted device
     if is_mounted_in_pid1_mountns(&device.path())? {
@@ -190,27 +446,79 @@ pub(crate) fn install_create_rootfs(
     }
 
     // Handle wiping any existing data
-    if opts.wipe {
-        let dev = &opts.device;
+    if wipe {
         for child in device.children.iter().flatten() {
             let child = child.path();
             println!("Wiping {child}");
             wipefs(Utf8Path::new(&child))?;
         }
-        println!("Wiping {dev}");
-        wipefs(dev)?;
+        println!("Wiping {path}");
+        wipefs(path)?;
     } else if device.has_children() {
         anyhow::bail!(
-            "Detected existing partitions on {}; use e.g. `wipefs` or --wipe if you intend to overwrite",
-            opts.device
+            "Detected existing partitions on {path}; use e.g. `wipefs` or --wipe if you intend to overwrite",
         );
     }
+    Ok(device)
+}
 
-    let run_bootc = Utf8Path::new(RUN_BOOTC);
-    let mntdir = run_bootc.join("mounts");
-    if mntdir.exists() {
-        std::fs::remove_dir_all(&mntdir)?;
+/// Create a RAID1 mdraid array from the provided members, returning its
+/// device path and UUID.
+#[context("Creating RAID1 array {name}")]
+fn create_md_raid1(name: &str, metadata: &str, members: &[String]) -> Result<(String, String)> {
+    let devpath = format!("/dev/md/{name}");
+    // The array is created from within the installation environment, so ensure
+    // its name isn't tied to the hostname here.
+    Task::new(format!("Creating RAID1 array {name}"), "mdadm")
+        .args([
+            "--create",
+            devpath.as_str(),
+            "--run",
+            "--level=1",
+            "--homehost=any",
+        ])
+        .arg(format!("--name={name}"))
+        .arg(format!("--metadata={metadata}"))
+        .arg(format!("--raid-devices={}", members.len()))
+        .args(members)
+        .verbose()
+        .run()?;
+    let detail = Task::new_quiet("mdadm")
+        .args(["--detail", "--export", devpath.as_str()])
+        .read()?;
+    let uuid = parse_md_uuid(&detail)?;
+    Ok((devpath, uuid))
+}
+
+/// Find the array UUID in the output of `mdadm --detail --export`.
+fn parse_md_uuid(detail: &str) -> Result<String> {
+    detail
+        .lines()
+        .find_map(|line| line.strip_prefix("MD_UUID="))
+        .map(|uuid| uuid.trim().to_owned())
+        .ok_or_else(|| anyhow::anyhow!("Missing MD_UUID in mdadm output"))
+}
+
+#[context("Creating rootfs")]
+#[cfg(feature = "install-to-disk")]
+pub(crate) fn install_create_rootfs(
+    state: &State,
+    opts: InstallBlockDeviceOpts,
+) -> Result<RootSetup> {
+    let install_config = state.install_config.as_ref();
+    let luks_name = "root";
+    // Ensure we have a root filesystem upfront
+    let root_filesystem = opts
+        .filesystem
+        .or(install_config
+            .and_then(|c| c.filesystem_root())
+            .and_then(|r| r.fstype))
+        .ok_or_else(|| anyhow::anyhow!("No root filesystem specified"))?;
+    let partitions = install_config.and_then(|c| c.partitions.as_ref());
+    if let Some(partitions) = partitions {
+        partitions.validate(opts.root_size.is_some())?;
     }
+    let extra_partitions = partitions.map(|p| p.extra()).unwrap_or_default();
 
     // Use the install configuration to find the block setup, if we have one
     let block_setup = if let Some(config) = install_config {
@@ -224,12 +532,49 @@ pub(crate) fn install_create_rootfs(
         // and we need to error out.
         anyhow::bail!("No install configuration found, and no filesystem specified")
     };
+    opts.validate_luks_opts(block_setup)?;
+    // Additional filesystems are created unencrypted, so refuse to mix them with LUKS
+    if !extra_partitions.is_empty() && block_setup != BlockSetup::Direct {
+        anyhow::bail!("Additional partitions are not supported with block setup {block_setup}");
+    }
+    // Read the passphrase upfront so we fail before touching the disk
+    let luks_passphrase = if block_setup == BlockSetup::LuksPassphrase {
+        Some(opts.read_luks_passphrase()?)
+    } else {
+        None
+    };
+    let raid = opts.validate_mirror(
+        block_setup,
+        root_filesystem,
+        !extra_partitions.is_empty(),
+        state.composefs_options.composefs_backend,
+    )?;
+
+    check_distinct_devices(std::iter::once(&opts.device).chain(opts.mirror.iter()))?;
+
+    let mut device = prepare_target_device(&opts.device, opts.wipe)?;
+    let mut mirrors = opts
+        .mirror
+        .iter()
+        .map(|dev| prepare_target_device(dev, opts.wipe))
+        .collect::<Result<Vec<_>>>()?;
+
+    let run_bootc = Utf8Path::new(RUN_BOOTC);
+    let mntdir = run_bootc.join("mounts");
+    if mntdir.exists() {
+        std::fs::remove_dir_all(&mntdir)?;
+    }
+
     let serial = device.serial.as_deref().unwrap_or("<unknown>");
     let model = device.model.as_deref().unwrap_or("<unknown>");
     println!("Block setup: {block_setup}");
     println!("       Size: {}", device.size);
     println!("     Serial: {serial}");
     println!("      Model: {model}");
+    if let Some(raid) = raid {
+        let mirrors = opts.mirror.iter().map(|d| d.as_str()).collect::<Vec<_>>();
+        println!("     Mirror: {} ({raid})", mirrors.join(", "));
+    }
 
     let root_size = opts
         .root_size
@@ -249,12 +594,10 @@ pub(crate) fn install_create_rootfs(
     let bootfs = mntdir.join("boot");
     std::fs::create_dir_all(bootfs)?;
 
-    // Generate partitioning spec as input to sfdisk
+    // Generate partitioning spec as input to sfdisk; the same layout is used for
+    // every device when mirroring.
     let mut partno = 0;
     let mut partitioning_buf = String::new();
-    writeln!(partitioning_buf, "label: gpt")?;
-    let random_label = uuid::Uuid::new_v4();
-    writeln!(&mut partitioning_buf, "label-id: {random_label}")?;
     if cfg!(target_arch = "x86_64") {
         partno += 1;
         writeln!(
@@ -280,11 +623,16 @@ pub(crate) fn install_create_rootfs(
         let esp_guid = crate::discoverable_partition_specification::ESP;
         partno += 1;
 
-        let esp_size = if state.composefs_options.composefs_backend {
+        let default_esp_size = if state.composefs_options.composefs_backend {
             CFS_EFIPN_SIZE_MB
         } else {
             EFIPN_SIZE_MB
         };
+        let esp_size = partitions
+            .map(|p| p.esp_size_mib())
+            .transpose()?
+            .flatten()
+            .unwrap_or(default_esp_size.into());
 
         writeln!(
             &mut partitioning_buf,
@@ -299,16 +647,29 @@ pub(crate) fn install_create_rootfs(
     // what systemd/uapi-group encourages and make /boot be FAT32 as well, as
     // it would aid systemd-boot.
     let boot_partno = if block_setup.requires_bootpart() {
+        let boot_size = partitions
+            .map(|p| p.boot_size_mib())
+            .transpose()?
+            .flatten()
+            .unwrap_or(BOOTPN_SIZE_MB.into());
         partno += 1;
-        writeln!(
-            &mut partitioning_buf,
-            r#"size={BOOTPN_SIZE_MB}MiB, name="boot""#
-        )?;
+        writeln!(&mut partitioning_buf, r#"size={boot_size}MiB, name="boot""#)?;
         Some(partno)
     } else {
         None
     };
-    let rootpn = partno + 1;
+    // Additional partitions with a fixed size go before the root, and the one
+    // using all remaining space (if any) goes last; this has been validated above.
+    let mut extra_partnos = Vec::new();
+    let (sized_partitions, unsized_partitions): (Vec<_>, Vec<_>) =
+        extra_partitions.iter().partition(|p| p.size.is_some());
+    for part in sized_partitions {
+        partno += 1;
+        writeln!(&mut partitioning_buf, "{}", extra_partition_spec(part)?)?;
+        extra_partnos.push((partno, part));
+    }
+    partno += 1;
+    let rootpn = partno;
     let root_size = root_size
         .map(|v| Cow::Owned(format!("size={v}MiB, ")))
         .unwrap_or_else(|| Cow::Borrowed(""));
@@ -318,13 +679,22 @@ pub(crate) fn install_create_rootfs(
         &mut partitioning_buf,
         r#"{root_size}type={rootpart_uuid}, name="root""#
     )?;
+    for part in unsized_partitions {
+        partno += 1;
+        writeln!(&mut partitioning_buf, "{}", extra_partition_spec(part)?)?;
+        extra_partnos.push((partno, part));
+    }
     tracing::debug!("Partitioning: {partitioning_buf}");
-    Task::new("Initializing partitions", "sfdisk")
-        .arg("--wipe=always")
-        .arg(device.path())
-        .quiet()
-        .run_with_stdin_buf(Some(partitioning_buf.as_bytes()))
-        .context("Failed to run sfdisk")?;
+    for device in std::iter::once(&device).chain(mirrors.iter()) {
+        let random_label = uuid::Uuid::new_v4();
+        let buf = format!("label: gpt\nlabel-id: {random_label}\n{partitioning_buf}");
+        Task::new("Initializing partitions", "sfdisk")
+            .arg("--wipe=always")
+            .arg(device.path())
+            .quiet()
+            .run_with_stdin_buf(Some(buf.as_bytes()))
+            .context("Failed to run sfdisk")?;
+    }
     tracing::debug!("Created partition table");
 
     // Full udev sync; it'd obviously be better to await just the devices
@@ -333,79 +703,139 @@ pub(crate) fn install_create_rootfs(
 
     // Re-read partition table to get updated children
     device.refresh()?;
+    for mirror in mirrors.iter_mut() {
+        mirror.refresh()?;
+    }
 
-    let root_device = device.find_device_by_partno(rootpn)?;
     // Verify the partition type matches the DPS root partition type for this architecture
     let expected_parttype = crate::discoverable_partition_specification::this_arch_root();
-    if !root_device
-        .parttype
-        .as_ref()
-        .is_some_and(|pt| pt.eq_ignore_ascii_case(expected_parttype))
-    {
-        anyhow::bail!(
-            "root partition {rootpn} has type {}; expected {expected_parttype}",
-            root_device.parttype.as_deref().unwrap_or("<none>")
-        );
+    let mut root_partitions = Vec::new();
+    for device in std::iter::once(&device).chain(mirrors.iter()) {
+        let root_device = device.find_device_by_partno(rootpn)?;
+        if !root_device
+            .parttype
+            .as_ref()
+            .is_some_and(|pt| pt.eq_ignore_ascii_case(expected_parttype))
+        {
+            anyhow::bail!(
+                "root partition {rootpn} has type {}; expected {expected_parttype}",
+                root_device.parttype.as_deref().unwrap_or("<none>")
+            );
+        }
+        root_partitions.push(root_device.path());
     }
-    let (rootdev_path, root_blockdev_kargs) = match block_setup {
-        BlockSetup::Direct => (root_device.path(), None),
-        BlockSetup::Tpm2Luks => {
+
+    // Set up mirroring of the root; for btrfs this is handled by mkfs instead.
+    let mut md_devices = Vec::new();
+    let mut raid_kargs = Vec::new();
+    let root_blockdev = if raid == Some(RaidSetup::Mdraid) {
+        let (md_device, md_uuid) = create_md_raid1("root", "1.2", &root_partitions)?;
+        md_devices.push(md_device.clone());
+        raid_kargs.push(format!("rd.md.uuid={md_uuid}"));
+        md_device
+    } else {
+        root_partitions[0].clone()
+    };
+
+    let (rootdev_path, root_blockdev_kargs, crypttab) = match block_setup {
+        BlockSetup::Direct => (root_blockdev, None, None),
+        BlockSetup::Tpm2Luks
+        | BlockSetup::LuksPassphrase
+        | BlockSetup::Fido2Luks
+        | BlockSetup::Pkcs11Luks => {
             let uuid = uuid::Uuid::new_v4().to_string();
-            // This will be replaced via --wipe-slot=all when binding to tpm below
-            let dummy_passphrase = uuid::Uuid::new_v4().to_string();
+            // For token-bound setups, this will be replaced via --wipe-slot when enrolling below
+            let passphrase = luks_passphrase
+                .clone()
+                .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
             let mut tmp_keyfile = tempfile::NamedTempFile::new()?;
-            tmp_keyfile.write_all(dummy_passphrase.as_bytes())?;
+            tmp_keyfile.write_all(passphrase.as_bytes())?;
             tmp_keyfile.flush()?;
             let tmp_keyfile = tmp_keyfile.path();
-            let dummy_passphrase_input = Some(dummy_passphrase.as_bytes());
+            // FIDO2 and PKCS#11 enrollment may need to prompt for a PIN, so only
+            // the TPM enrollment gets the passphrase on stdin.
+            let passphrase_input =
+                (block_setup == BlockSetup::Tpm2Luks).then_some(passphrase.as_bytes());
 
-            let root_devpath = root_device.path();
+            let root_devpath = root_blockdev;
 
             Task::new("Initializing LUKS for root", "cryptsetup")
                 .args(["luksFormat", "--uuid", uuid.as_str(), "--key-file"])
                 .args([tmp_keyfile])
                 .arg(&root_devpath)
                 .run()?;
-            // The --wipe-slot=all removes our temporary passphrase, and binds to the local TPM device.
-            // We also use .verbose() here as the details are important/notable.
-            Task::new("Enrolling root device with TPM", "systemd-cryptenroll")
-                .args(["--wipe-slot=all", "--tpm2-device=auto", "--unlock-key-file"])
+            // Open the device with the initial key, so that we don't need to
+            // interact with the token again.
+            Task::new("Opening root LUKS device", "cryptsetup")
+                .args(["luksOpen", "--key-file"])
+                .args([tmp_keyfile])
+                .args([root_devpath.as_str(), luks_name])
+                .run()?;
+            if let Some((token, enroll_arg)) = block_setup.luks_token() {
+                // The recovery key must be enrolled while the temporary passphrase still works
+                let wipe_slot = if opts.wants_luks_recovery_key() {
+                    enroll_luks_recovery_key(
+                        &root_devpath,
+                        tmp_keyfile,
+                        opts.luks_recovery_key_file.as_deref(),
+                    )?;
+                    "--wipe-slot=password"
+                } else {
+                    "--wipe-slot=all"
+                };
+                // The --wipe-slot removes our temporary passphrase, and binds to the token.
+                // We also use .verbose() here as the details are important/notable.
+                Task::new(
+                    format!("Enrolling root device with {token}"),
+                    "systemd-cryptenroll",
+                )
+                .args([wipe_slot, enroll_arg, "--unlock-key-file"])
                 .args([tmp_keyfile])
                 .arg(&root_devpath)
                 .verbose()
-                .run_with_stdin_buf(dummy_passphrase_input)?;
-            Task::new("Opening root LUKS device", "cryptsetup")
-                .args(["luksOpen", &root_devpath, luks_name])
-                .run()?;
+                .run_with_stdin_buf(passphrase_input)?;
+            }
             let rootdev = format!("/dev/mapper/{luks_name}");
-            let kargs = vec![
-                format!("luks.uuid={uuid}"),
-                format!("luks.options=tpm2-device=auto,headless=true"),
-            ];
-            (rootdev, Some(kargs))
+            let (kargs, crypttab) = luks_unlock_config(block_setup, luks_name, &uuid);
+            (rootdev, Some(kargs), Some(crypttab))
         }
     };
 
-    // Initialize the /boot filesystem
+    // Initialize the /boot filesystem, mirroring it too if needed
     let bootdev = if let Some(bootpn) = boot_partno {
-        Some(device.find_device_by_partno(bootpn)?)
+        let boot_partitions = std::iter::once(&device)
+            .chain(mirrors.iter())
+            .map(|d| d.find_device_by_partno(bootpn).map(|p| p.path()))
+            .collect::<Result<Vec<_>>>()?;
+        if raid == Some(RaidSetup::Mdraid) {
+            // Keep the RAID metadata at the end, so the bootloader can also
+            // read the filesystem from an individual member.
+            let (md_device, md_uuid) = create_md_raid1("boot", "1.0", &boot_partitions)?;
+            md_devices.push(md_device.clone());
+            raid_kargs.push(format!("rd.md.uuid={md_uuid}"));
+            Some(md_device)
+        } else {
+            boot_partitions.into_iter().next()
+        }
     } else {
         None
     };
-    let boot_uuid = if let Some(bootdev) = bootdev {
-        Some(
-            mkfs(&bootdev.path(), root_filesystem, "boot", opts.wipe, [])
-                .context("Initializing /boot")?,
-        )
+    let boot_uuid = if let Some(bootdev) = bootdev.as_deref() {
+        Some(mkfs(bootdev, root_filesystem, "boot", opts.wipe, []).context("Initializing /boot")?)
     } else {
         None
     };
 
+    let mut mkfs_options = Vec::new();
     // Unconditionally enable fsverity for ext4
-    let mkfs_options = match root_filesystem {
-        Filesystem::Ext4 => ["-O", "verity"].as_slice(),
-        _ => [].as_slice(),
-    };
+    if root_filesystem == Filesystem::Ext4 {
+        mkfs_options.extend(["-O", "verity"]);
+    }
+    // With btrfs, the mirroring is set up directly as part of the filesystem
+    if raid == Some(RaidSetup::Btrfs) {
+        mkfs_options.extend(["-d", "raid1", "-m", "raid1"]);
+        mkfs_options.extend(root_partitions.iter().skip(1).map(|p| p.as_str()));
+    }
 
     // Initialize rootfs
     let root_uuid = mkfs(
@@ -427,6 +857,11 @@ pub(crate) fn install_create_rootfs(
 
     let mut kargs = Cmdline::new();
 
+    // Add kargs to assemble the RAID arrays
+    for karg in raid_kargs {
+        kargs.extend(&Cmdline::from(karg.as_str()));
+    }
+
     // Add root blockdev kargs (e.g., LUKS parameters)
     if let Some(root_blockdev_kargs) = root_blockdev_kargs {
         for karg in root_blockdev_kargs {
@@ -456,37 +891,278 @@ pub(crate) fn install_create_rootfs(
     let bootfs = physical_root_path.join("boot");
     // Create the underlying mount point directory, which should be labeled
     crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
-    if let Some(bootdev) = bootdev {
-        bootc_mount::mount(&bootdev.path(), &bootfs)?;
+    if let Some(bootdev) = bootdev.as_deref() {
+        bootc_mount::mount(bootdev, &bootfs)?;
     }
     // And we want to label the root mount of /boot
     crate::lsm::ensure_dir_labeled(&target_rootfs, "boot", None, 0o755.into(), sepolicy)?;
 
-    // Create the EFI system partition, if applicable
+    // Create the EFI system partition, if applicable; when mirroring, each
+    // device gets its own copy.
     if let Some(esp_partno) = esp_partno {
-        let espdev = device.find_device_by_partno(esp_partno)?;
-        Task::new("Creating ESP filesystem", "mkfs.fat")
-            .args([&espdev.path(), "-n", "EFI-SYSTEM"])
-            .verbose()
-            .quiet_output()
-            .run()?;
+        for device in std::iter::once(&device).chain(mirrors.iter()) {
+            let espdev = device.find_device_by_partno(esp_partno)?;
+            Task::new("Creating ESP filesystem", "mkfs.fat")
+                .args([&espdev.path(), "-n", "EFI-SYSTEM"])
+                .verbose()
+                .quiet_output()
+                .run()?;
+        }
         let efifs_path = bootfs.join(crate::bootloader::EFI_DIR);
         std::fs::create_dir(&efifs_path).context("Creating efi dir")?;
     }
 
-    let luks_device = match block_setup {
-        BlockSetup::Direct => None,
-        BlockSetup::Tpm2Luks => Some(luks_name.to_string()),
-    };
+    // Initialize any additional filesystems
+    let mut mounts = Vec::new();
+    for (partno, part) in extra_partnos {
+        let dev = device.find_device_by_partno(partno)?.path();
+        let fs = part.filesystem.unwrap_or(root_filesystem);
+        // Truncate to the maximum label length of xfs
+        let label = part.name().chars().take(12).collect::<String>();
+        let uuid = mkfs(&dev, fs, &label, opts.wipe, [])
+            .with_context(|| format!("Initializing {}", part.mountpoint))?;
+        // Label the root of the new filesystem as its mount point
+        let mnt = bootc_mount::tempmount::TempMount::mount_dev(
+            &dev,
+            &fs.to_string(),
+            rustix::mount::MountFlags::empty(),
+            None,
+        )?;
+        crate::lsm::ensure_dir_labeled(
+            &mnt.fd,
+            "",
+            Some(part.mountpoint.as_path()),
+            0o755.into(),
+            sepolicy,
+        )?;
+        drop(mnt);
+        let mut mount = MountSpec::new_uuid_src(&uuid.to_string(), part.mountpoint.as_str());
+        mount.fstype = fs.to_string();
+        mount.options = part.options.clone();
+        mounts.push(mount);
+    }
+
+    let luks_device = crypttab.is_some().then(|| luks_name.to_string());
     Ok(RootSetup {
         luks_device,
+        crypttab,
+        md_devices,
+        block_setup: Some(block_setup),
         device_info: device,
+        mirror_devices: mirrors,
         physical_root_path,
         physical_root,
         target_root_path: None,
         rootfs_uuid: Some(root_uuid.to_string()),
         boot,
+        mounts,
         kargs,
         skip_finalize: false,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_luks_unlock_config() {
+        let uuid = "7c9e3d4a-0f7b-4a8e-9a43-6a1c2c5b2f10";
+        let cases = [
+            (
+                BlockSetup::Tpm2Luks,
+                &["luks.options=tpm2-device=auto,headless=true"][..],
+                "root UUID=7c9e3d4a-0f7b-4a8e-9a43-6a1c2c5b2f10 none luks,tpm2-device=auto,headless=true",
+            ),
+            (
+                BlockSetup::LuksPassphrase,
+                &[][..],
+                "root UUID=7c9e3d4a-0f7b-4a8e-9a43-6a1c2c5b2f10 none luks",
+            ),
+            (
+                BlockSetup::Fido2Luks,
+                &["luks.options=fido2-device=auto"][..],
+                "root UUID=7c9e3d4a-0f7b-4a8e-9a43-6a1c2c5b2f10 none luks,fido2-device=auto",
+            ),
+            (
+                BlockSetup::Pkcs11Luks,
+                &["luks.options=pkcs11-uri=auto"][..],
+                "root UUID=7c9e3d4a-0f7b-4a8e-9a43-6a1c2c5b2f10 none luks,pkcs11-uri=auto",
+            ),
+        ];
+        for (block_setup, options, expected_crypttab) in cases {
+            let (kargs, crypttab) = luks_unlock_config(block_setup, "root", uuid);
+            let expected_kargs = std::iter::once(format!("luks.uuid={uuid}"))
+                .chain(options.iter().map(|s| s.to_string()))
+                .collect::<Vec<_>>();
+            assert_eq!(kargs, expected_kargs, "{block_setup}");
+            assert_eq!(crypttab, expected_crypttab, "{block_setup}");
+        }
+    }
+
+    #[test]
+    fn test_merge_crypttab() -> Result<()> {
+        let entry = "root UUID=7c9e3d4a-0f7b-4a8e-9a43-6a1c2c5b2f10 none luks";
+        assert_eq!(merge_crypttab(None, entry)?, format!("{entry}\n"));
+        assert_eq!(merge_crypttab(Some(""), entry)?, format!("{entry}\n"));
+
+        let existing = "# root is added by the installer\ndata /dev/vdb1 none luks";
+        assert_eq!(
+            merge_crypttab(Some(existing), entry)?,
+            format!("{existing}\n{entry}\n")
+        );
+
+        let err = merge_crypttab(Some("data /dev/vdb1 none\n  root /dev/vdb2 none\n"), entry)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "/etc/crypttab in the image already has an entry for root"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_luks_opts() {
+        let base = InstallBlockDeviceOpts {
+            device: "/dev/vda".into(),
+            wipe: false,
+            block_setup: None,
+            luks_passphrase_file: None,
+            luks_passphrase_credential: None,
+            luks_recovery_key: false,
+            luks_recovery_key_file: None,
+            mirror: Vec::new(),
+            raid: None,
+            filesystem: None,
+            root_size: None,
+        };
+        let with_passphrase = InstallBlockDeviceOpts {
+            luks_passphrase_file: Some("/run/passphrase".into()),
+            ..base.clone()
+        };
+        let with_recovery = InstallBlockDeviceOpts {
+            luks_recovery_key_file: Some("/run/recovery-key".into()),
+            ..base.clone()
+        };
+        let cases = [
+            (&base, BlockSetup::Direct, true),
+            (&base, BlockSetup::Tpm2Luks, true),
+            (&base, BlockSetup::LuksPassphrase, false),
+            (&with_passphrase, BlockSetup::LuksPassphrase, true),
+            (&with_passphrase, BlockSetup::Direct, false),
+            (&with_passphrase, BlockSetup::Fido2Luks, false),
+            (&with_recovery, BlockSetup::Tpm2Luks, true),
+            (&with_recovery, BlockSetup::Pkcs11Luks, true),
+            (&with_recovery, BlockSetup::LuksPassphrase, false),
+            (&with_recovery, BlockSetup::Direct, false),
+        ];
+        for (opts, block_setup, ok) in cases {
+            assert_eq!(
+                opts.validate_luks_opts(block_setup).is_ok(),
+                ok,
+                "{block_setup}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_validate_mirror() {
+        let single = InstallBlockDeviceOpts {
+            device: "/dev/vda".into(),
+            wipe: false,
+            block_setup: None,
+            luks_passphrase_file: None,
+            luks_passphrase_credential: None,
+            luks_recovery_key: false,
+            luks_recovery_key_file: None,
+            mirror: Vec::new(),
+            raid: None,
+            filesystem: None,
+            root_size: None,
+        };
+        let mirrored = InstallBlockDeviceOpts {
+            mirror: vec!["/dev/vdb".into()],
+            ..single.clone()
+        };
+        let btrfs = InstallBlockDeviceOpts {
+            raid: Some(RaidSetup::Btrfs),
+            ..mirrored.clone()
+        };
+        let direct = BlockSetup::Direct;
+        let tpm2 = BlockSetup::Tpm2Luks;
+        let (xfs, fs_btrfs) = (Filesystem::Xfs, Filesystem::Btrfs);
+        let cases = [
+            (&single, direct, xfs, false, false, Some(None)),
+            (&single, tpm2, xfs, true, true, Some(None)),
+            (
+                &mirrored,
+                direct,
+                xfs,
+                false,
+                false,
+                Some(Some(RaidSetup::Mdraid)),
+            ),
+            (
+                &mirrored,
+                tpm2,
+                xfs,
+                false,
+                false,
+                Some(Some(RaidSetup::Mdraid)),
+            ),
+            (&mirrored, direct, xfs, true, false, None),
+            (&mirrored, direct, xfs, false, true, None),
+            (
+                &btrfs,
+                direct,
+                fs_btrfs,
+                false,
+                false,
+                Some(Some(RaidSetup::Btrfs)),
+            ),
+            (&btrfs, direct, xfs, false, false, None),
+            (&btrfs, tpm2, fs_btrfs, false, false, None),
+        ];
+        for (opts, block_setup, fs, extra, composefs, expected) in cases {
+            let r = opts.validate_mirror(block_setup, fs, extra, composefs);
+            assert_eq!(r.ok(), expected, "{opts:?} {block_setup} {fs}");
+        }
+    }
+
+    #[test]
+    fn test_check_distinct_devices() -> Result<()> {
+        let td = tempfile::tempdir()?;
+        let link = Utf8PathBuf::try_from(td.path().join("null"))?;
+        std::os::unix::fs::symlink("/dev/null", &link)?;
+        let (null, zero) = (
+            Utf8PathBuf::from("/dev/null"),
+            Utf8PathBuf::from("/dev/zero"),
+        );
+
+        check_distinct_devices([&null, &zero])?;
+        let err = check_distinct_devices([&null, &zero, &null]).unwrap_err();
+        assert_eq!(err.to_string(), "Device /dev/null specified multiple times");
+        let err = check_distinct_devices([&null, &link]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!("Devices /dev/null and {link} are the same")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_md_uuid() {
+        let detail = indoc::indoc! { "
+            MD_LEVEL=raid1
+            MD_DEVICES=2
+            MD_METADATA=1.2
+            MD_UUID=3f7c2a1b:9e8d4c6f:a1b2c3d4:e5f60718
+            MD_DEVNAME=root
+        " };
+        assert_eq!(
+            parse_md_uuid(detail).unwrap(),
+            "3f7c2a1b:9e8d4c6f:a1b2c3d4:e5f60718"
+        );
+        assert!(parse_md_uuid("MD_LEVEL=raid1\n").is_err());
+    }
+}
@@ -66,7 +66,10 @@ fn reconcile_kargs(sysroot: &ostree::Sysroot, deployment: &ostree::Deployment) -
         .flatten()
         .map(|s| s.as_str())
         .collect::<Vec<_>>();
-    let kargsd = crate::bootc_kargs::get_kargs_in_root(deployment_root, std::env::consts::ARCH)?;
+    let kargsd = crate::bootc_kargs::get_kargs_in_root(
+        deployment_root,
+        crate::bootc_kargs::KargsMatchContext::host()?,
+    )?;
     let kargsd_strs = kargsd.iter_str().collect::<Vec<_>>();
 
     current_kargs.append_argv(&install_config_kargs);
@@ -316,9 +319,10 @@ pub(crate) async fn impl_completion(
         // When we're run through ostree, we only lazily initialize the podman storage to avoid
         // having a hard dependency on it.
         let imgstorage = CStorage::create(&sysroot_dir, &rundir, sepolicy.as_ref())?;
-        crate::boundimage::pull_images_impl(&imgstorage, bound_images)
+        let resolved = crate::boundimage::pull_images_impl(&imgstorage, bound_images)
             .await
             .context("pulling bound images")?;
+        crate::boundimage::write_report(sysroot, deployment, &resolved)?;
         // Ensure the image storage is SELinux-labeled after all pulls are complete.
         imgstorage.ensure_labeled()?;
     }
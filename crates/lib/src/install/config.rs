@@ -4,6 +4,8 @@
 
 use crate::spec::Bootloader;
 use anyhow::{Context, Result};
+#[cfg(feature = "install-to-disk")]
+use camino::Utf8PathBuf;
 use clap::ValueEnum;
 use fn_error_context::context;
 use serde::{Deserialize, Serialize};
@@ -73,9 +75,165 @@ pub(crate) struct RootFS {
 #[serde(deny_unknown_fields)]
 pub(crate) struct BasicFilesystems {
     pub(crate) root: Option<RootFS>,
-    // TODO allow configuration of these other filesystems too
-    // pub(crate) xbootldr: Option<FilesystemCustomization>,
-    // pub(crate) esp: Option<FilesystemCustomization>,
+    // The sizes of the ESP and /boot, as well as additional partitions,
+    // are configured for `install to-disk` via `[install.partitions]`.
+}
+
+/// An additional partition created by `install to-disk`, e.g. a separate `/var`.
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct ExtraPartition {
+    /// The absolute path where the filesystem will be mounted
+    pub(crate) mountpoint: Utf8PathBuf,
+    /// Size of the partition (e.g. `20G`); if unset, all remaining space is used
+    pub(crate) size: Option<String>,
+    /// Filesystem type; defaults to the root filesystem type
+    pub(crate) filesystem: Option<Filesystem>,
+    /// Mount options
+    pub(crate) options: Option<String>,
+    /// The partition type; a name from the Discoverable Partitions Specification
+    /// (`var`, `tmp`, `home`, `srv`, `linux-data`) or a GUID.  Defaults based on the mount point.
+    #[serde(rename = "type")]
+    pub(crate) parttype: Option<String>,
+}
+
+/// The partition layout used by `install to-disk`
+#[cfg(feature = "install-to-disk")]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub(crate) struct Partitions {
+    /// Size of the EFI system partition
+    pub(crate) esp_size: Option<String>,
+    /// Size of the /boot partition, if one is required by the block setup
+    pub(crate) boot_size: Option<String>,
+    /// Additional partitions
+    pub(crate) extra: Option<Vec<ExtraPartition>>,
+}
+
+#[cfg(feature = "install-to-disk")]
+fn parse_partition_size(size: Option<&str>) -> Result<Option<u64>> {
+    size.map(|size| {
+        let v =
+            bootc_blockdev::parse_size_mib(size).with_context(|| format!("Parsing size {size}"))?;
+        if v == 0 {
+            anyhow::bail!("Invalid size: {size}");
+        }
+        Ok(v)
+    })
+    .transpose()
+}
+
+#[cfg(feature = "install-to-disk")]
+impl ExtraPartition {
+    /// Size of the partition in MiB, or `None` if it uses all remaining space
+    pub(crate) fn size_mib(&self) -> Result<Option<u64>> {
+        parse_partition_size(self.size.as_deref())
+    }
+
+    /// The partition name (also used as filesystem label), derived from the mount point
+    pub(crate) fn name(&self) -> &str {
+        self.mountpoint.file_name().unwrap_or("data")
+    }
+
+    /// The partition type GUID
+    pub(crate) fn parttype_guid(&self) -> Result<&str> {
+        use crate::discoverable_partition_specification as dps;
+        let Some(parttype) = self.parttype.as_deref() else {
+            let defaults = [
+                ("/var", dps::VAR),
+                ("/var/tmp", dps::TMP),
+                ("/home", dps::HOME),
+                ("/srv", dps::SRV),
+            ];
+            let guid = defaults
+                .into_iter()
+                .find(|(path, _)| self.mountpoint == camino::Utf8Path::new(path))
+                .map(|(_, guid)| guid)
+                .unwrap_or(dps::LINUX_DATA);
+            return Ok(guid);
+        };
+        let guid = match parttype {
+            "var" => dps::VAR,
+            "tmp" => dps::TMP,
+            "home" => dps::HOME,
+            "srv" => dps::SRV,
+            "linux-data" => dps::LINUX_DATA,
+            o => {
+                uuid::Uuid::parse_str(o).with_context(|| format!("Invalid partition type: {o}"))?;
+                o
+            }
+        };
+        Ok(guid)
+    }
+}
+
+#[cfg(feature = "install-to-disk")]
+impl Partitions {
+    /// Size of the EFI system partition in MiB, if configured
+    pub(crate) fn esp_size_mib(&self) -> Result<Option<u64>> {
+        parse_partition_size(self.esp_size.as_deref())
+    }
+
+    /// Size of the /boot partition in MiB, if configured
+    pub(crate) fn boot_size_mib(&self) -> Result<Option<u64>> {
+        parse_partition_size(self.boot_size.as_deref())
+    }
+
+    /// The additional partitions
+    pub(crate) fn extra(&self) -> &[ExtraPartition] {
+        self.extra.as_deref().unwrap_or_default()
+    }
+
+    /// Verify the partition layout; `root_sized` should be true if the
+    /// root partition has a fixed size.
+    #[context("Validating install.partitions")]
+    pub(crate) fn validate(&self, root_sized: bool) -> Result<()> {
+        // These are set up by the installer itself, or are part of the image
+        const RESERVED: &[&str] = &["/boot", "/efi", "/usr", "/etc", "/sysroot", "/ostree"];
+
+        self.esp_size_mib().context("esp-size")?;
+        self.boot_size_mib().context("boot-size")?;
+        let mut mountpoints = std::collections::HashSet::new();
+        let mut unsized_partitions = 0;
+        for part in self.extra() {
+            let mountpoint = part.mountpoint.as_path();
+            if !mountpoint.is_absolute()
+                || mountpoint.components().any(|c| {
+                    matches!(
+                        c,
+                        camino::Utf8Component::ParentDir | camino::Utf8Component::CurDir
+                    )
+                })
+            {
+                anyhow::bail!("Invalid mount point: {mountpoint}");
+            }
+            if mountpoint == camino::Utf8Path::new("/")
+                || RESERVED.iter().any(|r| mountpoint.starts_with(r))
+            {
+                anyhow::bail!("Mount point {mountpoint} cannot be a separate partition");
+            }
+            if !mountpoints.insert(mountpoint) {
+                anyhow::bail!("Duplicate mount point: {mountpoint}");
+            }
+            if part
+                .size_mib()
+                .with_context(|| format!("Partition {mountpoint}"))?
+                .is_none()
+            {
+                unsized_partitions += 1;
+            }
+            part.parttype_guid()
+                .with_context(|| format!("Partition {mountpoint}"))?;
+        }
+        if unsized_partitions > 1 {
+            anyhow::bail!("Only one partition may omit its size");
+        }
+        if unsized_partitions == 1 && !root_sized {
+            anyhow::bail!("A partition without a size requires a fixed root size (--root-size)");
+        }
+        Ok(())
+    }
 }
 
 /// Configuration for ostree repository
@@ -100,6 +258,9 @@ pub(crate) struct InstallConfiguration {
     /// Enabled block storage configurations
     #[cfg(feature = "install-to-disk")]
     pub(crate) block: Option<Vec<BlockSetup>>,
+    /// Partition layout for `install to-disk`
+    #[cfg(feature = "install-to-disk")]
+    pub(crate) partitions: Option<Partitions>,
     pub(crate) filesystem: Option<BasicFilesystems>,
     /// Kernel arguments, applied at installation time
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -168,6 +329,16 @@ impl Mergeable for BasicFilesystems {
     }
 }
 
+#[cfg(feature = "install-to-disk")]
+impl Mergeable for Partitions {
+    /// Apply any values in other, overriding any existing values in `self`.
+    fn merge(&mut self, other: Self, env: &EnvProperties) {
+        merge_basic(&mut self.esp_size, other.esp_size, env);
+        merge_basic(&mut self.boot_size, other.boot_size, env);
+        merge_basic(&mut self.extra, other.extra, env)
+    }
+}
+
 impl Mergeable for OstreeRepoOpts {
     /// Apply any values in other, overriding any existing values in `self`.
     fn merge(&mut self, other: Self, env: &EnvProperties) {
@@ -199,6 +370,8 @@ impl Mergeable for InstallConfiguration {
             merge_basic(&mut self.root_fs_type, other.root_fs_type, env);
             #[cfg(feature = "install-to-disk")]
             merge_basic(&mut self.block, other.block, env);
+            #[cfg(feature = "install-to-disk")]
+            self.partitions.merge(other.partitions, env);
             self.filesystem.merge(other.filesystem, env);
             self.ostree.merge(other.ostree, env);
             merge_basic(&mut self.stateroot, other.stateroot, env);
@@ -474,6 +647,105 @@ block = ["tpm2-luks"]"##,
 
         // And verify passing a disallowed config is an error
         assert!(install.get_block_setup(Some(BlockSetup::Direct)).is_err());
+
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[install]
+block = ["fido2-luks", "pkcs11-luks", "luks-passphrase"]"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        install.canonicalize();
+        assert_eq!(
+            install.get_block_setup(None).unwrap(),
+            BlockSetup::Fido2Luks
+        );
+        assert_eq!(
+            install
+                .get_block_setup(Some(BlockSetup::LuksPassphrase))
+                .unwrap(),
+            BlockSetup::LuksPassphrase
+        );
+        assert!(install.get_block_setup(Some(BlockSetup::Tpm2Luks)).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "install-to-disk")]
+    fn test_parse_partitions() {
+        use crate::discoverable_partition_specification as dps;
+
+        let env = EnvProperties {
+            sys_arch: "x86_64".to_string(),
+        };
+        let c: InstallConfigurationToplevel = toml::from_str(
+            r##"[install.partitions]
+esp-size = "1G"
+boot-size = "1024M"
+
+[[install.partitions.extra]]
+mountpoint = "/var"
+size = "20G"
+filesystem = "ext4"
+options = "nodev,nosuid"
+
+[[install.partitions.extra]]
+mountpoint = "/var/lib/containers"
+type = "linux-data"
+"##,
+        )
+        .unwrap();
+        let mut install = c.install.unwrap();
+        let partitions = install.partitions.as_ref().unwrap();
+        assert_eq!(partitions.esp_size_mib().unwrap(), Some(1024));
+        assert_eq!(partitions.boot_size_mib().unwrap(), Some(1024));
+        let extra = partitions.extra();
+        assert_eq!(extra.len(), 2);
+        assert_eq!(extra[0].size_mib().unwrap(), Some(20 * 1024));
+        assert_eq!(extra[0].filesystem, Some(Filesystem::Ext4));
+        assert_eq!(extra[0].parttype_guid().unwrap(), dps::VAR);
+        assert_eq!(extra[0].name(), "var");
+        assert_eq!(extra[1].size_mib().unwrap(), None);
+        assert_eq!(extra[1].parttype_guid().unwrap(), dps::LINUX_DATA);
+        assert_eq!(extra[1].name(), "containers");
+        // The last partition uses the remaining space, so the root must be sized
+        assert!(partitions.validate(false).is_err());
+        partitions.validate(true).unwrap();
+
+        // Merging replaces the set of additional partitions
+        let other: InstallConfigurationToplevel = toml::from_str(
+            r##"[install.partitions]
+esp-size = "2G"
+
+[[install.partitions.extra]]
+mountpoint = "/home"
+size = "10G"
+"##,
+        )
+        .unwrap();
+        install.merge(other.install.unwrap(), &env);
+        let partitions = install.partitions.as_ref().unwrap();
+        assert_eq!(partitions.esp_size_mib().unwrap(), Some(2048));
+        assert_eq!(partitions.boot_size_mib().unwrap(), Some(1024));
+        assert_eq!(partitions.extra().len(), 1);
+        assert_eq!(partitions.extra()[0].parttype_guid().unwrap(), dps::HOME);
+        partitions.validate(false).unwrap();
+
+        let invalid = [
+            r#"esp-size = "0""#,
+            r#"boot-size = "lots""#,
+            r#"extra = [{ mountpoint = "var", size = "1G" }]"#,
+            r#"extra = [{ mountpoint = "/", size = "1G" }]"#,
+            r#"extra = [{ mountpoint = "/usr/local", size = "1G" }]"#,
+            r#"extra = [{ mountpoint = "/var/../etc", size = "1G" }]"#,
+            r#"extra = [{ mountpoint = "/var", size = "1G" }, { mountpoint = "/var/", size = "2G" }]"#,
+            r#"extra = [{ mountpoint = "/var" }, { mountpoint = "/srv" }]"#,
+            r#"extra = [{ mountpoint = "/var", size = "1G", type = "bogus" }]"#,
+        ];
+        for case in invalid {
+            let c: InstallConfigurationToplevel =
+                toml::from_str(&format!("[install.partitions]\n{case}\n")).unwrap();
+            let partitions = c.install.unwrap().partitions.unwrap();
+            assert!(partitions.validate(true).is_err(), "{case}");
+        }
     }
 
     #[test]
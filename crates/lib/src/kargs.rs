@@ -0,0 +1,445 @@
+//! # Changing kernel arguments
+//!
+//! Implementation of `bootc kargs`, which changes the kernel arguments of the
+//! deployment which is booted next. With ostree, a new deployment of the same
+//! image is staged with the changed kernel arguments. With composefs, the
+//! bootloader entry is updated in place, or for UKIs, the local kernel arguments
+//! are written to a UKI addon, see [`crate::bootc_composefs::kargs`].
+//!
+//! Kernel arguments are either from the image (i.e. from `/usr/lib/bootc/kargs.d`,
+//! or built into the UKI) or local; this distinction is shown by `bootc status`.
+
+use std::io::{Seek, Write};
+
+use anyhow::{Result, anyhow};
+use bootc_kernel_cmdline::Action;
+use bootc_kernel_cmdline::utf8::{Cmdline, CmdlineOwned, Parameter};
+use fn_error_context::context;
+use ostree_ext::ostree;
+
+use crate::bootc_composefs::kargs::composefs_update_kargs;
+use crate::bootc_kargs::{KargsMatchContext, get_kargs_in_root};
+use crate::composefs_consts::COMPOSEFS_CMDLINE;
+use crate::deploy::{ImageState, MergeState, RequiredHostSpec};
+use crate::progress_jsonl::ProgressWriter;
+use crate::spec::BootEntryKargs;
+use crate::store::{BootedOstree, BootedStorageKind, Storage};
+use crate::ukify::SecureBootSigningOpts;
+
+/// Kernel arguments which are set up by the backend, and can't be changed.
+const INTERNAL_KARGS: &[&str] = &["ostree", COMPOSEFS_CMDLINE];
+
+fn is_internal(param: &Parameter) -> bool {
+    INTERNAL_KARGS.iter().any(|k| param.key() == (*k).into())
+}
+
+/// Split the kernel arguments of a deployment into the ones which come from
+/// the image, i.e. which are also in `image_kargs`, and local ones.
+pub(crate) fn split_kargs(kargs: &Cmdline, image_kargs: &Cmdline) -> BootEntryKargs {
+    let mut r = BootEntryKargs::default();
+    for param in kargs.iter().filter(|p| !is_internal(p)) {
+        let from_image = image_kargs.iter().any(|p| p == param);
+        let target = if from_image {
+            &mut r.image
+        } else {
+            &mut r.local
+        };
+        target.push(param.to_string());
+    }
+    r
+}
+
+/// Returns the kernel arguments of an ostree deployment.
+#[context("Reading kernel arguments")]
+pub(crate) fn ostree_deployment_kargs(
+    sysroot: &ostree::Sysroot,
+    deployment: &ostree::Deployment,
+) -> Result<Option<BootEntryKargs>> {
+    let Some(options) = deployment.bootconfig().and_then(|b| b.get("options")) else {
+        return Ok(None);
+    };
+    let deployment_root = crate::utils::deployment_fd(sysroot, deployment)?;
+    let image_kargs = get_kargs_in_root(&deployment_root, KargsMatchContext::host()?)?;
+    Ok(Some(split_kargs(
+        &Cmdline::from(options.as_str()),
+        &image_kargs,
+    )))
+}
+
+/// A change to the kernel arguments, as requested via `bootc kargs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum KargsOp {
+    /// Add kernel arguments, unless they are already present.
+    Append,
+    /// Remove kernel arguments; either all with the same key (`foo`), or the
+    /// exactly matching ones (`foo=bar`).
+    Delete,
+    /// Set the value of kernel arguments, replacing all with the same key.
+    Replace,
+}
+
+/// The kernel arguments of the deployment which is changed.
+#[derive(Debug)]
+pub(crate) struct DeploymentKargs {
+    /// Kernel arguments which can't be changed locally: the internal ones,
+    /// and the ones from the image.
+    pub(crate) fixed: CmdlineOwned,
+    /// Kernel arguments which can be changed.
+    pub(crate) editable: CmdlineOwned,
+}
+
+impl DeploymentKargs {
+    /// Split all kernel arguments of a deployment into the ones which can't
+    /// be changed, i.e. the internal ones and the ones in `image_kargs`, and
+    /// the local ones.
+    pub(crate) fn from_full(kargs: &Cmdline, image_kargs: &Cmdline) -> Self {
+        let mut fixed = Cmdline::new();
+        let mut editable = Cmdline::new();
+        for param in kargs {
+            if is_internal(&param) || image_kargs.iter().any(|p| p == param) {
+                fixed.add(&param);
+            } else {
+                editable.add(&param);
+            }
+        }
+        Self { fixed, editable }
+    }
+
+    /// All kernel arguments.
+    pub(crate) fn full(&self) -> CmdlineOwned {
+        let mut r = self.fixed.clone();
+        r.extend(&self.editable);
+        r
+    }
+
+    /// Apply a change, returning whether the kernel arguments changed.
+    fn apply(&mut self, op: KargsOp, args: &Cmdline) -> Result<bool> {
+        let mut changed = false;
+        for arg in args {
+            if is_internal(&arg) {
+                anyhow::bail!("Cannot change internal kernel argument: {arg}");
+            }
+            match op {
+                KargsOp::Append => {
+                    if !self.fixed.iter().any(|p| p == arg) {
+                        changed |= self.editable.add(&arg) == Action::Added;
+                    }
+                }
+                KargsOp::Delete => {
+                    let removed = if arg.value().is_some() {
+                        self.editable.remove_exact(&arg)
+                    } else {
+                        self.editable.remove(&arg.key())
+                    };
+                    if !removed {
+                        let key = arg.key();
+                        let fixed = self
+                            .fixed
+                            .iter()
+                            .any(|p| p == arg || (arg.value().is_none() && p.key() == key));
+                        if fixed {
+                            anyhow::bail!(
+                                "Kernel argument is not local and cannot be deleted: {arg}"
+                            );
+                        }
+                        anyhow::bail!("Kernel argument not found: {arg}");
+                    }
+                    changed = true;
+                }
+                KargsOp::Replace => {
+                    let key = arg.key();
+                    if self.fixed.iter().any(|p| p.key() == key) {
+                        anyhow::bail!("Kernel argument is not local and cannot be replaced: {key}");
+                    }
+                    changed |= self.editable.add_or_modify(&arg) != Action::Existed;
+                }
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Replace the kernel arguments which can be changed, returning whether
+    /// they changed.
+    fn set_editable(&mut self, kargs: CmdlineOwned) -> Result<bool> {
+        if let Some(arg) = kargs.iter().find(is_internal) {
+            anyhow::bail!("Cannot change internal kernel argument: {arg}");
+        }
+        let changed = !kargs.iter().eq(self.editable.iter());
+        self.editable = kargs;
+        Ok(changed)
+    }
+}
+
+/// Parse kernel arguments given on the command line.
+fn parse_args(args: &[String]) -> Result<CmdlineOwned> {
+    let kargs = Cmdline::from(args.join(" "));
+    if kargs.iter().next().is_none() {
+        anyhow::bail!("No kernel arguments provided");
+    }
+    Ok(kargs)
+}
+
+/// Parse the kernel arguments edited by the user, one or more per line;
+/// lines starting with `#` are ignored.
+fn parse_edited(text: &str) -> CmdlineOwned {
+    let kargs = text
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .collect::<Vec<_>>();
+    Cmdline::from(kargs.join(" "))
+}
+
+/// Present the kernel arguments in the system default `$EDITOR`.
+fn edit_interactively(kargs: &Cmdline) -> Result<CmdlineOwned> {
+    let mut tmpf = tempfile::NamedTempFile::with_suffix(".txt")?;
+    writeln!(
+        tmpf,
+        "# Kernel arguments, one per line. Lines starting with '#' are ignored."
+    )?;
+    for arg in kargs.iter_str() {
+        writeln!(tmpf, "{arg}")?;
+    }
+    tmpf.flush()?;
+    crate::utils::spawn_editor(&tmpf)?;
+    tmpf.as_file().seek(std::io::SeekFrom::Start(0))?;
+    let text = std::io::read_to_string(tmpf.as_file())?;
+    Ok(parse_edited(&text))
+}
+
+#[context("Changing kernel arguments (ostree)")]
+async fn ostree_update_kargs(
+    storage: &Storage,
+    booted_ostree: &BootedOstree<'_>,
+    f: impl FnOnce(&mut DeploymentKargs) -> Result<bool>,
+) -> Result<bool> {
+    let ostree = booted_ostree.sysroot;
+    let repo = &booted_ostree.repo();
+    let host = crate::status::get_status_require_booted(ostree)?.2;
+    if host.status.rollback_queued {
+        anyhow::bail!("Cannot change kernel arguments with a queued rollback");
+    }
+    // Change the deployment which is booted next
+    let (target, entry) = match ostree.staged_deployment() {
+        Some(staged) => (staged, host.status.staged.as_ref()),
+        None => (
+            booted_ostree.deployment.clone(),
+            host.status.booted.as_ref(),
+        ),
+    };
+    let stateroot = booted_ostree.stateroot();
+    if target.osname() != stateroot {
+        anyhow::bail!("Cannot change kernel arguments of a deployment in another stateroot");
+    }
+
+    let options = target
+        .bootconfig()
+        .and_then(|b| b.get("options"))
+        .map(|o| o.to_string())
+        .unwrap_or_default();
+    // The kernel arguments from kargs.d are only re-applied on upgrades when
+    // they change, so they must not be removed locally
+    let target_root = crate::utils::deployment_fd(ostree, &target)?;
+    let image_kargs = get_kargs_in_root(&target_root, KargsMatchContext::host()?)?;
+    let mut kargs = DeploymentKargs::from_full(&Cmdline::from(options), &image_kargs);
+    if !f(&mut kargs)? {
+        return Ok(false);
+    }
+
+    let imgstate = entry
+        .map(|e| e.query_image(repo))
+        .transpose()?
+        .flatten()
+        .ok_or_else(|| anyhow!("Deployment is not container image based"))?;
+    let fetched = ImageState::from(*imgstate);
+    let spec = RequiredHostSpec::from_spec(&host.spec)?;
+    let merge_deployment = ostree
+        .merge_deployment(Some(stateroot.as_str()))
+        .ok_or_else(|| anyhow!("No merge deployment found for stateroot {stateroot}"))?;
+    let from = MergeState::MergeDeploymentWithKargs {
+        deployment: merge_deployment,
+        kargs: kargs.full(),
+    };
+    let lock_finalization = target.is_staged() && target.is_finalization_locked();
+    crate::deploy::stage(
+        storage,
+        from,
+        &fetched,
+        &spec,
+        ProgressWriter::default(),
+        lock_finalization,
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Change the kernel arguments of the deployment which is booted next,
+/// returning whether they changed. With UKIs, the addon holding the local
+/// kernel arguments is signed with `signing`.
+async fn update_kargs(
+    signing: &SecureBootSigningOpts,
+    f: impl FnOnce(&mut DeploymentKargs) -> Result<bool>,
+) -> Result<bool> {
+    let storage = &crate::cli::get_storage().await?;
+    let changed = match storage.kind()? {
+        BootedStorageKind::Ostree(booted_ostree) => {
+            if signing.is_set() {
+                anyhow::bail!("Signing keys are only used for UKI addons (composefs)");
+            }
+            ostree_update_kargs(storage, &booted_ostree, f).await?
+        }
+        BootedStorageKind::Composefs(booted_cfs) => {
+            composefs_update_kargs(storage, &booted_cfs, signing, f).await?
+        }
+    };
+    if !changed {
+        println!("No changes to kernel arguments");
+    }
+    Ok(changed)
+}
+
+/// Implementation of `bootc kargs list`.
+pub(crate) async fn list(local: bool) -> Result<()> {
+    let host = crate::status::get_host().await?;
+    let entry = host
+        .status
+        .staged
+        .as_ref()
+        .or(host.status.booted.as_ref())
+        .ok_or_else(|| anyhow!("System not booted via bootc"))?;
+    let kargs = entry.kargs.as_ref().ok_or_else(|| {
+        anyhow!("The kernel arguments from the image are not known for this deployment")
+    })?;
+    let kargs = if local {
+        kargs.local.iter().collect::<Vec<_>>()
+    } else {
+        kargs.image.iter().chain(kargs.local.iter()).collect()
+    };
+    let mut out = std::io::stdout().lock();
+    for arg in kargs {
+        writeln!(out, "{arg}")?;
+    }
+    Ok(())
+}
+
+/// Implementation of `bootc kargs append`, `delete` and `replace`, returning
+/// whether the kernel arguments changed.
+pub(crate) async fn change(
+    op: KargsOp,
+    args: &[String],
+    signing: &SecureBootSigningOpts,
+) -> Result<bool> {
+    let args = parse_args(args)?;
+    update_kargs(signing, |kargs| kargs.apply(op, &args)).await
+}
+
+/// Implementation of `bootc kargs edit`, returning whether the kernel
+/// arguments changed.
+pub(crate) async fn edit(signing: &SecureBootSigningOpts) -> Result<bool> {
+    update_kargs(signing, |kargs| {
+        let edited = edit_interactively(&kargs.editable)?;
+        kargs.set_editable(edited)
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kargs(full: &str) -> DeploymentKargs {
+        DeploymentKargs::from_full(&Cmdline::from(full), &Cmdline::new())
+    }
+
+    fn apply(kargs: &mut DeploymentKargs, op: KargsOp, args: &str) -> Result<bool> {
+        kargs.apply(op, &Cmdline::from(args))
+    }
+
+    #[test]
+    fn test_split_kargs() {
+        let full = Cmdline::from("root=UUID=abc composefs=1234 quiet console=ttyS0 ostree=/x");
+        let image = Cmdline::from("quiet console=tty0");
+        let split = split_kargs(&full, &image);
+        assert_eq!(split.image, ["quiet"]);
+        assert_eq!(split.local, ["root=UUID=abc", "console=ttyS0"]);
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let mut k = kargs("root=UUID=abc composefs=1234 quiet console=tty0 console=ttyS0");
+        assert_eq!(&*k.fixed, "composefs=1234");
+
+        assert!(apply(&mut k, KargsOp::Append, "nosmt console=tty0")?);
+        assert!(!apply(&mut k, KargsOp::Append, "nosmt")?);
+        assert_eq!(
+            &*k.editable,
+            "root=UUID=abc quiet console=tty0 console=ttyS0 nosmt"
+        );
+
+        assert!(apply(&mut k, KargsOp::Delete, "console=tty0 quiet")?);
+        assert_eq!(&*k.editable, "root=UUID=abc console=ttyS0 nosmt");
+        assert!(apply(&mut k, KargsOp::Delete, "console")?);
+        assert!(apply(&mut k, KargsOp::Delete, "console").is_err());
+        assert!(apply(&mut k, KargsOp::Delete, "nosmt=1").is_err());
+
+        assert!(apply(
+            &mut k,
+            KargsOp::Replace,
+            "root=UUID=def mitigations=off"
+        )?);
+        assert!(!apply(&mut k, KargsOp::Replace, "mitigations=off")?);
+        assert_eq!(&*k.editable, "root=UUID=def nosmt mitigations=off");
+        assert_eq!(
+            &*k.full(),
+            "composefs=1234 root=UUID=def nosmt mitigations=off"
+        );
+
+        for op in [KargsOp::Append, KargsOp::Delete, KargsOp::Replace] {
+            assert!(apply(&mut k, op, "composefs=5678").is_err());
+            assert!(apply(&mut k, op, "ostree=/y").is_err());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_fixed() -> Result<()> {
+        // Kernel arguments which are built into a UKI
+        let mut k = DeploymentKargs {
+            fixed: Cmdline::from("composefs=1234 quiet console=tty0".to_owned()),
+            editable: Cmdline::from("nosmt".to_owned()),
+        };
+        assert!(!apply(&mut k, KargsOp::Append, "quiet")?);
+        assert!(apply(&mut k, KargsOp::Append, "console=ttyS0")?);
+        assert!(apply(&mut k, KargsOp::Delete, "quiet").is_err());
+        assert!(apply(&mut k, KargsOp::Delete, "console=tty0").is_err());
+        assert!(apply(&mut k, KargsOp::Replace, "console=ttyS1").is_err());
+        assert!(apply(&mut k, KargsOp::Delete, "console=ttyS0")?);
+        assert_eq!(&*k.editable, "nosmt");
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_full_image_kargs() -> Result<()> {
+        let image = Cmdline::from("quiet console=tty0");
+        let full = Cmdline::from("ostree=/x quiet console=tty0 console=ttyS0 nosmt");
+        let mut k = DeploymentKargs::from_full(&full, &image);
+        assert_eq!(&*k.fixed, "ostree=/x quiet console=tty0");
+        assert_eq!(&*k.editable, "console=ttyS0 nosmt");
+        assert!(apply(&mut k, KargsOp::Delete, "quiet").is_err());
+        assert!(apply(&mut k, KargsOp::Replace, "console=ttyS1").is_err());
+        assert!(apply(&mut k, KargsOp::Delete, "nosmt")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_editable() -> Result<()> {
+        let mut k = kargs("ostree=/x quiet nosmt");
+        let edited = parse_edited("# comment\nquiet\n\nnosmt\n");
+        assert!(!k.set_editable(edited)?);
+        let edited = parse_edited("# comment\nquiet console=ttyS0\n");
+        assert!(k.set_editable(edited)?);
+        assert_eq!(&*k.full(), "ostree=/x quiet console=ttyS0");
+        assert!(k.set_editable(parse_edited("ostree=/y")).is_err());
+        Ok(())
+    }
+}
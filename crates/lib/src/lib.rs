@@ -67,19 +67,27 @@ mod bootc_composefs;
 pub(crate) mod bootc_kargs;
 mod bootloader;
 mod boundimage;
+mod bundle;
 pub mod cli;
 mod composefs_consts;
+mod config_diff;
 mod container_export;
 mod containerenv;
 pub(crate) mod deploy;
+mod deployment_diff;
+mod deployment_report;
 mod discoverable_partition_specification;
+mod etcmerge;
+mod factory_reset;
 pub(crate) mod fsck;
 pub(crate) mod generator;
 mod glyph;
+mod iddrift;
 mod image;
 mod install;
 pub(crate) mod journal;
 mod k8sapitypes;
+mod kargs;
 mod kernel;
 mod lints;
 mod lsm;
@@ -94,6 +102,7 @@ mod status;
 mod store;
 mod task;
 mod ukify;
+mod update_policy;
 mod utils;
 
 #[cfg(feature = "docgen")]
@@ -13,8 +13,8 @@ use std::ops::ControlFlow;
 use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 
-use anyhow::Result;
-use bootc_utils::PathQuotedDisplay;
+use anyhow::{Context, Result};
+use bootc_utils::{CommandRunExt, PathQuotedDisplay};
 use camino::{Utf8Path, Utf8PathBuf};
 use cap_std::fs::Dir;
 use cap_std_ext::cap_std;
@@ -25,9 +25,11 @@ use fn_error_context::context;
 use indoc::indoc;
 use linkme::distributed_slice;
 use ostree_ext::ostree_prepareroot;
-use serde::Serialize;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
 
 use crate::bootc_composefs::boot::EFI_LINUX;
+use crate::bootc_kargs::KargsMatchContext;
 
 /// Create a default WalkConfiguration with noxdev enabled.
 ///
@@ -49,7 +51,11 @@ const DEFAULT_TRUNCATED_OUTPUT: NonZeroUsize = const { NonZeroUsize::new(5).unwr
 
 /// A lint check has failed.
 #[derive(thiserror::Error, Debug)]
-struct LintError(String);
+struct LintError {
+    msg: String,
+    /// Absolute paths in the target root affected by this failure.
+    paths: Vec<Utf8PathBuf>,
+}
 
 /// The outer error is for unexpected fatal runtime problems; the
 /// inner error is for the lint failing in an expected way.
@@ -66,21 +72,64 @@ fn lint_err(msg: impl AsRef<str>) -> LintResult {
     Ok(Err(LintError::new(msg)))
 }
 
+/// We successfully found a lint failure, affecting the provided paths.
+fn lint_err_at(
+    paths: impl IntoIterator<Item = impl Into<Utf8PathBuf>>,
+    msg: impl AsRef<str>,
+) -> LintResult {
+    Ok(Err(LintError::new(msg).with_paths(paths)))
+}
+
 impl std::fmt::Display for LintError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&self.0)
+        f.write_str(&self.msg)
     }
 }
 
 impl LintError {
     fn new(msg: impl AsRef<str>) -> Self {
-        Self(msg.as_ref().to_owned())
+        Self {
+            msg: msg.as_ref().to_owned(),
+            paths: Vec::new(),
+        }
+    }
+
+    fn with_paths(mut self, paths: impl IntoIterator<Item = impl Into<Utf8PathBuf>>) -> Self {
+        self.paths.extend(paths.into_iter().map(Into::into));
+        self
     }
 }
 
 #[derive(Debug, Default)]
 struct LintExecutionConfig {
     no_truncate: bool,
+    /// Paths (and everything underneath them) which should not be reported
+    /// by the lint currently being executed.
+    allow_paths: BTreeSet<Utf8PathBuf>,
+    /// Certificates in the target root, one of which the UKI must be signed with.
+    uki_certificates: Vec<Utf8PathBuf>,
+}
+
+impl LintExecutionConfig {
+    /// Create the configuration for a specific lint, taking into account
+    /// any paths allowlisted for it.
+    fn for_lint(&self, policy: &LintConfig, name: &str) -> Self {
+        let allow_paths = policy
+            .allow_paths
+            .get(name)
+            .map(|paths| paths.iter().cloned().collect())
+            .unwrap_or_default();
+        Self {
+            no_truncate: self.no_truncate,
+            allow_paths,
+            uki_certificates: policy.uki_certificates.clone(),
+        }
+    }
+
+    /// Returns true if this path has been allowlisted for the current lint.
+    fn is_path_allowed(&self, path: &Utf8Path) -> bool {
+        self.allow_paths.iter().any(|p| path.starts_with(p))
+    }
 }
 
 type LintFn = fn(&Dir, config: &LintExecutionConfig) -> LintResult;
@@ -99,13 +148,14 @@ enum LintFnTy {
 pub(crate) static LINTS: [Lint];
 
 /// The classification of a lint type.
-#[derive(Debug, Serialize)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(rename_all = "kebab-case")]
 enum LintType {
     /// If this fails, it is known to be fatal - the system will not install or
     /// is effectively guaranteed to fail at runtime.
     Fatal,
     /// This is not a fatal problem, but something you likely want to fix.
+    #[default]
     Warning,
 }
 
@@ -115,12 +165,27 @@ pub(crate) enum WarningDisposition {
     FatalWarnings,
 }
 
-#[derive(Debug, Copy, Clone, Serialize, PartialEq, Eq)]
+/// The type of root filesystem being checked.
+#[derive(Debug, Copy, Clone, Serialize, JsonSchema, PartialEq, Eq)]
 pub(crate) enum RootType {
+    /// The root filesystem of the running container.
     Running,
+    /// A root filesystem provided via `--rootfs`.
     Alternative,
 }
 
+/// The output format for lint results.
+#[derive(Debug, Clone, Copy, clap::ValueEnum, PartialEq, Eq)]
+#[clap(rename_all = "lowercase")]
+pub(crate) enum LintOutputFormat {
+    /// Output in human readable format.
+    HumanReadable,
+    /// Output a JSON report; see `lint-v1.schema.json`.
+    Json,
+    /// Output a SARIF 2.1.0 log.
+    Sarif,
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "kebab-case")]
 struct Lint {
@@ -133,6 +198,9 @@ struct Lint {
     // Set if this only applies to a specific root type.
     #[serde(skip_serializing_if = "Option::is_none")]
     root_type: Option<RootType>,
+    // Set if this honors `allow-paths` from the configuration.
+    #[serde(skip)]
+    allow_paths: bool,
 }
 
 // We require lint names to be unique, so we can just compare based on those.
@@ -172,6 +240,7 @@ impl Lint {
             f: LintFnTy::Regular(f),
             description,
             root_type: None,
+            allow_paths: false,
         }
     }
 
@@ -186,6 +255,7 @@ impl Lint {
             f: LintFnTy::Regular(f),
             description,
             root_type: None,
+            allow_paths: false,
         }
     }
 
@@ -193,6 +263,11 @@ impl Lint {
         self.root_type = Some(v);
         self
     }
+
+    const fn set_allow_paths(mut self) -> Self {
+        self.allow_paths = true;
+        self
+    }
 }
 
 pub(crate) fn lint_list(output: impl std::io::Write) -> Result<()> {
@@ -201,12 +276,262 @@ pub(crate) fn lint_list(output: impl std::io::Write) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug)]
+/// Path to the lint configuration file in the target root.
+const LINT_CONFIG_PATH: &str = "usr/lib/bootc/lint.toml";
+
+/// A declarative lint defined in the configuration file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct CustomRule {
+    /// Unique name for this rule; it can be referenced in the same way as
+    /// a builtin lint.
+    name: String,
+    /// Human readable description, included in the failure output.
+    description: Option<String>,
+    /// Whether a failure is fatal or a warning.
+    #[serde(rename = "type", default)]
+    ty: LintType,
+    /// The absolute path to check.
+    path: Utf8PathBuf,
+    /// If set, the path must (or must not) exist.
+    exists: Option<bool>,
+    /// If set, the path must be a regular file whose contents match this regular expression.
+    matches: Option<String>,
+}
+
+impl CustomRule {
+    fn validate(&self) -> Result<()> {
+        let name = &self.name;
+        if !self.path.is_absolute() {
+            anyhow::bail!("Rule {name}: path must be absolute: {}", self.path);
+        }
+        match (self.exists, self.matches.as_deref()) {
+            (Some(_), None) => {}
+            (None, Some(re)) => {
+                regex::Regex::new(re).with_context(|| format!("Rule {name}: invalid regex"))?;
+            }
+            _ => anyhow::bail!("Rule {name}: exactly one of exists or matches must be set"),
+        }
+        Ok(())
+    }
+
+    fn check(&self, root: &Dir) -> LintResult {
+        let path = &self.path;
+        let relpath = path.strip_prefix("/").unwrap_or(path.as_path());
+        let r = if let Some(exists) = self.exists {
+            let found = root.symlink_metadata_optional(relpath)?.is_some();
+            match (exists, found) {
+                (true, false) => Some(format!("Missing required path: {path}")),
+                (false, true) => Some(format!("Found forbidden path: {path}")),
+                _ => None,
+            }
+        } else {
+            // SAFETY: Verified by validate()
+            let re = regex::Regex::new(self.matches.as_deref().unwrap())?;
+            match root.symlink_metadata_optional(relpath)? {
+                Some(meta) if meta.is_file() => {
+                    let contents = root.read_to_string(relpath)?;
+                    (!re.is_match(&contents))
+                        .then(|| format!("{path} does not match regex: {}", re.as_str()))
+                }
+                Some(_) => Some(format!("Expected a regular file: {path}")),
+                None => Some(format!("Missing required file: {path}")),
+            }
+        };
+        match (r, self.description.as_deref()) {
+            (None, _) => lint_ok(),
+            (Some(msg), Some(description)) => lint_err_at([path], format!("{msg} ({description})")),
+            (Some(msg), None) => lint_err_at([path], msg),
+        }
+    }
+}
+
+/// Per-project lint policy, read from `/usr/lib/bootc/lint.toml` in the target
+/// root and/or a file provided via `--config`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct LintConfig {
+    /// Lints which should not be run.
+    #[serde(default)]
+    disable: BTreeSet<String>,
+    /// Lints which should be run even if disabled by an earlier configuration file.
+    #[serde(default)]
+    enable: BTreeSet<String>,
+    /// Lints whose warnings should be treated as fatal.
+    #[serde(default)]
+    fatal: BTreeSet<String>,
+    /// Paths which should not be reported, by lint name.
+    #[serde(default)]
+    allow_paths: BTreeMap<String, Vec<Utf8PathBuf>>,
+    /// Custom declarative rules.
+    #[serde(default, rename = "rule")]
+    rules: Vec<CustomRule>,
+    /// Absolute paths of PEM certificates in the target root; the UKI must be
+    /// signed with one of them.
+    #[serde(default)]
+    uki_certificates: Vec<Utf8PathBuf>,
+}
+
+impl LintConfig {
+    /// Parse a configuration file, returning an error on unknown keys
+    /// or invalid rules.
+    fn parse(buf: &str) -> Result<Self> {
+        let config: Self = toml::from_str(buf)?;
+        if let Some(name) = config.disable.intersection(&config.enable).next() {
+            anyhow::bail!("Lint {name} is both enabled and disabled");
+        }
+        for rule in config.rules.iter() {
+            rule.validate()?;
+        }
+        Ok(config)
+    }
+
+    /// Apply another configuration on top of this one. Rules with the same
+    /// name are replaced.
+    fn merge(&mut self, other: Self) {
+        self.disable.retain(|name| !other.enable.contains(name));
+        self.disable.extend(other.disable);
+        self.fatal.extend(other.fatal);
+        for (name, paths) in other.allow_paths {
+            self.allow_paths.entry(name).or_default().extend(paths);
+        }
+        for rule in other.rules {
+            self.rules.retain(|r| r.name != rule.name);
+            self.rules.push(rule);
+        }
+        self.uki_certificates.extend(other.uki_certificates);
+    }
+
+    /// Verify that all referenced lints exist.
+    fn validate(&self) -> Result<()> {
+        let mut names: BTreeSet<&str> = LINTS.iter().map(|lint| lint.name).collect();
+        for rule in self.rules.iter() {
+            if !names.insert(rule.name.as_str()) {
+                anyhow::bail!("Rule {} conflicts with an existing lint", rule.name);
+            }
+        }
+        let referenced = self
+            .disable
+            .iter()
+            .chain(self.enable.iter())
+            .chain(self.fatal.iter())
+            .chain(self.allow_paths.keys());
+        for name in referenced {
+            if !names.contains(name.as_str()) {
+                anyhow::bail!("Unknown lint: {name}");
+            }
+        }
+        for (name, paths) in self.allow_paths.iter() {
+            if self.rules.iter().any(|r| &r.name == name) {
+                anyhow::bail!("allow-paths is not supported for custom rule {name}");
+            }
+            if !LINTS.iter().any(|l| l.name == name && l.allow_paths) {
+                anyhow::bail!("allow-paths is not supported by lint {name}");
+            }
+            if let Some(p) = paths.iter().find(|p| !p.is_absolute()) {
+                anyhow::bail!("allow-paths for {name}: path must be absolute: {p}");
+            }
+        }
+        if let Some(p) = self.uki_certificates.iter().find(|p| !p.is_absolute()) {
+            anyhow::bail!("uki-certificates: path must be absolute: {p}");
+        }
+        Ok(())
+    }
+
+    /// Load the configuration from the target root, with an optional
+    /// additional file layered on top.
+    #[context("Loading lint configuration")]
+    fn load(root: &Dir, extra: Option<&Utf8Path>) -> Result<Self> {
+        let mut config = Self::default();
+        if let Some(buf) = root.read_to_string_optional(LINT_CONFIG_PATH)? {
+            let c = Self::parse(&buf).with_context(|| format!("Parsing /{LINT_CONFIG_PATH}"))?;
+            config.merge(c);
+        }
+        if let Some(path) = extra {
+            let buf = std::fs::read_to_string(path).with_context(|| format!("Reading {path}"))?;
+            let c = Self::parse(&buf).with_context(|| format!("Parsing {path}"))?;
+            config.merge(c);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Compute the effective type of a lint, taking promotion into account.
+    fn effective_type(&self, name: &str, ty: LintType) -> LintType {
+        if self.fatal.contains(name) {
+            LintType::Fatal
+        } else {
+            ty
+        }
+    }
+}
+
+/// The version of the JSON lint report format.
+const LINT_REPORT_VERSION: u32 = 1;
+
+/// The outcome of a single lint.
+#[derive(Debug, Clone, Copy, Serialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LintStatus {
+    /// The check passed.
+    Passed,
+    /// The check found a problem.
+    Failed,
+    /// The check was not run.
+    Skipped,
+}
+
+/// The result of a single lint.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+struct LintReportEntry {
+    /// The name of the lint.
+    name: String,
+    /// The severity of a failure, taking configuration into account.
+    #[serde(rename = "type")]
+    ty: LintType,
+    /// The outcome of the lint.
+    status: LintStatus,
+    /// If set, the lint only applies to this type of root.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    root_type: Option<RootType>,
+    /// A human readable description of the failure.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>,
+    /// Absolute paths in the target root affected by the failure.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    paths: Vec<String>,
+}
+
+/// Summary counts of lint results.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
 struct LintExecutionResult {
+    /// The number of lints which failed with a warning.
     warnings: usize,
+    /// The number of lints which passed.
     passed: usize,
+    /// The number of lints which were not run.
     skipped: usize,
+    /// The number of lints which failed fatally.
     fatal: usize,
+    // Included separately in the report
+    #[serde(skip)]
+    results: Vec<LintReportEntry>,
+}
+
+/// The machine readable output of `bootc container lint --format=json`.
+#[derive(Debug, Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct LintReport {
+    /// The version of this format; currently always 1.
+    version: u32,
+    /// The type of root filesystem which was checked.
+    root_type: RootType,
+    /// Summary counts.
+    summary: LintExecutionResult,
+    /// The result of each lint.
+    results: Vec<LintReportEntry>,
 }
 
 // Helper function to format items with optional truncation
@@ -263,17 +588,34 @@ where
     lint_err(msg)
 }
 
+// Like `format_lint_err_from_items`, but the items are paths which are
+// also recorded as affected by the failure.
+fn format_lint_err_from_paths(
+    config: &LintExecutionConfig,
+    header: &str,
+    paths: impl IntoIterator<Item = Utf8PathBuf>,
+) -> LintResult {
+    let paths = paths.into_iter().collect::<Vec<_>>();
+    let items = paths.iter().map(PathQuotedDisplay::new);
+    let mut msg = String::new();
+    // SAFETY: Writing to a string can't fail
+    format_items(config, header, items, &mut msg).unwrap();
+    lint_err_at(paths, msg)
+}
+
 fn lint_inner<'skip>(
     root: &Dir,
     root_type: RootType,
     config: &LintExecutionConfig,
+    policy: &LintConfig,
     skip: impl IntoIterator<Item = &'skip str>,
     mut output: impl std::io::Write,
 ) -> Result<LintExecutionResult> {
     let mut fatal = 0usize;
     let mut warnings = 0usize;
     let mut passed = 0usize;
-    let skip: std::collections::HashSet<_> = skip.into_iter().collect();
+    let mut skip: std::collections::HashSet<_> = skip.into_iter().collect();
+    skip.extend(policy.disable.iter().map(|s| s.as_str()));
     let (mut applicable_lints, skipped_lints): (Vec<_>, Vec<_>) = LINTS.iter().partition(|lint| {
         if skip.contains(lint.name) {
             return false;
@@ -285,8 +627,12 @@ fn lint_inner<'skip>(
         }
         true
     });
+    let (rules, skipped_rules): (Vec<_>, Vec<_>) = policy
+        .rules
+        .iter()
+        .partition(|rule| !skip.contains(rule.name.as_str()));
     // SAFETY: Length must be smaller.
-    let skipped = skipped_lints.len();
+    let skipped = skipped_lints.len() + skipped_rules.len();
     // Default to predictablility here
     applicable_lints.sort_by(|a, b| a.name.cmp(b.name));
     // Split the lints by type
@@ -299,10 +645,15 @@ fn lint_inner<'skip>(
             LintFnTy::Regular(f) => f,
             LintFnTy::Recursive(_) => unreachable!(),
         };
-        results.push((lint, f(&root, &config)));
+        let config = config.for_lint(policy, lint.name);
+        results.push((lint.name, lint.ty, lint.root_type, f(&root, &config)));
     }
 
-    let mut recursive_lints = BTreeSet::from_iter(recursive_lints);
+    let mut recursive_lints = BTreeMap::from_iter(
+        recursive_lints
+            .into_iter()
+            .map(|lint| (lint, config.for_lint(policy, lint.name))),
+    );
     let mut recursive_errors = BTreeMap::new();
     root.walk(
         &walk_configuration().path_base(Path::new("/")),
@@ -315,14 +666,14 @@ fn lint_inner<'skip>(
             // the recursive lints.
             let mut this_iteration_errors = Vec::new();
             // Call each recursive lint on this directory entry.
-            for &lint in recursive_lints.iter() {
+            for (&lint, config) in recursive_lints.iter() {
                 let f = match &lint.f {
                     // SAFETY: We know this set only holds recursive lints
                     LintFnTy::Regular(_) => unreachable!(),
                     LintFnTy::Recursive(f) => f,
                 };
                 // Keep track of the error if we found one
-                match f(e, &config) {
+                match f(e, config) {
                     Ok(Ok(())) => {}
                     o => this_iteration_errors.push((lint, o)),
                 }
@@ -337,18 +688,41 @@ fn lint_inner<'skip>(
         },
     )?;
     // Extend our overall result set with the recursive-lint errors.
-    results.extend(recursive_errors);
+    results.extend(
+        recursive_errors
+            .into_iter()
+            .map(|(lint, r)| (lint.name, lint.ty, lint.root_type, r)),
+    );
     // Any recursive lint still in this list succeeded.
-    results.extend(recursive_lints.into_iter().map(|lint| (lint, lint_ok())));
-    for (lint, r) in results {
-        let name = lint.name;
+    results.extend(
+        recursive_lints
+            .into_keys()
+            .map(|lint| (lint.name, lint.ty, lint.root_type, lint_ok())),
+    );
+    // Custom rules from the configuration are run last.
+    results.extend(
+        rules
+            .into_iter()
+            .map(|rule| (rule.name.as_str(), rule.ty, None, rule.check(root))),
+    );
+    let mut report = Vec::new();
+    for (name, ty, lint_root_type, r) in results {
         let r = match r {
             Ok(r) => r,
             Err(e) => anyhow::bail!("Unexpected runtime error running lint {name}: {e}"),
         };
 
+        let ty = policy.effective_type(name, ty);
+        let mut entry = LintReportEntry {
+            name: name.to_owned(),
+            ty,
+            status: LintStatus::Passed,
+            root_type: lint_root_type,
+            message: None,
+            paths: Vec::new(),
+        };
         if let Err(e) = r {
-            match lint.ty {
+            match ty {
                 LintType::Fatal => {
                     writeln!(output, "Failed lint: {name}: {e}")?;
                     fatal += 1;
@@ -358,11 +732,33 @@ fn lint_inner<'skip>(
                     warnings += 1;
                 }
             }
+            entry.status = LintStatus::Failed;
+            entry.paths = e.paths.into_iter().map(Into::into).collect();
+            entry.message = Some(e.msg);
         } else {
             // We'll be quiet for now
-            tracing::debug!("OK {name} (type={:?})", lint.ty);
+            tracing::debug!("OK {name} (type={ty:?})");
             passed += 1;
         }
+        report.push(entry);
+    }
+    let skipped_entries = skipped_lints
+        .into_iter()
+        .map(|lint| (lint.name, lint.ty, lint.root_type))
+        .chain(
+            skipped_rules
+                .into_iter()
+                .map(|rule| (rule.name.as_str(), rule.ty, None)),
+        );
+    for (name, ty, root_type) in skipped_entries {
+        report.push(LintReportEntry {
+            name: name.to_owned(),
+            ty: policy.effective_type(name, ty),
+            status: LintStatus::Skipped,
+            root_type,
+            message: None,
+            paths: Vec::new(),
+        });
     }
 
     Ok(LintExecutionResult {
@@ -370,31 +766,140 @@ fn lint_inner<'skip>(
         skipped,
         warnings,
         fatal,
+        results: report,
     })
 }
 
+/// Generate a SARIF 2.1.0 log from lint results.
+fn sarif_report(policy: &LintConfig, results: &[LintReportEntry]) -> serde_json::Value {
+    let rules = results
+        .iter()
+        .filter(|r| r.status != LintStatus::Skipped)
+        .map(|r| {
+            let description = LINTS
+                .iter()
+                .find(|lint| lint.name == r.name)
+                .map(|lint| lint.description.trim())
+                .or_else(|| {
+                    policy
+                        .rules
+                        .iter()
+                        .find(|rule| rule.name == r.name)
+                        .and_then(|rule| rule.description.as_deref())
+                })
+                .unwrap_or_default();
+            serde_json::json!({
+                "id": r.name,
+                "fullDescription": { "text": description },
+                "defaultConfiguration": { "level": sarif_level(r.ty) },
+            })
+        })
+        .collect::<Vec<_>>();
+    let results = results
+        .iter()
+        .filter(|r| r.status == LintStatus::Failed)
+        .map(|r| {
+            let locations = r
+                .paths
+                .iter()
+                .map(|p| {
+                    serde_json::json!({
+                        "physicalLocation": {
+                            "artifactLocation": {
+                                "uri": p.trim_start_matches('/'),
+                                "uriBaseId": "ROOTFS",
+                            }
+                        }
+                    })
+                })
+                .collect::<Vec<_>>();
+            serde_json::json!({
+                "ruleId": r.name,
+                "level": sarif_level(r.ty),
+                "message": { "text": r.message.as_deref().unwrap_or_default() },
+                "locations": locations,
+            })
+        })
+        .collect::<Vec<_>>();
+    serde_json::json!({
+        "$schema": "https://json.schemastore.org/sarif-2.1.0.json",
+        "version": "2.1.0",
+        "runs": [{
+            "tool": {
+                "driver": {
+                    "name": "bootc",
+                    "version": env!("CARGO_PKG_VERSION"),
+                    "informationUri": "https://bootc-dev.github.io/bootc/",
+                    "rules": rules,
+                }
+            },
+            "results": results,
+        }]
+    })
+}
+
+fn sarif_level(ty: LintType) -> &'static str {
+    match ty {
+        LintType::Fatal => "error",
+        LintType::Warning => "warning",
+    }
+}
+
 #[context("Linting")]
+#[allow(clippy::too_many_arguments)]
 pub(crate) fn lint<'skip>(
     root: &Dir,
     warning_disposition: WarningDisposition,
     root_type: RootType,
     skip: impl IntoIterator<Item = &'skip str>,
+    config_path: Option<&Utf8Path>,
+    format: LintOutputFormat,
     mut output: impl std::io::Write,
     no_truncate: bool,
 ) -> Result<()> {
-    let config = LintExecutionConfig { no_truncate };
-    let r = lint_inner(root, root_type, &config, skip, &mut output)?;
-    writeln!(output, "Checks passed: {}", r.passed)?;
-    if r.skipped > 0 {
-        writeln!(output, "Checks skipped: {}", r.skipped)?;
-    }
+    let policy = LintConfig::load(root, config_path)?;
+    let human_readable = format == LintOutputFormat::HumanReadable;
+    let config = LintExecutionConfig {
+        // Machine readable output is never truncated
+        no_truncate: no_truncate || !human_readable,
+        ..Default::default()
+    };
+    let mut r = if human_readable {
+        lint_inner(root, root_type, &config, &policy, skip, &mut output)?
+    } else {
+        lint_inner(root, root_type, &config, &policy, skip, std::io::sink())?
+    };
     let fatal = if matches!(warning_disposition, WarningDisposition::FatalWarnings) {
         r.fatal + r.warnings
     } else {
         r.fatal
     };
-    if r.warnings > 0 {
-        writeln!(output, "Warnings: {}", r.warnings)?;
+    match format {
+        LintOutputFormat::HumanReadable => {
+            writeln!(output, "Checks passed: {}", r.passed)?;
+            if r.skipped > 0 {
+                writeln!(output, "Checks skipped: {}", r.skipped)?;
+            }
+            if r.warnings > 0 {
+                writeln!(output, "Warnings: {}", r.warnings)?;
+            }
+        }
+        LintOutputFormat::Json => {
+            let results = std::mem::take(&mut r.results);
+            let report = LintReport {
+                version: LINT_REPORT_VERSION,
+                root_type,
+                summary: r,
+                results,
+            };
+            serde_json::to_writer_pretty(&mut output, &report)?;
+            writeln!(output)?;
+        }
+        LintOutputFormat::Sarif => {
+            let report = sarif_report(&policy, &r.results);
+            serde_json::to_writer_pretty(&mut output, &report)?;
+            writeln!(output)?;
+        }
     }
     if fatal > 0 {
         anyhow::bail!("Checks failed: {}", fatal)
@@ -402,6 +907,48 @@ pub(crate) fn lint<'skip>(
     Ok(())
 }
 
+/// Generate systemd sysusers.d and tmpfiles.d entries for content which
+/// would otherwise be reported by the `sysusers` and `var-tmpfiles` lints.
+///
+/// Note that content in /var which is translated to tmpfiles.d is removed.
+#[context("Fixing lints")]
+pub(crate) fn fix(root: &Dir, root_type: RootType, mut output: impl std::io::Write) -> Result<()> {
+    let r = bootc_sysusers::generate_missing(root)?;
+    if let Some((n, path)) = r.generated {
+        writeln!(output, "Generated {n} sysusers.d entries: /{path}")?;
+    }
+
+    if root.open_dir_optional("var")?.is_none() {
+        return Ok(());
+    }
+    // This is part of systemd; we can't generate entries without it.
+    if !root.try_exists("usr/lib/tmpfiles.d")? {
+        writeln!(
+            output,
+            "Skipping tmpfiles.d generation: /usr/lib/tmpfiles.d not found"
+        )?;
+        return Ok(());
+    }
+    let r = match root_type {
+        RootType::Running => bootc_tmpfiles::convert_var_to_tmpfiles_current_root()?,
+        RootType::Alternative => {
+            let userdb = bootc_sysusers::load_userdb(root)?;
+            bootc_tmpfiles::var_to_tmpfiles(root, &userdb, &userdb)?
+        }
+    };
+    if let Some((n, path)) = r.generated {
+        writeln!(output, "Generated {n} tmpfiles.d entries: /{path}")?;
+    }
+    if r.unsupported > 0 {
+        writeln!(
+            output,
+            "Ignored {} unsupported files in /var",
+            r.unsupported
+        )?;
+    }
+    Ok(())
+}
+
 /// check for the existence of the /var/run directory
 /// if it exists we need to check that it links to /run if not error
 #[distributed_slice(LINTS)]
@@ -413,7 +960,7 @@ static LINT_VAR_RUN: Lint = Lint::new_fatal(
 fn check_var_run(root: &Dir, _config: &LintExecutionConfig) -> LintResult {
     if let Some(meta) = root.symlink_metadata_optional("var/run")? {
         if !meta.is_symlink() {
-            return lint_err("Not a symlink: var/run");
+            return lint_err_at(["/var/run"], "Not a symlink: var/run");
         }
     }
     lint_ok()
@@ -435,9 +982,12 @@ fn check_buildah_injected(root: &Dir, _config: &LintExecutionConfig) -> LintResu
     for ent in RUNTIME_INJECTED {
         if let Some(meta) = root.symlink_metadata_optional(ent)? {
             if meta.is_file() && meta.size() == 0 {
-                return lint_err(format!(
-                    "/{ent} is an empty file; this may have been synthesized by a container runtime."
-                ));
+                return lint_err_at(
+                    [format!("/{ent}")],
+                    format!(
+                        "/{ent} is an empty file; this may have been synthesized by a container runtime."
+                    ),
+                );
             }
         }
     }
@@ -462,7 +1012,8 @@ fn check_usretc(root: &Dir, _config: &LintExecutionConfig) -> LintResult {
     }
     // But having both /etc and /usr/etc is not something we want to support.
     if root.symlink_metadata_optional("usr/etc")?.is_some() {
-        return lint_err(
+        return lint_err_at(
+            ["/usr/etc"],
             "Found /usr/etc - this is a bootc implementation detail and not supported to use in containers",
         );
     }
@@ -477,7 +1028,7 @@ static LINT_KARGS: Lint = Lint::new_fatal(
     check_parse_kargs,
 );
 fn check_parse_kargs(root: &Dir, _config: &LintExecutionConfig) -> LintResult {
-    let args = crate::bootc_kargs::get_kargs_in_root(root, ARCH)?;
+    let args = crate::bootc_kargs::get_kargs_in_root(root, &KargsMatchContext::for_arch(ARCH))?;
     tracing::debug!("found kargs: {args:?}");
     lint_ok()
 }
@@ -497,6 +1048,60 @@ fn check_kernel(root: &Dir, _config: &LintExecutionConfig) -> LintResult {
     lint_ok()
 }
 
+#[distributed_slice(LINTS)]
+static LINT_UKI_SIGNATURE: Lint = Lint::new_fatal(
+    "uki-signature",
+    indoc! { r#"
+Verify that the UKI is signed for Secure Boot with one of the certificates
+configured via `uki-certificates` in the lint configuration, using `sbverify`.
+Nothing is checked if no certificates are configured.
+"#},
+    check_uki_signature,
+);
+fn check_uki_signature(root: &Dir, config: &LintExecutionConfig) -> LintResult {
+    let certs = &config.uki_certificates;
+    if certs.is_empty() {
+        return lint_ok();
+    }
+    let uki = match crate::kernel::find_kernel(root)?.map(|k| k.k_type) {
+        Some(crate::kernel::KernelType::Uki { path, .. }) => path,
+        _ => {
+            return lint_err("Certificates for the UKI signature are configured, but no UKI found");
+        }
+    };
+    if !crate::utils::have_executable("sbverify")? {
+        return lint_err("sbverify not found in PATH; it is required to verify the UKI signature");
+    }
+
+    // sbverify operates on paths, but the target root may not be the running one
+    let td = tempfile::tempdir()?;
+    let uki_copy = td.path().join("uki.efi");
+    let mut src = root.open(&uki).with_context(|| format!("Opening /{uki}"))?;
+    let mut dest = std::fs::File::create(&uki_copy)?;
+    std::io::copy(&mut src, &mut dest).with_context(|| format!("Copying /{uki}"))?;
+    let cert_copy = td.path().join("cert.pem");
+    for cert in certs {
+        let rel = cert.strip_prefix("/")?;
+        if !root.try_exists(rel)? {
+            return lint_err_at([cert.clone()], format!("Missing certificate: {cert}"));
+        }
+        std::fs::write(&cert_copy, root.read(rel)?)?;
+        let r = std::process::Command::new("sbverify")
+            .arg("--cert")
+            .arg(&cert_copy)
+            .arg(&uki_copy)
+            .run_capture_stderr();
+        match r {
+            Ok(()) => return lint_ok(),
+            Err(e) => tracing::debug!("Verifying /{uki} with {cert}: {e:#}"),
+        }
+    }
+    lint_err_at(
+        [Utf8Path::new("/").join(&uki)],
+        format!("UKI /{uki} is not signed with any of the configured certificates"),
+    )
+}
+
 // This one can be lifted in the future, see https://github.com/bootc-dev/bootc/issues/975
 #[distributed_slice(LINTS)]
 static LINT_UTF8: Lint = Lint {
@@ -507,6 +1112,7 @@ UTF-8 filenames. Non-UTF8 filenames will cause a fatal error.
 "#},
     ty: LintType::Fatal,
     root_type: None,
+    allow_paths: false,
     f: LintFnTy::Recursive(check_utf8),
 };
 fn check_utf8(e: &WalkComponent, _config: &LintExecutionConfig) -> LintRecursiveResult {
@@ -592,6 +1198,27 @@ fn check_composefs(dir: &Dir, _config: &LintExecutionConfig) -> LintResult {
     lint_ok()
 }
 
+#[distributed_slice(LINTS)]
+static LINT_COMPOSEFS_SETUP_ROOT: Lint = Lint::new_fatal(
+    "composefs-setup-root",
+    indoc! { r#"
+Verify /usr/lib/composefs/setup-root-conf.toml, which configures how /etc and /var
+are set up at boot for the composefs backend. An invalid configuration would
+make the system fail to boot.
+"#},
+    check_composefs_setup_root,
+);
+fn check_composefs_setup_root(root: &Dir, _config: &LintExecutionConfig) -> LintResult {
+    let path = bootc_initramfs_setup::CONFIG_PATH;
+    let Some(text) = root.read_to_string_optional(path)? else {
+        return lint_ok();
+    };
+    if let Err(e) = bootc_initramfs_setup::Config::parse_strict(&text) {
+        return lint_err(format!("Invalid /{path}: {e:#}"));
+    }
+    lint_ok()
+}
+
 /// Check for a few files and directories we expect in the base image.
 fn check_baseimage_root_norecurse(dir: &Dir, _config: &LintExecutionConfig) -> LintResult {
     // Check /sysroot
@@ -676,21 +1303,22 @@ timestamps, causing unreproducible container images, and may contain
 sensitive build system information.
 "#},
     check_varlog,
-);
+)
+.set_allow_paths();
 fn check_varlog(root: &Dir, config: &LintExecutionConfig) -> LintResult {
     let Some(d) = root.open_dir_optional("var/log")? else {
         return lint_ok();
     };
     let mut nonempty_regfiles = BTreeSet::new();
     collect_nonempty_regfiles(&d, "/var/log".into(), &mut nonempty_regfiles)?;
+    nonempty_regfiles.retain(|p| !config.is_path_allowed(p));
 
     if nonempty_regfiles.is_empty() {
         return lint_ok();
     }
 
     let header = "Found non-empty logfiles";
-    let items = nonempty_regfiles.iter().map(PathQuotedDisplay::new);
-    format_lint_err_from_items(config, header, items)
+    format_lint_err_from_paths(config, header, nonempty_regfiles)
 }
 
 #[distributed_slice(LINTS)]
@@ -707,10 +1335,24 @@ as part of each boot.
 "#},
     check_var_tmpfiles,
 )
-.set_root_type(RootType::Running);
+.set_root_type(RootType::Running)
+.set_allow_paths();
 
 fn check_var_tmpfiles(_root: &Dir, config: &LintExecutionConfig) -> LintResult {
-    let r = bootc_tmpfiles::find_missing_tmpfiles_current_root()?;
+    let mut r = bootc_tmpfiles::find_missing_tmpfiles_current_root()?;
+    let entry_path = |entry: &str| {
+        bootc_tmpfiles::TmpfilesEntry::parse(entry)
+            .ok()
+            .flatten()
+            .and_then(|e| Utf8PathBuf::from_path_buf(e.path).ok())
+    };
+    r.tmpfiles
+        .retain(|entry| !entry_path(entry).is_some_and(|p| config.is_path_allowed(&p)));
+    // Unsupported paths are relative to the root
+    r.unsupported.retain(|p| {
+        let path = Path::new("/").join(p);
+        !Utf8Path::from_path(&path).is_some_and(|p| config.is_path_allowed(p))
+    });
     if r.tmpfiles.is_empty() && r.unsupported.is_empty() {
         return lint_ok();
     }
@@ -720,7 +1362,16 @@ fn check_var_tmpfiles(_root: &Dir, config: &LintExecutionConfig) -> LintResult {
     let header = "Found non-directory/non-symlink files in /var";
     let items = r.unsupported.iter().map(PathQuotedDisplay::new);
     format_items(config, header, items, &mut msg)?;
-    lint_err(msg)
+    let paths = r
+        .tmpfiles
+        .iter()
+        .filter_map(|entry| entry_path(entry))
+        .chain(
+            r.unsupported
+                .iter()
+                .filter_map(|p| Utf8Path::from_path(p).map(|p| Utf8Path::new("/").join(p))),
+        );
+    lint_err_at(paths, msg)
 }
 
 #[distributed_slice(LINTS)]
@@ -763,7 +1414,8 @@ content should be in /usr/lib/modules instead in the container image.
 Any content here in the container image will be masked at runtime.
 "#},
     check_boot,
-);
+)
+.set_allow_paths();
 fn check_boot(root: &Dir, config: &LintExecutionConfig) -> LintResult {
     let Some(d) = root.open_dir_optional("boot")? else {
         return lint_err("Missing /boot directory");
@@ -787,13 +1439,21 @@ fn check_boot(root: &Dir, config: &LintExecutionConfig) -> LintResult {
             .unwrap();
         entries.remove(efidir.as_os_str());
     }
+    entries.retain(|name| {
+        let path = Path::new("/boot").join(name);
+        !Utf8Path::from_path(&path).is_some_and(|p| config.is_path_allowed(p))
+    });
     if entries.is_empty() {
         return lint_ok();
     }
 
     let header = "Found non-empty /boot";
     let items = entries.iter().map(PathQuotedDisplay::new);
-    format_lint_err_from_items(config, header, items)
+    let paths = entries
+        .iter()
+        .map(|name| Utf8Path::new("/boot").join(name.to_string_lossy().as_ref()))
+        .collect::<Vec<_>>();
+    format_lint_err_from_items(config, header, items).map(|r| r.map_err(|e| e.with_paths(paths)))
 }
 
 /// Directories that should be empty in container images.
@@ -818,7 +1478,8 @@ These directories are normally mounted as `tmpfs` at runtime
 artifacts that serve no purpose in the final image.
 "#},
     check_runtime_only_dirs,
-);
+)
+.set_allow_paths();
 
 fn check_runtime_only_dirs(root: &Dir, config: &LintExecutionConfig) -> LintResult {
     let mut found_content = BTreeSet::new();
@@ -853,14 +1514,14 @@ fn check_runtime_only_dirs(root: &Dir, config: &LintExecutionConfig) -> LintResu
     // (e.g. stub-resolv.conf and its parent directories if they have
     // no other children).
     prune_known_run_paths(&mut found_content);
+    found_content.retain(|p| !config.is_path_allowed(p));
 
     if found_content.is_empty() {
         return lint_ok();
     }
 
     let header = "Found content in runtime-only directories (/run, /tmp)";
-    let items = found_content.iter().map(PathQuotedDisplay::new);
-    format_lint_err_from_items(config, header, items)
+    format_lint_err_from_paths(config, header, found_content)
 }
 
 /// Remove known container-runtime injected paths from `/run`.
@@ -970,10 +1631,30 @@ mod tests {
         let mut out = Vec::new();
         let warnings = WarningDisposition::FatalWarnings;
         let root_type = RootType::Alternative;
-        lint(root, warnings, root_type, [], &mut out, config.no_truncate).unwrap();
+        lint(
+            root,
+            warnings,
+            root_type,
+            [],
+            None,
+            &mut out,
+            config.no_truncate,
+        )
+        .unwrap();
         root.create_dir_all("var/run/foo")?;
         let mut out = Vec::new();
-        assert!(lint(root, warnings, root_type, [], &mut out, config.no_truncate).is_err());
+        assert!(
+            lint(
+                root,
+                warnings,
+                root_type,
+                [],
+                None,
+                &mut out,
+                config.no_truncate
+            )
+            .is_err()
+        );
         Ok(())
     }
 
@@ -981,18 +1662,19 @@ mod tests {
     fn test_lint_inner() -> Result<()> {
         let root = &passing_fixture()?;
         let config = &LintExecutionConfig::default();
+        let policy = &LintConfig::default();
 
         // Verify that all lints run
         let mut out = Vec::new();
         let root_type = RootType::Alternative;
-        let r = lint_inner(root, root_type, config, [], &mut out).unwrap();
+        let r = lint_inner(root, root_type, config, policy, [], &mut out).unwrap();
         let running_only_lints = LINTS.len().checked_sub(*ALTROOT_LINTS).unwrap();
         assert_eq!(r.warnings, 0);
         assert_eq!(r.fatal, 0);
         assert_eq!(r.skipped, running_only_lints);
         assert_eq!(r.passed, *ALTROOT_LINTS);
 
-        let r = lint_inner(root, root_type, config, ["var-log"], &mut out).unwrap();
+        let r = lint_inner(root, root_type, config, policy, ["var-log"], &mut out).unwrap();
         // Trigger a failure in var-log by creating a non-empty log file.
         root.create_dir_all("var/log/dnf")?;
         root.write("var/log/dnf/dnf.log", b"dummy dnf log")?;
@@ -1003,7 +1685,7 @@ mod tests {
 
         // But verify that not skipping it results in a warning
         let mut out = Vec::new();
-        let r = lint_inner(root, root_type, config, [], &mut out).unwrap();
+        let r = lint_inner(root, root_type, config, policy, [], &mut out).unwrap();
         assert_eq!(r.passed, ALTROOT_LINTS.checked_sub(1).unwrap());
         assert_eq!(r.fatal, 0);
         assert_eq!(r.skipped, running_only_lints);
@@ -1011,6 +1693,281 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_lint_report() -> Result<()> {
+        let root = &passing_fixture()?;
+        let warnings = WarningDisposition::AllowWarnings;
+        let root_type = RootType::Alternative;
+        root.create_dir_all("var/log")?;
+        root.write("var/log/foo.log", "log contents")?;
+
+        let mut out = Vec::new();
+        let format = LintOutputFormat::Json;
+        lint(
+            root,
+            warnings,
+            root_type,
+            ["var-run"],
+            None,
+            format,
+            &mut out,
+            false,
+        )?;
+        let report: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(report["version"], 1);
+        assert_eq!(report["rootType"], "Alternative");
+        assert_eq!(report["summary"]["warnings"], 1);
+        assert_eq!(report["summary"]["fatal"], 0);
+        let results = report["results"].as_array().unwrap();
+        assert_eq!(results.len(), LINTS.len());
+        let varlog = results.iter().find(|r| r["name"] == "var-log").unwrap();
+        similar_asserts::assert_eq!(
+            varlog,
+            &serde_json::json!({
+                "name": "var-log",
+                "type": "warning",
+                "status": "failed",
+                "message": "Found non-empty logfiles:\n  /var/log/foo.log\n",
+                "paths": ["/var/log/foo.log"],
+            })
+        );
+        let varrun = results.iter().find(|r| r["name"] == "var-run").unwrap();
+        assert_eq!(varrun["status"], "skipped");
+        let tmpfiles = results
+            .iter()
+            .find(|r| r["name"] == "var-tmpfiles")
+            .unwrap();
+        assert_eq!(tmpfiles["status"], "skipped");
+        assert_eq!(tmpfiles["rootType"], "Running");
+
+        // The SARIF log is still written when the checks fail
+        let mut out = Vec::new();
+        let format = LintOutputFormat::Sarif;
+        let warnings = WarningDisposition::FatalWarnings;
+        assert!(lint(root, warnings, root_type, [], None, format, &mut out, false).is_err());
+        let sarif: serde_json::Value = serde_json::from_slice(&out)?;
+        assert_eq!(sarif["version"], "2.1.0");
+        let run = &sarif["runs"][0];
+        assert_eq!(run["tool"]["driver"]["name"], "bootc");
+        assert_eq!(
+            run["tool"]["driver"]["rules"].as_array().unwrap().len(),
+            *ALTROOT_LINTS
+        );
+        let results = run["results"].as_array().unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["ruleId"], "var-log");
+        assert_eq!(results[0]["level"], "warning");
+        assert_eq!(
+            results[0]["locations"][0]["physicalLocation"]["artifactLocation"]["uri"],
+            "var/log/foo.log"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_lint_config() -> Result<()> {
+        let root = &fixture()?;
+
+        // No configuration at all is fine
+        let c = LintConfig::load(root, None)?;
+        assert!(c.disable.is_empty());
+        assert!(c.rules.is_empty());
+
+        root.create_dir_all("usr/lib/bootc")?;
+        root.write(
+            LINT_CONFIG_PATH,
+            indoc! { r#"
+                disable = ["nonempty-boot", "var-log"]
+                fatal = ["sysusers"]
+                uki-certificates = ["/usr/lib/bootc/db.pem"]
+
+                [allow-paths]
+                nonempty-run-tmp = ["/run/foo"]
+
+                [[rule]]
+                name = "no-ssh-hostkeys"
+                path = "/etc/ssh/ssh_host_rsa_key"
+                exists = false
+            "# },
+        )?;
+        let tmpd = tempfile::tempdir()?;
+        let extra = Utf8Path::from_path(tmpd.path()).unwrap().join("lint.toml");
+        std::fs::write(
+            &extra,
+            indoc! { r#"
+                enable = ["var-log"]
+                uki-certificates = ["/usr/lib/bootc/db2.pem"]
+
+                [allow-paths]
+                nonempty-run-tmp = ["/run/bar"]
+
+                [[rule]]
+                name = "no-ssh-hostkeys"
+                type = "fatal"
+                path = "/etc/ssh/ssh_host_ed25519_key"
+                exists = false
+            "# },
+        )?;
+        let c = LintConfig::load(root, Some(&extra))?;
+        assert_eq!(c.disable.iter().collect::<Vec<_>>(), vec!["nonempty-boot"]);
+        assert_eq!(
+            c.effective_type("sysusers", LintType::Warning),
+            LintType::Fatal
+        );
+        assert_eq!(
+            c.effective_type("var-log", LintType::Warning),
+            LintType::Warning
+        );
+        assert_eq!(c.allow_paths["nonempty-run-tmp"], ["/run/foo", "/run/bar"]);
+        assert_eq!(
+            c.uki_certificates,
+            ["/usr/lib/bootc/db.pem", "/usr/lib/bootc/db2.pem"]
+        );
+        assert_eq!(c.rules.len(), 1);
+        assert_eq!(c.rules[0].ty, LintType::Fatal);
+        assert_eq!(c.rules[0].path, "/etc/ssh/ssh_host_ed25519_key");
+
+        // Invalid configurations
+        let cases = [
+            "unknown-key = true",
+            "disable = [\"var-log\"]\nenable = [\"var-log\"]",
+            "disable = [\"no-such-lint\"]",
+            "fatal = [\"no-such-lint\"]",
+            "[allow-paths]\nvar-log = [\"var/log/foo\"]",
+            "uki-certificates = [\"db.pem\"]",
+            "[[rule]]\nname = \"var-log\"\npath = \"/foo\"\nexists = true",
+            "[[rule]]\nname = \"foo\"\npath = \"foo\"\nexists = true",
+            "[[rule]]\nname = \"foo\"\npath = \"/foo\"",
+            "[[rule]]\nname = \"foo\"\npath = \"/foo\"\nexists = true\nmatches = \"x\"",
+            "[[rule]]\nname = \"foo\"\npath = \"/foo\"\nmatches = \"(\"",
+            "[[rule]]\nname = \"foo\"\npath = \"/foo\"\nexists = true\n[allow-paths]\nfoo = [\"/foo\"]",
+            "[allow-paths]\netc-usretc = [\"/usr/etc\"]",
+        ];
+        for case in cases {
+            let r = LintConfig::parse(case).and_then(|c| c.validate());
+            assert!(r.is_err(), "Expected error for: {case}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_uki_signature() -> Result<()> {
+        let root = &passing_fixture()?;
+        let config = &LintExecutionConfig::default();
+        // Nothing to verify without configured certificates
+        check_uki_signature(root, config).unwrap().unwrap();
+
+        let config = &LintExecutionConfig {
+            uki_certificates: vec!["/usr/lib/bootc/db.pem".into()],
+            ..Default::default()
+        };
+        // The fixture has a traditional kernel
+        assert!(check_uki_signature(root, config).unwrap().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_custom_rules() -> Result<()> {
+        let root = &passing_fixture()?;
+        let config = &LintExecutionConfig::default();
+        let policy = LintConfig::parse(indoc! { r#"
+            fatal = ["require-selinux"]
+
+            [[rule]]
+            name = "no-hostkeys"
+            description = "host keys must be generated at runtime"
+            path = "/etc/ssh/ssh_host_rsa_key"
+            exists = false
+
+            [[rule]]
+            name = "require-selinux"
+            path = "/etc/selinux/config"
+            matches = "(?m)^SELINUX=enforcing$"
+        "# })?;
+        policy.validate()?;
+        let root_type = RootType::Alternative;
+
+        let mut out = Vec::new();
+        let r = lint_inner(root, root_type, config, &policy, [], &mut out).unwrap();
+        assert_eq!(r.warnings, 0);
+        assert_eq!(r.fatal, 1);
+        assert_eq!(r.passed, *ALTROOT_LINTS + 1);
+        let out = String::from_utf8(out)?;
+        similar_asserts::assert_eq!(
+            out,
+            "Failed lint: require-selinux: Missing required file: /etc/selinux/config\n"
+        );
+
+        root.create_dir_all("etc/selinux")?;
+        root.write("etc/selinux/config", "SELINUX=permissive\n")?;
+        root.create_dir_all("etc/ssh")?;
+        root.write("etc/ssh/ssh_host_rsa_key", "secret")?;
+        let mut out = Vec::new();
+        let r = lint_inner(root, root_type, config, &policy, [], &mut out).unwrap();
+        assert_eq!(r.warnings, 1);
+        assert_eq!(r.fatal, 1);
+        let out = String::from_utf8(out)?;
+        similar_asserts::assert_eq!(
+            out,
+            indoc! { r#"
+                Lint warning: no-hostkeys: Found forbidden path: /etc/ssh/ssh_host_rsa_key (host keys must be generated at runtime)
+                Failed lint: require-selinux: /etc/selinux/config does not match regex: (?m)^SELINUX=enforcing$
+            "# }
+        );
+
+        root.write("etc/selinux/config", "# comment\nSELINUX=enforcing\n")?;
+        let mut out = Vec::new();
+        let r = lint_inner(root, root_type, config, &policy, ["no-hostkeys"], &mut out).unwrap();
+        assert_eq!(r.warnings, 0);
+        assert_eq!(r.fatal, 0);
+        assert_eq!(r.passed, *ALTROOT_LINTS + 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fix() -> Result<()> {
+        let root = &passing_fixture()?;
+        let root_type = RootType::Alternative;
+        let config = &LintExecutionConfig::default();
+
+        // Nothing to do, and no tmpfiles.d
+        let mut out = Vec::new();
+        fix(root, root_type, &mut out)?;
+        assert_eq!(
+            String::from_utf8(out)?,
+            "Skipping tmpfiles.d generation: /usr/lib/tmpfiles.d not found\n"
+        );
+
+        let uid = rustix::process::getuid().as_raw();
+        let gid = rustix::process::getgid().as_raw();
+        root.create_dir_all("etc")?;
+        root.write(
+            "etc/passwd",
+            format!("builder:x:{uid}:{gid}::/var/lib/builder:/sbin/nologin\n"),
+        )?;
+        root.write("etc/group", format!("builder:x:{gid}:\n"))?;
+        root.create_dir_all("usr/lib/tmpfiles.d")?;
+        root.create_dir_all("var/lib/builder")?;
+        assert!(check_sysusers(root, config).unwrap().is_err());
+
+        let mut out = Vec::new();
+        fix(root, root_type, &mut out)?;
+        // The primary group is implicit if the IDs match
+        let n = if uid == gid { 1 } else { 2 };
+        similar_asserts::assert_eq!(
+            String::from_utf8(out)?,
+            indoc::formatdoc! { r#"
+                Generated {n} sysusers.d entries: /usr/lib/sysusers.d/bootc-autogenerated-users-0.conf
+                Generated 2 tmpfiles.d entries: /usr/lib/tmpfiles.d/bootc-autogenerated-var-0.conf
+            "# }
+        );
+        check_sysusers(root, config).unwrap().unwrap();
+        assert!(!root.try_exists("var/lib")?);
+        let tmpfiles = root.read_to_string("usr/lib/tmpfiles.d/bootc-autogenerated-var-0.conf")?;
+        assert!(tmpfiles.contains("d /var/lib/builder 0755 builder builder - -"));
+        Ok(())
+    }
+
     #[test]
     fn test_kernel_lint() -> Result<()> {
         let root = &fixture()?;
@@ -1088,6 +2045,28 @@ mod tests {
                 "# }
         );
 
+        // Allowlisted paths are not reported
+        let config = &LintExecutionConfig {
+            allow_paths: ["/var/log/someproject".into()].into(),
+            ..Default::default()
+        };
+        let Err(e) = check_varlog(root, config).unwrap() else {
+            unreachable!()
+        };
+        similar_asserts::assert_eq!(
+            e.to_string(),
+            "Found non-empty logfiles:\n  /var/log/somefile.log\n"
+        );
+        let config = &LintExecutionConfig {
+            allow_paths: [
+                "/var/log/somefile.log".into(),
+                "/var/log/someproject".into(),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        check_varlog(root, config).unwrap().unwrap();
+
         Ok(())
     }
 
@@ -1345,6 +2324,29 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_composefs_setup_root() -> Result<()> {
+        let root = &fixture()?;
+        let config = &LintExecutionConfig::default();
+        check_composefs_setup_root(root, config).unwrap().unwrap();
+
+        root.create_dir_all("usr/lib/composefs")?;
+        let path = bootc_initramfs_setup::CONFIG_PATH;
+        root.write(
+            path,
+            "[etc]\nmount = \"transient\"\n[var]\nmount = \"tmpfs\"\npersist = [\"lib/app\"]\n",
+        )?;
+        check_composefs_setup_root(root, config).unwrap().unwrap();
+
+        root.write(path, "[etc]\nmount = \"bind\"\npersist = [\"lib/app\"]\n")?;
+        assert!(check_composefs_setup_root(root, config).unwrap().is_err());
+        root.write(path, "[var]\nmount = \"rw\"\n")?;
+        assert!(check_composefs_setup_root(root, config).unwrap().is_err());
+        root.write(path, "[var]\nmonut = \"tmpfs\"\n")?;
+        assert!(check_composefs_setup_root(root, config).unwrap().is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_list() {
         let mut r = Vec::new();
@@ -1355,7 +2357,10 @@ mod tests {
 
     #[test]
     fn test_format_items_no_truncate() -> Result<()> {
-        let config = LintExecutionConfig { no_truncate: true };
+        let config = LintExecutionConfig {
+            no_truncate: true,
+            ..Default::default()
+        };
         let header = "Test Header";
         let mut output_str = String::new();
 
@@ -271,6 +271,19 @@ impl BLSConfig {
             _ => anyhow::bail!("No cmdline found for config"),
         }
     }
+
+    /// Sets the `options` field of the config
+    /// Returns an error if the config is of type `EFI`
+    pub(crate) fn set_cmdline(&mut self, cmdline: CmdlineOwned) -> Result<()> {
+        match &mut self.cfg_type {
+            BLSConfigType::NonEFI { options, .. } => {
+                *options = Some(cmdline);
+                Ok(())
+            }
+
+            _ => anyhow::bail!("Cannot set cmdline for config"),
+        }
+    }
 }
 
 pub(crate) fn parse_bls_config(input: &str) -> Result<BLSConfig> {
@@ -471,6 +484,32 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_set_cmdline() -> Result<()> {
+        let input = r#"
+            title Test OS
+            version 10
+            linux /boot/vmlinuz
+            initrd /boot/initrd.img
+            options root=UUID=abc composefs=some-uuid
+        "#;
+
+        let mut config = parse_bls_config(input)?;
+        config.set_cmdline(CmdlineOwned::from(
+            "root=UUID=abc composefs=some-uuid console=ttyS0".to_owned(),
+        ))?;
+        let config = parse_bls_config(&config.to_string())?;
+        assert_eq!(
+            &**config.get_cmdline()?,
+            "root=UUID=abc composefs=some-uuid console=ttyS0"
+        );
+
+        let mut config = parse_bls_config("version 10\nefi /EFI/Linux/test.efi")?;
+        assert!(config.set_cmdline(CmdlineOwned::default()).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_ordering_by_version() -> Result<()> {
         let config1 = parse_bls_config(
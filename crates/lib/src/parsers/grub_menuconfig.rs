@@ -16,6 +16,7 @@ use nom::{
 
 use crate::{
     bootc_composefs::boot::{BOOTC_UKI_DIR, get_uki_name},
+    bootc_composefs::boot_counting::{BOOT_COUNTER, BOOT_SUCCESS},
     composefs_consts::UKI_NAME_PREFIX,
 };
 
@@ -153,6 +154,38 @@ impl<'a> MenuEntry<'a> {
     }
 }
 
+/// Returns a snippet for grub2/user.cfg implementing boot counting.
+///
+/// When `boot_counter` is set in grubenv and the last boot was not marked
+/// successful, each boot decrements the counter. Once it reaches zero the
+/// second entry (the rollback deployment) is booted instead, and the counter
+/// is set to `-1` to record that the tries ran out.
+///
+/// GRUB script has no arithmetic, so the decrement is an explicit chain.
+/// Note that this must not contain the word that starts an entry, as the
+/// parser skips everything up to the first one.
+pub(crate) fn get_boot_counting_source(tries: u32) -> String {
+    let mut decrements = String::new();
+    for n in (1..=tries).rev() {
+        decrements.push_str(&format!(
+            "  elif [ \"${{{BOOT_COUNTER}}}\" = \"{n}\" ]; then\n    set {BOOT_COUNTER}={}\n",
+            n - 1
+        ));
+    }
+
+    format!(
+        r#"
+if [ -n "${{{BOOT_COUNTER}}}" -a "${{{BOOT_SUCCESS}}}" = "0" ]; then
+  if [ "${{{BOOT_COUNTER}}}" = "0" -o "${{{BOOT_COUNTER}}}" = "-1" ]; then
+    set default=1
+    set {BOOT_COUNTER}=-1
+{decrements}  fi
+  save_env {BOOT_COUNTER}
+fi
+"#
+    )
+}
+
 /// Parser that takes content until balanced brackets, handling nested brackets and escapes.
 fn take_until_balanced_allow_nested(
     opening_bracket: char,
@@ -368,6 +401,33 @@ mod test {
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_boot_counting_source() {
+        let expected = r#"
+if [ -n "${boot_counter}" -a "${boot_success}" = "0" ]; then
+  if [ "${boot_counter}" = "0" -o "${boot_counter}" = "-1" ]; then
+    set default=1
+    set boot_counter=-1
+  elif [ "${boot_counter}" = "2" ]; then
+    set boot_counter=1
+  elif [ "${boot_counter}" = "1" ]; then
+    set boot_counter=0
+  fi
+  save_env boot_counter
+fi
+"#;
+        let source = get_boot_counting_source(2);
+        similar_asserts::assert_eq!(source, expected);
+
+        // The snippet must not confuse the menuentry parser
+        let user_cfg = format!(
+            "{source}\nmenuentry \"Fedora\" {{\n  chainloader /EFI/Linux/bootc/foo.efi\n}}\n"
+        );
+        let entries = parse_grub_menuentry_file(&user_cfg).expect("Parsing user.cfg");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].body.chainloader, "/EFI/Linux/bootc/foo.efi");
+    }
+
     #[test]
     fn test_escaped_quotes_in_title() {
         let menuentry = r#"
@@ -0,0 +1,131 @@
+//! Parser for the GRUB environment block (`grubenv`).
+//!
+//! The environment block is a fixed size file which GRUB itself can
+//! update in place via `save_env`. It starts with a header line, followed
+//! by `key=value` lines, and is padded to its full size with `#`.
+
+use std::fmt::Write as _;
+
+use anyhow::Result;
+
+/// The size in bytes of a GRUB environment block
+pub(crate) const GRUBENV_SIZE: usize = 1024;
+
+const GRUBENV_HEADER: &str = "# GRUB Environment Block\n";
+
+/// A parsed GRUB environment block, preserving the order of variables.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct GrubEnv {
+    vars: Vec<(String, String)>,
+}
+
+impl GrubEnv {
+    /// Parse the contents of a grubenv file.
+    pub(crate) fn parse(contents: &str) -> Result<Self> {
+        let Some(body) = contents.strip_prefix(GRUBENV_HEADER) else {
+            anyhow::bail!("Missing GRUB environment block header");
+        };
+
+        let mut vars = Vec::new();
+        for line in body.lines() {
+            // Padding and comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((k, v)) = line.split_once('=') else {
+                anyhow::bail!("Invalid line in GRUB environment block: {line}");
+            };
+
+            vars.push((k.to_owned(), v.to_owned()));
+        }
+
+        Ok(Self { vars })
+    }
+
+    /// Get the value of a variable
+    pub(crate) fn get(&self, key: &str) -> Option<&str> {
+        self.vars
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Set a variable, replacing any previous value
+    pub(crate) fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.vars.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.vars.push((key.to_owned(), value)),
+        }
+    }
+
+    /// Remove a variable, returning whether it was present
+    pub(crate) fn unset(&mut self, key: &str) -> bool {
+        let len = self.vars.len();
+        self.vars.retain(|(k, _)| k != key);
+        len != self.vars.len()
+    }
+
+    /// Serialize into a padded environment block.
+    pub(crate) fn to_block(&self) -> Result<Vec<u8>> {
+        let mut s = String::from(GRUBENV_HEADER);
+        for (k, v) in &self.vars {
+            writeln!(s, "{k}={v}")?;
+        }
+
+        let Some(padding) = GRUBENV_SIZE.checked_sub(s.len()) else {
+            anyhow::bail!("GRUB environment block exceeds {GRUBENV_SIZE} bytes");
+        };
+
+        let mut block = s.into_bytes();
+        block.resize(block.len() + padding, b'#');
+
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_grubenv_roundtrip() -> Result<()> {
+        let mut contents = String::from(GRUBENV_HEADER);
+        contents.push_str("saved_entry=foo\nboot_success=0\nboot_counter=2\n");
+        contents.push_str(&"#".repeat(GRUBENV_SIZE - contents.len()));
+
+        let mut env = GrubEnv::parse(&contents)?;
+        assert_eq!(env.get("saved_entry"), Some("foo"));
+        assert_eq!(env.get("boot_counter"), Some("2"));
+        assert_eq!(env.get("nonexistent"), None);
+
+        env.set("boot_success", "1");
+        assert!(env.unset("boot_counter"));
+        assert!(!env.unset("boot_counter"));
+
+        let block = env.to_block()?;
+        assert_eq!(block.len(), GRUBENV_SIZE);
+
+        let reparsed = GrubEnv::parse(std::str::from_utf8(&block)?)?;
+        assert_eq!(
+            reparsed.vars,
+            [
+                ("saved_entry".to_owned(), "foo".to_owned()),
+                ("boot_success".to_owned(), "1".to_owned())
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_grubenv_invalid() {
+        assert!(GrubEnv::parse("saved_entry=foo\n").is_err());
+        assert!(GrubEnv::parse(&format!("{GRUBENV_HEADER}novalue\n")).is_err());
+
+        let mut env = GrubEnv::default();
+        env.set("big", "x".repeat(GRUBENV_SIZE));
+        assert!(env.to_block().is_err());
+    }
+}
@@ -1,2 +1,3 @@
 pub(crate) mod bls_config;
 pub(crate) mod grub_menuconfig;
+pub(crate) mod grubenv;
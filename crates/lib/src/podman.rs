@@ -19,6 +19,32 @@ pub(crate) struct Inspect {
 pub(crate) struct ImageListEntry {
     pub(crate) id: String,
     pub(crate) names: Option<Vec<String>>,
+    /// The manifest digest
+    #[serde(default)]
+    pub(crate) digest: Option<String>,
+    /// The digests of all manifests referencing the image
+    #[serde(default)]
+    pub(crate) digests: Option<Vec<String>>,
+}
+
+impl ImageListEntry {
+    /// All manifest digests of the image.
+    pub(crate) fn digests(&self) -> impl Iterator<Item = &str> {
+        self.digest
+            .iter()
+            .chain(self.digests.iter().flatten())
+            .map(String::as_str)
+    }
+
+    /// A name for display: the first name of the image, or its ID if it has none.
+    pub(crate) fn display_name(&self) -> &str {
+        self.names
+            .iter()
+            .flatten()
+            .next()
+            .unwrap_or(&self.id)
+            .as_str()
+    }
 }
 
 /// Given an image ID, return its manifest digest
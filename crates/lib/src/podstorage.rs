@@ -69,7 +69,7 @@ pub(crate) struct CStorage {
     _unsync: std::cell::Cell<()>,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum PullMode {
     /// Pull only if the image is not present
     IfNotExists,
@@ -368,33 +368,58 @@ impl CStorage {
         .await?
     }
 
+    /// Remove all images which are neither referenced by one of the `roots`
+    /// names, nor have one of the `root_digests`.
     #[context("Pruning")]
-    pub(crate) async fn prune_except_roots(&self, roots: &HashSet<&str>) -> Result<Vec<String>> {
+    pub(crate) async fn prune_except_roots(
+        &self,
+        roots: &HashSet<&str>,
+        root_digests: &HashSet<&str>,
+        dry_run: bool,
+    ) -> Result<Vec<crate::podman::ImageListEntry>> {
         let all_images = self.list_images().await?;
         tracing::debug!("Images total: {}", all_images.len(),);
-        let mut garbage = Vec::new();
-        for image in all_images {
-            if image
-                .names
-                .iter()
-                .flatten()
-                .all(|name| !roots.contains(name.as_str()))
-            {
-                garbage.push(image.id);
-            }
-        }
+        let garbage = all_images
+            .into_iter()
+            .filter(|image| {
+                let named = image
+                    .names
+                    .iter()
+                    .flatten()
+                    .any(|name| roots.contains(name.as_str()));
+                let pinned = image.digests().any(|digest| root_digests.contains(digest));
+                !named && !pinned
+            })
+            .collect::<Vec<_>>();
         tracing::debug!("Images to prune: {}", garbage.len());
-        for garbage in garbage.chunks(SUBCMD_ARGV_CHUNKING) {
+        if dry_run {
+            return Ok(garbage);
+        }
+        for chunk in garbage.chunks(SUBCMD_ARGV_CHUNKING) {
             let mut cmd = self.new_image_cmd()?;
             cmd.stdin(Stdio::null());
             cmd.stdout(Stdio::null());
             cmd.arg("rm");
-            cmd.args(garbage);
+            cmd.args(chunk.iter().map(|image| image.id.as_str()));
             AsyncCommand::from(cmd).run().await?;
         }
         Ok(garbage)
     }
 
+    /// Return the manifest digest of an image in the storage.
+    #[context("Querying digest of {image}")]
+    pub(crate) async fn image_digest(&self, image: &str) -> Result<String> {
+        let mut cmd = self.new_image_cmd()?;
+        cmd.stdin(Stdio::null());
+        cmd.args(["inspect", image]);
+        let o: Vec<crate::podman::Inspect> =
+            tokio::task::spawn_blocking(move || cmd.run_and_parse_json()).await??;
+        o.into_iter()
+            .next()
+            .map(|i| i.digest)
+            .ok_or_else(|| anyhow::anyhow!("No images returned for inspect"))
+    }
+
     /// Return true if the image exists in the storage.
     pub(crate) async fn exists(&self, image: &str) -> Result<bool> {
         // Sadly https://docs.rs/containers-image-proxy/latest/containers_image_proxy/struct.ImageProxy.html#method.open_image_optional
@@ -407,6 +432,16 @@ impl CStorage {
     /// Fetch the image if it is not already present; return whether
     /// or not the image was fetched.
     pub(crate) async fn pull(&self, image: &str, mode: PullMode) -> Result<bool> {
+        self.pull_with_args(image, mode, &[]).await
+    }
+
+    /// Like [`Self::pull`], with additional arguments for `podman pull`.
+    pub(crate) async fn pull_with_args(
+        &self,
+        image: &str,
+        mode: PullMode,
+        args: &[String],
+    ) -> Result<bool> {
         match mode {
             PullMode::IfNotExists => {
                 if self.exists(image).await? {
@@ -419,7 +454,9 @@ impl CStorage {
         let mut cmd = self.new_image_cmd()?;
         cmd.stdin(Stdio::null());
         cmd.stdout(Stdio::null());
-        cmd.args(["pull", image]);
+        cmd.arg("pull");
+        cmd.args(args);
+        cmd.arg(image);
         tracing::debug!("Pulling image: {image}");
         let mut cmd = AsyncCommand::from(cmd);
         cmd.run().await.context("Failed to pull image")?;
@@ -449,6 +486,28 @@ impl CStorage {
         Ok(())
     }
 
+    /// Copy an image from an arbitrary source (e.g. `oci-archive:/path/to/archive:name`)
+    /// to this storage, storing it as `image`.
+    #[context("Copying {src} to {image}")]
+    pub(crate) async fn copy_from(&self, src: &str, image: &str) -> Result<()> {
+        let mut cmd = Command::new("skopeo");
+        cmd.stdin(Stdio::null());
+        cmd.stdout(Stdio::null());
+        // An ephemeral place for the transient state;
+        let temp_runroot = TempDir::new(cap_std::ambient_authority())?;
+        bind_storage_roots(&mut cmd, &self.storage_root, &temp_runroot)?;
+
+        let storage_dest = &format!(
+            "containers-storage:[overlay@{STORAGE_ALIAS_DIR}+/proc/self/fd/{STORAGE_RUN_FD}]"
+        );
+        cmd.args(["copy", "--remove-signatures", src])
+            .arg(format!("{storage_dest}{image}"));
+        let mut cmd = AsyncCommand::from(cmd);
+        cmd.run().await?;
+        temp_runroot.close()?;
+        Ok(())
+    }
+
     pub(crate) fn subpath() -> Utf8PathBuf {
         Utf8Path::new(crate::store::BOOTC_ROOT).join(SUBPATH)
     }
@@ -18,7 +18,7 @@ use tokio::sync::Mutex;
 const REFRESH_HZ: u16 = 5;
 
 /// Semantic version of the protocol.
-const API_VERSION: &str = "0.1.0";
+const API_VERSION: &str = "1.0.0";
 
 /// An incremental update to e.g. a container image layer download.
 /// The first time a given "subtask" name is seen, a new progress bar should be created.
@@ -70,6 +70,97 @@ pub struct SubTaskStep<'t> {
     pub completed: bool,
 }
 
+/// A phase of an operation such as an upgrade; see [`Event::Phase`].
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// Fetching the container image.
+    Pull,
+    /// Writing the new deployment.
+    Deploy,
+    /// Merging the local changes in `/etc` into the new deployment.
+    EtcMerge,
+    /// Updating the bootloader.
+    Bootloader,
+    /// Fetching the logically bound images.
+    BoundImages,
+}
+
+impl Phase {
+    /// A human readable description of the phase.
+    fn description(&self) -> &'static str {
+        match self {
+            Phase::Pull => "Fetching image",
+            Phase::Deploy => "Deploying image",
+            Phase::EtcMerge => "Merging /etc",
+            Phase::Bootloader => "Updating bootloader",
+            Phase::BoundImages => "Fetching bound images",
+        }
+    }
+}
+
+/// A machine readable classification of an error.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorCode {
+    /// Fetching the container image failed.
+    Pull,
+    /// Writing the new deployment failed.
+    Deploy,
+    /// Merging `/etc` failed.
+    EtcMerge,
+    /// Updating the bootloader failed.
+    Bootloader,
+    /// Fetching a logically bound image failed.
+    BoundImages,
+    /// There is not enough free disk space.
+    NoSpace,
+    /// Any other error.
+    Other,
+}
+
+impl ErrorCode {
+    /// Classify `e`, which happened while `phase` was running.
+    fn classify(e: &anyhow::Error, phase: Option<Phase>) -> Self {
+        let no_space = e.chain().any(|e| {
+            if let Some(e) = e.downcast_ref::<std::io::Error>() {
+                e.raw_os_error() == Some(rustix::io::Errno::NOSPC.raw_os_error())
+            } else if let Some(e) = e.downcast_ref::<rustix::io::Errno>() {
+                *e == rustix::io::Errno::NOSPC
+            } else if let Some(e) = e.downcast_ref::<ostree_ext::glib::Error>() {
+                e.matches(ostree_ext::gio::IOErrorEnum::NoSpace)
+            } else {
+                false
+            }
+        });
+        if no_space {
+            return Self::NoSpace;
+        }
+        match phase {
+            Some(Phase::Pull) => Self::Pull,
+            Some(Phase::Deploy) => Self::Deploy,
+            Some(Phase::EtcMerge) => Self::EtcMerge,
+            Some(Phase::Bootloader) => Self::Bootloader,
+            Some(Phase::BoundImages) => Self::BoundImages,
+            None => Self::Other,
+        }
+    }
+}
+
+/// The error which caused an operation to fail.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorInfo<'t> {
+    /// A machine readable classification of the error.
+    pub code: ErrorCode,
+    /// The phase which was running when the error occurred, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    /// A human readable description of the error, including its causes.
+    #[serde(borrow)]
+    pub message: Cow<'t, str>,
+}
+
 /// An event emitted as JSON.
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize, JsonSchema, PartialEq, Eq)]
 #[serde(
@@ -135,6 +226,31 @@ pub enum Event<'t> {
         /// The currently running subtasks.
         subtasks: Vec<SubTaskStep<'t>>,
     },
+    /// A phase of the operation started or completed. Phases may be nested;
+    /// e.g. `etc-merge` happens during `deploy`.
+    Phase {
+        /// The phase.
+        phase: Phase,
+        /// A human readable description of the phase.
+        #[serde(borrow)]
+        description: Cow<'t, str>,
+        /// False when the phase starts, true when it completed successfully.
+        completed: bool,
+    },
+    /// A problem which does not cause the operation to fail.
+    Warning {
+        /// A human readable description of the problem.
+        #[serde(borrow)]
+        message: Cow<'t, str>,
+    },
+    /// The operation finished; this is always the last event.
+    End {
+        /// True if the operation succeeded.
+        success: bool,
+        /// The error, if the operation failed.
+        #[serde(borrow, default, skip_serializing_if = "Option::is_none")]
+        error: Option<ErrorInfo<'t>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -157,6 +273,8 @@ impl FromStr for RawProgressFd {
 struct ProgressWriterInner {
     /// true if we sent the initial Start message
     sent_start: bool,
+    /// The currently running phases, innermost last
+    phases: Vec<Phase>,
     last_write: Option<std::time::Instant>,
     fd: BufWriter<Sender>,
 }
@@ -179,6 +297,7 @@ impl From<Sender> for ProgressWriter {
     fn from(value: Sender) -> Self {
         let inner = ProgressWriterInner {
             sent_start: false,
+            phases: Vec::new(),
             last_write: None,
             fd: BufWriter::new(value),
         };
@@ -265,6 +384,77 @@ impl ProgressWriter {
         }
     }
 
+    /// Send an event marking the start of `phase`.
+    pub(crate) async fn start_phase(&self, phase: Phase) {
+        if let Some(inner) = self.inner.lock().await.as_mut() {
+            inner.phases.push(phase);
+        }
+        self.send(Event::Phase {
+            phase,
+            description: phase.description().into(),
+            completed: false,
+        })
+        .await
+    }
+
+    /// Send an event marking the successful completion of `phase`.
+    pub(crate) async fn complete_phase(&self, phase: Phase) {
+        if let Some(inner) = self.inner.lock().await.as_mut() {
+            if let Some(i) = inner.phases.iter().rposition(|p| *p == phase) {
+                inner.phases.remove(i);
+            }
+        }
+        self.send(Event::Phase {
+            phase,
+            description: phase.description().into(),
+            completed: true,
+        })
+        .await
+    }
+
+    /// Send a warning; note this does not print it.
+    pub(crate) async fn warn(&self, message: &str) {
+        self.send(Event::Warning {
+            message: message.into(),
+        })
+        .await
+    }
+
+    /// Send the final event for the result of the operation.
+    pub(crate) async fn end<T>(&self, r: &Result<T>) {
+        let error = match r {
+            Ok(_) => None,
+            Err(e) => {
+                let Some(phase) = self
+                    .inner
+                    .lock()
+                    .await
+                    .as_ref()
+                    .map(|inner| inner.phases.last().copied())
+                else {
+                    return;
+                };
+                Some(ErrorInfo {
+                    code: ErrorCode::classify(e, phase),
+                    phase,
+                    message: format!("{e:#}").into(),
+                })
+            }
+        };
+        self.send(Event::End {
+            success: error.is_none(),
+            error,
+        })
+        .await
+    }
+
+    /// Await the operation `f`, and send the final event for its result.
+    pub(crate) async fn end_with<T>(&self, f: impl Future<Output = Result<T>>) -> Result<T> {
+        let r = f.await;
+        self.end(&r).await;
+        r
+    }
+
     /// Flush remaining data and return the underlying file.
     #[allow(dead_code)]
     pub(crate) async fn into_inner(self) -> Result<Option<Sender>> {
@@ -308,6 +498,22 @@ mod test {
                 steps_total: 3,
                 subtasks: Vec::new(),
             },
+            Event::Phase {
+                phase: Phase::Deploy,
+                description: "Deploying image".into(),
+                completed: false,
+            },
+            Event::Warning {
+                message: "somewarning".into(),
+            },
+            Event::End {
+                success: false,
+                error: Some(ErrorInfo {
+                    code: ErrorCode::Deploy,
+                    phase: Some(Phase::Deploy),
+                    message: "someerror".into(),
+                }),
+            },
         ];
         let (send, recv) = tokio::net::unix::pipe::pipe()?;
         let testvalues_sender = testvalues.iter().cloned();
@@ -341,4 +547,58 @@ mod test {
         tokio::try_join!(sender, receiver)?;
         Ok(())
     }
+    #[test]
+    fn test_error_code() {
+        let e = anyhow::anyhow!("some error");
+        assert_eq!(ErrorCode::classify(&e, None), ErrorCode::Other);
+        assert_eq!(
+            ErrorCode::classify(&e, Some(Phase::EtcMerge)),
+            ErrorCode::EtcMerge
+        );
+        let e = anyhow::Error::from(std::io::Error::from(rustix::io::Errno::NOSPC))
+            .context("Writing file");
+        assert_eq!(
+            ErrorCode::classify(&e, Some(Phase::Pull)),
+            ErrorCode::NoSpace
+        );
+        let e = anyhow::Error::from(rustix::io::Errno::NOSPC);
+        assert_eq!(ErrorCode::classify(&e, None), ErrorCode::NoSpace);
+    }
+
+    #[tokio::test]
+    async fn test_phases() -> Result<()> {
+        let (send, recv) = tokio::net::unix::pipe::pipe()?;
+        let w = ProgressWriter::try_from(send)?;
+        w.start_phase(Phase::Deploy).await;
+        w.start_phase(Phase::EtcMerge).await;
+        w.complete_phase(Phase::EtcMerge).await;
+        let r = w
+            .end_with(async { Err::<(), _>(anyhow::anyhow!("failed")) })
+            .await;
+        assert!(r.is_err());
+        drop(w);
+
+        let mut lines = BufReader::new(recv).lines();
+        let mut buf = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            buf.push(line);
+        }
+        let events = buf
+            .iter()
+            .map(|line| serde_json::from_str::<Event>(line))
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        assert_eq!(events.len(), 5);
+        assert_eq!(
+            events.last().unwrap(),
+            &Event::End {
+                success: false,
+                error: Some(ErrorInfo {
+                    code: ErrorCode::Deploy,
+                    phase: Some(Phase::Deploy),
+                    message: "failed".into(),
+                }),
+            }
+        );
+        Ok(())
+    }
 }
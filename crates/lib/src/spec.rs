@@ -68,6 +68,66 @@ pub struct HostSpec {
     /// If set, and there is a rollback deployment, it will be set for the next boot.
     #[serde(default)]
     pub boot_order: BootOrder,
+    /// Policy for automatic updates via `bootc-fetch-apply-updates.timer`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub update_policy: Option<UpdatePolicy>,
+}
+
+/// What to do when an automatic update check finds a new image.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum UpdateMode {
+    /// Download the update, but don't apply it on the next reboot (`bootc upgrade --download-only`)
+    DownloadOnly,
+    /// Download the update and queue it for the next reboot (`bootc upgrade`)
+    Stage,
+    /// Download the update and reboot into it (`bootc upgrade --apply`)
+    #[default]
+    Apply,
+}
+
+/// Whether to prefer a soft reboot when automatically applying updates.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum SoftRebootPreference {
+    /// Require a soft reboot; fail if not possible
+    Required,
+    /// Use soft reboot if possible, otherwise use regular reboot
+    Auto,
+}
+
+/// A recurring period in which automatic updates may be performed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MaintenanceWindow {
+    /// The start of the window, as a systemd calendar event (e.g. `Sat *-*-* 02:00`).
+    /// See `systemd.time(7)`.
+    pub start: String,
+    /// The length of the window, as a systemd time span (e.g. `2h`).
+    /// The update will start at a random point within it. All windows
+    /// must have the same duration.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<String>,
+}
+
+/// Policy for automatic updates.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePolicy {
+    /// How often to check for updates, as a systemd time span (e.g. `8h`).
+    /// Mutually exclusive with `maintenanceWindows`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval: Option<String>,
+    /// If set, updates are only performed within one of these windows.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    /// What to do with a found update.
+    #[serde(default)]
+    pub mode: UpdateMode,
+    /// Whether to use a soft reboot when applying an update.
+    /// Only valid with the `apply` mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_reboot: Option<SoftRebootPreference>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -270,6 +330,155 @@ pub struct BootEntryComposefs {
     /// The sha256sum of vmlinuz + initrd
     /// Only `Some` for Type1 boot entries
     pub boot_digest: Option<String>,
+    /// The number of boot attempts left before this deployment is considered
+    /// bad and the bootloader falls back to the previous one.
+    /// Only `Some` while the deployment has not yet been marked as successfully booted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_tries_left: Option<u32>,
+    /// How the root filesystem, `/etc` and `/var` are set up at boot.
+    /// Only `Some` if it was recorded when the deployment was created.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root_setup: Option<RootSetup>,
+}
+
+/// How a directory is mounted at boot
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RootSetupMountType {
+    /// The directory from the image is used read-only
+    None,
+    /// Bind mount of the directory in the state
+    Bind,
+    /// Overlay of the state over the directory from the image
+    Overlay,
+    /// Overlay of a tmpfs over the directory from the image
+    Transient,
+    /// An empty tmpfs
+    Tmpfs,
+}
+
+/// Where the persistent state of a mount is stored
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RootSetupState {
+    /// The state directory of the deployment
+    Deployment,
+    /// The state directory shared by all deployments
+    Shared,
+}
+
+/// How a directory is set up at boot
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RootSetupMount {
+    /// The type of mount
+    pub mount: RootSetupMountType,
+    /// Where the persistent state is stored
+    pub state: RootSetupState,
+    /// Directories below the mount point which are persisted in the state
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub persist: Vec<String>,
+}
+
+/// How the root filesystem, `/etc` and `/var` are set up at boot
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct RootSetup {
+    /// Whether changes to the root filesystem are lost on reboot
+    pub root_transient: bool,
+    /// How `/etc` is set up
+    pub etc: RootSetupMount,
+    /// How `/var` is set up
+    pub var: RootSetupMount,
+}
+
+/// How a path in /etc was changed locally
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum EtcChange {
+    /// The path was added
+    Added,
+    /// The path was modified
+    Modified,
+    /// The path was removed
+    Removed,
+}
+
+/// How a conflicting change to /etc was resolved
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum EtcMergeResolution {
+    /// The local change was kept
+    #[default]
+    KeepLocal,
+    /// The local change was discarded in favor of the image
+    TakeUpstream,
+    /// The `key=value` lines of the local and image versions were merged
+    MergeLines,
+}
+
+/// A local change to /etc which conflicts with a change made in the image
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EtcMergeConflict {
+    /// The path, relative to /etc
+    pub path: String,
+    /// How the path was changed locally
+    pub local_change: EtcChange,
+    /// How the conflict was resolved
+    #[serde(default)]
+    pub resolution: EtcMergeResolution,
+}
+
+/// Whether an ID refers to a user or a group
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum IdType {
+    /// A user ID
+    User,
+    /// A group ID
+    Group,
+}
+
+/// A user or group whose ID in /etc differs from the static ID defined for it
+/// by the image's sysusers.d configuration
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct IdDrift {
+    /// Whether this is a user or a group
+    #[serde(rename = "type")]
+    pub ty: IdType,
+    /// The user or group name
+    pub name: String,
+    /// The ID in /etc/passwd or /etc/group
+    pub current: u32,
+    /// The ID defined by the image
+    pub expected: u32,
+    /// Whether /etc and the files in /var were remapped to the expected ID
+    #[serde(default)]
+    pub remapped: bool,
+}
+
+/// A logically bound image, resolved to the digest which was pulled
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BoundImage {
+    /// The image reference
+    pub image: String,
+    /// The manifest digest
+    pub digest: String,
+}
+
+/// The kernel arguments of a deployment, excluding the ones which are
+/// internal to the backend (`ostree=` and `composefs=`)
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BootEntryKargs {
+    /// Kernel arguments which come from the image, i.e. from `/usr/lib/bootc/kargs.d`
+    /// or built into the UKI
+    pub image: Vec<String>,
+    /// Kernel arguments which were added locally, e.g. at install time or via `bootc kargs`
+    pub local: Vec<String>,
 }
 
 /// A bootable entry
@@ -298,6 +507,21 @@ pub struct BootEntry {
     pub ostree: Option<BootEntryOstree>,
     /// If this boot entry is composefs based, the corresponding state
     pub composefs: Option<BootEntryComposefs>,
+    /// Local changes to /etc which conflicted with changes to the same paths
+    /// in the image when merging /etc for this deployment.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub etc_merge_conflicts: Vec<EtcMergeConflict>,
+    /// Users and groups whose IDs in /etc differ from the ones defined by
+    /// the image. Only detected for the ostree backend.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub id_drift: Vec<IdDrift>,
+    /// The logically bound images pulled for this deployment.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bound_images: Vec<BoundImage>,
+    /// The kernel arguments of this deployment.
+    /// Only `Some` if it is known which of them come from the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kargs: Option<BootEntryKargs>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
@@ -547,6 +771,9 @@ impl HostSpec {
         if rollback && image_change {
             anyhow::bail!("Invalid state transition: rollback and image change");
         }
+        if let Some(policy) = new.update_policy.as_ref() {
+            policy.validate()?;
+        }
         Ok(())
     }
 }
@@ -802,6 +1029,10 @@ mod tests {
                 store: None,
                 ostree: None,
                 composefs: None,
+                etc_merge_conflicts: Vec::new(),
+                id_drift: Vec::new(),
+                bound_images: Vec::new(),
+                kargs: None,
             }
         }
 
@@ -285,6 +285,14 @@ pub(crate) fn boot_entry_from_deployment(
             stateroot: deployment.stateroot().into(),
         }),
         composefs: None,
+        etc_merge_conflicts: crate::etcmerge::read_report(sysroot, deployment)?,
+        id_drift: crate::iddrift::read_report(sysroot, deployment)?,
+        bound_images: crate::boundimage::read_report(sysroot, deployment)?,
+        // Failing to tell the kernel arguments apart must not break status
+        kargs: crate::kargs::ostree_deployment_kargs(sysroot, deployment).unwrap_or_else(|e| {
+            tracing::warn!("{e:#}");
+            None
+        }),
     };
     Ok(r)
 }
@@ -402,15 +410,20 @@ pub(crate) fn get_status(
         .map(|d| boot_entry_from_deployment(sysroot, d))
         .collect::<Result<Vec<_>>>()
         .context("Other deployments")?;
-    let spec = staged
+    let mut spec = staged
         .as_ref()
         .or(booted.as_ref())
         .and_then(|entry| entry.image.as_ref())
         .map(|img| HostSpec {
             image: Some(img.image.clone()),
             boot_order,
+            update_policy: None,
         })
         .unwrap_or_default();
+    spec.update_policy = crate::update_policy::load_host().unwrap_or_else(|e| {
+        tracing::warn!("{e:#}");
+        None
+    });
 
     let ty = if booted
         .as_ref()
@@ -480,6 +493,34 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
         0 | 1 => {}
         o => anyhow::bail!("Unsupported format version: {o}"),
     };
+    let out = std::io::stdout();
+    let mut out = out.lock();
+    let legacy_opt = if opts.json {
+        OutputFormat::Json
+    } else if std::io::stdout().is_terminal() {
+        OutputFormat::HumanReadable
+    } else {
+        OutputFormat::Yaml
+    };
+    let format = opts.format.unwrap_or(legacy_opt);
+
+    if let Some(target) = opts.diff {
+        let diff = crate::deployment_diff::diff(target).await?;
+        match format {
+            OutputFormat::Json => diff
+                .to_canon_json_writer(&mut out)
+                .map_err(anyhow::Error::new),
+            OutputFormat::Yaml => {
+                serde_yaml::to_writer(&mut out, &diff).map_err(anyhow::Error::new)
+            }
+            OutputFormat::HumanReadable => {
+                crate::deployment_diff::human_readable_output(&mut out, &diff)
+            }
+        }
+        .context("Writing to stdout")?;
+        return Ok(());
+    }
+
     let mut host = get_host().await?;
 
     // We could support querying the staged or rollback deployments
@@ -491,16 +532,6 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
     // If we're in JSON mode, then convert the ostree data into Rust-native
     // structures that can be serialized.
     // Filter to just the serializable status structures.
-    let out = std::io::stdout();
-    let mut out = out.lock();
-    let legacy_opt = if opts.json {
-        OutputFormat::Json
-    } else if std::io::stdout().is_terminal() {
-        OutputFormat::HumanReadable
-    } else {
-        OutputFormat::Yaml
-    };
-    let format = opts.format.unwrap_or(legacy_opt);
     match format {
         OutputFormat::Json => host
             .to_canon_json_writer(&mut out)
@@ -513,7 +544,7 @@ pub(crate) async fn status(opts: super::cli::StatusOpts) -> Result<()> {
     Ok(())
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Slot {
     Staged,
     Booted,
@@ -675,6 +706,17 @@ fn human_render_slot(
     if let Some(composefs) = &entry.composefs {
         write_row_name(&mut out, "Verity", prefix_len)?;
         writeln!(out, "{}", composefs.verity)?;
+
+        // Only present while the deployment is still being assessed
+        if let Some(tries) = composefs.boot_tries_left {
+            write_row_name(&mut out, "Boot tries", prefix_len)?;
+            writeln!(out, "{tries} remaining")?;
+        }
+
+        if let Some(setup) = &composefs.root_setup {
+            write_row_name(&mut out, "Root setup", prefix_len)?;
+            writeln!(out, "{}", render_root_setup(setup))?;
+        }
     }
 
     let timestamp = image.timestamp.as_ref().map(format_timestamp);
@@ -705,6 +747,11 @@ fn human_render_slot(
     // Show /usr overlay status
     write_usr_overlay(&mut out, slot, host_status, prefix_len)?;
 
+    write_etc_merge_conflicts(&mut out, entry, verbose, prefix_len)?;
+    write_id_drift(&mut out, entry, verbose, prefix_len)?;
+    write_bound_images(&mut out, entry, verbose, prefix_len)?;
+    write_kargs(&mut out, entry, verbose, prefix_len)?;
+
     if verbose {
         // Show additional information in verbose mode similar to rpm-ostree
         if let Some(ostree) = &entry.ostree {
@@ -761,6 +808,109 @@ fn write_usr_overlay(
     Ok(())
 }
 
+/// Helper function to render local /etc changes which shadow image changes
+fn write_etc_merge_conflicts(
+    mut out: impl Write,
+    entry: &crate::spec::BootEntry,
+    verbose: bool,
+    prefix_len: usize,
+) -> Result<()> {
+    let conflicts = &entry.etc_merge_conflicts;
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    write_row_name(&mut out, "/etc conflicts", prefix_len)?;
+    writeln!(out, "{}", conflicts.len())?;
+    // List the paths, aligned with the row values
+    if verbose {
+        for conflict in conflicts {
+            let change = match conflict.local_change {
+                crate::spec::EtcChange::Added => "added",
+                crate::spec::EtcChange::Modified => "modified",
+                crate::spec::EtcChange::Removed => "removed",
+            };
+            let resolution = match conflict.resolution {
+                crate::spec::EtcMergeResolution::KeepLocal => "",
+                crate::spec::EtcMergeResolution::TakeUpstream => " (took image version)",
+                crate::spec::EtcMergeResolution::MergeLines => " (merged lines)",
+            };
+            let indent = " ".repeat(prefix_len + 2);
+            writeln!(out, "{indent}{change}: /etc/{}{resolution}", conflict.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to render users and groups whose IDs drifted from the image
+fn write_id_drift(
+    mut out: impl Write,
+    entry: &crate::spec::BootEntry,
+    verbose: bool,
+    prefix_len: usize,
+) -> Result<()> {
+    let drift = &entry.id_drift;
+    if drift.is_empty() {
+        return Ok(());
+    }
+    write_row_name(&mut out, "ID drift", prefix_len)?;
+    if drift.iter().all(|d| d.remapped) {
+        writeln!(out, "{} (remapped)", drift.len())?;
+    } else {
+        writeln!(out, "{} (see bootc internals remap-ids)", drift.len())?;
+    }
+    if verbose {
+        let indent = " ".repeat(prefix_len + 2);
+        for d in drift {
+            writeln!(out, "{indent}{d}")?;
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to render the logically bound images of a deployment
+fn write_bound_images(
+    mut out: impl Write,
+    entry: &crate::spec::BootEntry,
+    verbose: bool,
+    prefix_len: usize,
+) -> Result<()> {
+    let images = &entry.bound_images;
+    if images.is_empty() {
+        return Ok(());
+    }
+    write_row_name(&mut out, "Bound images", prefix_len)?;
+    writeln!(out, "{}", images.len())?;
+    if verbose {
+        let indent = " ".repeat(prefix_len + 2);
+        for image in images {
+            writeln!(out, "{indent}{} ({})", image.image, image.digest)?;
+        }
+    }
+    Ok(())
+}
+
+/// Helper function to render the kernel arguments of a deployment; the ones
+/// from the image are only shown in verbose mode
+fn write_kargs(
+    mut out: impl Write,
+    entry: &crate::spec::BootEntry,
+    verbose: bool,
+    prefix_len: usize,
+) -> Result<()> {
+    let Some(kargs) = &entry.kargs else {
+        return Ok(());
+    };
+    if verbose && !kargs.image.is_empty() {
+        write_row_name(&mut out, "Image kargs", prefix_len)?;
+        writeln!(out, "{}", kargs.image.join(" "))?;
+    }
+    if !kargs.local.is_empty() {
+        write_row_name(&mut out, "Local kargs", prefix_len)?;
+        writeln!(out, "{}", kargs.local.join(" "))?;
+    }
+    Ok(())
+}
+
 /// Output a rendering of a non-container boot entry.
 fn human_render_slot_ostree(
     mut out: impl Write,
@@ -807,6 +957,34 @@ fn human_render_slot_ostree(
     Ok(())
 }
 
+/// Render how the root filesystem, /etc and /var are set up, e.g.
+/// `etc: transient, var: tmpfs (persist: lib/app)`.
+fn render_root_setup(setup: &crate::spec::RootSetup) -> String {
+    use crate::spec::{RootSetupMountType, RootSetupState};
+    let mut parts = Vec::new();
+    if setup.root_transient {
+        parts.push("root: transient".to_owned());
+    }
+    for (name, m) in [("etc", &setup.etc), ("var", &setup.var)] {
+        let mount = match m.mount {
+            RootSetupMountType::None => "none",
+            RootSetupMountType::Bind => "bind",
+            RootSetupMountType::Overlay => "overlay",
+            RootSetupMountType::Transient => "transient",
+            RootSetupMountType::Tmpfs => "tmpfs",
+        };
+        let mut part = format!("{name}: {mount}");
+        if m.state == RootSetupState::Shared {
+            part.push_str(" (shared)");
+        }
+        if !m.persist.is_empty() {
+            part.push_str(&format!(" (persist: {})", m.persist.join(", ")));
+        }
+        parts.push(part);
+    }
+    parts.join(", ")
+}
+
 /// Output a rendering of a non-container composefs boot entry.
 fn human_render_slot_composefs(
     mut out: impl Write,
@@ -948,7 +1126,10 @@ pub(crate) fn container_inspect(
         rootfs,
         cap_std_ext::cap_std::ambient_authority(),
     )?;
-    let kargs = crate::bootc_kargs::get_kargs_in_root(&root, std::env::consts::ARCH)?;
+    let kargs = crate::bootc_kargs::get_kargs_in_root(
+        &root,
+        &crate::bootc_kargs::KargsMatchContext::for_arch(std::env::consts::ARCH),
+    )?;
     let kargs: Vec<String> = kargs.iter_str().map(|s| s.to_owned()).collect();
     let kernel = crate::kernel::find_kernel(&root)?.map(Into::into);
     let inspect = crate::spec::ContainerInspect { kargs, kernel };
@@ -1050,6 +1231,109 @@ mod tests {
         similar_asserts::assert_eq!(w, expected);
     }
 
+    #[test]
+    fn test_human_readable_etc_merge_conflicts() {
+        let fixture = include_str!("fixtures/spec-staged-etc-conflicts.yaml");
+        let w = human_status_from_spec_fixture(fixture).expect("No spec found");
+        let expected = indoc::indoc! { r"
+            Staged image: quay.io/example/someimage:latest
+                  Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (arm64)
+                 Version: nightly (2023-10-14T19:22:15Z)
+          /etc conflicts: 3
+
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (arm64)
+                 Version: nightly (2023-09-30T19:22:16Z)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+
+        let w = human_status_from_spec_fixture_verbose(fixture).expect("No spec found");
+        assert!(w.contains(indoc::indoc! { r"
+          /etc conflicts: 3
+                          modified: /etc/chrony.conf
+                          modified: /etc/sysconfig/network (merged lines)
+                          removed: /etc/motd.d/welcome
+        "}));
+    }
+
+    #[test]
+    fn test_human_readable_id_drift() {
+        let fixture = include_str!("fixtures/spec-staged-id-drift.yaml");
+        let w = human_status_from_spec_fixture(fixture).expect("No spec found");
+        let expected = indoc::indoc! { r"
+            Staged image: quay.io/example/someimage:latest
+                  Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (arm64)
+                 Version: nightly (2023-10-14T19:22:15Z)
+                ID drift: 2 (see bootc internals remap-ids)
+
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (arm64)
+                 Version: nightly (2023-09-30T19:22:16Z)
+                ID drift: 1 (remapped)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+
+        let w = human_status_from_spec_fixture_verbose(fixture).expect("No spec found");
+        // The entries are aligned with the row values
+        let drift_rows = [
+            "      ID drift: 2 (see bootc internals remap-ids)",
+            "                group builder: 1400 => 1500",
+            "                user builder: 1400 => 1500",
+        ];
+        assert!(w.contains(&drift_rows.join("\n")));
+    }
+
+    #[test]
+    fn test_human_readable_bound_images() {
+        let fixture = include_str!("fixtures/spec-staged-bound-images.yaml");
+        let w = human_status_from_spec_fixture(fixture).expect("No spec found");
+        let expected = indoc::indoc! { r"
+            Staged image: quay.io/example/someimage:latest
+                  Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (arm64)
+                 Version: nightly (2023-10-14T19:22:15Z)
+            Bound images: 2
+
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (arm64)
+                 Version: nightly (2023-09-30T19:22:16Z)
+            Bound images: 1
+        "};
+        similar_asserts::assert_eq!(w, expected);
+
+        let w = human_status_from_spec_fixture_verbose(fixture).expect("No spec found");
+        let rows = [
+            "  Bound images: 2",
+            "                quay.io/example/logger:latest (sha256:2d1f8bbd2c7fbe3a8e3b0d1d2d5a7c1fd1e4a0fa5b7e0e2c85e3ca2e8fdb0c11)",
+            "                quay.io/example/monitor:v2 (sha256:7a4e0fd4b1b9c67cc1a3f6b4d16d0b5e5a1e3c7a9e8f0b2d4c6e8a0b2d4f6a8c)",
+        ];
+        assert!(w.contains(&rows.join("\n")));
+    }
+
+    #[test]
+    fn test_human_readable_kargs() {
+        let fixture = include_str!("fixtures/spec-staged-kargs.yaml");
+        let w = human_status_from_spec_fixture(fixture).expect("No spec found");
+        let expected = indoc::indoc! { r"
+            Staged image: quay.io/example/someimage:latest
+                  Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (arm64)
+                 Version: nightly (2023-10-14T19:22:15Z)
+             Local kargs: root=UUID=6b7e6fb8-cb54-4bd4-a5ef-4d2a0b6c2c1e rw nosmt
+
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (arm64)
+                 Version: nightly (2023-09-30T19:22:16Z)
+             Local kargs: root=UUID=6b7e6fb8-cb54-4bd4-a5ef-4d2a0b6c2c1e rw
+        "};
+        similar_asserts::assert_eq!(w, expected);
+
+        let w = human_status_from_spec_fixture_verbose(fixture).expect("No spec found");
+        let rows = [
+            "   Image kargs: console=ttyS0,115200n8 mitigations=auto",
+            "   Local kargs: root=UUID=6b7e6fb8-cb54-4bd4-a5ef-4d2a0b6c2c1e rw nosmt",
+        ];
+        assert!(w.contains(&rows.join("\n")));
+    }
+
     #[test]
     fn test_human_readable_rfe_spec() {
         // Basic rhel for edge bootc install with nothing
@@ -1339,4 +1623,46 @@ mod tests {
         "};
         similar_asserts::assert_eq!(w, expected);
     }
+
+    #[test]
+    fn test_human_readable_composefs_root_setup() {
+        let w =
+            human_status_from_spec_fixture(include_str!("fixtures/spec-composefs-root-setup.yaml"))
+                .expect("No spec found");
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/example/kiosk:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (amd64)
+                  Verity: 7e11ac46e3e022053e7226a20104ac656bf72d1a84e3a398b7cce70e9df188b6
+              Root setup: etc: transient, var: tmpfs (persist: lib/app)
+                 Version: nightly (2023-09-30T19:22:16Z)
+
+            Rollback image: quay.io/example/kiosk:latest
+                    Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (amd64)
+                    Verity: 8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52
+                Root setup: etc: bind, var: bind (shared)
+                   Version: nightly (2023-09-20T19:22:16Z)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
+
+    #[test]
+    fn test_human_readable_composefs_boot_tries() {
+        // A freshly booted composefs deployment which was not yet marked as good
+        let w =
+            human_status_from_spec_fixture(include_str!("fixtures/spec-composefs-boot-tries.yaml"))
+                .expect("No spec found");
+        let expected = indoc::indoc! { r"
+          ● Booted image: quay.io/example/someimage:latest
+                  Digest: sha256:736b359467c9437c1ac915acaae952aad854e07eb4a16a94999a48af08c83c34 (amd64)
+                  Verity: 7e11ac46e3e022053e7226a20104ac656bf72d1a84e3a398b7cce70e9df188b6
+              Boot tries: 2 remaining
+                 Version: nightly (2023-09-30T19:22:16Z)
+
+            Rollback image: quay.io/example/someimage:latest
+                    Digest: sha256:16dc2b6256b4ff0d2ec18d2dbfb06d117904010c8cf9732cdb022818cf7a7566 (amd64)
+                    Verity: 8b7df143d91c716ecfa5fc1730022f6b421b05cedee8fd52b1fc65a96030ad52
+                   Version: nightly (2023-09-20T19:22:16Z)
+        "};
+        similar_asserts::assert_eq!(w, expected);
+    }
 }
@@ -9,12 +9,115 @@ use std::process::Command;
 use anyhow::{Context, Result};
 use bootc_kernel_cmdline::utf8::Cmdline;
 use bootc_utils::CommandRunExt;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use cap_std_ext::cap_std::fs::Dir;
 use fn_error_context::context;
 
 use crate::bootc_composefs::digest::compute_composefs_digest;
 use crate::bootc_composefs::status::ComposefsCmdline;
+use crate::bootc_kargs::{Firmware, KargsMatchContext};
+
+/// Options for signing a UKI or UKI addon for Secure Boot.
+#[derive(Debug, Default, Clone, clap::Args, PartialEq, Eq)]
+pub(crate) struct SecureBootSigningOpts {
+    /// Sign for Secure Boot with this private key.
+    ///
+    /// This is either a path or a PKCS#11 URI (`pkcs11:...`).
+    #[clap(long, value_name = "KEY", requires = "secureboot_certificate")]
+    pub(crate) secureboot_key: Option<String>,
+
+    /// The certificate matching `--secureboot-key`.
+    #[clap(long, value_name = "PATH", requires = "secureboot_key")]
+    pub(crate) secureboot_certificate: Option<Utf8PathBuf>,
+}
+
+impl SecureBootSigningOpts {
+    /// Whether a signing key was provided.
+    pub(crate) fn is_set(&self) -> bool {
+        self.secureboot_key.is_some()
+    }
+
+    /// Compute the ukify arguments for signing.
+    pub(crate) fn ukify_args(&self) -> Result<Vec<OsString>> {
+        signing_args(&UkiSigningOpts {
+            secureboot: self.clone(),
+            ..Default::default()
+        })
+    }
+}
+
+/// Options for signing the UKI and the policy for its PCR 11 measurements.
+#[derive(Debug, Default, Clone, clap::Args, PartialEq, Eq)]
+pub(crate) struct UkiSigningOpts {
+    #[clap(flatten)]
+    pub(crate) secureboot: SecureBootSigningOpts,
+
+    /// Sign the expected PCR 11 values of the UKI with this private key.
+    ///
+    /// Disk encryption keys sealed to the TPM with the matching public key
+    /// (`systemd-cryptenroll --tpm2-public-key`) can then be unlocked by any
+    /// UKI signed with it, so they keep working across upgrades. This is either
+    /// a path or a PKCS#11 URI (`pkcs11:...`).
+    #[clap(long, value_name = "KEY")]
+    pub(crate) pcr_private_key: Option<String>,
+
+    /// The public key matching `--pcr-private-key`, which is embedded in the UKI.
+    ///
+    /// If not given, ukify derives it from the private key.
+    #[clap(long, value_name = "PATH", requires = "pcr_private_key")]
+    pub(crate) pcr_public_key: Option<Utf8PathBuf>,
+}
+
+fn is_pkcs11_uri(key: &str) -> bool {
+    key.starts_with("pkcs11:")
+}
+
+/// Returns a path in a form usable by ukify, which runs in the rootfs.
+fn absolute_path(path: &str) -> Result<OsString> {
+    let path = std::path::absolute(path).with_context(|| format!("Resolving {path}"))?;
+    Ok(path.into_os_string())
+}
+
+/// Compute the ukify arguments for signing.
+fn signing_args(opts: &UkiSigningOpts) -> Result<Vec<OsString>> {
+    let keys = [&opts.secureboot.secureboot_key, &opts.pcr_private_key];
+    let keys = keys.iter().filter_map(|k| k.as_deref());
+    let (pkcs11, files): (Vec<_>, Vec<_>) = keys.partition(|k| is_pkcs11_uri(k));
+    if !pkcs11.is_empty() && !files.is_empty() {
+        anyhow::bail!("Cannot combine keys from a PKCS#11 URI and from a file");
+    }
+
+    let key_arg = |key: &str| -> Result<OsString> {
+        if is_pkcs11_uri(key) {
+            Ok(key.into())
+        } else {
+            absolute_path(key)
+        }
+    };
+    let mut args = Vec::new();
+    if !pkcs11.is_empty() {
+        args.push("--signing-engine=pkcs11".into());
+    }
+    let secureboot = &opts.secureboot;
+    if let (Some(key), Some(cert)) = (
+        &secureboot.secureboot_key,
+        &secureboot.secureboot_certificate,
+    ) {
+        args.push("--secureboot-private-key".into());
+        args.push(key_arg(key)?);
+        args.push("--secureboot-certificate".into());
+        args.push(absolute_path(cert.as_str())?);
+    }
+    if let Some(key) = &opts.pcr_private_key {
+        args.push("--pcr-private-key".into());
+        args.push(key_arg(key)?);
+        if let Some(public) = &opts.pcr_public_key {
+            args.push("--pcr-public-key".into());
+            args.push(absolute_path(public.as_str())?);
+        }
+    }
+    Ok(args)
+}
 
 /// Build a UKI from the given rootfs.
 ///
@@ -24,13 +127,15 @@ use crate::bootc_composefs::status::ComposefsCmdline;
 /// 3. Computes the composefs digest
 /// 4. Reads kernel arguments from kargs.d
 /// 5. Appends any additional kargs provided via --karg
-/// 6. Invokes ukify with computed arguments plus any pass-through args
+/// 6. Invokes ukify with computed arguments, including the ones for signing,
+///    plus any pass-through args
 #[context("Building UKI")]
 pub(crate) fn build_ukify(
     rootfs: &Utf8Path,
     extra_kargs: &[String],
     args: &[OsString],
     allow_missing_fsverity: bool,
+    signing: &UkiSigningOpts,
 ) -> Result<()> {
     // Warn if --karg is used (temporary workaround)
     if !extra_kargs.is_empty() {
@@ -47,6 +152,9 @@ pub(crate) fn build_ukify(
         );
     }
 
+    // Resolve key paths before ukify runs in the rootfs
+    let signing_args = signing_args(signing)?;
+
     // Open the rootfs directory
     let root = Dir::open_ambient_dir(rootfs, cap_std_ext::cap_std::ambient_authority())
         .with_context(|| format!("Opening rootfs {rootfs}"))?;
@@ -80,8 +188,13 @@ pub(crate) fn build_ukify(
     // Compute the composefs digest
     let composefs_digest = compute_composefs_digest(rootfs, None)?;
 
-    // Get kernel arguments from kargs.d
-    let mut cmdline = crate::bootc_kargs::get_kargs_in_root(&root, std::env::consts::ARCH)?;
+    // Get kernel arguments from kargs.d; of the target system, it is only
+    // known that it boots via UEFI
+    let ctx = KargsMatchContext {
+        firmware: Some(Firmware::Uefi),
+        ..KargsMatchContext::for_arch(std::env::consts::ARCH)
+    };
+    let mut cmdline = crate::bootc_kargs::get_kargs_in_root(&root, &ctx)?;
 
     // Add the composefs digest
     cmdline.extend(&Cmdline::from(
@@ -110,6 +223,8 @@ pub(crate) fn build_ukify(
         .arg("--os-release")
         .arg("@usr/lib/os-release");
 
+    cmd.args(&signing_args);
+
     // Add pass-through arguments
     cmd.args(args);
 
@@ -131,7 +246,7 @@ mod tests {
         let tempdir = tempfile::tempdir().unwrap();
         let path = Utf8Path::from_path(tempdir.path()).unwrap();
 
-        let result = build_ukify(path, &[], &[], false);
+        let result = build_ukify(path, &[], &[], false, &UkiSigningOpts::default());
         assert!(result.is_err());
         let err = format!("{:#}", result.unwrap_err());
         assert!(
@@ -149,7 +264,7 @@ mod tests {
         fs::create_dir_all(tempdir.path().join("boot/EFI/Linux")).unwrap();
         fs::write(tempdir.path().join("boot/EFI/Linux/test.efi"), b"fake uki").unwrap();
 
-        let result = build_ukify(path, &[], &[], false);
+        let result = build_ukify(path, &[], &[], false, &UkiSigningOpts::default());
         assert!(result.is_err());
         let err = format!("{:#}", result.unwrap_err());
         assert!(
@@ -157,4 +272,72 @@ mod tests {
             "Unexpected error message: {err}"
         );
     }
+
+    #[test]
+    fn test_signing_args() -> Result<()> {
+        assert!(signing_args(&UkiSigningOpts::default())?.is_empty());
+
+        let opts = UkiSigningOpts {
+            secureboot: SecureBootSigningOpts {
+                secureboot_key: Some("/keys/db.key".into()),
+                secureboot_certificate: Some("/keys/db.crt".into()),
+            },
+            pcr_private_key: Some("/keys/pcr.key".into()),
+            pcr_public_key: None,
+        };
+        assert_eq!(
+            signing_args(&opts)?,
+            [
+                "--secureboot-private-key",
+                "/keys/db.key",
+                "--secureboot-certificate",
+                "/keys/db.crt",
+                "--pcr-private-key",
+                "/keys/pcr.key",
+            ]
+        );
+
+        // Relative paths are resolved against the current directory
+        let opts = UkiSigningOpts {
+            pcr_private_key: Some("pcr.key".into()),
+            pcr_public_key: Some("pcr.pub".into()),
+            ..Default::default()
+        };
+        let cwd = std::env::current_dir()?;
+        assert_eq!(
+            signing_args(&opts)?,
+            [
+                OsString::from("--pcr-private-key"),
+                cwd.join("pcr.key").into(),
+                "--pcr-public-key".into(),
+                cwd.join("pcr.pub").into(),
+            ]
+        );
+
+        let opts = UkiSigningOpts {
+            secureboot: SecureBootSigningOpts {
+                secureboot_key: Some("pkcs11:token=db;object=db".into()),
+                secureboot_certificate: Some("/keys/db.crt".into()),
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.secureboot.ukify_args()?,
+            [
+                "--signing-engine=pkcs11",
+                "--secureboot-private-key",
+                "pkcs11:token=db;object=db",
+                "--secureboot-certificate",
+                "/keys/db.crt",
+            ]
+        );
+
+        // Keys from a token and from files can't be mixed
+        let opts = UkiSigningOpts {
+            pcr_private_key: Some("/keys/pcr.key".into()),
+            ..opts
+        };
+        assert!(signing_args(&opts).is_err());
+        Ok(())
+    }
 }
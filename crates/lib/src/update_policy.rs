@@ -0,0 +1,331 @@
+//! # Declarative policy for automatic updates
+//!
+//! The `updatePolicy` section of the host spec is persisted in `/etc`, so that
+//! it is carried forward across upgrades by the usual `/etc` merge. Our systemd
+//! generator translates it into drop-ins for `bootc-fetch-apply-updates.timer`
+//! and `bootc-fetch-apply-updates.service`.
+
+use std::fmt::Write as _;
+
+use anyhow::{Context, Result};
+use cap_std_ext::cap_std::fs::Dir;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use fn_error_context::context;
+
+use crate::spec::{SoftRebootPreference, UpdateMode, UpdatePolicy};
+use crate::task::Task;
+
+/// Where the policy is stored, relative to the root
+const POLICY_PATH: &str = "etc/bootc/update-policy.json";
+const FETCH_APPLY_TIMER: &str = "bootc-fetch-apply-updates.timer";
+const FETCH_APPLY_SERVICE: &str = "bootc-fetch-apply-updates.service";
+const DROPIN_NAME: &str = "50-bootc-update-policy.conf";
+
+/// Values end up verbatim in unit files; at least ensure they can't inject
+/// additional keys.
+fn validate_unit_value(field: &str, value: &str) -> Result<()> {
+    if value.trim().is_empty() {
+        anyhow::bail!("Invalid empty value for {field}");
+    }
+    if value.contains(['\n', '\r']) {
+        anyhow::bail!("Invalid newline in {field}: {value:?}");
+    }
+    Ok(())
+}
+
+impl UpdatePolicy {
+    /// Verify the policy can be turned into units.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if let Some(interval) = self.interval.as_deref() {
+            validate_unit_value("interval", interval)?;
+        }
+        if self.interval.is_some() && !self.maintenance_windows.is_empty() {
+            anyhow::bail!("interval and maintenanceWindows are mutually exclusive");
+        }
+        for window in &self.maintenance_windows {
+            validate_unit_value("maintenanceWindows.start", &window.start)?;
+            if let Some(duration) = window.duration.as_deref() {
+                validate_unit_value("maintenanceWindows.duration", duration)?;
+            }
+        }
+        // A timer only has a single randomized delay
+        if self
+            .maintenance_windows
+            .windows(2)
+            .any(|w| w[0].duration != w[1].duration)
+        {
+            anyhow::bail!("All maintenanceWindows must have the same duration");
+        }
+        if self.soft_reboot.is_some() && self.mode != UpdateMode::Apply {
+            anyhow::bail!("softReboot requires the apply mode");
+        }
+        Ok(())
+    }
+
+    /// Contents of the drop-in for the timer unit.
+    fn timer_dropin(&self) -> Result<String> {
+        let mut r = String::from("[Timer]\n");
+
+        if let Some(interval) = self.interval.as_deref() {
+            // Timer settings accumulate; replace the shipped interval
+            writeln!(r, "OnUnitInactiveSec=\nOnUnitInactiveSec={interval}")?;
+        }
+
+        if !self.maintenance_windows.is_empty() {
+            // Only trigger at the start of a window
+            r.push_str("OnBootSec=\nOnUnitInactiveSec=\n");
+            for window in &self.maintenance_windows {
+                writeln!(r, "OnCalendar={}", window.start)?;
+            }
+            // Spread out within the window; validation ensures all durations are equal
+            let delay = self.maintenance_windows[0]
+                .duration
+                .as_deref()
+                .unwrap_or("0");
+            writeln!(r, "RandomizedDelaySec={delay}")?;
+        }
+
+        Ok(r)
+    }
+
+    /// The `bootc upgrade` invocation implementing this policy
+    fn upgrade_args(&self) -> Vec<&'static str> {
+        let mut args = vec!["upgrade", "--quiet"];
+        match self.mode {
+            UpdateMode::DownloadOnly => args.push("--download-only"),
+            UpdateMode::Stage => {}
+            UpdateMode::Apply => args.push("--apply"),
+        }
+        match self.soft_reboot {
+            Some(SoftRebootPreference::Required) => args.push("--soft-reboot=required"),
+            Some(SoftRebootPreference::Auto) => args.push("--soft-reboot=auto"),
+            None => {}
+        }
+        args
+    }
+
+    /// Contents of the drop-in for the service unit.
+    fn service_dropin(&self) -> String {
+        format!(
+            "[Service]\nExecStart=\nExecStart=/usr/bin/bootc {}\n",
+            self.upgrade_args().join(" ")
+        )
+    }
+}
+
+/// Load the update policy from the target root, if any.
+#[context("Loading update policy")]
+pub(crate) fn load(root: &Dir) -> Result<Option<UpdatePolicy>> {
+    root.read_to_string_optional(POLICY_PATH)
+        .with_context(|| format!("Reading {POLICY_PATH}"))?
+        .map(|s| serde_json::from_str(&s))
+        .transpose()
+        .with_context(|| format!("Parsing {POLICY_PATH}"))
+}
+
+/// Load the update policy of the running system.
+pub(crate) fn load_host() -> Result<Option<UpdatePolicy>> {
+    let root = Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    load(&root)
+}
+
+/// Persist the update policy, or remove it if `None`.
+#[context("Storing update policy")]
+pub(crate) fn store(root: &Dir, policy: Option<&UpdatePolicy>) -> Result<()> {
+    let Some(policy) = policy else {
+        root.remove_file_optional(POLICY_PATH)?;
+        return Ok(());
+    };
+
+    policy.validate()?;
+    let parent = std::path::Path::new(POLICY_PATH)
+        .parent()
+        .expect("policy path has a parent");
+    root.create_dir_all(parent)?;
+    root.atomic_write(POLICY_PATH, serde_json::to_vec_pretty(policy)?)
+        .with_context(|| format!("Writing {POLICY_PATH}"))
+}
+
+/// Persist a changed update policy for the running system, and make systemd
+/// pick it up.
+pub(crate) fn apply_host(policy: Option<&UpdatePolicy>) -> Result<()> {
+    let root = Dir::open_ambient_dir("/", cap_std_ext::cap_std::ambient_authority())?;
+    store(&root, policy)?;
+    Task::new("Reloading systemd units", "systemctl")
+        .arg("daemon-reload")
+        .run()?;
+    // The timer may already be elapsing using the previous settings
+    Task::new("Restarting update timer", "systemctl")
+        .args(["try-restart", FETCH_APPLY_TIMER])
+        .run()
+}
+
+/// Generate unit drop-ins for the update policy, if any.
+#[context("Generating update policy units")]
+pub(crate) fn generate(root: &Dir, unit_dir: &Dir) -> Result<()> {
+    // Don't fail the whole generator on a bad policy; the defaults still apply
+    let policy = match load(root) {
+        Ok(Some(policy)) => policy,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            tracing::warn!("Ignoring unreadable update policy: {e:#}");
+            return Ok(());
+        }
+    };
+    if let Err(e) = policy.validate() {
+        tracing::warn!("Ignoring invalid update policy: {e:#}");
+        return Ok(());
+    }
+
+    for (unit, contents) in [
+        (FETCH_APPLY_TIMER, policy.timer_dropin()?),
+        (FETCH_APPLY_SERVICE, policy.service_dropin()),
+    ] {
+        let dropin_dir = format!("{unit}.d");
+        unit_dir.create_dir_all(&dropin_dir)?;
+        unit_dir
+            .atomic_write(format!("{dropin_dir}/{DROPIN_NAME}"), contents)
+            .with_context(|| format!("Writing drop-in for {unit}"))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+
+    use super::*;
+    use crate::spec::MaintenanceWindow;
+
+    #[test]
+    fn test_validate() {
+        let window = MaintenanceWindow {
+            start: "Sat *-*-* 02:00".into(),
+            duration: Some("2h".into()),
+        };
+        let cases = [
+            (UpdatePolicy::default(), true),
+            (
+                UpdatePolicy {
+                    interval: Some("6h".into()),
+                    ..Default::default()
+                },
+                true,
+            ),
+            (
+                UpdatePolicy {
+                    interval: Some("6h\nExecStart=/bin/false".into()),
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                UpdatePolicy {
+                    interval: Some("6h".into()),
+                    maintenance_windows: vec![window.clone()],
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                UpdatePolicy {
+                    mode: UpdateMode::Stage,
+                    soft_reboot: Some(SoftRebootPreference::Auto),
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                UpdatePolicy {
+                    maintenance_windows: vec![
+                        window.clone(),
+                        MaintenanceWindow {
+                            start: "Wed *-*-* 02:00".into(),
+                            duration: None,
+                        },
+                    ],
+                    ..Default::default()
+                },
+                false,
+            ),
+            (
+                UpdatePolicy {
+                    maintenance_windows: vec![MaintenanceWindow {
+                        start: " ".into(),
+                        duration: None,
+                    }],
+                    ..Default::default()
+                },
+                false,
+            ),
+        ];
+        for (policy, valid) in cases {
+            assert_eq!(policy.validate().is_ok(), valid, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn test_generate() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("run/systemd/generator")?;
+        let unit_dir = &td.open_dir("run/systemd/generator")?;
+
+        // No policy, nothing generated
+        generate(&td, unit_dir)?;
+        assert_eq!(unit_dir.entries()?.count(), 0);
+
+        let policy = UpdatePolicy {
+            maintenance_windows: vec![MaintenanceWindow {
+                start: "Sat *-*-* 02:00".into(),
+                duration: Some("2h".into()),
+            }],
+            mode: UpdateMode::Apply,
+            soft_reboot: Some(SoftRebootPreference::Auto),
+            ..Default::default()
+        };
+        store(&td, Some(&policy))?;
+        assert_eq!(load(&td)?.as_ref(), Some(&policy));
+
+        generate(&td, unit_dir)?;
+        similar_asserts::assert_eq!(
+            unit_dir.read_to_string(format!("{FETCH_APPLY_TIMER}.d/{DROPIN_NAME}"))?,
+            indoc::indoc! { "
+                [Timer]
+                OnBootSec=
+                OnUnitInactiveSec=
+                OnCalendar=Sat *-*-* 02:00
+                RandomizedDelaySec=2h
+            " }
+        );
+        similar_asserts::assert_eq!(
+            unit_dir.read_to_string(format!("{FETCH_APPLY_SERVICE}.d/{DROPIN_NAME}"))?,
+            indoc::indoc! { "
+                [Service]
+                ExecStart=
+                ExecStart=/usr/bin/bootc upgrade --quiet --apply --soft-reboot=auto
+            " }
+        );
+
+        let policy = UpdatePolicy {
+            interval: Some("1d".into()),
+            mode: UpdateMode::DownloadOnly,
+            ..Default::default()
+        };
+        store(&td, Some(&policy))?;
+        generate(&td, unit_dir)?;
+        similar_asserts::assert_eq!(
+            unit_dir.read_to_string(format!("{FETCH_APPLY_TIMER}.d/{DROPIN_NAME}"))?,
+            "[Timer]\nOnUnitInactiveSec=\nOnUnitInactiveSec=1d\n"
+        );
+        similar_asserts::assert_eq!(
+            unit_dir.read_to_string(format!("{FETCH_APPLY_SERVICE}.d/{DROPIN_NAME}"))?,
+            "[Service]\nExecStart=\nExecStart=/usr/bin/bootc upgrade --quiet --download-only\n"
+        );
+
+        store(&td, None)?;
+        assert!(load(&td)?.is_none());
+
+        Ok(())
+    }
+}
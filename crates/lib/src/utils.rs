@@ -254,6 +254,20 @@ pub fn read_uefi_var(var_name: &str) -> Result<String, EfiError> {
     }
 }
 
+/// The EFI variable holding whether Secure Boot is enforced.
+const EFI_SECURE_BOOT: &str = "SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+
+/// Returns whether the system was booted with Secure Boot enabled.
+pub(crate) fn secure_boot_enabled() -> Result<bool> {
+    let path = Path::new(crate::install::EFIVARFS).join(EFI_SECURE_BOOT);
+    match std::fs::read(&path) {
+        // The value follows the 4 bytes of attributes
+        Ok(data) => Ok(data.get(4) == Some(&1)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("Reading {}", path.display())),
+    }
+}
+
 /// Computes a relative path from `from` to `to`.
 ///
 /// Both `from` and `to` must be absolute paths.
@@ -5,17 +5,28 @@
 mod nameservice;
 
 use std::collections::{BTreeMap, BTreeSet};
-use std::io::{BufRead, BufReader};
-use std::num::ParseIntError;
+use std::io::{BufRead, BufReader, Write};
+use std::num::{NonZeroUsize, ParseIntError};
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use cap_std_ext::cap_std::fs::{MetadataExt, Permissions, PermissionsExt};
 use cap_std_ext::dirext::{CapStdExtDirExt, CapStdExtDirExtUtf8};
 use cap_std_ext::{cap_std::fs::Dir, cap_std::fs_utf8::Dir as DirUtf8};
+use rustix::fs::{AtFlags, Gid, Uid};
 use thiserror::Error;
 
 const SYSUSERSD: &str = "usr/lib/sysusers.d";
+const ETC_SYSUSERSD: &str = "etc/sysusers.d";
+/// The prefix of credentials holding a hashed password for a user
+const CREDENTIAL_HASHED_PASSWORD: &str = "passwd.hashed-password.";
+/// The prefix of credentials holding the login shell for a user
+const CREDENTIAL_SHELL: &str = "passwd.shell.";
+/// The default range for dynamically allocated IDs, matching SYS_UID_MAX.
+const DEFAULT_ID_RANGE: (u32, u32) = (1, 999);
+/// The prefix of the files we generate in sysusers.d
+const BOOTC_GENERATED_PREFIX: &str = "bootc-autogenerated-users";
 
 /// An error when processing sysusers
 #[derive(Debug, Error)]
@@ -33,6 +44,22 @@ pub enum Error {
     PasswdLoadFailure(String),
     #[error("Failed to load etc/group: {0}")]
     GroupLoadFailure(String),
+    #[error("Failed to load etc/shadow: {0}")]
+    ShadowLoadFailure(String),
+    #[error("Failed to load etc/gshadow: {0}")]
+    GshadowLoadFailure(String),
+    #[error("Failed to write {path}: {err}")]
+    NameserviceWriteFailure { path: String, err: String },
+    #[error("No free ID available for {0}")]
+    IdAllocationFailure(String),
+    #[error("Group {group} for user {user} does not exist")]
+    UnknownGroup { user: String, group: String },
+    #[error("Cannot change the ID of {name} to {id}, which is used by {other}")]
+    IdCollision {
+        name: String,
+        id: u32,
+        other: String,
+    },
 }
 
 /// The type of Result.
@@ -55,6 +82,15 @@ impl From<u32> for GroupReference {
     }
 }
 
+impl std::fmt::Display for GroupReference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Numeric(n) => write!(f, "{n}"),
+            Self::Name(s) | Self::Path(s) => f.write_str(s),
+        }
+    }
+}
+
 impl FromStr for GroupReference {
     type Err = ParseIntError;
 
@@ -107,12 +143,19 @@ pub enum SysusersEntry {
         name: String,
         uid: Option<IdSource>,
         pgid: Option<GroupReference>,
+        /// Set unless the primary group was given explicitly via `uid:gid`, in
+        /// which case no group with the same name as the user is created.
+        implicit_group: bool,
+        /// Set for `u!`, which creates a fully locked account.
+        locked: bool,
         gecos: String,
         home: Option<String>,
         shell: Option<String>,
     },
     /// Defines a group
     Group { name: String, id: Option<IdSource> },
+    /// Adds a user to a group
+    Membership { user: String, group: String },
     /// Defines a range of uids
     Range { start: u32, end: u32 },
 }
@@ -133,7 +176,13 @@ impl SysusersEntry {
                 None => (s, ""),
             },
             Some(rest) => {
-                let end = rest.find('"')?;
+                // Skip over backslash-escaped characters to find the closing quote
+                let mut escaped = false;
+                let (end, _) = rest.char_indices().find(|&(_, c)| {
+                    let end = !escaped && c == '"';
+                    escaped = !escaped && c == '\\';
+                    end
+                })?;
                 (&rest[..end], &rest[end + 1..])
             }
         };
@@ -144,6 +193,23 @@ impl SysusersEntry {
         }
     }
 
+    /// Undoes the escaping of a quoted string as written by [`sysusers_quote`].
+    fn unescape(s: &str) -> String {
+        let mut r = String::with_capacity(s.len());
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => r.extend(chars.next()),
+                '%' if chars.as_str().starts_with('%') => {
+                    r.push('%');
+                    chars.next();
+                }
+                c => r.push(c),
+            }
+        }
+        r
+    }
+
     fn next_token_owned(s: &str) -> Option<(String, &str)> {
         Self::next_token(s).map(|(a, b)| (a.to_owned(), b))
     }
@@ -165,6 +231,7 @@ impl SysusersEntry {
             "u" | "u!" => {
                 let (name, s) = Self::next_token_owned(s).ok_or_else(err)?;
                 let (id, s) = Self::next_optional_token(s).unwrap_or_default();
+                let implicit_group = !id.is_some_and(|v| v.contains(':'));
                 let (uid, pgid) = id
                     .and_then(|v| v.split_once(':'))
                     .or_else(|| id.map(|id| (id, id)))
@@ -177,13 +244,15 @@ impl SysusersEntry {
                     .map_err(|_| err())?;
                 let pgid = pgid.map(|id| id.parse()).transpose().map_err(|_| err())?;
                 let (gecos, s) = Self::next_token(s).unwrap_or_default();
-                let gecos = gecos.to_owned();
+                let gecos = Self::unescape(gecos);
                 let (home, s) = Self::next_optional_token_owned(s).unwrap_or_default();
                 let (shell, _) = Self::next_optional_token_owned(s).unwrap_or_default();
                 SysusersEntry::User {
                     name,
                     uid,
                     pgid,
+                    implicit_group,
+                    locked: ftype == "u!",
                     gecos,
                     home,
                     shell,
@@ -195,6 +264,11 @@ impl SysusersEntry {
                 let id = id.map(|id| id.parse()).transpose().map_err(|_| err())?;
                 SysusersEntry::Group { name, id }
             }
+            "m" => {
+                let (user, s) = Self::next_token_owned(s).ok_or_else(err)?;
+                let (group, _) = Self::next_token_owned(s).ok_or_else(err)?;
+                SysusersEntry::Membership { user, group }
+            }
             "r" => {
                 let (_, s) = Self::next_optional_token(s).ok_or_else(err)?;
                 let (range, _) = Self::next_token(s).ok_or_else(err)?;
@@ -210,6 +284,27 @@ impl SysusersEntry {
     }
 }
 
+/// Parse all entries in a single sysusers.d file.
+fn read_sysusers_file(d: &Dir, name: &str) -> Result<Vec<SysusersEntry>> {
+    let r = d.open(name).map(BufReader::new)?;
+    let mut result = Vec::new();
+    for line in r.lines() {
+        let line = line?;
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        let Some(e) = SysusersEntry::parse(&line).map_err(|e| Error::ParseFailureInFile {
+            path: name.into(),
+            err: e.to_string(),
+        })?
+        else {
+            continue;
+        };
+        result.push(e);
+    }
+    Ok(result)
+}
+
 /// Read all tmpfiles.d entries in the target directory, and return a mapping
 /// from (file path) => (single tmpfiles.d entry line)
 pub fn read_sysusers(rootfs: &Dir) -> Result<Vec<SysusersEntry>> {
@@ -224,35 +319,28 @@ pub fn read_sysusers(rootfs: &Dir) -> Result<Vec<SysusersEntry>> {
         let Some("conf") = Utf8Path::new(&name).extension() else {
             continue;
         };
-        let r = d.open(&name).map(BufReader::new)?;
-        for line in r.lines() {
-            let line = line?;
-            if line.is_empty() || line.starts_with("#") {
-                continue;
-            }
-            let Some(e) = SysusersEntry::parse(&line).map_err(|e| Error::ParseFailureInFile {
-                path: name.clone().into(),
-                err: e.to_string(),
-            })?
-            else {
-                continue;
-            };
+        for e in read_sysusers_file(d.as_cap_std(), &name)? {
             match e {
                 SysusersEntry::User {
-                    ref name, ref pgid, ..
+                    ref name,
+                    ref pgid,
+                    implicit_group,
+                    ..
                 } if !found_users.contains(name.as_str()) => {
                     found_users.insert(name.clone());
-                    found_groups.insert(name.clone());
-                    // Users implicitly create a group with the same name
-                    let pgid = pgid.as_ref().and_then(|g| match g {
-                        GroupReference::Numeric(n) => Some(IdSource::Numeric(*n)),
-                        GroupReference::Path(p) => Some(IdSource::Path(p.clone())),
-                        GroupReference::Name(_) => None,
-                    });
-                    result.push(SysusersEntry::Group {
-                        name: name.clone(),
-                        id: pgid,
-                    });
+                    // Users implicitly create a group with the same name, unless
+                    // the primary group was given explicitly.
+                    if implicit_group && found_groups.insert(name.clone()) {
+                        let pgid = pgid.as_ref().and_then(|g| match g {
+                            GroupReference::Numeric(n) => Some(IdSource::Numeric(*n)),
+                            GroupReference::Path(p) => Some(IdSource::Path(p.clone())),
+                            GroupReference::Name(_) => None,
+                        });
+                        result.push(SysusersEntry::Group {
+                            name: name.clone(),
+                            id: pgid,
+                        });
+                    }
                     result.push(e);
                 }
                 SysusersEntry::Group { ref name, .. } if !found_groups.contains(name.as_str()) => {
@@ -338,7 +426,7 @@ pub fn analyze(rootfs: &Dir) -> Result<SysusersAnalysis> {
                 SysusersEntry::Group { name, id } => {
                     groups.insert(name, SysgroupData { id });
                 }
-                SysusersEntry::Range { .. } => {
+                SysusersEntry::Membership { .. } | SysusersEntry::Range { .. } => {
                     // Nothing to do here
                 }
             }
@@ -355,6 +443,767 @@ pub fn analyze(rootfs: &Dir) -> Result<SysusersAnalysis> {
     })
 }
 
+/// The result of a sysusers.d generation run
+#[derive(Debug, Default)]
+pub struct SysusersWrittenResult {
+    /// Set if we generated entries; this is the count and the path.
+    pub generated: Option<(NonZeroUsize, Utf8PathBuf)>,
+}
+
+/// Format an optional sysusers.d field, using `-` if empty.
+fn sysusers_field(s: &str) -> &str {
+    if s.is_empty() { "-" } else { s }
+}
+
+/// Quote a sysusers.d field, escaping the characters which would otherwise end the
+/// string or be interpreted, i.e. quotes, backslashes and `%` specifiers.
+fn sysusers_quote(s: &str) -> String {
+    let mut r = String::with_capacity(s.len() + 2);
+    r.push('"');
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => {
+                r.push('\\');
+                r.push(c);
+            }
+            '%' => r.push_str("%%"),
+            c => r.push(c),
+        }
+    }
+    r.push('"');
+    r
+}
+
+/// Generate sysusers.d entries for users and groups in /etc/passwd and /etc/group
+/// which do not have one, writing them to a new file in /usr/lib/sysusers.d.
+///
+/// The static IDs from /etc/passwd and /etc/group are preserved. For each
+/// generated group, its members are added via `m` entries.
+pub fn generate_missing(rootfs: &Dir) -> Result<SysusersWrittenResult> {
+    let analysis = analyze(rootfs)?;
+    if analysis.is_empty() {
+        return Ok(SysusersWrittenResult::default());
+    }
+    let passwd = nameservice::passwd::load_etc_passwd(rootfs)
+        .map_err(|e| Error::PasswdLoadFailure(e.to_string()))?
+        .unwrap_or_default();
+    let groups = nameservice::group::load_etc_group(rootfs)
+        .map_err(|e| Error::GroupLoadFailure(e.to_string()))?;
+    let missing_users = passwd
+        .iter()
+        .filter(|u| analysis.missing_users.contains(&u.name))
+        .collect::<Vec<_>>();
+    let missing_groups = groups
+        .iter()
+        .filter(|g| analysis.missing_groups.contains(&g.name))
+        .collect::<Vec<_>>();
+
+    // A `u` entry with a single ID implicitly creates a group of the same name and ID
+    let implicit_groups = missing_users
+        .iter()
+        .filter(|u| {
+            u.uid == u.gid
+                && missing_groups
+                    .iter()
+                    .any(|g| g.name == u.name && g.gid == u.gid)
+        })
+        .map(|u| u.name.as_str())
+        .collect::<BTreeSet<_>>();
+
+    let mut entries = Vec::new();
+    for g in missing_groups.iter() {
+        if !implicit_groups.contains(g.name.as_str()) {
+            entries.push(format!("g {} {}", g.name, g.gid));
+        }
+    }
+    for u in missing_users.iter() {
+        let id = if implicit_groups.contains(u.name.as_str()) {
+            u.uid.to_string()
+        } else {
+            format!("{}:{}", u.uid, u.gid)
+        };
+        let gecos = if u.gecos.is_empty() {
+            "-".to_owned()
+        } else {
+            sysusers_quote(&u.gecos)
+        };
+        let home = sysusers_field(&u.home_dir);
+        let shell = sysusers_field(&u.shell);
+        entries.push(format!("u {} {id} {gecos} {home} {shell}", u.name));
+    }
+    for g in missing_groups.iter() {
+        for user in g.users.iter().filter(|u| !u.is_empty()) {
+            entries.push(format!("m {user} {}", g.name));
+        }
+    }
+    // SAFETY: The analysis found at least one missing entry
+    let entries_count = NonZeroUsize::new(entries.len()).unwrap();
+
+    rootfs.create_dir_all(SYSUSERSD)?;
+    let path = (0u32..)
+        .map(|i| Utf8PathBuf::from(format!("{SYSUSERSD}/{BOOTC_GENERATED_PREFIX}-{i}.conf")))
+        .find_map(|p| match rootfs.try_exists(&p) {
+            Ok(true) => None,
+            Ok(false) => Some(Ok(p)),
+            Err(e) => Some(Err(e)),
+        })
+        // SAFETY: The iterator is unbounded
+        .unwrap()?;
+    rootfs.atomic_replace_with(&path, |w| -> std::io::Result<()> {
+        w.get_mut()
+            .as_file_mut()
+            .set_permissions(Permissions::from_mode(0o644))?;
+        for line in entries.iter() {
+            writeln!(w, "{line}")?;
+        }
+        Ok(())
+    })?;
+
+    Ok(SysusersWrittenResult {
+        generated: Some((entries_count, path)),
+    })
+}
+
+/// Load a user database from /etc/passwd and /etc/group in the target root,
+/// suitable for resolving file ownership without consulting the host.
+pub fn load_userdb(rootfs: &Dir) -> Result<uzers::mock::MockUsers> {
+    let mut db = uzers::mock::MockUsers::with_current_uid(0);
+    let passwd = nameservice::passwd::load_etc_passwd(rootfs)
+        .map_err(|e| Error::PasswdLoadFailure(e.to_string()))?
+        .unwrap_or_default();
+    for u in passwd {
+        db.add_user(uzers::User::new(u.uid, &u.name, u.gid));
+    }
+    if rootfs.try_exists("etc/group")? {
+        let groups = nameservice::group::load_etc_group(rootfs)
+            .map_err(|e| Error::GroupLoadFailure(e.to_string()))?;
+        for g in groups {
+            db.add_group(uzers::Group::new(g.gid, &g.name));
+        }
+    }
+    Ok(db)
+}
+
+/// Read all sysusers.d entries from /usr/lib/sysusers.d and /etc/sysusers.d in
+/// the target root, in the order systemd-sysusers processes them. A file in
+/// /etc/sysusers.d overrides one of the same name in /usr/lib/sysusers.d; if it
+/// is a symlink to /dev/null, the file is masked.
+pub fn read_all_sysusers(rootfs: &Dir) -> Result<Vec<SysusersEntry>> {
+    let dirs = [SYSUSERSD, ETC_SYSUSERSD]
+        .into_iter()
+        .map(|p| rootfs.open_dir_optional(p))
+        .collect::<std::io::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let mut files = BTreeMap::new();
+    for (i, d) in dirs.iter().enumerate() {
+        for ent in d.entries()? {
+            let Ok(name) = ent?.file_name().into_string() else {
+                continue;
+            };
+            if Utf8Path::new(&name).extension() == Some("conf") {
+                files.insert(name, i);
+            }
+        }
+    }
+    let mut result = Vec::new();
+    for (name, i) in files {
+        let d = &dirs[i];
+        if d.symlink_metadata(&name)?.is_symlink()
+            && d.read_link_contents(&name)?.as_os_str() == "/dev/null"
+        {
+            continue;
+        }
+        result.extend(read_sysusers_file(d, &name)?);
+    }
+    Ok(result)
+}
+
+/// Credentials consulted when creating users, using the same names as
+/// systemd-sysusers.
+#[derive(Debug, Default)]
+pub struct SysusersCredentials {
+    /// Hashed passwords from `passwd.hashed-password.<user>`, keyed by user name.
+    pub hashed_passwords: BTreeMap<String, String>,
+    /// Login shells from `passwd.shell.<user>`, keyed by user name.
+    pub shells: BTreeMap<String, String>,
+}
+
+impl SysusersCredentials {
+    /// Load credentials from a directory, such as `$CREDENTIALS_DIRECTORY`.
+    /// Unrelated files are ignored.
+    pub fn load(dir: &Dir) -> Result<Self> {
+        let mut r = Self::default();
+        for ent in dir.entries()? {
+            let Ok(name) = ent?.file_name().into_string() else {
+                continue;
+            };
+            let (map, user) = if let Some(user) = name.strip_prefix(CREDENTIAL_HASHED_PASSWORD) {
+                (&mut r.hashed_passwords, user)
+            } else if let Some(user) = name.strip_prefix(CREDENTIAL_SHELL) {
+                (&mut r.shells, user)
+            } else {
+                continue;
+            };
+            let value = dir.read_to_string(&name)?;
+            map.insert(user.to_owned(), value.trim_end_matches('\n').to_owned());
+        }
+        Ok(r)
+    }
+}
+
+/// The result of applying sysusers.d entries to a root.
+#[derive(Debug, Default)]
+pub struct SysusersApplyResult {
+    /// Users which were created, with their allocated uid.
+    pub created_users: Vec<(String, u32)>,
+    /// Groups which were created, with their allocated gid.
+    pub created_groups: Vec<(String, u32)>,
+    /// Users which were added to a group, as (user, group).
+    pub added_memberships: Vec<(String, String)>,
+}
+
+impl SysusersApplyResult {
+    /// Returns true if nothing was changed.
+    pub fn is_empty(&self) -> bool {
+        self.created_users.is_empty()
+            && self.created_groups.is_empty()
+            && self.added_memberships.is_empty()
+    }
+}
+
+/// The contents of /etc/{passwd,group,shadow,gshadow} in a root.
+struct Nameservice {
+    passwd: Vec<nameservice::passwd::PasswdEntry>,
+    group: Vec<nameservice::group::GroupEntry>,
+    shadow: Vec<nameservice::shadow::ShadowEntry>,
+    gshadow: Vec<nameservice::gshadow::GshadowEntry>,
+}
+
+impl Nameservice {
+    fn load(rootfs: &Dir) -> Result<Self> {
+        let passwd = nameservice::passwd::load_etc_passwd(rootfs)
+            .map_err(|e| Error::PasswdLoadFailure(e.to_string()))?
+            .unwrap_or_default();
+        let group = if rootfs.try_exists("etc/group")? {
+            nameservice::group::load_etc_group(rootfs)
+                .map_err(|e| Error::GroupLoadFailure(e.to_string()))?
+        } else {
+            Vec::new()
+        };
+        let shadow = nameservice::shadow::load_etc_shadow(rootfs)
+            .map_err(|e| Error::ShadowLoadFailure(e.to_string()))?
+            .unwrap_or_default();
+        let gshadow = nameservice::gshadow::load_etc_gshadow(rootfs)
+            .map_err(|e| Error::GshadowLoadFailure(e.to_string()))?
+            .unwrap_or_default();
+        Ok(Self {
+            passwd,
+            group,
+            shadow,
+            gshadow,
+        })
+    }
+
+    fn has_user(&self, name: &str) -> bool {
+        self.passwd.iter().any(|u| u.name == name)
+    }
+
+    fn gid_of(&self, name: &str) -> Option<u32> {
+        self.group.iter().find(|g| g.name == name).map(|g| g.gid)
+    }
+
+    fn uid_used(&self, id: u32) -> bool {
+        self.passwd.iter().any(|u| u.uid == id)
+    }
+
+    fn gid_used(&self, id: u32) -> bool {
+        self.group.iter().any(|g| g.gid == id)
+    }
+
+    /// Write out all files, preserving the mode of existing ones.
+    fn write(&self, rootfs: &Dir) -> Result<()> {
+        fn write_file(rootfs: &Dir, path: &str, mode: u32, buf: Vec<u8>) -> Result<()> {
+            let perms = match rootfs.symlink_metadata_optional(path)? {
+                Some(meta) => meta.permissions(),
+                // Don't create files which would be empty
+                None if buf.is_empty() => return Ok(()),
+                None => Permissions::from_mode(mode),
+            };
+            rootfs.atomic_write_with_perms(path, buf, perms)?;
+            Ok(())
+        }
+        fn serialize<T>(
+            path: &str,
+            entries: &[T],
+            f: impl Fn(&T, &mut Vec<u8>) -> anyhow::Result<()>,
+        ) -> Result<Vec<u8>> {
+            let mut buf = Vec::new();
+            for e in entries {
+                f(e, &mut buf).map_err(|e| Error::NameserviceWriteFailure {
+                    path: path.to_owned(),
+                    err: e.to_string(),
+                })?;
+            }
+            Ok(buf)
+        }
+
+        rootfs.create_dir_all("etc")?;
+        let buf = serialize("etc/passwd", &self.passwd, |e, w| e.to_writer(w))?;
+        write_file(rootfs, "etc/passwd", 0o644, buf)?;
+        let buf = serialize("etc/group", &self.group, |e, w| e.to_writer(w))?;
+        write_file(rootfs, "etc/group", 0o644, buf)?;
+        let buf = serialize("etc/shadow", &self.shadow, |e, w| e.to_writer(w))?;
+        write_file(rootfs, "etc/shadow", 0o000, buf)?;
+        let buf = serialize("etc/gshadow", &self.gshadow, |e, w| e.to_writer(w))?;
+        write_file(rootfs, "etc/gshadow", 0o000, buf)?;
+        Ok(())
+    }
+}
+
+/// Find the highest ID in the provided ranges accepted by `is_free`.
+fn allocate_id(ranges: &[(u32, u32)], is_free: impl Fn(u32) -> bool) -> Option<u32> {
+    ranges
+        .iter()
+        .find_map(|&(start, end)| (start..=end).rev().find(|&id| is_free(id)))
+}
+
+/// Return the (uid, gid) owning a path in the root, if it exists.
+fn path_owner(rootfs: &Dir, path: &str) -> Result<Option<(u32, u32)>> {
+    let path = path.trim_start_matches('/');
+    Ok(rootfs
+        .symlink_metadata_optional(path)?
+        .map(|m| (m.uid(), m.gid())))
+}
+
+/// Create the users and groups defined in the sysusers.d configuration of the
+/// target root which do not exist yet, writing /etc/{passwd,group,shadow,gshadow}
+/// directly. This does not require `systemd-sysusers` to be installed.
+///
+/// Groups are processed first, then users, then group memberships; entries are
+/// appended in configuration order. Requested static IDs are used if free, and
+/// other IDs are allocated from the top of the `r` ranges (by default 1-999),
+/// so the result depends only on the input files. The files are only written
+/// if something changed.
+pub fn apply(rootfs: &Dir, credentials: &SysusersCredentials) -> Result<SysusersApplyResult> {
+    let mut entries = read_all_sysusers(rootfs)?;
+    let mut db = Nameservice::load(rootfs)?;
+    let mut result = SysusersApplyResult::default();
+
+    let mut ranges = entries
+        .iter()
+        .filter_map(|e| match e {
+            SysusersEntry::Range { start, end } => Some((*start, *end)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if ranges.is_empty() {
+        ranges.push(DEFAULT_ID_RANGE);
+    }
+
+    // Memberships implicitly create their user and group if they are not defined.
+    let mut defined_users = BTreeSet::new();
+    let mut defined_groups = BTreeSet::new();
+    for e in entries.iter() {
+        match e {
+            SysusersEntry::User {
+                name,
+                implicit_group,
+                ..
+            } => {
+                defined_users.insert(name.clone());
+                if *implicit_group {
+                    defined_groups.insert(name.clone());
+                }
+            }
+            SysusersEntry::Group { name, .. } => {
+                defined_groups.insert(name.clone());
+            }
+            _ => {}
+        }
+    }
+    let mut implied = Vec::new();
+    for e in entries.iter() {
+        let SysusersEntry::Membership { user, group } = e else {
+            continue;
+        };
+        if !db.has_user(user) && defined_users.insert(user.clone()) {
+            defined_groups.insert(user.clone());
+            implied.push(SysusersEntry::User {
+                name: user.clone(),
+                uid: None,
+                pgid: None,
+                implicit_group: true,
+                locked: false,
+                gecos: String::new(),
+                home: None,
+                shell: None,
+            });
+        }
+        if db.gid_of(group).is_none() && defined_groups.insert(group.clone()) {
+            implied.push(SysusersEntry::Group {
+                name: group.clone(),
+                id: None,
+            });
+        }
+    }
+    entries.extend(implied);
+
+    for e in entries.iter() {
+        let (name, id) = match e {
+            SysusersEntry::Group { name, id } => (name, id.as_ref()),
+            SysusersEntry::User {
+                name,
+                uid,
+                implicit_group: true,
+                ..
+            } => (name, uid.as_ref()),
+            _ => continue,
+        };
+        if db.gid_of(name).is_some() {
+            continue;
+        }
+        let requested = match id {
+            Some(IdSource::Numeric(n)) => Some(*n),
+            Some(IdSource::Path(p)) => path_owner(rootfs, p)?.map(|(_, gid)| gid),
+            None => None,
+        };
+        let gid = match requested.filter(|&id| !db.gid_used(id)) {
+            Some(gid) => gid,
+            // Prefer an ID which is also free as a uid, so that a user of the
+            // same name can use it too.
+            None => allocate_id(&ranges, |id| !db.gid_used(id) && !db.uid_used(id))
+                .or_else(|| allocate_id(&ranges, |id| !db.gid_used(id)))
+                .ok_or_else(|| Error::IdAllocationFailure(name.clone()))?,
+        };
+        db.group.push(nameservice::group::GroupEntry {
+            name: name.clone(),
+            passwd: "x".into(),
+            gid,
+            users: Vec::new(),
+        });
+        db.gshadow.push(nameservice::gshadow::GshadowEntry {
+            name: name.clone(),
+            passwd: "!*".into(),
+            admins: Vec::new(),
+            members: Vec::new(),
+        });
+        result.created_groups.push((name.clone(), gid));
+    }
+
+    for e in entries.iter() {
+        let SysusersEntry::User {
+            name,
+            uid,
+            pgid,
+            implicit_group,
+            locked,
+            gecos,
+            home,
+            shell,
+        } = e
+        else {
+            continue;
+        };
+        if db.has_user(name) {
+            continue;
+        }
+        let gid = if *implicit_group {
+            db.gid_of(name)
+        } else {
+            match pgid {
+                Some(GroupReference::Numeric(n)) => Some(*n).filter(|&n| db.gid_used(n)),
+                Some(GroupReference::Name(g)) => db.gid_of(g),
+                Some(GroupReference::Path(p)) => path_owner(rootfs, p)?.map(|(_, gid)| gid),
+                None => None,
+            }
+        };
+        let gid = gid.ok_or_else(|| Error::UnknownGroup {
+            user: name.clone(),
+            group: pgid
+                .as_ref()
+                .map(ToString::to_string)
+                .unwrap_or_else(|| name.clone()),
+        })?;
+        let requested = match uid {
+            Some(IdSource::Numeric(n)) => Some(*n),
+            Some(IdSource::Path(p)) => path_owner(rootfs, p)?.map(|(uid, _)| uid),
+            None => implicit_group.then_some(gid),
+        };
+        let uid = match requested.filter(|&id| !db.uid_used(id)) {
+            Some(uid) => uid,
+            None => allocate_id(&ranges, |id| !db.uid_used(id))
+                .ok_or_else(|| Error::IdAllocationFailure(name.clone()))?,
+        };
+        let shell = credentials
+            .shells
+            .get(name)
+            .or(shell.as_ref())
+            .cloned()
+            .unwrap_or_else(|| {
+                if uid == 0 {
+                    "/bin/sh".into()
+                } else {
+                    "/usr/sbin/nologin".into()
+                }
+            });
+        db.passwd.push(nameservice::passwd::PasswdEntry {
+            name: name.clone(),
+            passwd: "x".into(),
+            uid,
+            gid,
+            gecos: if gecos == "-" { "" } else { gecos.as_str() }.to_owned(),
+            home_dir: home.clone().unwrap_or_else(|| "/".into()),
+            shell,
+        });
+        db.shadow.push(nameservice::shadow::ShadowEntry {
+            namp: name.clone(),
+            pwdp: credentials
+                .hashed_passwords
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| "!*".into()),
+            lstchg: None,
+            min: None,
+            max: None,
+            warn: None,
+            inact: None,
+            // An expiry date of 1 means the account is fully locked
+            expire: locked.then_some(1),
+            flag: String::new(),
+        });
+        result.created_users.push((name.clone(), uid));
+    }
+
+    for e in entries.iter() {
+        let SysusersEntry::Membership { user, group } = e else {
+            continue;
+        };
+        let g = db
+            .group
+            .iter_mut()
+            .find(|g| &g.name == group)
+            .ok_or_else(|| Error::UnknownGroup {
+                user: user.clone(),
+                group: group.clone(),
+            })?;
+        g.users.retain(|u| !u.is_empty());
+        if g.users.contains(user) {
+            continue;
+        }
+        g.users.push(user.clone());
+        if let Some(gs) = db
+            .gshadow
+            .iter_mut()
+            .find(|gs| &gs.name == group)
+            .filter(|gs| !gs.members.contains(user))
+        {
+            gs.members.push(user.clone());
+        }
+        result.added_memberships.push((user.clone(), group.clone()));
+    }
+
+    if !result.is_empty() {
+        db.write(rootfs)?;
+    }
+    Ok(result)
+}
+
+/// Whether an ID refers to a user or a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IdKind {
+    /// A user ID
+    User,
+    /// A group ID
+    Group,
+}
+
+/// A user or group whose ID in /etc differs from the static ID defined for it
+/// in sysusers.d.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IdDrift {
+    /// Whether this is a user or a group
+    pub kind: IdKind,
+    /// The user or group name
+    pub name: String,
+    /// The ID in /etc/passwd or /etc/group
+    pub current: u32,
+    /// The ID defined in sysusers.d
+    pub expected: u32,
+}
+
+/// Compare the static IDs defined in the sysusers.d configuration of
+/// `image_root` with /etc/passwd and /etc/group in `etc_root`, which may be a
+/// different root (such as the currently booted system). Users and groups which
+/// do not exist in `etc_root`, or which use dynamically allocated IDs, are
+/// ignored.
+pub fn detect_drift(image_root: &Dir, etc_root: &Dir) -> Result<Vec<IdDrift>> {
+    let db = Nameservice::load(etc_root)?;
+    let mut result = Vec::new();
+    for e in read_sysusers(image_root)? {
+        let (kind, name, id) = match e {
+            SysusersEntry::User {
+                name,
+                uid: Some(id),
+                ..
+            } => (IdKind::User, name, id),
+            SysusersEntry::Group { name, id: Some(id) } => (IdKind::Group, name, id),
+            _ => continue,
+        };
+        let expected = match id {
+            IdSource::Numeric(n) => n,
+            IdSource::Path(p) => match (path_owner(image_root, &p)?, kind) {
+                (Some((uid, _)), IdKind::User) => uid,
+                (Some((_, gid)), IdKind::Group) => gid,
+                (None, _) => continue,
+            },
+        };
+        let current = match kind {
+            IdKind::User => db.passwd.iter().find(|u| u.name == name).map(|u| u.uid),
+            IdKind::Group => db.gid_of(&name),
+        };
+        if let Some(current) = current.filter(|&c| c != expected) {
+            result.push(IdDrift {
+                kind,
+                name,
+                current,
+                expected,
+            });
+        }
+    }
+    Ok(result)
+}
+
+/// Build the (current => expected) mapping of IDs of the given kind.
+fn drift_map(drifts: &[IdDrift], kind: IdKind) -> BTreeMap<u32, u32> {
+    drifts
+        .iter()
+        .filter(|d| d.kind == kind)
+        .map(|d| (d.current, d.expected))
+        .collect()
+}
+
+/// Verify that no ID remapped from `ids` collides with the ID (possibly also
+/// remapped) of another entry.
+fn check_collisions<'a>(
+    entries: impl Iterator<Item = (&'a str, u32)> + Clone,
+    ids: &BTreeMap<u32, u32>,
+) -> Result<()> {
+    let remapped = entries
+        .clone()
+        .filter_map(|(name, id)| ids.get(&id).map(|&new| (new, name)))
+        .collect::<BTreeMap<_, _>>();
+    for (other, id) in entries {
+        let id = ids.get(&id).copied().unwrap_or(id);
+        if let Some(&name) = remapped.get(&id).filter(|&&name| name != other) {
+            return Err(Error::IdCollision {
+                name: name.to_owned(),
+                id,
+                other: other.to_owned(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Verify that remapping the IDs in /etc/passwd and /etc/group of `etc_root`
+/// doesn't make a user or group share its new ID with another one.
+pub fn check_remap(etc_root: &Dir, drifts: &[IdDrift]) -> Result<()> {
+    let db = Nameservice::load(etc_root)?;
+    check_collisions(
+        db.passwd.iter().map(|u| (u.name.as_str(), u.uid)),
+        &drift_map(drifts, IdKind::User),
+    )?;
+    check_collisions(
+        db.group.iter().map(|g| (g.name.as_str(), g.gid)),
+        &drift_map(drifts, IdKind::Group),
+    )
+}
+
+/// Rewrite /etc/passwd and /etc/group in `etc_root` to use the expected IDs,
+/// including for the primary group of users. Fails without changing anything
+/// if a new ID is already in use; see [`check_remap`].
+pub fn remap_nameservice(etc_root: &Dir, drifts: &[IdDrift]) -> Result<()> {
+    if drifts.is_empty() {
+        return Ok(());
+    }
+    check_remap(etc_root, drifts)?;
+    let uids = drift_map(drifts, IdKind::User);
+    let gids = drift_map(drifts, IdKind::Group);
+    let mut db = Nameservice::load(etc_root)?;
+    for u in db.passwd.iter_mut() {
+        if let Some(&uid) = uids.get(&u.uid) {
+            u.uid = uid;
+        }
+        if let Some(&gid) = gids.get(&u.gid) {
+            u.gid = gid;
+        }
+    }
+    for g in db.group.iter_mut() {
+        if let Some(&gid) = gids.get(&g.gid) {
+            g.gid = gid;
+        }
+    }
+    db.write(etc_root)
+}
+
+/// Change the ownership of all files below `dir` from the current to the
+/// expected IDs, without crossing mount points. Setuid and setgid bits, which
+/// are cleared by the kernel on ownership changes, are preserved. Returns the
+/// number of changed files.
+pub fn remap_ownership(dir: &Dir, drifts: &[IdDrift]) -> Result<u64> {
+    fn remap_recurse(
+        dir: &Dir,
+        dev: u64,
+        uids: &BTreeMap<u32, u32>,
+        gids: &BTreeMap<u32, u32>,
+        changed: &mut u64,
+    ) -> Result<()> {
+        for ent in dir.entries()? {
+            let name = ent?.file_name();
+            let meta = dir.symlink_metadata(&name)?;
+            if meta.dev() != dev {
+                continue;
+            }
+            let uid = uids.get(&meta.uid()).copied();
+            let gid = gids.get(&meta.gid()).copied();
+            if uid.is_some() || gid.is_some() {
+                rustix::fs::chownat(
+                    dir,
+                    name.as_os_str(),
+                    uid.map(Uid::from_raw),
+                    gid.map(Gid::from_raw),
+                    AtFlags::SYMLINK_NOFOLLOW,
+                )
+                .map_err(|e| Error::PathIo {
+                    path: name.clone().into(),
+                    err: e.into(),
+                })?;
+                if !meta.is_symlink() && meta.mode() & 0o6000 != 0 {
+                    dir.set_permissions(&name, meta.permissions())?;
+                }
+                *changed += 1;
+            }
+            if meta.is_dir() {
+                let d = dir.open_dir(&name)?;
+                remap_recurse(&d, dev, uids, gids, changed)?;
+            }
+        }
+        Ok(())
+    }
+
+    let uids = drift_map(drifts, IdKind::User);
+    let gids = drift_map(drifts, IdKind::Group);
+    let mut changed = 0;
+    if uids.is_empty() && gids.is_empty() {
+        return Ok(changed);
+    }
+    let dev = dir.dir_metadata()?.dev();
+    remap_recurse(dir, dev, &uids, &gids, &mut changed)?;
+    Ok(changed)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -438,7 +1287,7 @@ mod tests {
         g justgroupname
     "#};
 
-    const OTHER_SYSUSERS_UNHANDLED: &str = indoc! { r#"
+    const OTHER_SYSUSERS_MISC: &str = indoc! { r#"
         m     user_name  group_name
         r     -          42-43
     "#};
@@ -458,6 +1307,8 @@ mod tests {
                 name: "root".into(),
                 uid: Some(0.into()),
                 pgid: Some(0.into()),
+                implicit_group: true,
+                locked: false,
                 gecos: "Super User".into(),
                 home: Some("/root".into()),
                 shell: Some("/bin/bash".into())
@@ -469,6 +1320,8 @@ mod tests {
                 name: "root".into(),
                 uid: Some(0.into()),
                 pgid: Some(0.into()),
+                implicit_group: true,
+                locked: false,
                 gecos: "Super User".into(),
                 home: Some("/root".into()),
                 shell: None
@@ -480,6 +1333,8 @@ mod tests {
                 name: "bin".into(),
                 uid: Some(1.into()),
                 pgid: Some(1.into()),
+                implicit_group: false,
+                locked: false,
                 gecos: "bin".into(),
                 home: Some("/bin".into()),
                 shell: None
@@ -492,6 +1347,8 @@ mod tests {
                 name: "adm".into(),
                 uid: Some(3.into()),
                 pgid: Some(4.into()),
+                implicit_group: false,
+                locked: false,
                 gecos: "adm".into(),
                 home: Some("/var/adm".into()),
                 shell: None
@@ -506,6 +1363,8 @@ mod tests {
                 name: "qemu".into(),
                 uid: Some(107.into()),
                 pgid: Some(GroupReference::Name("qemu".into())),
+                implicit_group: false,
+                locked: false,
                 gecos: "qemu user".into(),
                 home: None,
                 shell: None
@@ -517,6 +1376,8 @@ mod tests {
                 name: "vboxadd".into(),
                 uid: None,
                 pgid: Some(1.into()),
+                implicit_group: false,
+                locked: false,
                 gecos: "-".into(),
                 home: Some("/var/run/vboxadd".into()),
                 shell: None
@@ -531,6 +1392,8 @@ mod tests {
                 name: "user_name".into(),
                 uid: Some(IdSource::Path("/file/owned/by/user".into())),
                 pgid: Some(GroupReference::Path("/file/owned/by/user".into())),
+                implicit_group: true,
+                locked: false,
                 gecos: "User Description".into(),
                 home: Some("/home/dir".into()),
                 shell: Some("/path/to/shell".into())
@@ -549,6 +1412,8 @@ mod tests {
                 name: "otheruser".into(),
                 uid: None,
                 pgid: None,
+                implicit_group: true,
+                locked: false,
                 gecos: "".into(),
                 home: None,
                 shell: None
@@ -560,6 +1425,8 @@ mod tests {
                 name: "justusername".into(),
                 uid: None,
                 pgid: None,
+                implicit_group: true,
+                locked: false,
                 gecos: "".into(),
                 home: None,
                 shell: None
@@ -574,7 +1441,7 @@ mod tests {
         );
         assert_eq!(entries.count(), 0);
 
-        let n = OTHER_SYSUSERS_UNHANDLED
+        let n = OTHER_SYSUSERS_MISC
             .lines()
             .filter(|line| !(line.is_empty() || line.starts_with('#')))
             .try_fold(Vec::new(), |mut acc, line| {
@@ -583,8 +1450,15 @@ mod tests {
                 }
                 anyhow::Ok(acc)
             })?;
-        assert_eq!(n.len(), 1);
-        assert_eq!(n[0], SysusersEntry::Range { start: 42, end: 43 });
+        assert_eq!(n.len(), 2);
+        assert_eq!(
+            n[0],
+            SysusersEntry::Membership {
+                user: "user_name".into(),
+                group: "group_name".into()
+            }
+        );
+        assert_eq!(n[1], SysusersEntry::Range { start: 42, end: 43 });
 
         Ok(())
     }
@@ -678,4 +1552,323 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_generate_missing() -> Result<()> {
+        let root = &newroot()?;
+
+        // Nothing to do
+        let r = generate_missing(root)?;
+        assert!(r.generated.is_none());
+
+        root.write(
+            "etc/passwd",
+            indoc! { r#"
+            root:x:0:0:Super User:/root:/bin/bash
+            passim:x:982:982:Local Caching Server:/usr/share/empty:/usr/bin/nologin
+            builder:x:1500:4::/var/lib/builder:
+        "#},
+        )?;
+        root.write(
+            "etc/group",
+            indoc! { r#"
+            root:x:0:
+            adm:x:4:
+            passim:x:982:
+            printadmin:x:983:builder,passim
+        "#},
+        )?;
+
+        let r = generate_missing(root)?;
+        let (n, path) = r.generated.unwrap();
+        assert_eq!(n.get(), 5);
+        assert_eq!(path, "usr/lib/sysusers.d/bootc-autogenerated-users-0.conf");
+        similar_asserts::assert_eq!(
+            root.read_to_string(&path)?,
+            indoc! { r#"
+                g printadmin 983
+                u passim 982 "Local Caching Server" /usr/share/empty /usr/bin/nologin
+                u builder 1500:4 - /var/lib/builder -
+                m builder printadmin
+                m passim printadmin
+            "#}
+        );
+        // The generated entries must parse, and cover everything
+        assert!(analyze(root)?.is_empty());
+        assert!(generate_missing(root)?.generated.is_none());
+
+        // A new user results in a new file
+        root.write(
+            "etc/passwd",
+            "root:x:0:0:Super User:/root:/bin/bash\nother:x:1501:1501::/:/sbin/nologin\n",
+        )?;
+        root.write("etc/group", "root:x:0:\nother:x:1501:\n")?;
+        let (n, path) = generate_missing(root)?.generated.unwrap();
+        assert_eq!(n.get(), 1);
+        assert_eq!(path, "usr/lib/sysusers.d/bootc-autogenerated-users-1.conf");
+        assert!(analyze(root)?.is_empty());
+
+        // Quotes, backslashes and specifiers in the GECOS field are escaped
+        let gecos = r#"Say "hi" \o/ 100%"#;
+        let passwd = format!("quoted:x:1502:1502:{gecos}:/:/sbin/nologin\n");
+        root.write(
+            "etc/passwd",
+            format!("root:x:0:0:Super User:/root:/bin/bash\n{passwd}"),
+        )?;
+        root.write("etc/group", "root:x:0:\nquoted:x:1502:\n")?;
+        let (_, path) = generate_missing(root)?.generated.unwrap();
+        assert_eq!(
+            root.read_to_string(&path)?,
+            "u quoted 1502 \"Say \\\"hi\\\" \\\\o/ 100%%\" / /sbin/nologin\n"
+        );
+        let entry = SysusersEntry::parse(root.read_to_string(&path)?.trim_end())?.unwrap();
+        let SysusersEntry::User { gecos: parsed, .. } = entry else {
+            panic!("Expected a user entry");
+        };
+        assert_eq!(parsed, gecos);
+        assert!(analyze(root)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_userdb() -> Result<()> {
+        use uzers::{Groups, Users};
+
+        let root = &newroot()?;
+        root.write("etc/passwd", "builder:x:1500:4::/var/lib/builder:\n")?;
+        root.write("etc/group", "adm:x:4:\n")?;
+        let db = load_userdb(root)?;
+        let user = db.get_user_by_uid(1500).unwrap();
+        assert_eq!(user.name(), "builder");
+        assert_eq!(user.primary_group_id(), 4);
+        assert_eq!(db.get_group_by_gid(4).unwrap().name(), "adm");
+        assert!(db.get_user_by_uid(0).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let root = &cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        root.create_dir("etc")?;
+        root.write("etc/passwd", "root:x:0:0:Super User:/root:/bin/bash\n")?;
+        root.write("etc/group", "root:x:0:\nwheel:x:10:\n")?;
+        root.create_dir_all(SYSUSERSD)?;
+        root.write(
+            Utf8Path::new(SYSUSERSD).join("base.conf"),
+            indoc! { r#"
+                u root 0 "Super User" /root /bin/bash
+                g wheel 10
+                u builder 1500 "Builder" /var/lib/builder
+                u! locked -
+                u web -:wheel - /srv
+                g static 1500
+                m builder wheel
+                m builder extra
+            "#},
+        )?;
+        // A masked file in /etc
+        root.write(
+            Utf8Path::new(SYSUSERSD).join("masked.conf"),
+            "u masked 900\n",
+        )?;
+        root.create_dir_all(ETC_SYSUSERSD)?;
+        root.symlink_contents(
+            "/dev/null",
+            Utf8Path::new(ETC_SYSUSERSD).join("masked.conf"),
+        )?;
+
+        let creds = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        creds.write("passwd.hashed-password.builder", "$6$foo$bar\n")?;
+        creds.write("passwd.shell.builder", "/bin/bash")?;
+        creds.write("unrelated", "ignored")?;
+        let creds = SysusersCredentials::load(&creds)?;
+
+        let r = apply(root, &creds)?;
+        assert_eq!(
+            r.created_groups,
+            [
+                ("builder".to_owned(), 1500),
+                ("locked".to_owned(), 999),
+                ("static".to_owned(), 998),
+                ("extra".to_owned(), 997)
+            ]
+        );
+        assert_eq!(
+            r.created_users,
+            [
+                ("builder".to_owned(), 1500),
+                ("locked".to_owned(), 999),
+                ("web".to_owned(), 998)
+            ]
+        );
+        assert_eq!(r.added_memberships.len(), 2);
+
+        similar_asserts::assert_eq!(
+            root.read_to_string("etc/passwd")?,
+            indoc! { r#"
+                root:x:0:0:Super User:/root:/bin/bash
+                builder:x:1500:1500:Builder:/var/lib/builder:/bin/bash
+                locked:x:999:999::/:/usr/sbin/nologin
+                web:x:998:10::/srv:/usr/sbin/nologin
+            "#}
+        );
+        similar_asserts::assert_eq!(
+            root.read_to_string("etc/group")?,
+            indoc! { r#"
+                root:x:0:
+                wheel:x:10:builder
+                builder:x:1500:
+                locked:x:999:
+                static:x:998:
+                extra:x:997:builder
+            "#}
+        );
+        similar_asserts::assert_eq!(
+            root.read_to_string("etc/shadow")?,
+            indoc! { r#"
+                builder:$6$foo$bar:::::::
+                locked:!*::::::1:
+                web:!*:::::::
+            "#}
+        );
+        similar_asserts::assert_eq!(
+            root.read_to_string("etc/gshadow")?,
+            indoc! { r#"
+                builder:!*::
+                locked:!*::
+                static:!*::
+                extra:!*::builder
+            "#}
+        );
+        assert_eq!(
+            root.symlink_metadata("etc/shadow")?.permissions().mode() & 0o777,
+            0
+        );
+
+        // Applying again is a no-op
+        assert!(apply(root, &creds)?.is_empty());
+
+        // An explicit primary group must exist
+        root.write(
+            Utf8Path::new(ETC_SYSUSERSD).join("other.conf"),
+            "u other 1600:nosuchgroup\n",
+        )?;
+        assert!(matches!(
+            apply(root, &creds),
+            Err(Error::UnknownGroup { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_drift() -> Result<()> {
+        let image = &newroot()?;
+        image.write(
+            Utf8Path::new(SYSUSERSD).join("extra.conf"),
+            indoc! { r#"
+                u builder 1500 "Builder"
+                u dynamic -
+                g extra 1600
+            "#},
+        )?;
+        let etc_root = &newroot()?;
+        etc_root.write(
+            "etc/passwd",
+            indoc! { r#"
+                root:x:0:0:Super User:/root:/bin/bash
+                builder:x:1400:1400:Builder:/:/usr/sbin/nologin
+                dynamic:x:900:900::/:/usr/sbin/nologin
+            "#},
+        )?;
+        etc_root.write(
+            "etc/group",
+            "root:x:0:\nbuilder:x:1400:\ndynamic:x:900:\nextra:x:1600:\n",
+        )?;
+
+        let drifts = detect_drift(image, etc_root)?;
+        assert_eq!(
+            drifts,
+            [
+                IdDrift {
+                    kind: IdKind::Group,
+                    name: "builder".into(),
+                    current: 1400,
+                    expected: 1500
+                },
+                IdDrift {
+                    kind: IdKind::User,
+                    name: "builder".into(),
+                    current: 1400,
+                    expected: 1500
+                },
+            ]
+        );
+
+        remap_nameservice(etc_root, &drifts)?;
+        assert!(
+            etc_root
+                .read_to_string("etc/passwd")?
+                .contains("builder:x:1500:1500:Builder:/:/usr/sbin/nologin\n")
+        );
+        assert!(
+            etc_root
+                .read_to_string("etc/group")?
+                .contains("builder:x:1500:\n")
+        );
+        assert!(!etc_root.try_exists("etc/shadow")?);
+        assert!(detect_drift(image, etc_root)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remap_collision() -> Result<()> {
+        let etc_root = &newroot()?;
+        let passwd = indoc! { r#"
+            root:x:0:0:Super User:/root:/bin/bash
+            builder:x:1400:1400:Builder:/:/usr/sbin/nologin
+            other:x:1500:1500::/:/usr/sbin/nologin
+            swapped:x:1600:1600::/:/usr/sbin/nologin
+        "#};
+        let group = "root:x:0:\nbuilder:x:1400:\nother:x:1500:\nswapped:x:1600:\n";
+        etc_root.write("etc/passwd", passwd)?;
+        etc_root.write("etc/group", group)?;
+
+        let drift = |kind, name: &str, current, expected| IdDrift {
+            kind,
+            name: name.into(),
+            current,
+            expected,
+        };
+        // The new ID of builder is used by another user
+        let drifts = [drift(IdKind::User, "builder", 1400, 1500)];
+        assert!(matches!(
+            remap_nameservice(etc_root, &drifts),
+            Err(Error::IdCollision { id: 1500, .. })
+        ));
+        // Nothing was changed
+        assert_eq!(etc_root.read_to_string("etc/passwd")?, passwd);
+        assert_eq!(etc_root.read_to_string("etc/group")?, group);
+
+        // Same for groups
+        let drifts = [drift(IdKind::Group, "swapped", 1600, 1400)];
+        assert!(matches!(
+            check_remap(etc_root, &drifts),
+            Err(Error::IdCollision { id: 1400, .. })
+        ));
+
+        // Exchanging IDs is fine
+        let drifts = [
+            drift(IdKind::User, "builder", 1400, 1500),
+            drift(IdKind::User, "other", 1500, 1400),
+        ];
+        remap_nameservice(etc_root, &drifts)?;
+        let passwd = etc_root.read_to_string("etc/passwd")?;
+        assert!(passwd.contains("builder:x:1500:1400:"));
+        assert!(passwd.contains("other:x:1400:1500:"));
+
+        Ok(())
+    }
 }
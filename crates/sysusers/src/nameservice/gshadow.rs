@@ -0,0 +1,121 @@
+//! Helpers for [shadowed group file](https://man7.org/linux/man-pages/man5/gshadow.5.html).
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use anyhow::{Context, Result, anyhow};
+use cap_std_ext::{cap_std::fs::Dir, dirext::CapStdExtDirExt};
+use std::io::{BufRead, BufReader, Write};
+
+// Entry from gshadow file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct GshadowEntry {
+    pub(crate) name: String,
+    pub(crate) passwd: String,
+    pub(crate) admins: Vec<String>,
+    pub(crate) members: Vec<String>,
+}
+
+fn parse_list(s: &str) -> Vec<String> {
+    s.split(',')
+        .filter(|v| !v.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+impl GshadowEntry {
+    /// Parse a single gshadow entry.
+    pub fn parse_line(s: impl AsRef<str>) -> Option<Self> {
+        let mut parts = s.as_ref().splitn(4, ':');
+        let entry = Self {
+            name: parts.next()?.to_string(),
+            passwd: parts.next()?.to_string(),
+            admins: parse_list(parts.next()?),
+            members: parse_list(parts.next()?),
+        };
+        Some(entry)
+    }
+
+    /// Serialize entry to writer, as a gshadow line.
+    pub fn to_writer(&self, writer: &mut impl Write) -> Result<()> {
+        std::writeln!(
+            writer,
+            "{}:{}:{}:{}",
+            self.name,
+            self.passwd,
+            self.admins.join(","),
+            self.members.join(","),
+        )
+        .with_context(|| "failed to write gshadow entry")
+    }
+}
+
+pub(crate) fn parse_gshadow_content(content: impl BufRead) -> Result<Vec<GshadowEntry>> {
+    let mut entries = vec![];
+    for (line_num, line) in content.lines().enumerate() {
+        let input =
+            line.with_context(|| format!("failed to read gshadow entry at line {line_num}"))?;
+
+        // Skip empty and comment lines
+        if input.is_empty() || input.starts_with('#') {
+            continue;
+        }
+
+        let entry = GshadowEntry::parse_line(&input).ok_or_else(|| {
+            anyhow!(
+                "failed to parse gshadow entry at line {}, content: {}",
+                line_num,
+                &input
+            )
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub(crate) fn load_etc_gshadow(rootfs: &Dir) -> Result<Option<Vec<GshadowEntry>>> {
+    if let Some(r) = rootfs.open_optional("etc/gshadow")? {
+        parse_gshadow_content(BufReader::new(r)).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn mock_gshadow_entry() -> GshadowEntry {
+        GshadowEntry {
+            name: "staff".to_string(),
+            passwd: "!*".to_string(),
+            admins: vec![],
+            members: vec!["operator".to_string(), "someuser".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let content = r#"
+root:::
+wheel:::someuser
+
+# Dummy comment
+staff:!*::operator,someuser
+"#;
+
+        let input = Cursor::new(content);
+        let entries = parse_gshadow_content(input).unwrap();
+        assert_eq!(entries.len(), 3);
+        assert!(entries[0].members.is_empty());
+        assert_eq!(entries[2], mock_gshadow_entry());
+    }
+
+    #[test]
+    fn test_write_entry() {
+        let entry = mock_gshadow_entry();
+        let expected = b"staff:!*::operator,someuser\n";
+        let mut buf = Vec::new();
+        entry.to_writer(&mut buf).unwrap();
+        assert_eq!(&buf, expected);
+    }
+}
@@ -0,0 +1,1021 @@
+//! Apply tmpfiles.d entries against a target root, without a running system.
+//!
+//! This implements the subset of `systemd-tmpfiles --create --remove` which
+//! makes sense for an offline root, such as at install time. The contents of
+//! `D` directories are removed, except for paths excluded by `x` and `X`
+//! entries (globs are not supported there). Cleanup by age is never performed,
+//! and entries which need the running kernel (extended attributes, file
+//! attributes, ACLs), credentials, specifiers such as `%h` or unknown users
+//! and groups are reported as skipped.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use cap_std::fs::{Dir, MetadataExt, OpenOptions, Permissions, PermissionsExt};
+use cap_std_ext::cap_std;
+use cap_std_ext::dirext::CapStdExtDirExt;
+use rustix::fs::{AtFlags, FileType, Gid, Mode, Uid};
+
+use crate::entry::{LineType, TmpfilesEntry};
+use crate::{ETC_TMPFILESD, Error, Result, TMPFILESD};
+
+/// The default source for `L` and `C` lines without an argument.
+const FACTORY_DIR: &str = "usr/share/factory";
+
+/// Options for [`apply`].
+#[derive(Debug, Default)]
+pub struct ApplyOptions {
+    /// Also apply entries only meant for boot (with the `!` modifier)
+    pub boot: bool,
+    /// Where the target root is mounted in the system, e.g. `/var` to
+    /// initialize a separate `/var`. Only entries below it are applied.
+    pub prefix: Option<PathBuf>,
+}
+
+/// An entry that was not applied.
+#[derive(Debug)]
+pub struct SkippedEntry {
+    /// The entry
+    pub entry: TmpfilesEntry,
+    /// Why it was skipped
+    pub reason: String,
+}
+
+/// The result of [`apply`].
+#[derive(Debug, Default)]
+pub struct ApplyResult {
+    /// The number of entries which were processed
+    pub applied: u64,
+    /// Entries which were not applied
+    pub skipped: Vec<SkippedEntry>,
+}
+
+/// A tmpfiles.d line which could not be parsed.
+#[derive(Debug)]
+pub struct InvalidLine {
+    /// The file, relative to the root
+    pub file: PathBuf,
+    /// The line number, starting at 1
+    pub line: usize,
+    /// Why it could not be parsed
+    pub reason: String,
+}
+
+/// The result of [`read_all_tmpfiles`].
+#[derive(Debug, Default)]
+pub struct ReadResult {
+    /// The entries, in order
+    pub entries: Vec<TmpfilesEntry>,
+    /// Lines which were ignored
+    pub invalid: Vec<InvalidLine>,
+}
+
+/// Read all tmpfiles.d files in the target root, in the order systemd-tmpfiles
+/// processes them.
+///
+/// Files in `/etc/tmpfiles.d` override files of the same name in
+/// `/usr/lib/tmpfiles.d`, and a symlink to `/dev/null` masks a file entirely.
+/// Like systemd-tmpfiles, lines which cannot be parsed are ignored, e.g.
+/// ones using a line type or unit this implementation doesn't know about.
+pub fn read_all_tmpfiles(rootfs: &Dir) -> Result<ReadResult> {
+    let mut files = std::collections::BTreeMap::new();
+    for dir_path in [TMPFILESD, ETC_TMPFILESD] {
+        let Some(d) = rootfs.open_dir_optional(dir_path)? else {
+            continue;
+        };
+        for entry in d.entries()? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if Path::new(&name).extension().is_none_or(|ext| ext != "conf") {
+                continue;
+            }
+            files.insert(name, Path::new(dir_path).join(entry.file_name()));
+        }
+    }
+    let mut r = ReadResult::default();
+    for path in files.into_values() {
+        if rootfs.symlink_metadata(&path)?.is_symlink()
+            && rootfs.read_link_contents(&path)? == Path::new("/dev/null")
+        {
+            continue;
+        }
+        let f = BufReader::new(rootfs.open(&path)?);
+        for (i, line) in f.lines().enumerate() {
+            match TmpfilesEntry::parse(&line?) {
+                Ok(Some(entry)) => r.entries.push(entry),
+                Ok(None) => {}
+                Err(e) => r.invalid.push(InvalidLine {
+                    file: path.clone(),
+                    line: i + 1,
+                    reason: e.to_string(),
+                }),
+            }
+        }
+    }
+    Ok(r)
+}
+
+/// The order in which entries for the same path are applied.
+fn phase(ty: LineType) -> u8 {
+    match ty {
+        LineType::RemovePath | LineType::RecursiveRemovePath => 0,
+        ty if ty.creates() => 1,
+        LineType::WriteFile => 2,
+        _ => 3,
+    }
+}
+
+/// Resolved ownership for an entry.
+#[derive(Debug, Clone, Copy, Default)]
+struct Owner {
+    uid: Option<u32>,
+    gid: Option<u32>,
+    uid_create_only: bool,
+    gid_create_only: bool,
+}
+
+impl Owner {
+    fn resolve<U: uzers::Users, G: uzers::Groups>(
+        entry: &TmpfilesEntry,
+        users: &U,
+        groups: &G,
+    ) -> Result<Self> {
+        let mut r = Self::default();
+        if let Some(user) = entry.user.as_ref() {
+            let uid = match user.name.parse::<u32>() {
+                Ok(uid) => uid,
+                Err(_) => users
+                    .get_user_by_name(&user.name)
+                    .ok_or_else(|| Error::UserNameNotFound(user.name.clone()))?
+                    .uid(),
+            };
+            r.uid = Some(uid);
+            r.uid_create_only = user.create_only;
+        }
+        if let Some(group) = entry.group.as_ref() {
+            let gid = match group.name.parse::<u32>() {
+                Ok(gid) => gid,
+                Err(_) => groups
+                    .get_group_by_name(&group.name)
+                    .ok_or_else(|| Error::GroupNameNotFound(group.name.clone()))?
+                    .gid(),
+            };
+            r.gid = Some(gid);
+            r.gid_create_only = group.create_only;
+        }
+        Ok(r)
+    }
+}
+
+/// Apply the given tmpfiles.d entries to the target root.
+///
+/// Entries are applied ordered by path, so that parent directories are
+/// created first; for a given path, removals happen before creation, and
+/// creation before adjustments. Only the first entry of each kind is used
+/// for a path, matching systemd-tmpfiles.
+pub fn apply<U: uzers::Users, G: uzers::Groups>(
+    rootfs: &Dir,
+    entries: &[TmpfilesEntry],
+    users: &U,
+    groups: &G,
+    opts: &ApplyOptions,
+) -> Result<ApplyResult> {
+    let mut result = ApplyResult::default();
+    let prefix = opts.prefix.as_deref().unwrap_or(Path::new("/"));
+    // Paths excluded from removal, relative to the root
+    let ignored = entries
+        .iter()
+        .filter(|e| {
+            matches!(
+                e.line_type,
+                LineType::IgnorePath | LineType::IgnoreDirectoryPath
+            )
+        })
+        .filter_map(|e| Some((e.path.strip_prefix(prefix).ok()?.to_owned(), e.line_type)))
+        .collect::<BTreeMap<_, _>>();
+    let mut entries = entries.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| {
+        a.path
+            .cmp(&b.path)
+            .then_with(|| phase(a.line_type).cmp(&phase(b.line_type)))
+    });
+    let mut prev: Option<&TmpfilesEntry> = None;
+    for entry in entries {
+        let Ok(relpath) = entry.path.strip_prefix(prefix) else {
+            continue;
+        };
+        let relpath = if relpath.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            relpath
+        };
+        let skip = |reason: &str| SkippedEntry {
+            entry: entry.clone(),
+            reason: reason.to_owned(),
+        };
+        let duplicate = prev
+            .is_some_and(|p| p.path == entry.path && phase(p.line_type) == phase(entry.line_type));
+        prev = Some(entry);
+        if duplicate {
+            result.skipped.push(skip("duplicate entry for path"));
+            continue;
+        }
+        if entry.boot_only && !opts.boot {
+            result.skipped.push(skip("only applied at boot"));
+            continue;
+        }
+        if entry.credential {
+            result.skipped.push(skip("credentials are not available"));
+            continue;
+        }
+        if entry.has_specifiers() {
+            result.skipped.push(skip("specifiers are not supported"));
+            continue;
+        }
+        match entry.line_type {
+            LineType::SetXattr
+            | LineType::RecursiveSetXattr
+            | LineType::SetAttribute
+            | LineType::RecursiveSetAttribute
+            | LineType::SetAcl
+            | LineType::RecursiveSetAcl => {
+                result.skipped.push(skip("not supported offline"));
+                continue;
+            }
+            // These only affect cleanup, which is not performed offline.
+            LineType::IgnorePath | LineType::IgnoreDirectoryPath => continue,
+            _ => {}
+        }
+
+        let owner = match Owner::resolve(entry, users, groups) {
+            Ok(owner) => owner,
+            Err(e) => {
+                result.skipped.push(skip(&e.to_string()));
+                continue;
+            }
+        };
+        let content = entry.content()?;
+        // The source of a copy, relative to the root
+        let source = if entry.line_type == LineType::Copy {
+            let source = match entry.argument.as_deref() {
+                Some(source) => PathBuf::from(source),
+                None => Path::new("/").join(FACTORY_DIR).join(relative(&entry.path)),
+            };
+            let Ok(source) = source.strip_prefix(prefix) else {
+                result
+                    .skipped
+                    .push(skip("copy source is outside of the root"));
+                continue;
+            };
+            Some(source.to_owned())
+        } else {
+            None
+        };
+        let paths = if entry.line_type.supports_globs() {
+            expand_glob(rootfs, relpath).map_err(|err| Error::PathIo {
+                path: entry.path.clone(),
+                err,
+            })?
+        } else {
+            vec![relpath.to_owned()]
+        };
+        for path in paths {
+            let target = Target {
+                owner,
+                content: content.as_deref(),
+                source: source.as_deref(),
+                ignored: &ignored,
+            };
+            if let Err(err) = apply_one(rootfs, entry, &path, target) {
+                if entry.ignore_errors {
+                    result.skipped.push(skip(&err.to_string()));
+                    continue;
+                }
+                return Err(Error::PathIo {
+                    path: entry.path.clone(),
+                    err,
+                });
+            }
+        }
+        result.applied += 1;
+    }
+    Ok(result)
+}
+
+/// Strip the leading `/` of an absolute path.
+fn relative(path: &Path) -> &Path {
+    path.strip_prefix("/").unwrap_or(path)
+}
+
+/// What is needed to apply an entry, besides the entry itself.
+#[derive(Debug, Clone, Copy)]
+struct Target<'a> {
+    /// The resolved ownership
+    owner: Owner,
+    /// The processed argument of `f` and `w` lines
+    content: Option<&'a [u8]>,
+    /// The source of `C` lines, relative to the root
+    source: Option<&'a Path>,
+    /// Paths excluded by `x` and `X`, relative to the root
+    ignored: &'a BTreeMap<PathBuf, LineType>,
+}
+
+/// Apply a single entry to a concrete path (relative to the root).
+fn apply_one(
+    rootfs: &Dir,
+    entry: &TmpfilesEntry,
+    path: &Path,
+    target: Target<'_>,
+) -> std::io::Result<()> {
+    let Target {
+        owner,
+        content,
+        source,
+        ignored,
+    } = target;
+    let existing = rootfs.symlink_metadata_optional(path)?;
+    // With `=`, remove an existing path of the wrong type.
+    let existing = match existing {
+        Some(meta) if entry.force_type && entry.line_type.creates() => {
+            let wrong_type = match entry.line_type {
+                ty if ty.is_directory() => !meta.is_dir(),
+                LineType::CreateSymlink => !meta.is_symlink(),
+                LineType::CreateFile => !meta.is_file(),
+                LineType::Copy => false,
+                _ => meta.is_dir(),
+            };
+            if wrong_type {
+                if meta.is_dir() {
+                    rootfs.remove_dir_all(path)?;
+                } else {
+                    rootfs.remove_file(path)?;
+                }
+                None
+            } else {
+                Some(meta)
+            }
+        }
+        o => o,
+    };
+    let exists = existing.is_some();
+    if entry.line_type.creates() && !exists {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            rootfs.create_dir_all(parent)?;
+        }
+    }
+
+    let created = match entry.line_type {
+        LineType::CreateFile => {
+            if !exists {
+                rootfs.write(path, content.unwrap_or_default())?;
+            } else if entry.plus {
+                let mut f =
+                    rootfs.open_with(path, OpenOptions::new().write(true).truncate(true))?;
+                f.write_all(content.unwrap_or_default())?;
+            }
+            !exists
+        }
+        LineType::WriteFile => {
+            // Writing never creates the path.
+            if let Some(content) = content.filter(|_| exists) {
+                let mut opts = OpenOptions::new();
+                opts.write(true);
+                if entry.plus {
+                    opts.append(true);
+                } else {
+                    opts.truncate(true);
+                }
+                rootfs.open_with(path, &opts)?.write_all(content)?;
+            }
+            false
+        }
+        LineType::TruncateDirectory => {
+            match existing.as_ref() {
+                Some(meta) if !meta.is_dir() => {
+                    return Err(std::io::Error::other("exists and is not a directory"));
+                }
+                Some(_) => remove_contents(rootfs, path, ignored)?,
+                None => rootfs.create_dir(path)?,
+            }
+            !exists
+        }
+        LineType::CreateDirectory
+        | LineType::CreateSubvolume
+        | LineType::CreateSubvolumeInheritQuota
+        | LineType::CreateSubvolumeNewQuota => {
+            match existing.as_ref() {
+                Some(meta) if !meta.is_dir() => {
+                    return Err(std::io::Error::other("exists and is not a directory"));
+                }
+                Some(_) => {}
+                None => rootfs.create_dir(path)?,
+            }
+            !exists
+        }
+        LineType::AdjustDirectory => {
+            if existing.as_ref().is_some_and(|m| !m.is_dir()) {
+                return Err(std::io::Error::other("exists and is not a directory"));
+            }
+            false
+        }
+        LineType::CreateFifo | LineType::CreateCharDevice | LineType::CreateBlockDevice => {
+            if !exists {
+                let (ty, dev) = match entry.line_type {
+                    LineType::CreateFifo => (FileType::Fifo, 0),
+                    LineType::CreateCharDevice => (FileType::CharacterDevice, parse_dev(entry)?),
+                    _ => (FileType::BlockDevice, parse_dev(entry)?),
+                };
+                rustix::fs::mknodat(rootfs, path, ty, Mode::from_raw_mode(0o644), dev)?;
+            }
+            !exists
+        }
+        LineType::CreateSymlink => {
+            let target = match entry.argument.as_deref() {
+                Some(target) => PathBuf::from(target),
+                None => Path::new("/").join(FACTORY_DIR).join(relative(&entry.path)),
+            };
+            if let Some(meta) = existing.as_ref() {
+                let matches =
+                    meta.is_symlink() && rootfs.read_link_contents(path)? == target.as_path();
+                if matches || !entry.plus {
+                    return Ok(());
+                }
+                if meta.is_dir() {
+                    rootfs.remove_dir_all(path)?;
+                } else {
+                    rootfs.remove_file(path)?;
+                }
+            }
+            rootfs.symlink_contents(&target, path)?;
+            // Symlinks have no mode, and are owned by the creator.
+            return Ok(());
+        }
+        LineType::Copy => {
+            let source = source.ok_or_else(|| std::io::Error::other("missing copy source"))?;
+            match existing.as_ref() {
+                None => copy_recursive(rootfs, source, path, false)?,
+                // `C+` merges into an existing directory.
+                Some(meta) if entry.plus && meta.is_dir() => {
+                    copy_recursive(rootfs, source, path, true)?
+                }
+                Some(_) => {}
+            }
+            !exists
+        }
+        LineType::RemovePath => {
+            match existing.as_ref() {
+                Some(meta) if meta.is_dir() => rootfs.remove_dir(path)?,
+                Some(_) => rootfs.remove_file(path)?,
+                None => {}
+            }
+            return Ok(());
+        }
+        LineType::RecursiveRemovePath => {
+            if let Some(meta) = existing.as_ref() {
+                if meta.is_dir() {
+                    rootfs.remove_dir_all(path)?;
+                } else {
+                    rootfs.remove_file(path)?;
+                }
+            }
+            return Ok(());
+        }
+        LineType::RelabelPath | LineType::RecursiveRelabelPath => false,
+        LineType::IgnorePath
+        | LineType::IgnoreDirectoryPath
+        | LineType::SetXattr
+        | LineType::RecursiveSetXattr
+        | LineType::SetAttribute
+        | LineType::RecursiveSetAttribute
+        | LineType::SetAcl
+        | LineType::RecursiveSetAcl => return Ok(()),
+    };
+
+    // Nothing to adjust if the path did not exist and was not created.
+    if !created && !exists {
+        return Ok(());
+    }
+    if entry.line_type == LineType::RecursiveRelabelPath {
+        adjust_recursive(rootfs, entry, path, owner)
+    } else {
+        adjust(rootfs, entry, path, owner, created)
+    }
+}
+
+/// Remove the contents of a directory, as done for `D` with `--remove`. Paths
+/// excluded by `x` are kept along with their contents, and directories
+/// excluded by `X` or containing an excluded path are kept, but emptied.
+fn remove_contents(
+    rootfs: &Dir,
+    path: &Path,
+    ignored: &BTreeMap<PathBuf, LineType>,
+) -> std::io::Result<()> {
+    for child in rootfs.read_dir(path)? {
+        let child = path.join(child?.file_name());
+        if ignored.get(&child) == Some(&LineType::IgnorePath) {
+            continue;
+        }
+        if !rootfs.symlink_metadata(&child)?.is_dir() {
+            rootfs.remove_file(&child)?;
+        } else if ignored.keys().any(|p| p.starts_with(&child)) {
+            remove_contents(rootfs, &child, ignored)?;
+        } else {
+            rootfs.remove_dir_all(&child)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parse the `major:minor` argument of a device node entry.
+fn parse_dev(entry: &TmpfilesEntry) -> std::io::Result<rustix::fs::Dev> {
+    entry
+        .argument
+        .as_deref()
+        .and_then(|a| a.split_once(':'))
+        .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)))
+        .map(|(major, minor)| rustix::fs::makedev(major, minor))
+        .ok_or_else(|| std::io::Error::other("invalid device number"))
+}
+
+/// Mask a mode by the access bits of an existing path, for modes prefixed by `~`.
+fn mask_mode(mode: u32, current: u32, is_dir: bool) -> u32 {
+    let mut mode = mode;
+    for bits in [0o444, 0o222, 0o111] {
+        if current & bits == 0 {
+            mode &= !bits;
+        }
+    }
+    if !is_dir {
+        mode &= 0o777;
+    }
+    mode
+}
+
+/// Set the mode and ownership of a path as specified by the entry.
+fn adjust(
+    rootfs: &Dir,
+    entry: &TmpfilesEntry,
+    path: &Path,
+    owner: Owner,
+    created: bool,
+) -> std::io::Result<()> {
+    let meta = rootfs.symlink_metadata(path)?;
+    if meta.is_symlink() {
+        return Ok(());
+    }
+    // Ownership first, as changing it may clear the setuid and setgid bits.
+    let uid = owner
+        .uid
+        .filter(|&uid| (created || !owner.uid_create_only) && uid != meta.uid());
+    let gid = owner
+        .gid
+        .filter(|&gid| (created || !owner.gid_create_only) && gid != meta.gid());
+    if uid.is_some() || gid.is_some() {
+        rustix::fs::chownat(
+            rootfs,
+            path,
+            uid.map(Uid::from_raw),
+            gid.map(Gid::from_raw),
+            AtFlags::SYMLINK_NOFOLLOW,
+        )?;
+    }
+    let current = meta.mode() & 0o7777;
+    let mode = match entry.mode {
+        Some(m) if created || !m.create_only => {
+            if m.masked && !created {
+                Some(mask_mode(m.mode, current, meta.is_dir()))
+            } else {
+                Some(m.mode)
+            }
+        }
+        Some(_) => None,
+        // Copies keep the mode of their source.
+        None if created && entry.line_type != LineType::Copy => {
+            Some(if meta.is_dir() { 0o755 } else { 0o644 })
+        }
+        None => None,
+    };
+    // Re-apply the mode after a chown, which may have cleared bits.
+    if let Some(mode) = mode.filter(|&m| m != current || uid.is_some() || gid.is_some()) {
+        rootfs.set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Set the mode and ownership of a path and everything below it, without
+/// crossing into other filesystems.
+fn adjust_recursive(
+    rootfs: &Dir,
+    entry: &TmpfilesEntry,
+    path: &Path,
+    owner: Owner,
+) -> std::io::Result<()> {
+    adjust(rootfs, entry, path, owner, false)?;
+    let Some(d) = rootfs.open_dir_noxdev(path)? else {
+        return Ok(());
+    };
+    for child in d.entries()? {
+        let child = child?;
+        let name = child.file_name();
+        if child.file_type()?.is_dir() {
+            adjust_recursive(&d, entry, Path::new(&name), owner)?;
+        } else {
+            adjust(&d, entry, Path::new(&name), owner, false)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file, symlink or directory tree within the root, preserving modes.
+/// If `merge` is set, the destination directory may already exist, and
+/// existing paths underneath it are left alone.
+fn copy_recursive(rootfs: &Dir, src: &Path, dest: &Path, merge: bool) -> std::io::Result<()> {
+    let meta = rootfs.symlink_metadata(src)?;
+    if meta.is_symlink() {
+        let target = rootfs.read_link_contents(src)?;
+        rootfs.symlink_contents(target, dest)?;
+    } else if meta.is_dir() {
+        if !(merge && rootfs.try_exists(dest)?) {
+            rootfs.create_dir(dest)?;
+            rootfs.set_permissions(dest, meta.permissions())?;
+        }
+        for child in rootfs.read_dir(src)? {
+            let name = child?.file_name();
+            let child_dest = dest.join(&name);
+            if merge && rootfs.symlink_metadata_optional(&child_dest)?.is_some() {
+                if rootfs.symlink_metadata(&child_dest)?.is_dir() {
+                    copy_recursive(rootfs, &src.join(&name), &child_dest, true)?;
+                }
+                continue;
+            }
+            copy_recursive(rootfs, &src.join(&name), &child_dest, false)?;
+        }
+    } else if meta.is_file() {
+        rootfs.copy(src, rootfs, dest)?;
+    } else {
+        return Err(std::io::Error::other(format!(
+            "unsupported file type at {}",
+            src.display()
+        )));
+    }
+    Ok(())
+}
+
+/// Match a single path component against a glob pattern supporting `*`, `?`
+/// and bracket expressions.
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some(b'*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some(b'?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(b'['), Some(&c)) => {
+            let Some(end) = pattern.iter().skip(2).position(|&b| b == b']') else {
+                return pattern[0] == c && glob_match(&pattern[1..], &name[1..]);
+            };
+            let class = &pattern[1..end + 2];
+            let (negate, class) = match class.first() {
+                Some(b'!' | b'^') => (true, &class[1..]),
+                _ => (false, class),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < class.len() {
+                if i + 2 < class.len() && class[i + 1] == b'-' {
+                    found |= (class[i]..=class[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= class[i] == c;
+                    i += 1;
+                }
+            }
+            found != negate && glob_match(&pattern[end + 3..], &name[1..])
+        }
+        (Some(&p), Some(&c)) => p == c && glob_match(&pattern[1..], &name[1..]),
+        _ => false,
+    }
+}
+
+/// Expand glob patterns in a path relative to the root, returning only paths
+/// which exist. A path without patterns is returned as is.
+fn expand_glob(rootfs: &Dir, path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let is_glob = |s: &[u8]| s.iter().any(|c| matches!(c, b'*' | b'?' | b'['));
+    if !is_glob(path.as_os_str().as_bytes()) {
+        return Ok(vec![path.to_owned()]);
+    }
+    let mut candidates = vec![PathBuf::new()];
+    for component in path.iter() {
+        let pattern = component.as_bytes();
+        let mut next = Vec::new();
+        for prefix in candidates {
+            if !is_glob(pattern) {
+                next.push(prefix.join(component));
+                continue;
+            }
+            let d = if prefix.as_os_str().is_empty() {
+                rootfs.try_clone()?
+            } else {
+                match rootfs.open_dir_optional(&prefix)? {
+                    Some(d) => d,
+                    None => continue,
+                }
+            };
+            let mut matches = Vec::new();
+            for child in d.entries()? {
+                let name = child?.file_name();
+                // Like shell globs, patterns don't match dotfiles unless explicit
+                if name.as_bytes().starts_with(b".") && !pattern.starts_with(b".") {
+                    continue;
+                }
+                if glob_match(pattern, name.as_bytes()) {
+                    matches.push(prefix.join(name));
+                }
+            }
+            matches.sort();
+            next.extend(matches);
+        }
+        candidates = next;
+    }
+    let mut r = Vec::new();
+    for p in candidates {
+        if rootfs.symlink_metadata_optional(&p)?.is_some() {
+            r.push(p);
+        }
+    }
+    Ok(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::mock_userdb;
+
+    #[test]
+    fn test_glob_match() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("foo", "foo", true),
+            ("foo", "bar", false),
+            ("*", "anything", true),
+            ("f*o", "fooo", true),
+            ("f*o", "foob", false),
+            ("f?o", "foo", true),
+            ("f?o", "fo", false),
+            ("[a-c]x", "bx", true),
+            ("[a-c]x", "dx", false),
+            ("[!a-c]x", "dx", true),
+            ("systemd-private-*", "systemd-private-abc", true),
+        ];
+        for (pattern, name, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), name.as_bytes()),
+                *expected,
+                "{pattern} {name}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_apply() -> anyhow::Result<()> {
+        let rootfs = &cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let userdb = &mock_userdb();
+        let uid = rustix::process::getuid().as_raw();
+
+        rootfs.create_dir_all("usr/share/factory/etc/skel")?;
+        rootfs.write("usr/share/factory/etc/skel/.bashrc", "# bashrc\n")?;
+        rootfs.create_dir_all("var/tmp/old")?;
+        rootfs.write("var/tmp/old/file", "x")?;
+        rootfs.create_dir_all("var/cache/foo")?;
+        rootfs.write("var/cache/foo/a", "a")?;
+        rootfs.write("var/cache/foo/b", "b")?;
+        rootfs.create_dir("etc")?;
+        rootfs.write("etc/hostname", "old\n")?;
+
+        rootfs.create_dir_all(TMPFILESD)?;
+        rootfs.create_dir_all(ETC_TMPFILESD)?;
+        rootfs.write(
+            Path::new(TMPFILESD).join("test.conf"),
+            indoc::indoc! { r#"
+            d /var/lib/foo 0750 testuser testgroup -
+            f /var/lib/foo/config 0600 - - - key=value\n
+            f /etc/hostname 0644 - - - new
+            F /var/lib/foo/truncated - - - - replaced
+            w+ /etc/hostname - - - - appended
+            L /etc/localtime - - - - ../usr/share/zoneinfo/UTC
+            L /var/lib/factory-link
+            C /etc/skel
+            R /var/tmp/old
+            Z /var/cache/foo ~0750 - - -
+            z /var/cache/missing* 0600 - - -
+            p /run/foo.fifo 0600
+            x /tmp/systemd-private-*
+            d! /run/bootonly
+            t /var/lib/foo - - - - user.foo=bar
+            f^ /etc/secret - - - - my.credential
+            f~ /var/lib/foo/encoded - - - - aGVsbG8=
+            "#},
+        )?;
+        rootfs.write(Path::new(TMPFILESD).join("masked.conf"), "d /var/masked\n")?;
+        rootfs.symlink_contents("/dev/null", Path::new(ETC_TMPFILESD).join("masked.conf"))?;
+
+        let entries = read_all_tmpfiles(rootfs)?.entries;
+        assert_eq!(entries.len(), 17);
+        let r = apply(rootfs, &entries, userdb, userdb, &ApplyOptions::default())?;
+
+        let meta = rootfs.metadata("var/lib/foo")?;
+        assert!(meta.is_dir());
+        assert_eq!(meta.mode() & 0o7777, 0o750);
+        assert_eq!(meta.uid(), uid);
+        let meta = rootfs.metadata("var/lib/foo/config")?;
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert_eq!(rootfs.read_to_string("var/lib/foo/config")?, "key=value\n");
+        assert_eq!(rootfs.read_to_string("var/lib/foo/truncated")?, "replaced");
+        assert_eq!(rootfs.read_to_string("var/lib/foo/encoded")?, "hello");
+        // `f` doesn't replace existing content, but `w+` appends
+        assert_eq!(rootfs.read_to_string("etc/hostname")?, "old\nappended");
+        assert_eq!(
+            rootfs.read_link_contents("etc/localtime")?,
+            Path::new("../usr/share/zoneinfo/UTC")
+        );
+        assert_eq!(
+            rootfs.read_link_contents("var/lib/factory-link")?,
+            Path::new("/usr/share/factory/var/lib/factory-link")
+        );
+        assert_eq!(rootfs.read_to_string("etc/skel/.bashrc")?, "# bashrc\n");
+        assert!(!rootfs.try_exists("var/tmp/old")?);
+        assert!(rootfs.try_exists("var/tmp")?);
+        assert_eq!(rootfs.metadata("var/cache/foo")?.mode() & 0o7777, 0o750);
+        for p in ["var/cache/foo/a", "var/cache/foo/b"] {
+            assert_eq!(rootfs.metadata(p)?.mode() & 0o7777, 0o640);
+        }
+        let meta = rootfs.symlink_metadata("run/foo.fifo")?;
+        assert_eq!(FileType::from_raw_mode(meta.mode()), FileType::Fifo);
+        assert_eq!(meta.mode() & 0o7777, 0o600);
+        assert!(!rootfs.try_exists("run/bootonly")?);
+        assert!(!rootfs.try_exists("var/masked")?);
+        assert!(!rootfs.try_exists("etc/secret")?);
+
+        let skipped = r
+            .skipped
+            .iter()
+            .map(|s| (s.entry.path.to_str().unwrap(), s.reason.as_str()))
+            .collect::<Vec<_>>();
+        similar_asserts::assert_eq!(
+            skipped,
+            [
+                ("/etc/secret", "credentials are not available"),
+                ("/run/bootonly", "only applied at boot"),
+                ("/var/lib/foo", "not supported offline"),
+            ]
+        );
+        assert_eq!(r.applied, 13);
+
+        // Applying again is idempotent
+        let r = apply(rootfs, &entries, userdb, userdb, &ApplyOptions::default())?;
+        assert_eq!(r.applied, 13);
+        assert_eq!(
+            rootfs.read_to_string("etc/hostname")?,
+            "old\nappendedappended"
+        );
+
+        // Errors are fatal, unless ignored with `-`; unknown users are skipped
+        let entries = [
+            TmpfilesEntry::parse("d- /etc/hostname")?.unwrap(),
+            TmpfilesEntry::parse("d /var/lib/foo - nosuchuser -")?.unwrap(),
+        ];
+        let r = apply(rootfs, &entries, userdb, userdb, &ApplyOptions::default())?;
+        let skipped = r
+            .skipped
+            .iter()
+            .map(|s| s.reason.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(skipped.len(), 2);
+        assert_eq!(skipped[1], "User not found: nosuchuser");
+        let r = apply(
+            rootfs,
+            &[TmpfilesEntry::parse("d /etc/hostname")?.unwrap()],
+            userdb,
+            userdb,
+            &ApplyOptions::default(),
+        );
+        assert!(matches!(r, Err(Error::PathIo { .. })));
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_prefix() -> anyhow::Result<()> {
+        let var = &cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let userdb = &mock_userdb();
+
+        var.create_dir_all("cache/app/keep")?;
+        var.write("cache/app/keep/file", "x")?;
+        var.create_dir_all("cache/app/sub/kept")?;
+        var.write("cache/app/sub/kept/file", "x")?;
+        var.write("cache/app/sub/file", "x")?;
+        var.create_dir_all("cache/app/other")?;
+        var.write("cache/app/file", "x")?;
+
+        let entries = indoc::indoc! { r#"
+            d /var 0755 - - -
+            d /var/lib/foo 0750 - - -
+            L /var/lib/link
+            D /var/cache/app 0755 - - -
+            x /var/cache/app/keep
+            X /var/cache/app/sub/kept
+            d /etc/outside
+            C /var/lib/copy
+            C /var/lib/copy2 - - - - /var/cache/app/keep
+        "#}
+        .lines()
+        .map(|l| TmpfilesEntry::parse(l).map(Option::unwrap))
+        .collect::<Result<Vec<_>>>()?;
+        let opts = ApplyOptions {
+            prefix: Some("/var".into()),
+            ..Default::default()
+        };
+        let r = apply(var, &entries, userdb, userdb, &opts)?;
+
+        assert_eq!(var.metadata("lib/foo")?.mode() & 0o7777, 0o750);
+        assert!(!var.try_exists("etc")?);
+        assert_eq!(
+            var.read_link_contents("lib/link")?,
+            Path::new("/usr/share/factory/var/lib/link")
+        );
+        // The contents of `D` are removed, except for excluded paths
+        assert!(var.try_exists("cache/app/keep/file")?);
+        assert!(var.try_exists("cache/app/sub/kept")?);
+        for p in [
+            "cache/app/file",
+            "cache/app/other",
+            "cache/app/sub/file",
+            "cache/app/sub/kept/file",
+        ] {
+            assert!(!var.try_exists(p)?, "{p}");
+        }
+        // Copies are only possible from within the prefix
+        assert_eq!(var.read_to_string("lib/copy2/file")?, "x");
+        assert!(!var.try_exists("lib/copy")?);
+        let skipped = r
+            .skipped
+            .iter()
+            .map(|s| (s.entry.path.to_str().unwrap(), s.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            skipped,
+            [("/var/lib/copy", "copy source is outside of the root")]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_invalid_lines() -> anyhow::Result<()> {
+        let rootfs = &cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let userdb = &mock_userdb();
+
+        rootfs.create_dir_all(TMPFILESD)?;
+        rootfs.write(
+            Path::new(TMPFILESD).join("test.conf"),
+            indoc::indoc! { r#"
+            d /var/lib/foo 0750 - - -
+            y /var/lib/newtype
+            d /var/tmp/foo - - - 1fortnight
+            d /var/lib/%u
+            f /var/lib/foo/config - - - - x
+            "#},
+        )?;
+
+        // Lines which can't be parsed are reported and ignored
+        let r = read_all_tmpfiles(rootfs)?;
+        let invalid = r
+            .invalid
+            .iter()
+            .map(|l| (l.file.to_str().unwrap(), l.line))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            invalid,
+            [
+                ("usr/lib/tmpfiles.d/test.conf", 2),
+                ("usr/lib/tmpfiles.d/test.conf", 3)
+            ]
+        );
+        assert_eq!(r.entries.len(), 3);
+
+        let r = apply(rootfs, &r.entries, userdb, userdb, &ApplyOptions::default())?;
+        assert_eq!(rootfs.metadata("var/lib/foo")?.mode() & 0o7777, 0o750);
+        assert_eq!(rootfs.read_to_string("var/lib/foo/config")?, "x");
+        assert!(!rootfs.try_exists("var/tmp/foo")?);
+        // Specifiers can't be expanded without the running system
+        assert!(!rootfs.try_exists("var/lib/%u")?);
+        let skipped = r
+            .skipped
+            .iter()
+            .map(|s| (s.entry.path.to_str().unwrap(), s.reason.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(skipped, [("/var/lib/%u", "specifiers are not supported")]);
+        assert_eq!(r.applied, 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,631 @@
+//! Parsing of tmpfiles.d lines, following the grammar in tmpfiles.d(5).
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::iter::Peekable;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::{Error, Result, impl_unescape_path_until, unescape_path};
+
+/// The type of a tmpfiles.d line, without modifiers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LineType {
+    /// `f`: Create a file
+    CreateFile,
+    /// `w`: Write the argument to an existing file
+    WriteFile,
+    /// `d`: Create a directory
+    CreateDirectory,
+    /// `D`: Create a directory, whose contents are removed with `--remove`
+    TruncateDirectory,
+    /// `e`: Adjust an existing directory
+    AdjustDirectory,
+    /// `v`: Create a btrfs subvolume, or a directory
+    CreateSubvolume,
+    /// `q`: Like `v`, inheriting the quota group of the parent
+    CreateSubvolumeInheritQuota,
+    /// `Q`: Like `v`, with a new quota group
+    CreateSubvolumeNewQuota,
+    /// `p`: Create a named pipe
+    CreateFifo,
+    /// `L`: Create a symbolic link
+    CreateSymlink,
+    /// `c`: Create a character device node
+    CreateCharDevice,
+    /// `b`: Create a block device node
+    CreateBlockDevice,
+    /// `C`: Recursively copy a file or directory
+    Copy,
+    /// `x`: Exclude a path from cleanup
+    IgnorePath,
+    /// `X`: Exclude a path, but not its contents, from cleanup
+    IgnoreDirectoryPath,
+    /// `r`: Remove a file or empty directory
+    RemovePath,
+    /// `R`: Recursively remove a path
+    RecursiveRemovePath,
+    /// `z`: Adjust the mode and ownership of a path
+    RelabelPath,
+    /// `Z`: Recursively adjust the mode and ownership of a path
+    RecursiveRelabelPath,
+    /// `t`: Set extended attributes
+    SetXattr,
+    /// `T`: Recursively set extended attributes
+    RecursiveSetXattr,
+    /// `h`: Set file attributes
+    SetAttribute,
+    /// `H`: Recursively set file attributes
+    RecursiveSetAttribute,
+    /// `a`: Set POSIX ACLs
+    SetAcl,
+    /// `A`: Recursively set POSIX ACLs
+    RecursiveSetAcl,
+}
+
+impl LineType {
+    /// Parse a single type character.
+    fn from_char(c: char) -> Option<Self> {
+        let r = match c {
+            'f' | 'F' => Self::CreateFile,
+            'w' => Self::WriteFile,
+            'd' => Self::CreateDirectory,
+            'D' => Self::TruncateDirectory,
+            'e' => Self::AdjustDirectory,
+            'v' => Self::CreateSubvolume,
+            'q' => Self::CreateSubvolumeInheritQuota,
+            'Q' => Self::CreateSubvolumeNewQuota,
+            'p' => Self::CreateFifo,
+            'L' => Self::CreateSymlink,
+            'c' => Self::CreateCharDevice,
+            'b' => Self::CreateBlockDevice,
+            'C' => Self::Copy,
+            'x' => Self::IgnorePath,
+            'X' => Self::IgnoreDirectoryPath,
+            'r' => Self::RemovePath,
+            'R' => Self::RecursiveRemovePath,
+            'z' => Self::RelabelPath,
+            'Z' => Self::RecursiveRelabelPath,
+            't' => Self::SetXattr,
+            'T' => Self::RecursiveSetXattr,
+            'h' => Self::SetAttribute,
+            'H' => Self::RecursiveSetAttribute,
+            'a' => Self::SetAcl,
+            'A' => Self::RecursiveSetAcl,
+            _ => return None,
+        };
+        Some(r)
+    }
+
+    /// Whether this type creates the path if it does not exist.
+    pub fn creates(&self) -> bool {
+        matches!(
+            self,
+            Self::CreateFile
+                | Self::CreateDirectory
+                | Self::TruncateDirectory
+                | Self::CreateSubvolume
+                | Self::CreateSubvolumeInheritQuota
+                | Self::CreateSubvolumeNewQuota
+                | Self::CreateFifo
+                | Self::CreateSymlink
+                | Self::CreateCharDevice
+                | Self::CreateBlockDevice
+                | Self::Copy
+        )
+    }
+
+    /// Whether this type creates a directory.
+    pub fn is_directory(&self) -> bool {
+        matches!(
+            self,
+            Self::CreateDirectory
+                | Self::TruncateDirectory
+                | Self::AdjustDirectory
+                | Self::CreateSubvolume
+                | Self::CreateSubvolumeInheritQuota
+                | Self::CreateSubvolumeNewQuota
+        )
+    }
+
+    /// Whether the path may contain glob patterns.
+    pub fn supports_globs(&self) -> bool {
+        matches!(
+            self,
+            Self::WriteFile
+                | Self::AdjustDirectory
+                | Self::IgnorePath
+                | Self::IgnoreDirectoryPath
+                | Self::RemovePath
+                | Self::RecursiveRemovePath
+                | Self::RelabelPath
+                | Self::RecursiveRelabelPath
+                | Self::SetXattr
+                | Self::RecursiveSetXattr
+                | Self::SetAttribute
+                | Self::RecursiveSetAttribute
+                | Self::SetAcl
+                | Self::RecursiveSetAcl
+        )
+    }
+}
+
+/// The mode field of a tmpfiles.d line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMode {
+    /// The permission bits
+    pub mode: u32,
+    /// `~`: Mask the mode based on the access bits already set on the path
+    pub masked: bool,
+    /// `:`: Only apply the mode when creating the path
+    pub create_only: bool,
+}
+
+/// The user or group field of a tmpfiles.d line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryOwner {
+    /// The user or group name, or a numeric ID
+    pub name: String,
+    /// `:`: Only apply the owner when creating the path
+    pub create_only: bool,
+}
+
+/// The age field of a tmpfiles.d line, used for cleanup.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryAge {
+    /// Files older than this are removed on cleanup
+    pub age: Duration,
+    /// `~`: Only consider the contents of subdirectories
+    pub subdirs_only: bool,
+    /// Which timestamps to consider, e.g. `cmA`; by default all of them
+    pub age_by: Option<String>,
+}
+
+/// A parsed tmpfiles.d line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TmpfilesEntry {
+    /// The line type
+    pub line_type: LineType,
+    /// `+`: Replace or append, depending on the type; also set for `F`
+    pub plus: bool,
+    /// `!`: Only apply at boot
+    pub boot_only: bool,
+    /// `-`: Ignore errors when creating the path
+    pub ignore_errors: bool,
+    /// `=`: Remove an existing path of a different type
+    pub force_type: bool,
+    /// `~`: The argument is base64 encoded
+    pub base64: bool,
+    /// `^`: The argument names a credential
+    pub credential: bool,
+    /// `$`: The path is removed with `--purge`
+    pub purge: bool,
+    /// The absolute path
+    pub path: PathBuf,
+    /// The mode, if set
+    pub mode: Option<EntryMode>,
+    /// The user, if set
+    pub user: Option<EntryOwner>,
+    /// The group, if set
+    pub group: Option<EntryOwner>,
+    /// The age, if set
+    pub age: Option<EntryAge>,
+    /// The argument, if set. For `f` and `w` lines C-style escapes have been
+    /// processed.
+    pub argument: Option<String>,
+}
+
+/// Skip whitespace, and return the next field if there is one, processing
+/// quotes and escapes.
+fn next_field<I>(it: &mut Peekable<I>) -> Result<Option<String>>
+where
+    I: Iterator<Item = u8>,
+{
+    while it.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+    if it.peek().is_none() {
+        return Ok(None);
+    }
+    let mut buf = Vec::new();
+    if it.next_if_eq(&b'"').is_some() {
+        impl_unescape_path_until(it, &mut buf, true)?;
+        // Skip the closing quote
+        it.next();
+    } else {
+        impl_unescape_path_until(it, &mut buf, false)?;
+    }
+    String::from_utf8(buf)
+        .map(Some)
+        .map_err(|_| Error::MalformedTmpfilesPath)
+}
+
+/// Process C-style escapes, as used for the argument of `f` and `w` lines.
+fn cunescape(s: &str) -> Option<String> {
+    let mut r = Vec::new();
+    let mut it = s.bytes();
+    while let Some(c) = it.next() {
+        if c != b'\\' {
+            r.push(c);
+            continue;
+        }
+        let c = match it.next()? {
+            b'\\' => b'\\',
+            b'"' => b'"',
+            b'\'' => b'\'',
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            b's' => b' ',
+            b'x' => {
+                let hex = [it.next()?, it.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            c @ b'0'..=b'7' => {
+                let oct = [c, it.next()?, it.next()?];
+                u8::from_str_radix(std::str::from_utf8(&oct).ok()?, 8).ok()?
+            }
+            _ => return None,
+        };
+        r.push(c);
+    }
+    String::from_utf8(r).ok()
+}
+
+/// Parse a time span such as `10d`, `1h30min` or `500ms`; a number without a
+/// unit is in seconds.
+fn parse_timespan(s: &str) -> Option<Duration> {
+    let mut total = Duration::ZERO;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.as_bytes().len());
+        if digits == 0 {
+            return None;
+        }
+        let n: u64 = rest[..digits].parse().ok()?;
+        rest = rest[digits..].trim_start();
+        let unit_end = rest
+            .find(|c: char| !c.is_ascii_alphabetic())
+            .unwrap_or(rest.as_bytes().len());
+        let (unit, next) = rest.split_at(unit_end);
+        let v = match unit {
+            "us" | "usec" => Duration::from_micros(n),
+            "ms" | "msec" => Duration::from_millis(n),
+            "" | "s" | "sec" | "second" | "seconds" => Duration::from_secs(n),
+            "m" | "min" | "minute" | "minutes" => Duration::from_secs(n.checked_mul(60)?),
+            "h" | "hr" | "hour" | "hours" => Duration::from_secs(n.checked_mul(60 * 60)?),
+            "d" | "day" | "days" => Duration::from_secs(n.checked_mul(24 * 60 * 60)?),
+            "w" | "week" | "weeks" => Duration::from_secs(n.checked_mul(7 * 24 * 60 * 60)?),
+            // As in systemd, a month is 30.44 days and a year 365.25 days
+            "M" | "month" | "months" => Duration::from_secs(n.checked_mul(2_629_800)?),
+            "y" | "year" | "years" => Duration::from_secs(n.checked_mul(31_557_600)?),
+            _ => return None,
+        };
+        total = total.checked_add(v)?;
+        rest = next.trim_start();
+    }
+    Some(total)
+}
+
+impl EntryMode {
+    fn parse(s: &str) -> Option<Self> {
+        let (create_only, s) = match s.strip_prefix(':') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let (masked, s) = match s.strip_prefix('~') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let mode = u32::from_str_radix(s, 8).ok().filter(|&m| m <= 0o7777)?;
+        Some(Self {
+            mode,
+            masked,
+            create_only,
+        })
+    }
+}
+
+impl EntryOwner {
+    fn parse(s: &str) -> Self {
+        match s.strip_prefix(':') {
+            Some(name) => Self {
+                name: name.to_owned(),
+                create_only: true,
+            },
+            None => Self {
+                name: s.to_owned(),
+                create_only: false,
+            },
+        }
+    }
+}
+
+impl EntryAge {
+    fn parse(s: &str) -> Option<Self> {
+        let (age_by, s) = match s.split_once(':') {
+            Some((by, rest)) if !by.is_empty() && by.chars().all(|c| "abcmABCM".contains(c)) => {
+                (Some(by.to_owned()), rest)
+            }
+            _ => (None, s),
+        };
+        let (subdirs_only, s) = match s.strip_prefix('~') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+        let age = parse_timespan(s)?;
+        Some(Self {
+            age,
+            subdirs_only,
+            age_by,
+        })
+    }
+}
+
+impl TmpfilesEntry {
+    /// Parse a single tmpfiles.d line. Returns `None` for empty lines and
+    /// comments.
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let err = || Error::MalformedTmpfilesEntry(line.to_string());
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Ok(None);
+        }
+        let mut it = trimmed.bytes().peekable();
+        let ty = next_field(&mut it)?.ok_or_else(err)?;
+        let mut chars = ty.chars();
+        let type_char = chars.next().ok_or_else(err)?;
+        let line_type = LineType::from_char(type_char).ok_or_else(err)?;
+        let mut r = Self {
+            line_type,
+            plus: type_char == 'F',
+            boot_only: false,
+            ignore_errors: false,
+            force_type: false,
+            base64: false,
+            credential: false,
+            purge: false,
+            path: PathBuf::new(),
+            mode: None,
+            user: None,
+            group: None,
+            age: None,
+            argument: None,
+        };
+        for c in chars {
+            let flag = match c {
+                '+' => &mut r.plus,
+                '!' => &mut r.boot_only,
+                '-' => &mut r.ignore_errors,
+                '=' => &mut r.force_type,
+                '~' => &mut r.base64,
+                '^' => &mut r.credential,
+                '$' => &mut r.purge,
+                _ => return Err(err()),
+            };
+            *flag = true;
+        }
+
+        while it.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        r.path = unescape_path(&mut it)?;
+        // Skip the closing quote, if any
+        it.next_if_eq(&b'"');
+        if !r.path.is_absolute() {
+            return Err(err());
+        }
+
+        let optional = |v: Option<String>| v.filter(|v| v != "-");
+        r.mode = optional(next_field(&mut it)?)
+            .map(|m| EntryMode::parse(&m).ok_or_else(err))
+            .transpose()?;
+        r.user = optional(next_field(&mut it)?).map(|u| EntryOwner::parse(&u));
+        r.group = optional(next_field(&mut it)?).map(|g| EntryOwner::parse(&g));
+        r.age = optional(next_field(&mut it)?)
+            .map(|a| EntryAge::parse(&a).ok_or_else(err))
+            .transpose()?;
+
+        // The argument is the rest of the line
+        while it.next_if(|c| c.is_ascii_whitespace()).is_some() {}
+        let argument = String::from_utf8(it.collect()).map_err(|_| err())?;
+        r.argument = match argument.as_str() {
+            "" | "-" => None,
+            a if matches!(line_type, LineType::CreateFile | LineType::WriteFile) => {
+                Some(cunescape(a).ok_or_else(err)?)
+            }
+            a => Some(a.to_owned()),
+        };
+        Ok(Some(r))
+    }
+
+    /// Whether the path or argument use specifiers such as `%h`, which
+    /// systemd-tmpfiles expands for the running system.
+    pub fn has_specifiers(&self) -> bool {
+        self.path.as_os_str().as_bytes().contains(&b'%')
+            || self.argument.as_ref().is_some_and(|a| a.contains('%'))
+    }
+
+    /// The content for `f` and `w` lines, decoding base64 if requested.
+    pub fn content(&self) -> Result<Option<Vec<u8>>> {
+        let Some(argument) = self.argument.as_deref() else {
+            return Ok(None);
+        };
+        if !self.base64 {
+            return Ok(Some(argument.as_bytes().to_vec()));
+        }
+        base64_decode(argument)
+            .map(Some)
+            .ok_or_else(|| Error::MalformedTmpfilesEntry(argument.to_owned()))
+    }
+}
+
+/// Decode standard base64, ignoring whitespace.
+fn base64_decode(s: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        Some(v.into())
+    }
+
+    let input = s
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace())
+        .collect::<Vec<_>>();
+    let input = match input.iter().position(|&c| c == b'=') {
+        Some(i) if input[i..].iter().all(|&c| c == b'=') => &input[..i],
+        Some(_) => return None,
+        None => &input[..],
+    };
+    let mut r = Vec::with_capacity(input.len() * 3 / 4);
+    for chunk in input.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut acc = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            acc |= value(c)? << (18 - 6 * i);
+        }
+        let bytes = acc.to_be_bytes();
+        r.extend_from_slice(&bytes[1..chunk.len()]);
+    }
+    Some(r)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() -> anyhow::Result<()> {
+        assert!(TmpfilesEntry::parse("")?.is_none());
+        assert!(TmpfilesEntry::parse("  # comment")?.is_none());
+
+        let e = TmpfilesEntry::parse("d /var/lib/foo 0750 foo :bar 10d -")?.unwrap();
+        assert_eq!(e.line_type, LineType::CreateDirectory);
+        assert_eq!(e.path, PathBuf::from("/var/lib/foo"));
+        assert_eq!(
+            e.mode,
+            Some(EntryMode {
+                mode: 0o750,
+                masked: false,
+                create_only: false
+            })
+        );
+        assert_eq!(e.user.as_ref().unwrap().name, "foo");
+        assert!(!e.user.as_ref().unwrap().create_only);
+        assert_eq!(
+            e.group,
+            Some(EntryOwner {
+                name: "bar".into(),
+                create_only: true
+            })
+        );
+        assert_eq!(e.age.as_ref().unwrap().age, Duration::from_secs(10 * 86400));
+        assert_eq!(e.argument, None);
+        assert!(!e.has_specifiers());
+
+        for (age, secs) in [
+            ("30days", 30 * 86400),
+            ("1week 2d", 9 * 86400),
+            ("1M", 2_629_800),
+            ("2y", 2 * 31_557_600),
+            ("1h30min", 5400),
+        ] {
+            let e = TmpfilesEntry::parse(&format!("d /var/tmp/foo - - - {age}"))?.unwrap();
+            assert_eq!(e.age.unwrap().age, Duration::from_secs(secs), "{age}");
+        }
+        let e = TmpfilesEntry::parse("d /var/lib/%u/cache")?.unwrap();
+        assert!(e.has_specifiers());
+        let e = TmpfilesEntry::parse("L /var/lib/foo - - - - /run/%m")?.unwrap();
+        assert!(e.has_specifiers());
+
+        let e =
+            TmpfilesEntry::parse(r#"f+! "/etc/my file" ~0644 - - - hello\tworld \x41"#)?.unwrap();
+        assert_eq!(e.line_type, LineType::CreateFile);
+        assert!(e.plus && e.boot_only && !e.ignore_errors);
+        assert_eq!(e.path, PathBuf::from("/etc/my file"));
+        assert!(e.mode.unwrap().masked);
+        assert_eq!(e.argument.as_deref(), Some("hello\tworld A"));
+
+        let e = TmpfilesEntry::parse("F /run/foo")?.unwrap();
+        assert_eq!(e.line_type, LineType::CreateFile);
+        assert!(e.plus);
+        assert_eq!(e.mode, None);
+
+        let e = TmpfilesEntry::parse("f~ /root/.ssh/authorized_keys 600 root root - aGVsbG8=")?
+            .unwrap();
+        assert_eq!(e.content()?.unwrap(), b"hello");
+
+        let e =
+            TmpfilesEntry::parse("L+ /etc/localtime - - - - ../usr/share/zoneinfo/UTC")?.unwrap();
+        assert_eq!(e.line_type, LineType::CreateSymlink);
+        assert_eq!(e.argument.as_deref(), Some("../usr/share/zoneinfo/UTC"));
+
+        let e = TmpfilesEntry::parse("e /var/tmp 1777 root root cm:~1h30min")?.unwrap();
+        let age = e.age.unwrap();
+        assert_eq!(age.age, Duration::from_secs(90 * 60));
+        assert!(age.subdirs_only);
+        assert_eq!(age.age_by.as_deref(), Some("cm"));
+
+        let e = TmpfilesEntry::parse("t /var/log/journal - - - - user.foo=bar baz")?.unwrap();
+        assert_eq!(e.line_type, LineType::SetXattr);
+        assert_eq!(e.argument.as_deref(), Some("user.foo=bar baz"));
+
+        for (line, ty) in [
+            ("w /proc/sys/kernel/foo - - - - 1", LineType::WriteFile),
+            ("C /etc/foo - - - - /usr/share/foo", LineType::Copy),
+            ("z /dev/kvm 0666 - kvm -", LineType::RelabelPath),
+            ("Z /var/lib/foo - foo foo", LineType::RecursiveRelabelPath),
+            ("x /tmp/systemd-private-*", LineType::IgnorePath),
+            ("R /var/tmp/foo", LineType::RecursiveRemovePath),
+            ("c! /dev/fuse 0666 - - - 10:229", LineType::CreateCharDevice),
+            (
+                "a+ /var/lib/foo - - - - default:group:tss:rwx",
+                LineType::SetAcl,
+            ),
+        ] {
+            assert_eq!(TmpfilesEntry::parse(line)?.unwrap().line_type, ty, "{line}");
+        }
+
+        for invalid in [
+            "y /foo",
+            "d relative/path",
+            "d /foo 0999",
+            "d /foo - - - 3parsecs",
+            "d+% /foo",
+            "f /foo - - - - \\q",
+        ] {
+            assert!(TmpfilesEntry::parse(invalid).is_err(), "{invalid}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_base64_decode() {
+        let cases: &[(&str, &[u8])] = &[
+            ("", b""),
+            ("Zg==", b"f"),
+            ("Zm8=", b"fo"),
+            ("Zm9v", b"foo"),
+            ("Zm9v\nYmFy", b"foobar"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(base64_decode(input).unwrap(), *expected, "{input}");
+        }
+        assert!(base64_decode("Z").is_none());
+        assert!(base64_decode("Zm=v").is_none());
+        assert!(base64_decode("Zm9*").is_none());
+    }
+}
@@ -1,10 +1,10 @@
 //! Parse and generate systemd tmpfiles.d entries.
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use std::collections::{BTreeMap, BTreeSet};
+use std::collections::BTreeSet;
 use std::ffi::{OsStr, OsString};
 use std::fmt::Write as WriteFmt;
-use std::io::{BufRead, BufReader, Write as StdWrite};
+use std::io::Write as StdWrite;
 use std::iter::Peekable;
 use std::num::NonZeroUsize;
 use std::os::unix::ffi::{OsStrExt, OsStringExt};
@@ -19,6 +19,13 @@ use rustix::fs::Mode;
 use rustix::path::Arg;
 use thiserror::Error;
 
+mod apply;
+mod entry;
+pub use apply::{
+    ApplyOptions, ApplyResult, InvalidLine, ReadResult, SkippedEntry, apply, read_all_tmpfiles,
+};
+pub use entry::{EntryAge, EntryMode, EntryOwner, LineType, TmpfilesEntry};
+
 const TMPFILESD: &str = "usr/lib/tmpfiles.d";
 const ETC_TMPFILESD: &str = "etc/tmpfiles.d";
 /// The path to the file we use for generation
@@ -37,6 +44,29 @@ impl BootcTmpfilesGeneration {
     fn path(&self) -> Utf8PathBuf {
         format!("{TMPFILESD}/{BOOTC_GENERATED_PREFIX}-{}.conf", self.0).into()
     }
+
+    /// Count the tmpfiles.d files previously generated by bootc.
+    fn load(rootfs: &Dir) -> Result<Self> {
+        let mut r = Self::default();
+        for dir_path in [TMPFILESD, ETC_TMPFILESD] {
+            let Some(tmpfiles_dir) = rootfs.open_dir_optional(dir_path)? else {
+                continue;
+            };
+            for entry in tmpfiles_dir.entries()? {
+                let name = entry?.file_name();
+                let name = Path::new(&name);
+                if name.extension().is_some_and(|ext| ext == "conf")
+                    && name
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .is_some_and(|stem| stem.starts_with(BOOTC_GENERATED_PREFIX))
+                {
+                    r.increment();
+                }
+            }
+        }
+        Ok(r)
+    }
 }
 
 /// An error when translating tmpfiles.d.
@@ -61,6 +91,10 @@ pub enum Error {
     MissingTmpfilesDir {},
     #[error("Found /var/run as a non-symlink")]
     FoundVarRunNonSymlink {},
+    #[error("User not found: {0}")]
+    UserNameNotFound(String),
+    #[error("Group not found: {0}")]
+    GroupNameNotFound(String),
     #[error("Malformed tmpfiles.d")]
     MalformedTmpfilesPath,
     #[error("Malformed tmpfiles.d line {0}")]
@@ -317,7 +351,7 @@ struct TmpfilesConvertConfig<'a, U: uzers::Users, G: uzers::Groups> {
     users: &'a U,
     groups: &'a G,
     rootfs: &'a Dir,
-    existing: &'a BTreeMap<PathBuf, String>,
+    existing: &'a BTreeSet<PathBuf>,
     readonly: bool,
 }
 
@@ -340,7 +374,7 @@ fn convert_path_to_tmpfiles_d_recurse<U: uzers::Users, G: uzers::Groups>(
         let fname = subpath.file_name();
         prefix.push(fname);
 
-        let has_tmpfiles_entry = config.existing.contains_key(prefix);
+        let has_tmpfiles_entry = config.existing.contains(prefix);
 
         // Translate this file entry.
         if !has_tmpfiles_entry {
@@ -453,80 +487,15 @@ pub fn find_missing_tmpfiles_current_root() -> Result<TmpfilesResult> {
     })
 }
 
-/// Read all tmpfiles.d entries from a single directory
-fn read_tmpfiles_from_dir(
-    rootfs: &Dir,
-    dir_path: &str,
-    generation: &mut BootcTmpfilesGeneration,
-) -> Result<BTreeMap<PathBuf, String>> {
-    let Some(tmpfiles_dir) = rootfs.open_dir_optional(dir_path)? else {
-        return Ok(Default::default());
-    };
-    let mut result = BTreeMap::new();
-    for entry in tmpfiles_dir.entries()? {
-        let entry = entry?;
-        let name = entry.file_name();
-        let (Some(stem), Some(extension)) =
-            (Path::new(&name).file_stem(), Path::new(&name).extension())
-        else {
-            continue;
-        };
-        if extension != "conf" {
-            continue;
-        }
-        if let Ok(s) = stem.as_str() {
-            if s.starts_with(BOOTC_GENERATED_PREFIX) {
-                generation.increment();
-            }
-        }
-        let r = BufReader::new(entry.open()?);
-        for line in r.lines() {
-            let line = line?;
-            if line.is_empty() || line.starts_with("#") {
-                continue;
-            }
-            let path = tmpfiles_entry_get_path(&line)?;
-            result.insert(path.to_owned(), line);
-        }
-    }
-    Ok(result)
-}
-
-/// Read all tmpfiles.d entries in the target directory, and return a mapping
-/// from (file path) => (single tmpfiles.d entry line)
-///
-/// This function reads from both `/usr/lib/tmpfiles.d` and `/etc/tmpfiles.d`,
-/// with `/etc` entries taking precedence (matching systemd's behavior).
-fn read_tmpfiles(rootfs: &Dir) -> Result<(BTreeMap<PathBuf, String>, BootcTmpfilesGeneration)> {
-    let mut generation = BootcTmpfilesGeneration::default();
-
-    // Read from /usr/lib/tmpfiles.d first (system/package-provided)
-    let mut result = read_tmpfiles_from_dir(rootfs, TMPFILESD, &mut generation)?;
-
-    // Read from /etc/tmpfiles.d and merge (user-provided, takes precedence)
-    let etc_result = read_tmpfiles_from_dir(rootfs, ETC_TMPFILESD, &mut generation)?;
-    // /etc entries override /usr/lib entries for the same path
-    result.extend(etc_result);
-
-    Ok((result, generation))
-}
-
-fn tmpfiles_entry_get_path(line: &str) -> Result<PathBuf> {
-    let err = || Error::MalformedTmpfilesEntry(line.to_string());
-    let mut it = line.as_bytes().iter().copied().peekable();
-    // Skip leading whitespace
-    while it.next_if(|c| c.is_ascii_whitespace()).is_some() {}
-    // Skip the file type
-    let mut found_ftype = false;
-    while it.next_if(|c| !c.is_ascii_whitespace()).is_some() {
-        found_ftype = true
-    }
-    if !found_ftype {
-        return Err(err());
-    }
-    // Skip trailing whitespace
-    while it.next_if(|c| c.is_ascii_whitespace()).is_some() {}
-    unescape_path(&mut it)
+/// Read the paths which have a tmpfiles.d entry in the target root, and the
+/// generation of the files written by bootc.
+fn read_tmpfiles(rootfs: &Dir) -> Result<(BTreeSet<PathBuf>, BootcTmpfilesGeneration)> {
+    let paths = read_all_tmpfiles(rootfs)?
+        .entries
+        .into_iter()
+        .map(|entry| entry.path)
+        .collect();
+    Ok((paths, BootcTmpfilesGeneration::load(rootfs)?))
 }
 
 #[cfg(test)]
@@ -554,8 +523,8 @@ mod tests {
             ),
         ];
         for (input, expected) in cases {
-            let path = tmpfiles_entry_get_path(input).unwrap();
-            assert_eq!(path, Path::new(expected), "Input: {input}");
+            let entry = TmpfilesEntry::parse(input).unwrap().unwrap();
+            assert_eq!(entry.path, Path::new(expected), "Input: {input}");
         }
     }
 
@@ -565,7 +534,7 @@ mod tests {
         Ok(root)
     }
 
-    fn mock_userdb() -> uzers::mock::MockUsers {
+    pub(crate) fn mock_userdb() -> uzers::mock::MockUsers {
         let testuid = rustix::process::getuid();
         let testgid = rustix::process::getgid();
         let mut users = uzers::mock::MockUsers::with_current_uid(testuid.as_raw());
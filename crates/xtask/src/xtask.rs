@@ -489,7 +489,8 @@ fn package_srpm(sh: &Shell) -> Result<()> {
 fn update_json_schemas(sh: &Shell) -> Result<()> {
     for (of, target) in [
         ("host", "docs/src/host-v1.schema.json"),
-        ("progress", "docs/src/progress-v0.schema.json"),
+        ("progress", "docs/src/progress-v1.schema.json"),
+        ("lint", "docs/src/lint-v1.schema.json"),
     ] {
         let schema = cmd!(sh, "cargo run -q -- internals print-json-schema --of={of}").read()?;
         std::fs::write(target, &schema)?;